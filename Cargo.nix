@@ -3777,6 +3777,10 @@ rec {
             name = "regex";
             packageId = "regex 1.2.1 (registry+https://github.com/rust-lang/crates.io-index)";
           }
+          {
+            name = "reqwest";
+            packageId = "reqwest 0.9.19 (registry+https://github.com/rust-lang/crates.io-index)";
+          }
           {
             name = "serde";
             packageId = "serde 1.0.98 (registry+https://github.com/rust-lang/crates.io-index)";
@@ -4429,6 +4433,10 @@ rec {
             name = "regex";
             packageId = "regex 1.2.1 (registry+https://github.com/rust-lang/crates.io-index)";
           }
+          {
+            name = "reqwest";
+            packageId = "reqwest 0.9.19 (registry+https://github.com/rust-lang/crates.io-index)";
+          }
           {
             name = "serde";
             packageId = "serde 1.0.98 (registry+https://github.com/rust-lang/crates.io-index)";
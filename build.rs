@@ -0,0 +1,30 @@
+//! Record which commit is being built, for the rfc39_build_info
+//! metric. Builds without git, like Nix's, can set RFC39_GIT_SHA.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=RFC39_GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
+    let sha = std::env::var("RFC39_GIT_SHA")
+        .ok()
+        .or_else(git_sha)
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=RFC39_GIT_SHA={}", sha);
+}
+
+fn git_sha() -> Option<String> {
+    let output = Command::new("git")
+        .args(&["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|sha| sha.trim().to_string())
+}
@@ -0,0 +1,15 @@
+# A maintainer entry with the optional matrix and keys attributes, in
+# the shape nixpkgs uses.
+{
+  0x4A6F = {
+    email = "0x4A6F@shackspace.de";
+    matrix = "@0x4a6f:matrix.org";
+    name = "Joachim Ernst";
+    github = "0x4A6F";
+    githubId = 9675338;
+    keys = [{
+      longkeyid = "rsa4096/0x1E7F6A7D6C9A1B2C";
+      fingerprint = "F466 A548 AD3F C1F1 8C88  4576 8702 7528 B006 D66D";
+    }];
+  };
+}
@@ -0,0 +1,8 @@
+# A maintainer entry without an email, which nixpkgs allows.
+{
+  0x4A6F = {
+    name = "Joachim Ernst";
+    github = "0x4A6F";
+    githubId = 9675338;
+  };
+}
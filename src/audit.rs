@@ -0,0 +1,132 @@
+//! A JSON Lines record of every action sync-team decides on, kept
+//! for dry runs too so they can be reviewed before the real run.
+
+use crate::cli::ExitError;
+use crate::maintainers::{GitHubID, GitHubName, Handle};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    /// Seconds since the epoch.
+    timestamp: u64,
+    action: &'a str,
+    github_id: Option<GitHubID>,
+    github_name: Option<&'a GitHubName>,
+    handle: Option<&'a Handle>,
+    dry_run: bool,
+    result: &'a str,
+}
+
+pub struct AuditLog {
+    file: Option<File>,
+    dry_run: bool,
+}
+
+impl AuditLog {
+    /// Append to the log at `path`. Without a path nothing is recorded.
+    pub fn open(
+        logger: &slog::Logger,
+        path: Option<&Path>,
+        dry_run: bool,
+    ) -> Result<AuditLog, ExitError> {
+        let file = match path {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .map_err(|err| {
+                        error!(logger, "Failed to open audit log {:?}: {:?}", path, err);
+                        err
+                    })?,
+            ),
+            None => None,
+        };
+
+        Ok(AuditLog { file, dry_run })
+    }
+
+    pub fn record(
+        &mut self,
+        action: &str,
+        github_id: Option<GitHubID>,
+        github_name: Option<&GitHubName>,
+        handle: Option<&Handle>,
+        result: &str,
+    ) -> Result<(), ExitError> {
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => return Ok(()),
+        };
+
+        let entry = Entry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("the clock is before the epoch")
+                .as_secs(),
+            action,
+            github_id,
+            github_name,
+            handle,
+            dry_run: self.dry_run,
+            result,
+        };
+
+        // One write per line, so concurrent readers never see half an
+        // entry.
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::read_to_string;
+
+    #[test]
+    fn test_record() {
+        let logger = crate::test_logger();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("audit.jsonl");
+
+        for _ in 0..2 {
+            let mut log = AuditLog::open(&logger, Some(&path), true).unwrap();
+            log.record(
+                "add",
+                Some(GitHubID::new(1)),
+                Some(&GitHubName::new("alice")),
+                Some(&Handle::new("alice")),
+                "planned",
+            )
+            .unwrap();
+        }
+
+        let lines: Vec<serde_json::Value> = read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["action"], "add");
+        assert_eq!(lines[1]["github_id"], 1);
+        assert_eq!(lines[1]["github_name"], "alice");
+        assert_eq!(lines[1]["handle"], "alice");
+        assert_eq!(lines[1]["dry_run"], true);
+        assert_eq!(lines[1]["result"], "planned");
+        assert!(lines[1]["timestamp"].is_u64());
+    }
+
+    #[test]
+    fn test_no_path() {
+        let mut log = AuditLog::open(&crate::test_logger(), None, false).unwrap();
+        log.record("keep", None, None, Some(&Handle::new("alice")), "noop")
+            .unwrap();
+    }
+}
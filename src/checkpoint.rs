@@ -0,0 +1,223 @@
+//! Track what happened to each GitHub ID a sync has already handled,
+//! so an interrupted sync can pick up where it left off.
+//!
+//! One `<github id>,<outcome>` per line, the outcome being the same
+//! result the audit log records. Lines with only an ID, from before
+//! outcomes were recorded, count as done.
+
+use crate::cli::ExitError;
+use crate::maintainers::GitHubID;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// The outcome of IDs checkpointed before outcomes were recorded.
+const DONE: &str = "done";
+
+/// Outcomes where a GitHub call failed, so the next run should try
+/// again.
+const RETRIED: &[&str] = &["failed", "lookup_failed"];
+
+pub struct Checkpoint {
+    path: PathBuf,
+    outcomes: HashMap<GitHubID, String>,
+    logger: slog::Logger,
+}
+
+impl Checkpoint {
+    pub fn load(logger: slog::Logger, path: &Path) -> Result<Checkpoint, ExitError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .map_err(|err| {
+                error!(
+                    logger,
+                    "Failed to open checkpoint file {:?}: {:?}", path, err
+                );
+                err
+            })?;
+
+        let mut outcomes = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|err| {
+                error!(
+                    logger,
+                    "Failed to read line from checkpoint file {:?}: {:?}", path, err
+                );
+                err
+            })?;
+
+            let mut parts = line.splitn(2, ',');
+            let id = parts.next().unwrap_or("").parse().map_err(|err| {
+                error!(logger, "Failed to parse checkpointed github id: {:?}", err);
+                err
+            })?;
+            let outcome = parts.next().unwrap_or(DONE);
+
+            outcomes.insert(GitHubID::new(id), outcome.to_string());
+        }
+
+        if !outcomes.is_empty() {
+            let retried = outcomes
+                .values()
+                .filter(|outcome| RETRIED.contains(&outcome.as_str()))
+                .count();
+            info!(logger, "Resuming from checkpoint";
+                  "completed" => outcomes.len() - retried,
+                  "retrying" => retried,
+            );
+        }
+
+        Ok(Checkpoint {
+            path: path.to_path_buf(),
+            outcomes,
+            logger,
+        })
+    }
+
+    /// Whether an earlier run already dealt with this ID for good.
+    /// IDs whose calls failed are tried again.
+    pub fn done(&self, id: &GitHubID) -> bool {
+        self.outcomes
+            .get(id)
+            .map_or(false, |outcome| !RETRIED.contains(&outcome.as_str()))
+    }
+
+    /// Record what happened to the ID and write the checkpoint out
+    /// straight away, so it survives the process being killed.
+    pub fn complete(&mut self, id: GitHubID, outcome: &str) -> Result<(), ExitError> {
+        self.outcomes.insert(id, outcome.to_string());
+        self.save()
+    }
+
+    /// Replace the file in one rename, so being killed mid-write
+    /// leaves the previous checkpoint intact.
+    fn save(&self) -> Result<(), ExitError> {
+        self.write_temp()?.persist(&self.path).map_err(|err| {
+            error!(
+                self.logger,
+                "Failed to replace checkpoint file {:?}: {:?}", self.path, err.error
+            );
+            err.error
+        })?;
+
+        Ok(())
+    }
+
+    fn write_temp(&self) -> Result<NamedTempFile, ExitError> {
+        let dir = match self.path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let mut file = NamedTempFile::new_in(dir).map_err(|err| {
+            error!(
+                self.logger,
+                "Failed to create checkpoint file next to {:?}: {:?}", self.path, err,
+            );
+            err
+        })?;
+
+        let mut values = self.outcomes.iter().collect::<Vec<_>>();
+        values.sort();
+
+        let string = values
+            .into_iter()
+            .map(|(id, outcome)| format!("{},{}", id, outcome))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        file.write_all(string.as_ref()).map_err(|err| {
+            error!(
+                self.logger,
+                "Failed to write checkpoint file {:?}: {:?}", self.path, err
+            );
+            err
+        })?;
+
+        Ok(file)
+    }
+
+    /// The sync finished, so the next one should start from scratch.
+    pub fn clear(self) -> Result<(), ExitError> {
+        fs::remove_file(&self.path).map_err(|err| {
+            error!(
+                self.logger,
+                "Failed to remove checkpoint file {:?}: {:?}", self.path, err
+            );
+            err
+        })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpfile = tmpdir.path().join("checkpoint.txt");
+
+        let mut checkpoint = Checkpoint::load(crate::test_logger(), &tmpfile).unwrap();
+        assert!(!checkpoint.done(&GitHubID::new(1)));
+        checkpoint.complete(GitHubID::new(1), "added").unwrap();
+        checkpoint.complete(GitHubID::new(7), "removed").unwrap();
+        checkpoint.complete(GitHubID::new(8), "failed").unwrap();
+
+        // an interrupted run leaves the checkpoint behind
+        drop(checkpoint);
+        assert_eq!(
+            fs::read_to_string(&tmpfile).unwrap(),
+            "1,added\n7,removed\n8,failed"
+        );
+
+        let resumed = Checkpoint::load(crate::test_logger(), &tmpfile).unwrap();
+        assert!(resumed.done(&GitHubID::new(1)));
+        assert!(resumed.done(&GitHubID::new(7)));
+        assert!(!resumed.done(&GitHubID::new(2)));
+        assert!(!resumed.done(&GitHubID::new(8)));
+
+        resumed.clear().unwrap();
+        assert!(!tmpfile.exists());
+
+        let fresh = Checkpoint::load(crate::test_logger(), &tmpfile).unwrap();
+        assert!(!fresh.done(&GitHubID::new(1)));
+    }
+
+    #[test]
+    fn test_interrupted_save_keeps_old_checkpoint() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpfile = tmpdir.path().join("checkpoint.txt");
+
+        let mut checkpoint = Checkpoint::load(crate::test_logger(), &tmpfile).unwrap();
+        checkpoint.complete(GitHubID::new(1), "added").unwrap();
+
+        // Stop after writing the new checkpoint but before renaming it
+        checkpoint
+            .outcomes
+            .insert(GitHubID::new(2), "added".to_string());
+        drop(checkpoint.write_temp().unwrap());
+
+        let resumed = Checkpoint::load(crate::test_logger(), &tmpfile).unwrap();
+        assert!(resumed.done(&GitHubID::new(1)));
+        assert!(!resumed.done(&GitHubID::new(2)));
+        assert_eq!(fs::read_dir(tmpdir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_ids_without_outcome() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpfile = tmpdir.path().join("checkpoint.txt");
+        fs::write(&tmpfile, "1\n7").unwrap();
+
+        let resumed = Checkpoint::load(crate::test_logger(), &tmpfile).unwrap();
+        assert!(resumed.done(&GitHubID::new(1)));
+        assert!(resumed.done(&GitHubID::new(7)));
+    }
+}
@@ -1,51 +1,414 @@
-use std::path::PathBuf;
+use crate::credentials::Secret;
+use crate::github::{Repo, TeamRole};
+use crate::maintainers::{GitHubID, Handle};
+use crate::metrics::Bind;
+use crate::nix::NixInvocation;
+use hyper::Uri;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use structopt::clap::Shell;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 pub struct Options {
-    /// Dump metrics to stdout after completion
+    /// Dump metrics to stdout after completion, or to metrics.txt
+    /// if --output-dir is set.
     #[structopt(long = "dump-metrics")]
     pub dump_metrics: bool,
 
-    /// Address and port information for binding the metrics server
-    #[structopt(long = "metrics-addr")]
-    pub metrics_bind: Option<String>,
+    /// Dump metrics to this file after completion.
+    /// Overrides --output-dir.
+    #[structopt(long = "dump-metrics-file", parse(from_os_str))]
+    pub dump_metrics_file: Option<PathBuf>,
+
+    /// Directory to write artifacts to, using each artifact's default
+    /// file name. An artifact's own --*-file option takes precedence.
+    #[structopt(long = "output-dir", parse(from_os_str))]
+    pub output_dir: Option<PathBuf>,
+
+    /// How to write logs to stderr: terminal or json.
+    #[structopt(long = "log-format", default_value = "terminal")]
+    pub log_format: crate::LogFormat,
+
+    /// Least severe logs to write: error, warn, info, debug or trace.
+    #[structopt(
+        long = "log-level",
+        default_value = "info",
+        parse(try_from_str = "crate::parse_log_level")
+    )]
+    pub log_level: sloggers::types::Severity,
+
+    /// Also append logs to this file, in the --log-format.
+    #[structopt(long = "log-file", parse(from_os_str))]
+    pub log_file: Option<PathBuf>,
+
+    /// Only write logs to the --log-file, not stderr.
+    #[structopt(long = "quiet", requires = "log_file")]
+    pub quiet: bool,
+
+    /// Address and port information for binding the metrics server,
+    /// as host:port, or unix:/path/to.sock for a Unix domain socket.
+    /// It serves /metrics and /healthz.
+    #[structopt(long = "metrics-addr", parse(try_from_str = "parse_bind"))]
+    pub metrics_bind: Option<Bind>,
+
+    /// Write metrics to this file after completion, for
+    /// node_exporter's textfile collector. Overrides --output-dir.
+    #[structopt(long = "metrics-textfile", parse(from_os_str))]
+    pub metrics_textfile: Option<PathBuf>,
+
+    /// Push metrics to this Prometheus Pushgateway after completion,
+    /// as the rfc39 job. Replaces --metrics-addr and --metrics-delay.
+    #[structopt(long = "pushgateway")]
+    pub pushgateway: Option<String>,
 
     /// How long in seconds to keep the server up after operation is
     /// completed. Recommended to be 4x the scrape frequency.
     /// Only takes effect if metrics-addr is specified.
     /// Default: 240 seconds.
-    #[structopt(long = "metrics-delay", default_value = "240")]
-    pub metrics_delay: u64,
+    #[structopt(long = "metrics-delay")]
+    pub metrics_delay: Option<u64>,
+
+    /// Stop serving metrics as soon as they've been scraped after the
+    /// operation completed, waiting at most --metrics-delay.
+    #[structopt(long = "metrics-until-scraped")]
+    pub metrics_until_scraped: bool,
+
+    /// Upper bounds in seconds of the GitHub latency histograms'
+    /// buckets, comma separated.
+    /// Default: 0.05,0.1,0.25,0.5,1,2.5,5,10
+    #[structopt(long = "histogram-buckets")]
+    pub histogram_buckets: Option<crate::metrics::Buckets>,
+
+    /// Path to the nix-instantiate binary
+    #[structopt(
+        long = "nix-instantiate",
+        default_value = "nix-instantiate",
+        parse(from_os_str)
+    )]
+    pub nix_instantiate: PathBuf,
+
+    /// Set NIX_PATH for nix-instantiate instead of inheriting it
+    #[structopt(long = "nix-path")]
+    pub nix_path: Option<String>,
+
+    /// Extra nix option, as key=value. May be repeated.
+    #[structopt(long = "nix-option", parse(try_from_str = "parse_nix_option"))]
+    pub nix_options: Vec<(String, String)>,
 
-    /// Maintainer list
+    /// Maintainer list. With --attr, the root of a Nixpkgs checkout
+    /// instead. `-` reads the list from stdin, which backfill-ids,
+    /// blame-author and fix-handles can't use.
     #[structopt(short = "m", long = "maintainers", parse(from_os_str))]
     pub maintainers: PathBuf,
 
-    /// GitHub Credential File
+    /// What the maintainer lists are: nix, json as rendered by
+    /// `nix-instantiate --json`, or auto to go by the file extension.
+    #[structopt(long = "maintainers-format", default_value = "auto")]
+    pub maintainers_format: InputFormat,
+
+    /// Load the maintainer list from this attribute of the Nixpkgs
+    /// checkout given by --maintainers, for example `lib.maintainers`.
+    /// backfill-ids and blame-author still need the maintainer file
+    /// itself.
+    #[structopt(long = "attr")]
+    pub attr: Option<String>,
+
+    /// Fail if the maintainer list has problems which are otherwise
+    /// only reported, like GitHub IDs shared between handles.
+    #[structopt(long = "strict")]
+    pub strict: bool,
+
+    /// How many GitHub lookups backfill-ids and blame-author make at
+    /// once.
+    #[structopt(long = "concurrency", default_value = "4")]
+    pub concurrency: usize,
+
+    /// Remember commit authors in this file between runs, for
+    /// blame-author and backfill-ids.
+    #[structopt(long = "commit-cache", parse(from_os_str))]
+    pub commit_cache: Option<PathBuf>,
+
+    /// Read the maintainer list's history from this directory instead
+    /// of the copy built in: barrier commits from barriers.txt, and
+    /// snapshots from pairs of <name>.blame and <name>.nix files, and
+    /// author mismatches to accept from overrides.json.
+    #[structopt(long = "history-dir", parse(from_os_str))]
+    pub history_dir: Option<PathBuf>,
+
+    /// Retry GitHub calls which fail with network or server errors up
+    /// to this many times.
+    #[structopt(long = "max-retries", default_value = "3")]
+    pub max_retries: u32,
+
+    /// Give up on a GitHub call after this many seconds, retrying it
+    /// like a network error. 0 waits forever.
+    #[structopt(long = "github-timeout", default_value = "120")]
+    pub github_timeout: u64,
+
+    /// Wait at least this many milliseconds between adding or removing
+    /// team members, so GitHub's secondary rate limit isn't tripped.
+    #[structopt(long = "write-delay-ms", default_value = "1000")]
+    pub write_delay_ms: u64,
+
+    /// Wait up to this many more milliseconds, at random, between
+    /// writes.
+    #[structopt(long = "write-jitter-ms", default_value = "0")]
+    pub write_jitter_ms: u64,
+
+    /// GitHub Credential File. Not needed when the credentials are in
+    /// the environment: RFC39_GITHUB_TOKEN, or RFC39_APP_ID,
+    /// RFC39_INSTALLATION_ID and RFC39_PRIVATE_KEY.
     #[structopt(short = "c", long = "credentials", parse(from_os_str))]
-    pub credential_file: PathBuf,
+    pub credential_file: Option<PathBuf>,
 
     /// Execution Mode
     #[structopt(subcommand)]
     pub mode: ExecMode,
 }
 
+/// The shell to print a completion script for, when the arguments
+/// are `generate-completions <bash|zsh|fish>`. It's checked before
+/// `Options` are parsed, since it needs none of the options they
+/// require.
+pub fn completions_shell(args: &[String]) -> Option<Result<Shell, String>> {
+    if args.get(1).map(String::as_str) != Some("generate-completions") {
+        return None;
+    }
+
+    Some(match args.get(2) {
+        Some(shell) if args.len() == 3 => shell.parse(),
+        _ => Err("usage: rfc39 generate-completions <bash|zsh|fish>".to_string()),
+    })
+}
+
+/// A host:port to listen on. The host may be a name, like localhost,
+/// which resolves to its first address.
+fn parse_bind(bind: &str) -> Result<Bind, String> {
+    if bind.starts_with("unix:") {
+        let path = &bind["unix:".len()..];
+        if path.is_empty() {
+            return Err("expected unix:/path/to.sock, got \"unix:\"".to_string());
+        }
+        return Ok(Bind::Unix(PathBuf::from(path)));
+    }
+
+    bind.to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .map(Bind::Tcp)
+        .ok_or_else(|| format!("expected host:port, got {:?}", bind))
+}
+
+fn parse_nix_option(option: &str) -> Result<(String, String), String> {
+    let mut parts = option.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected key=value, got {:?}", option)),
+    }
+}
+
+impl Options {
+    pub fn nix_invocation(&self) -> NixInvocation {
+        NixInvocation {
+            nix_instantiate: self.nix_instantiate.clone(),
+            nix_path: self.nix_path.clone(),
+            options: self.nix_options.clone(),
+        }
+    }
+
+    /// Reject option combinations which contradict each other. The
+    /// warnings returned are for options which are accepted but have
+    /// no effect.
+    pub fn validate(&self) -> Result<Vec<String>, ExitError> {
+        let mut warnings = vec![];
+
+        if self.maintainers_format.resolve(&self.maintainers) == InputFormat::Json {
+            if self.attr.is_some() {
+                return Err(ExitError::InvalidArguments(
+                    "--attr needs a Nixpkgs checkout, not a JSON maintainer list".to_string(),
+                ));
+            }
+            if self.mode.needs_maintainer_file() {
+                return Err(ExitError::InvalidArguments(
+                    "this mode edits or blames the maintainer file, so it needs the Nix file"
+                        .to_string(),
+                ));
+            }
+        }
+
+        if self.maintainers_from_stdin() {
+            if self.attr.is_some() {
+                return Err(ExitError::InvalidArguments(
+                    "--attr needs a Nixpkgs checkout, not --maintainers -".to_string(),
+                ));
+            }
+            if self.mode.needs_maintainer_file() {
+                return Err(ExitError::InvalidArguments(
+                    "this mode edits or blames the maintainer file, so it can't read it from stdin"
+                        .to_string(),
+                ));
+            }
+        }
+
+        if self.metrics_delay.is_some() {
+            if self.pushgateway.is_some() {
+                warnings.push("--metrics-delay has no effect with --pushgateway".to_string());
+            } else if self.metrics_bind.is_none() {
+                warnings.push("--metrics-delay has no effect without --metrics-addr".to_string());
+            }
+        }
+
+        if self.metrics_until_scraped {
+            if self.pushgateway.is_some() {
+                warnings
+                    .push("--metrics-until-scraped has no effect with --pushgateway".to_string());
+            } else if self.metrics_bind.is_none() {
+                warnings.push(
+                    "--metrics-until-scraped has no effect without --metrics-addr".to_string(),
+                );
+            }
+        }
+
+        if let ExecMode::SyncTeam(params) = &self.mode {
+            if params.no_add && params.add_limit.is_some() {
+                warnings.push("--add-limit has no effect with --no-add".to_string());
+            }
+            if params.no_add && params.reinvite_after.is_some() {
+                warnings.push("--reinvite-after has no effect with --no-add".to_string());
+            }
+            if params.no_remove && params.remove_limit.is_some() {
+                warnings.push("--remove-limit has no effect with --no-remove".to_string());
+            }
+            if params.no_remove && params.remove_owners {
+                warnings.push("--remove-owners has no effect with --no-remove".to_string());
+            }
+            if params.no_remove && params.notify_issue.is_some() {
+                warnings.push("--notify-issue has no effect with --no-remove".to_string());
+            }
+
+            // Each of these is written during the sync, so two sharing
+            // a path would overwrite each other.
+            let outputs = vec![
+                ("--invited-list", Some(params.invited_list.as_path())),
+                ("--audit-log", params.audit_log.as_deref()),
+                ("--summary", params.summary.as_deref()),
+                ("--checkpoint-file", params.checkpoint_file.as_deref()),
+                ("--notified-list", params.notified_list.as_deref()),
+                ("--plan-out", params.plan_out.as_deref()),
+            ];
+            for (i, (flag, path)) in outputs.iter().enumerate() {
+                for (other_flag, other_path) in &outputs[i + 1..] {
+                    if path.is_some() && path == other_path {
+                        return Err(ExitError::InvalidArguments(format!(
+                            "{} and {} must be different files",
+                            flag, other_flag
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(warnings)
+    }
+
+    /// Whether this run evaluates Nix. JSON maintainer lists don't
+    /// need it, but a credential file, or a maintainer file to edit or
+    /// blame, still does.
+    pub fn needs_nix(&self) -> bool {
+        let lists: Vec<&Path> = match &self.mode {
+            ExecMode::Diff { old, new } => vec![old, new],
+            _ => vec![&self.maintainers],
+        };
+
+        lists
+            .iter()
+            .any(|list| self.maintainers_format.resolve(list) == InputFormat::Nix)
+            || self.mode.needs_maintainer_file()
+            || (self.mode.needs_github() && self.credential_file.is_some())
+    }
+
+    /// Whether `--maintainers -` asks for the list on stdin.
+    pub fn maintainers_from_stdin(&self) -> bool {
+        self.maintainers == Path::new("-")
+    }
+
+    /// Where to write an artifact: the explicitly requested path if
+    /// there is one, otherwise `default_name` inside --output-dir.
+    /// None means the artifact has no file to go to.
+    pub fn artifact_path(&self, explicit: Option<&Path>, default_name: &str) -> Option<PathBuf> {
+        resolve_artifact_path(self.output_dir.as_deref(), explicit, default_name)
+    }
+}
+
+pub fn resolve_artifact_path(
+    output_dir: Option<&Path>,
+    explicit: Option<&Path>,
+    default_name: &str,
+) -> Option<PathBuf> {
+    if let Some(explicit) = explicit {
+        Some(explicit.to_path_buf())
+    } else {
+        output_dir.map(|dir| dir.join(default_name))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Auto,
+    Nix,
+    Json,
+}
+
+impl InputFormat {
+    /// Nix or Json for this file, going by its extension for Auto.
+    pub fn resolve(self, path: &Path) -> InputFormat {
+        match self {
+            InputFormat::Auto => match path.extension() {
+                Some(extension) if extension == "json" => InputFormat::Json,
+                _ => InputFormat::Nix,
+            },
+            format => format,
+        }
+    }
+}
+
+impl std::str::FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "auto" => Ok(InputFormat::Auto),
+            "nix" => Ok(InputFormat::Nix),
+            "json" => Ok(InputFormat::Json),
+            _ => Err(format!(
+                "unsupported maintainer list format {:?}, expected auto, nix or json",
+                format
+            )),
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 pub enum ExecMode {
     /// Verify maintainers, their GitHub handle, and GitHub ID
     #[structopt(name = "check-handles")]
-    CheckHandles,
+    CheckHandles(CheckHandlesParams),
 
     /// Poorly edit the maintainers.nix file to add missing GitHub IDs
     #[structopt(name = "backfill-ids")]
-    BackfillIDs,
+    BackfillIDs(BackfillIDsParams),
 
     /// Look to see if any of the GitHub handles have probably changed
     /// by examining who authored the commit adding the maintainer
     /// to the .nix file.
     #[structopt(name = "blame-author")]
-    BlameAuthor,
+    BlameAuthor(BlameAuthorParams),
+
+    /// Print the maintainers.nix file with the GitHub handles
+    /// blame-author finds have changed replaced by the current ones
+    #[structopt(name = "fix-handles")]
+    FixHandles(FixHandlesParams),
 
     /// Add and remove team members from a GitHub team based on
     /// maintainership information. Use list-teams to find a team's
@@ -53,34 +416,395 @@ pub enum ExecMode {
     #[structopt(name = "sync-team")]
     SyncTeam(SyncTeamParams),
 
+    /// Print what sync-team would change, without changing anything.
+    /// Unlike sync-team, token credentials are enough
+    #[structopt(name = "plan-sync")]
+    PlanSync(PlanSyncParams),
+
+    /// Compare two maintainer files, failing if they differ.
+    /// --maintainers and --credentials are not used.
+    #[structopt(name = "diff")]
+    Diff {
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+
+        #[structopt(parse(from_os_str))]
+        new: PathBuf,
+    },
+
+    /// Print the maintainer list as rfc39 understands it
+    #[structopt(name = "list-maintainers")]
+    ListMaintainers(ListMaintainersParams),
+
+    /// Count maintainers with and without a GitHub handle, GitHub ID
+    /// and email
+    #[structopt(name = "stats")]
+    Stats,
+
     /// List an org's teams, to get the ID for sync-team
     #[structopt(name = "list-teams")]
     ListTeams(ListTeamParams),
 }
 
+impl ExecMode {
+    /// Whether this mode needs the maintainer file itself, to edit
+    /// it or read its history, rather than only the list in it.
+    pub fn needs_maintainer_file(&self) -> bool {
+        match self {
+            ExecMode::BackfillIDs(_) | ExecMode::BlameAuthor(_) | ExecMode::FixHandles(_) => true,
+            ExecMode::CheckHandles(_)
+            | ExecMode::SyncTeam(_)
+            | ExecMode::PlanSync(_)
+            | ExecMode::Diff { .. }
+            | ExecMode::ListMaintainers(_)
+            | ExecMode::Stats
+            | ExecMode::ListTeams(_) => false,
+        }
+    }
+
+    /// Whether this mode calls the GitHub API.
+    pub fn needs_github(&self) -> bool {
+        match self {
+            ExecMode::CheckHandles(_)
+            | ExecMode::BackfillIDs(_)
+            | ExecMode::BlameAuthor(_)
+            | ExecMode::FixHandles(_)
+            | ExecMode::SyncTeam(_)
+            | ExecMode::PlanSync(_)
+            | ExecMode::ListTeams(_) => true,
+            ExecMode::Diff { .. } | ExecMode::ListMaintainers(_) | ExecMode::Stats => false,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CheckHandlesParams {
+    /// Fail if fewer than this percentage of maintainers with a
+    /// GitHub account also have a GitHub ID.
+    #[structopt(long = "require-id-coverage")]
+    pub require_id_coverage: Option<f64>,
+
+    /// Report maintainers who don't have a Matrix handle.
+    #[structopt(long = "check-matrix")]
+    pub check_matrix: bool,
+
+    /// Write every maintainer's status as JSON to this file.
+    /// Defaults to check-handles.json in --output-dir, if set.
+    #[structopt(long = "report", parse(from_os_str))]
+    pub report: Option<PathBuf>,
+
+    /// text, or github-actions to also print a workflow annotation
+    /// for each problem, pointing at its maintainer file entry.
+    #[structopt(long = "format", default_value = "text")]
+    pub format: CheckFormat,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CheckFormat {
+    Text,
+    GitHubActions,
+}
+
+impl std::str::FromStr for CheckFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(CheckFormat::Text),
+            "github-actions" => Ok(CheckFormat::GitHubActions),
+            _ => Err(format!(
+                "unsupported format {:?}, expected text or github-actions",
+                format
+            )),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct HistoryParams {
+    /// The repository holding the maintainer file's history, as
+    /// owner/name.
+    #[structopt(long = "blame-repo", default_value = "NixOS/nixpkgs")]
+    pub blame_repo: Repo,
+
+    /// Ask GitHub to blame the maintainer file as of this commit in
+    /// --blame-repo, instead of running git blame in a local checkout.
+    /// Needs token credentials.
+    #[structopt(long = "blame-rev")]
+    pub blame_rev: Option<String>,
+
+    /// Where the maintainer file is in --blame-repo, for --blame-rev.
+    #[structopt(long = "blame-path", default_value = "maintainers/maintainer-list.nix")]
+    pub blame_path: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct BackfillIDsParams {
+    /// Replace the maintainer file instead of printing the new one.
+    #[structopt(long = "in-place")]
+    pub in_place: bool,
+
+    /// Print the whole new file (file), or a unified diff against the
+    /// current one (diff). Nothing is printed for --in-place file.
+    #[structopt(long = "format", default_value = "file")]
+    pub format: BackfillFormat,
+
+    /// Look up the missing IDs with batched GraphQL queries instead of
+    /// one REST call per user, falling back to REST if they fail.
+    /// Needs token credentials.
+    #[structopt(long = "graphql")]
+    pub graphql: bool,
+
+    #[structopt(flatten)]
+    pub history: HistoryParams,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BackfillFormat {
+    File,
+    Diff,
+}
+
+impl std::str::FromStr for BackfillFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "file" => Ok(BackfillFormat::File),
+            "diff" => Ok(BackfillFormat::Diff),
+            _ => Err(format!(
+                "unsupported format {:?}, expected file or diff",
+                format
+            )),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct BlameAuthorParams {
+    /// Write the attribution report as CSV to this file.
+    /// Defaults to attribution.csv in --output-dir, if set.
+    #[structopt(long = "csv-file", parse(from_os_str))]
+    pub csv_file: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    pub history: HistoryParams,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct FixHandlesParams {
+    /// List the handles which would be renamed instead of printing
+    /// the file.
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+
+    #[structopt(flatten)]
+    pub history: HistoryParams,
+}
+
 #[derive(Debug, StructOpt)]
 pub struct SyncTeamParams {
     pub organization: String,
 
     /// Find the team ID by going to
-    pub team_id: u64,
+    #[structopt(
+        long = "team-id",
+        required_unless = "team_slug",
+        conflicts_with = "team_slug"
+    )]
+    pub team_id: Option<u64>,
+
+    /// The team's slug, as in its URL, to look up the team ID with.
+    #[structopt(long = "team-slug")]
+    pub team_slug: Option<String>,
 
     #[structopt(long = "dry-run")]
     pub dry_run: bool,
 
+    /// The role users are added to the team with: member or
+    /// maintainer.
+    #[structopt(long = "role", default_value = "member")]
+    pub role: TeamRole,
+
+    /// Only remove users, leaving additions for a later run.
+    #[structopt(long = "no-add", conflicts_with = "no_remove")]
+    pub no_add: bool,
+
+    /// Only add users, leaving removals for a later run.
+    #[structopt(long = "no-remove")]
+    pub no_remove: bool,
+
+    /// Remove organization owners from the team too. Otherwise they
+    /// are kept, since it can change what they may manage.
+    #[structopt(long = "remove-owners")]
+    pub remove_owners: bool,
+
+    /// Stop after this many additions and removals in total.
     #[structopt(long = "limit")]
     pub limit: Option<u64>,
 
+    /// Stop adding users after this many additions.
+    #[structopt(long = "add-limit")]
+    pub add_limit: Option<u64>,
+
+    /// Stop removing users after this many removals.
+    #[structopt(long = "remove-limit")]
+    pub remove_limit: Option<u64>,
+
     /// File to track previously invited users. Setting this parameter
     /// guarantees that users that have been previously invited and rejected
-    /// will not keep getting spammed.
+    /// will not keep getting spammed. For a directory, each team gets
+    /// its own <org>-<team_id>.txt file in it.
     #[structopt(long = "invited-list", parse(from_os_str))]
     pub invited_list: PathBuf,
+
+    /// Invite users again if they still aren't members this many days
+    /// after their last invitation. By default they are never invited
+    /// again.
+    #[structopt(long = "reinvite-after")]
+    pub reinvite_after: Option<u64>,
+
+    /// File of GitHub logins or IDs, one per line, to never remove
+    /// from the team.
+    #[structopt(long = "protect", parse(from_os_str))]
+    pub protect: Option<PathBuf>,
+
+    /// Append a JSON line recording each action to this file, for
+    /// dry runs too. Overrides --output-dir.
+    #[structopt(long = "audit-log", parse(from_os_str))]
+    pub audit_log: Option<PathBuf>,
+
+    /// Also write the end-of-run summary to this file, as JSON.
+    /// Overrides --output-dir.
+    #[structopt(long = "summary", parse(from_os_str))]
+    pub summary: Option<PathBuf>,
+
+    /// File to track what this sync did for each user. If a sync is
+    /// interrupted, the next run skips those users, except where a
+    /// GitHub call failed. Removed once a sync completes.
+    #[structopt(long = "checkpoint-file", parse(from_os_str))]
+    pub checkpoint_file: Option<PathBuf>,
+
+    /// Before removing users, mention them in a new issue in this
+    /// owner/name repository, and only remove them once the grace
+    /// period has passed.
+    #[structopt(long = "notify-issue", requires = "notified_list")]
+    pub notify_issue: Option<Repo>,
+
+    /// File to track when users were told they'd be removed. For a
+    /// directory, each team gets its own <org>-<team_id>.txt file in
+    /// it.
+    #[structopt(long = "notified-list", parse(from_os_str))]
+    pub notified_list: Option<PathBuf>,
+
+    /// Days between notifying users and removing them.
+    #[structopt(long = "removal-grace-days", default_value = "7")]
+    pub removal_grace_days: u64,
+
+    /// Post the summary to this Slack or Discord incoming webhook
+    /// URL. Failing to post doesn't fail the sync.
+    #[structopt(long = "webhook")]
+    pub webhook: Option<Secret<Uri>>,
+
+    /// Write the additions and removals this dry run would make to
+    /// this file, as JSON, for --plan-in to carry out once reviewed.
+    /// Overrides --output-dir.
+    #[structopt(long = "plan-out", parse(from_os_str), requires = "dry_run")]
+    pub plan_out: Option<PathBuf>,
+
+    /// Carry out exactly the additions and removals in this
+    /// --plan-out file, instead of comparing the team with the
+    /// maintainer list. Nothing is changed if the team or any of the
+    /// users changed since the plan was made.
+    #[structopt(long = "plan-in", parse(from_os_str), conflicts_with = "plan_out")]
+    pub plan_in: Option<PathBuf>,
+}
+
+impl SyncTeamParams {
+    /// Put the artifacts without their own path in --output-dir. A
+    /// plan is only written by dry runs.
+    pub fn resolve_artifact_paths(&mut self, output_dir: Option<&Path>) {
+        self.audit_log =
+            resolve_artifact_path(output_dir, self.audit_log.as_deref(), "audit.jsonl");
+        self.summary = resolve_artifact_path(output_dir, self.summary.as_deref(), "summary.json");
+        if self.dry_run {
+            self.plan_out =
+                resolve_artifact_path(output_dir, self.plan_out.as_deref(), "plan.json");
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct PlanSyncParams {
+    pub organization: String,
+
+    #[structopt(
+        long = "team-id",
+        required_unless = "team_slug",
+        conflicts_with = "team_slug"
+    )]
+    pub team_id: Option<u64>,
+
+    /// The team's slug, as in its URL, to look up the team ID with.
+    #[structopt(long = "team-slug")]
+    pub team_slug: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ListMaintainersParams {
+    /// Output format. Only json is supported.
+    #[structopt(long = "format", default_value = "json")]
+    pub format: MaintainerFormat,
+}
+
+#[derive(Debug)]
+pub enum MaintainerFormat {
+    Json,
+}
+
+impl std::str::FromStr for MaintainerFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "json" => Ok(MaintainerFormat::Json),
+            _ => Err(format!("unsupported format {:?}, expected json", format)),
+        }
+    }
 }
 
 #[derive(Debug, StructOpt)]
 pub struct ListTeamParams {
     pub organization: String,
+
+    /// Also fetch how many members each team has.
+    #[structopt(long = "with-counts")]
+    pub with_counts: bool,
+
+    /// Output format: text or json.
+    #[structopt(long = "format", default_value = "text")]
+    pub format: TeamFormat,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TeamFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for TeamFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(TeamFormat::Text),
+            "json" => Ok(TeamFormat::Json),
+            _ => Err(format!(
+                "unsupported format {:?}, expected text or json",
+                format
+            )),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -88,6 +812,75 @@ pub enum ExitError {
     Io(std::io::Error),
     InvalidGitHubID(std::num::ParseIntError),
     Serde(serde_json::error::Error),
+    Nix(crate::nix::NixError),
+    InsufficientIDCoverage {
+        coverage: f64,
+        required: f64,
+    },
+    NixUnavailable(std::io::Error),
+    TeamOrgMismatch {
+        organization: String,
+        team_id: u64,
+    },
+    DuplicateGitHubIDs(Vec<GitHubID>),
+    MaintainersDiffer {
+        added: usize,
+        removed: usize,
+        changed: usize,
+    },
+    GraphQL(String),
+    TeamNotFound {
+        organization: String,
+        team_id: u64,
+    },
+    UnknownTeamSlug {
+        organization: String,
+        slug: String,
+    },
+    MissingCredentials(String),
+    InvalidCredentials(String),
+    InvalidCredentialFile {
+        app_auth: crate::nix::NixError,
+        token_auth: crate::nix::NixError,
+    },
+    InvalidArguments(String),
+    /// How many of a --plan-in's actions no longer match GitHub.
+    PlanDrifted(usize),
+    /// Maintainers whose entry backfilling changed beyond adding an ID.
+    BackfillChanged(Vec<Handle>),
+    /// A GitHub call failed for good, after any retries.
+    GitHub(String),
+}
+
+/// Exit code when the operation panicked, matching Rust's own.
+pub const PANIC_EXIT_CODE: i32 = 101;
+
+impl ExitError {
+    /// A distinct process exit code for each kind of failure, so a
+    /// supervising script can tell them apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ExitError::Io(_) => 2,
+            ExitError::InvalidGitHubID(_) => 3,
+            ExitError::Serde(_) => 4,
+            ExitError::Nix(_) => 5,
+            ExitError::NixUnavailable(_) => 6,
+            ExitError::InsufficientIDCoverage { .. } => 7,
+            ExitError::TeamOrgMismatch { .. } => 8,
+            ExitError::DuplicateGitHubIDs(_) => 9,
+            ExitError::MaintainersDiffer { .. } => 10,
+            ExitError::GraphQL(_) => 11,
+            ExitError::TeamNotFound { .. } => 12,
+            ExitError::UnknownTeamSlug { .. } => 13,
+            ExitError::MissingCredentials(_) => 14,
+            ExitError::InvalidCredentials(_) => 15,
+            ExitError::InvalidCredentialFile { .. } => 16,
+            ExitError::InvalidArguments(_) => 17,
+            ExitError::PlanDrifted(_) => 18,
+            ExitError::BackfillChanged(_) => 19,
+            ExitError::GitHub(_) => 20,
+        }
+    }
 }
 
 impl From<std::io::Error> for ExitError {
@@ -102,8 +895,302 @@ impl From<std::num::ParseIntError> for ExitError {
     }
 }
 
+impl From<crate::nix::NixError> for ExitError {
+    fn from(e: crate::nix::NixError) -> Self {
+        Self::Nix(e)
+    }
+}
+
 impl From<serde_json::error::Error> for ExitError {
     fn from(e: serde_json::error::Error) -> Self {
         Self::Serde(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completions_shell() {
+        let args =
+            |args: &[&str]| -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() };
+
+        assert_eq!(
+            completions_shell(&args(&["rfc39", "generate-completions", "zsh"])),
+            Some(Ok(Shell::Zsh))
+        );
+        assert!(
+            completions_shell(&args(&["rfc39", "generate-completions", "tcsh"]))
+                .unwrap()
+                .is_err()
+        );
+        assert!(completions_shell(&args(&["rfc39", "generate-completions"]))
+            .unwrap()
+            .is_err());
+        assert_eq!(
+            completions_shell(&args(&["rfc39", "-m", "maintainers.nix", "list-teams"])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_bind() {
+        assert_eq!(
+            parse_bind("127.0.0.1:9000"),
+            Ok(Bind::Tcp("127.0.0.1:9000".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_bind("[::1]:9000"),
+            Ok(Bind::Tcp("[::1]:9000".parse().unwrap()))
+        );
+        match parse_bind("localhost:9000").unwrap() {
+            Bind::Tcp(addr) => assert_eq!(addr.port(), 9000),
+            bind => panic!("expected a TCP address, got {}", bind),
+        }
+        assert_eq!(
+            parse_bind("unix:/run/rfc39/metrics.sock"),
+            Ok(Bind::Unix(PathBuf::from("/run/rfc39/metrics.sock")))
+        );
+        assert!(parse_bind("unix:").is_err());
+        assert_eq!(
+            parse_bind("localhost"),
+            Err("expected host:port, got \"localhost\"".to_string())
+        );
+        assert!(parse_bind("127.0.0.1:http").is_err());
+    }
+
+    #[test]
+    fn test_parse_nix_option() {
+        assert_eq!(
+            parse_nix_option("restrict-eval=true"),
+            Ok(("restrict-eval".to_string(), "true".to_string()))
+        );
+        assert_eq!(
+            parse_nix_option("allowed-uris=https://a=b"),
+            Ok(("allowed-uris".to_string(), "https://a=b".to_string()))
+        );
+        assert!(parse_nix_option("restrict-eval").is_err());
+        assert!(parse_nix_option("=true").is_err());
+    }
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let io_error = || std::io::Error::new(std::io::ErrorKind::Other, "test");
+        let errors = vec![
+            ExitError::Io(io_error()),
+            ExitError::InvalidGitHubID("x".parse::<u64>().unwrap_err()),
+            ExitError::Serde(serde_json::from_str::<u64>("x").unwrap_err()),
+            ExitError::Nix(crate::nix::NixError::SpawnFailed(io_error())),
+            ExitError::NixUnavailable(io_error()),
+            ExitError::InsufficientIDCoverage {
+                coverage: 50.0,
+                required: 75.0,
+            },
+            ExitError::TeamOrgMismatch {
+                organization: "NixOS".into(),
+                team_id: 1,
+            },
+            ExitError::DuplicateGitHubIDs(vec![]),
+            ExitError::MaintainersDiffer {
+                added: 1,
+                removed: 0,
+                changed: 0,
+            },
+            ExitError::GraphQL("test".into()),
+            ExitError::TeamNotFound {
+                organization: "NixOS".into(),
+                team_id: 1,
+            },
+            ExitError::UnknownTeamSlug {
+                organization: "NixOS".into(),
+                slug: "maintainers".into(),
+            },
+            ExitError::MissingCredentials("test".into()),
+            ExitError::InvalidCredentials("test".into()),
+            ExitError::InvalidCredentialFile {
+                app_auth: crate::nix::NixError::SpawnFailed(io_error()),
+                token_auth: crate::nix::NixError::SpawnFailed(io_error()),
+            },
+            ExitError::InvalidArguments("test".into()),
+            ExitError::PlanDrifted(1),
+            ExitError::BackfillChanged(vec![]),
+            ExitError::GitHub("test".into()),
+        ];
+
+        let mut codes: Vec<i32> = errors.iter().map(ExitError::exit_code).collect();
+        codes.push(PANIC_EXIT_CODE);
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len() + 1);
+        assert!(!codes.contains(&0));
+    }
+
+    fn options(args: &[&str]) -> Options {
+        Options::from_iter(["rfc39", "-m", "maintainer-list.nix"].iter().chain(args))
+    }
+
+    #[test]
+    fn test_validate_warnings() {
+        assert!(options(&["list-teams", "NixOS"])
+            .validate()
+            .unwrap()
+            .is_empty());
+        assert!(options(&[
+            "--metrics-delay",
+            "60",
+            "--metrics-addr",
+            "127.0.0.1:9000",
+            "list-teams",
+            "NixOS"
+        ])
+        .validate()
+        .unwrap()
+        .is_empty());
+        assert_eq!(
+            options(&["--metrics-delay", "60", "list-teams", "NixOS"])
+                .validate()
+                .unwrap(),
+            vec!["--metrics-delay has no effect without --metrics-addr".to_string()]
+        );
+        assert_eq!(
+            options(&["--metrics-until-scraped", "list-teams", "NixOS"])
+                .validate()
+                .unwrap(),
+            vec!["--metrics-until-scraped has no effect without --metrics-addr".to_string()]
+        );
+        assert_eq!(
+            options(&[
+                "sync-team",
+                "NixOS",
+                "--team-id",
+                "1",
+                "--invited-list",
+                "invited.txt",
+                "--no-remove",
+                "--remove-limit",
+                "5",
+            ])
+            .validate()
+            .unwrap(),
+            vec!["--remove-limit has no effect with --no-remove".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_validate_stdin() {
+        let stdin =
+            |args: &[&str]| Options::from_iter(["rfc39", "-m", "-"].iter().chain(args)).validate();
+
+        assert!(stdin(&["list-maintainers"]).is_ok());
+        match stdin(&["fix-handles"]) {
+            Err(ExitError::InvalidArguments(_)) => {}
+            other => panic!("expected invalid arguments, got {:?}", other),
+        }
+        assert!(stdin(&["--attr", "lib.maintainers", "list-maintainers"]).is_err());
+    }
+
+    #[test]
+    fn test_input_format() {
+        let json = Path::new("maintainers.json");
+        let nix = Path::new("maintainer-list.nix");
+
+        assert_eq!(InputFormat::Auto.resolve(json), InputFormat::Json);
+        assert_eq!(InputFormat::Auto.resolve(nix), InputFormat::Nix);
+        assert_eq!(InputFormat::Auto.resolve(Path::new("-")), InputFormat::Nix);
+        assert_eq!(InputFormat::Nix.resolve(json), InputFormat::Nix);
+        assert_eq!(InputFormat::Json.resolve(nix), InputFormat::Json);
+
+        let options = Options::from_iter(&["rfc39", "-m", "maintainers.json", "list-maintainers"]);
+        assert!(!options.needs_nix());
+        assert!(options.validate().is_ok());
+        let options = Options::from_iter(&[
+            "rfc39",
+            "-m",
+            "maintainers.json",
+            "-c",
+            "creds.nix",
+            "list-teams",
+            "NixOS",
+        ]);
+        assert!(options.needs_nix());
+        let options =
+            Options::from_iter(&["rfc39", "-m", "maintainers.json", "list-teams", "NixOS"]);
+        assert!(!options.needs_nix());
+        assert!(Options::from_iter(&["rfc39", "-m", "maintainers.nix", "stats"]).needs_nix());
+    }
+
+    #[test]
+    fn test_validate_shared_output() {
+        let sync_team = |extra: &[&str]| {
+            let mut args = vec![
+                "sync-team",
+                "NixOS",
+                "--team-id",
+                "1",
+                "--invited-list",
+                "invited.txt",
+            ];
+            args.extend_from_slice(extra);
+            options(&args).validate()
+        };
+
+        assert!(sync_team(&["--audit-log", "audit.json", "--summary", "summary.json"]).is_ok());
+        match sync_team(&["--checkpoint-file", "invited.txt"]) {
+            Err(ExitError::InvalidArguments(message)) => assert_eq!(
+                message,
+                "--invited-list and --checkpoint-file must be different files"
+            ),
+            other => panic!("expected invalid arguments, got {:?}", other),
+        }
+        assert!(sync_team(&["--audit-log", "out.json", "--summary", "out.json"]).is_err());
+    }
+
+    #[test]
+    fn test_artifact_path_precedence() {
+        let dir = Path::new("/out");
+        let explicit = Path::new("/elsewhere/metrics.txt");
+
+        assert_eq!(
+            resolve_artifact_path(Some(dir), Some(explicit), "metrics.txt"),
+            Some(PathBuf::from("/elsewhere/metrics.txt"))
+        );
+        assert_eq!(
+            resolve_artifact_path(None, Some(explicit), "metrics.txt"),
+            Some(PathBuf::from("/elsewhere/metrics.txt"))
+        );
+        assert_eq!(
+            resolve_artifact_path(Some(dir), None, "metrics.txt"),
+            Some(PathBuf::from("/out/metrics.txt"))
+        );
+        assert_eq!(resolve_artifact_path(None, None, "metrics.txt"), None);
+    }
+
+    #[test]
+    fn test_sync_team_artifact_paths() {
+        let params = |args: &[&str]| {
+            let mut args = args.to_vec();
+            args.extend_from_slice(&["--team-id", "1", "--invited-list", "invited.txt"]);
+            match options(&args).mode {
+                ExecMode::SyncTeam(params) => params,
+                _ => unreachable!(),
+            }
+        };
+        let dir = Path::new("/out");
+
+        let mut dry_run = params(&["sync-team", "NixOS", "--dry-run", "--summary", "s.json"]);
+        dry_run.resolve_artifact_paths(Some(dir));
+        assert_eq!(dry_run.summary, Some(PathBuf::from("s.json")));
+        assert_eq!(dry_run.audit_log, Some(PathBuf::from("/out/audit.jsonl")));
+        assert_eq!(dry_run.plan_out, Some(PathBuf::from("/out/plan.json")));
+
+        let mut live = params(&["sync-team", "NixOS"]);
+        live.resolve_artifact_paths(Some(dir));
+        assert_eq!(live.summary, Some(PathBuf::from("/out/summary.json")));
+        assert_eq!(live.plan_out, None);
+
+        let mut no_dir = params(&["sync-team", "NixOS"]);
+        no_dir.resolve_artifact_paths(None);
+        assert_eq!(no_dir.audit_log, None);
+    }
+}
@@ -0,0 +1,341 @@
+//! rfc39's operations behind one value, for tools which run them
+//! in-process instead of through the command line.
+
+use crate::cli::{BackfillFormat, ExitError, InputFormat, PlanSyncParams, SyncTeamParams};
+use crate::commit_cache::CommitCache;
+use crate::credentials::{app_credentials, Secret};
+use crate::github::{github_client, RetryPolicy, Retrying, Throttled};
+use crate::maintainerhistory::HistorySource;
+use crate::maintainers::MaintainerList;
+use crate::metrics::Metrics;
+use crate::nix::NixInvocation;
+use crate::user_resolver::UserResolver;
+use crate::{graphql, op_backfill, op_check_handles, op_sync_team};
+use hubcaps::{Credentials, Github};
+use std::cell::RefCell;
+use std::path::Path;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+// NOTE: DO NOT MAKE "Debug"! This will leak secrets
+enum Auth {
+    Token(Secret<String>),
+    App {
+        app_id: u64,
+        installation_id: u64,
+        private_key: Secret<Vec<u8>>,
+    },
+}
+
+/// Sets up a `Client`, with the command line's defaults.
+// NOTE: DO NOT MAKE "Debug"! This will leak secrets
+pub struct ClientBuilder {
+    logger: slog::Logger,
+    auth: Option<Auth>,
+    nix_invocation: NixInvocation,
+    max_retries: u32,
+    github_timeout: Duration,
+    write_delay: Duration,
+    write_jitter: Duration,
+    concurrency: usize,
+    metrics: Option<Metrics>,
+}
+
+impl ClientBuilder {
+    pub fn new(logger: slog::Logger) -> ClientBuilder {
+        ClientBuilder {
+            logger,
+            auth: None,
+            nix_invocation: NixInvocation::default(),
+            max_retries: 3,
+            github_timeout: Duration::from_secs(120),
+            write_delay: Duration::from_secs(1),
+            write_jitter: Duration::from_secs(0),
+            concurrency: 4,
+            metrics: None,
+        }
+    }
+
+    /// Authenticate with a personal access token, which can't sync
+    /// teams.
+    pub fn token(mut self, token: Secret<String>) -> ClientBuilder {
+        self.auth = Some(Auth::Token(token));
+        self
+    }
+
+    /// Authenticate as a GitHub App installation, with the app's
+    /// private key in DER.
+    pub fn app(
+        mut self,
+        app_id: u64,
+        installation_id: u64,
+        private_key: Secret<Vec<u8>>,
+    ) -> ClientBuilder {
+        self.auth = Some(Auth::App {
+            app_id,
+            installation_id,
+            private_key,
+        });
+        self
+    }
+
+    pub fn nix_invocation(mut self, nix_invocation: NixInvocation) -> ClientBuilder {
+        self.nix_invocation = nix_invocation;
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: u32) -> ClientBuilder {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Give up on a GitHub call after this long. Zero waits forever.
+    pub fn github_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.github_timeout = timeout;
+        self
+    }
+
+    /// Space out team additions and removals by at least `delay`,
+    /// plus up to `jitter` at random.
+    pub fn write_delay(mut self, delay: Duration, jitter: Duration) -> ClientBuilder {
+        self.write_delay = delay;
+        self.write_jitter = jitter;
+        self
+    }
+
+    /// How many GitHub lookups backfilling makes at once.
+    pub fn concurrency(mut self, concurrency: usize) -> ClientBuilder {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Report into these metrics, for example ones registered in the
+    /// default registry with `Metrics::register`. Without them, the
+    /// client's metrics aren't reported anywhere.
+    pub fn metrics(mut self, metrics: Metrics) -> ClientBuilder {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Wrap `github` in the retries, write throttling and commit cache
+    /// every op expects.
+    pub fn connect(
+        &self,
+        github: Github,
+        commit_cache: Option<&Path>,
+    ) -> Result<CommitCache<Retrying<Throttled<Github>>>, ExitError> {
+        let retry_metrics = match self.metrics {
+            Some(ref metrics) => metrics.github.retry.clone(),
+            None => Metrics::unregistered().github.retry,
+        };
+
+        CommitCache::load(
+            self.logger.new(o!()),
+            Retrying::new(
+                self.logger.new(o!()),
+                Throttled::new(github, self.write_delay, self.write_jitter),
+                RetryPolicy::new(self.max_retries).with_timeout(self.github_timeout),
+                retry_metrics,
+            ),
+            commit_cache,
+        )
+    }
+
+    pub fn build(mut self) -> Result<Client, ExitError> {
+        let (credentials, token) = match self.auth {
+            Some(Auth::Token(token)) => (Credentials::Token(token.expose().clone()), Some(token)),
+            Some(Auth::App {
+                app_id,
+                installation_id,
+                private_key,
+            }) => (
+                app_credentials(app_id, installation_id, private_key.into_inner())?,
+                None,
+            ),
+            None => {
+                return Err(ExitError::MissingCredentials(
+                    "set a token or app credentials".to_string(),
+                ))
+            }
+        };
+
+        let metrics = self.metrics.take().unwrap_or_else(Metrics::unregistered);
+        self.metrics = Some(metrics.clone());
+        let github = self.connect(github_client(&self.logger, credentials)?, None)?;
+
+        Ok(Client {
+            logger: self.logger,
+            github,
+            token,
+            nix_invocation: self.nix_invocation,
+            concurrency: self.concurrency,
+            metrics,
+            rt: Runtime::new()?,
+        })
+    }
+}
+
+/// Runs rfc39's operations against GitHub.
+// NOTE: DO NOT MAKE "Debug"! This will leak secrets
+pub struct Client {
+    logger: slog::Logger,
+    github: CommitCache<Retrying<Throttled<Github>>>,
+    token: Option<Secret<String>>,
+    nix_invocation: NixInvocation,
+    concurrency: usize,
+    metrics: Metrics,
+    rt: Runtime,
+}
+
+impl Client {
+    /// What the operations run so far have counted.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Load a Nix or JSON maintainer list, as `--maintainers` does.
+    pub fn load_maintainers(
+        &self,
+        path: &Path,
+        format: InputFormat,
+        attr: Option<&str>,
+    ) -> Result<MaintainerList, ExitError> {
+        crate::load_maintainer_file(
+            self.logger.new(o!()),
+            &self.nix_invocation,
+            path,
+            format,
+            attr,
+        )
+    }
+
+    pub fn sync_team(
+        &mut self,
+        maintainers: MaintainerList,
+        params: SyncTeamParams,
+    ) -> Result<(), ExitError> {
+        op_sync_team::sync_team(
+            self.logger.new(o!("exec-mode" => "SyncTeam")),
+            &self.github,
+            &mut self.rt,
+            &self.metrics,
+            maintainers,
+            params,
+        )
+    }
+
+    pub fn plan_sync(
+        &mut self,
+        maintainers: MaintainerList,
+        params: PlanSyncParams,
+    ) -> Result<(), ExitError> {
+        op_sync_team::plan_sync(
+            self.logger.new(o!("exec-mode" => "PlanSync")),
+            &self.github,
+            &mut self.rt,
+            &self.metrics,
+            maintainers,
+            params,
+        )
+    }
+
+    /// Needs token authentication, since the lookups use GraphQL.
+    pub fn check_handles(
+        &mut self,
+        maintainers: MaintainerList,
+        require_id_coverage: Option<f64>,
+        report: Option<&Path>,
+    ) -> Result<(), ExitError> {
+        let token = self.token.as_ref().ok_or_else(|| {
+            ExitError::MissingCredentials("check_handles needs a token".to_string())
+        })?;
+        let logger = self.logger.new(o!("exec-mode" => "CheckHandles"));
+        let users = RefCell::new(UserResolver::new(
+            self.logger.new(o!()),
+            self.github.clone(),
+            self.metrics.github.user_cache_hits.clone(),
+        ));
+        let rt = RefCell::new(&mut self.rt);
+        let concurrency = self.concurrency;
+        op_check_handles::check_handles(
+            logger.clone(),
+            &self.metrics,
+            maintainers,
+            |ids| {
+                users.borrow_mut().logins_by_id(ids, |ids| {
+                    graphql::logins_by_id(&logger, &mut rt.borrow_mut(), token, ids)
+                })
+            },
+            |names| {
+                Ok(users
+                    .borrow_mut()
+                    .ids_by_login(&mut rt.borrow_mut(), names, concurrency))
+            },
+            require_id_coverage,
+            false,
+            report,
+            None,
+        )
+    }
+
+    /// Fill in missing GitHub IDs in `file`, which `maintainers` was
+    /// loaded from. With token authentication the IDs are looked up
+    /// with GraphQL.
+    pub fn backfill_ids(
+        &mut self,
+        file: &Path,
+        maintainers: MaintainerList,
+        history: HistorySource,
+        in_place: bool,
+        format: BackfillFormat,
+    ) -> Result<(), ExitError> {
+        let mut users = UserResolver::new(
+            self.logger.new(o!()),
+            self.github.clone(),
+            self.metrics.github.user_cache_hits.clone(),
+        );
+        op_backfill::backfill_ids(
+            self.logger.new(o!("exec-mode" => "BackfillIDs")),
+            &self.github,
+            &mut self.rt,
+            &self.metrics,
+            &mut users,
+            &self.nix_invocation,
+            file,
+            maintainers,
+            history,
+            in_place,
+            format,
+            self.concurrency,
+            self.token.as_ref(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_needs_credentials() {
+        match ClientBuilder::new(crate::test_logger()).build() {
+            Err(ExitError::MissingCredentials(_)) => {}
+            Err(other) => panic!("expected missing credentials, got {:?}", other),
+            Ok(_) => panic!("expected missing credentials, got a client"),
+        }
+    }
+
+    #[test]
+    fn test_check_handles_needs_token() {
+        let mut client = ClientBuilder::new(crate::test_logger())
+            .token(Secret::new("token".to_string()))
+            .build()
+            .unwrap_or_else(|e| panic!("failed to build a client: {:?}", e));
+        // As if it were authenticated as an app.
+        client.token = None;
+        match client.check_handles(MaintainerList::new(Default::default()), None, None) {
+            Err(ExitError::MissingCredentials(_)) => {}
+            other => panic!("expected missing credentials, got {:?}", other),
+        }
+    }
+}
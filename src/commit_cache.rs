@@ -0,0 +1,211 @@
+//! Remember who authored each nixpkgs commit across runs. A commit's
+//! author never changes, so once fetched it never needs fetching again.
+
+use crate::cli::ExitError;
+use crate::github::{
+    Commit, GitHubApi, GitHubFuture, GitHubStream, OrgRole, RateLimit, Repo, Team, TeamRole, User,
+};
+use crate::maintainers::GitHubName;
+use futures::future::{self, Future};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Answers commit lookups from the cache where it can, and otherwise
+/// passes every call through to the wrapped GitHub. Without a path the
+/// cache only lasts for this run.
+#[derive(Clone)]
+pub struct CommitCache<G> {
+    github: G,
+    path: Option<PathBuf>,
+    authors: Arc<Mutex<HashMap<String, User>>>,
+    logger: slog::Logger,
+}
+
+impl<G: GitHubApi> CommitCache<G> {
+    pub fn load(
+        logger: slog::Logger,
+        github: G,
+        path: Option<&Path>,
+    ) -> Result<CommitCache<G>, ExitError> {
+        let authors = match path {
+            Some(path) if path.exists() => {
+                let file = File::open(path).map_err(|err| {
+                    error!(logger, "Failed to open commit cache {:?}: {:?}", path, err);
+                    err
+                })?;
+                let authors: HashMap<String, User> = serde_json::from_reader(BufReader::new(file))
+                    .map_err(|err| {
+                        error!(logger, "Failed to parse commit cache {:?}: {:?}", path, err);
+                        err
+                    })?;
+
+                debug!(logger, "Loaded commit cache";
+                       "commits" => authors.len(),
+                );
+                authors
+            }
+            _ => HashMap::new(),
+        };
+
+        Ok(CommitCache {
+            github,
+            path: path.map(Path::to_path_buf),
+            authors: Arc::new(Mutex::new(authors)),
+            logger,
+        })
+    }
+
+    /// Write the cache out, replacing the old one atomically.
+    pub fn save(&self) -> Result<(), ExitError> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        {
+            let authors = self.authors.lock().unwrap();
+            let sorted: std::collections::BTreeMap<&String, &User> = authors.iter().collect();
+            file.write_all(serde_json::to_string_pretty(&sorted)?.as_bytes())?;
+        }
+        file.persist(path).map_err(|err| {
+            error!(
+                self.logger,
+                "Failed to write commit cache {:?}: {:?}", path, err.error
+            );
+            err.error
+        })?;
+
+        Ok(())
+    }
+}
+
+impl<G: GitHubApi> GitHubApi for CommitCache<G> {
+    fn get_user(&self, login: &GitHubName) -> GitHubFuture<User> {
+        self.github.get_user(login)
+    }
+
+    fn iter_teams(&self, org: &str) -> GitHubStream<Team> {
+        self.github.iter_teams(org)
+    }
+
+    fn get_team(&self, org: &str, team_id: u64) -> GitHubFuture<Team> {
+        self.github.get_team(org, team_id)
+    }
+
+    fn iter_team_members(&self, org: &str, team_id: u64) -> GitHubStream<User> {
+        self.github.iter_team_members(org, team_id)
+    }
+
+    fn add_user(
+        &self,
+        org: &str,
+        team_id: u64,
+        login: &GitHubName,
+        role: TeamRole,
+    ) -> GitHubFuture<()> {
+        self.github.add_user(org, team_id, login, role)
+    }
+
+    fn remove_user(&self, org: &str, team_id: u64, login: &GitHubName) -> GitHubFuture<()> {
+        self.github.remove_user(org, team_id, login)
+    }
+
+    fn get_org_role(&self, org: &str, login: &GitHubName) -> GitHubFuture<OrgRole> {
+        self.github.get_org_role(org, login)
+    }
+
+    fn iter_invitations(&self, org: &str) -> GitHubStream<GitHubName> {
+        self.github.iter_invitations(org)
+    }
+
+    fn get_commit(&self, owner: &str, repo: &str, sha: &str) -> GitHubFuture<Commit> {
+        if let Some(author) = self.authors.lock().unwrap().get(sha) {
+            trace!(self.logger, "Commit author is cached";
+                   "commit" => sha,
+            );
+            return Box::new(future::ok(Commit {
+                author: author.clone(),
+            }));
+        }
+
+        let authors = self.authors.clone();
+        let sha = sha.to_string();
+        Box::new(
+            self.github
+                .get_commit(owner, repo, &sha)
+                .map(move |commit| {
+                    authors.lock().unwrap().insert(sha, commit.author.clone());
+                    commit
+                }),
+        )
+    }
+
+    fn rate_limit(&self) -> GitHubFuture<RateLimit> {
+        self.github.rate_limit()
+    }
+
+    fn create_issue(&self, repo: &Repo, title: &str, body: &str) -> GitHubFuture<u64> {
+        self.github.create_issue(repo, title, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::{FakeGitHub, FakeState};
+    use crate::maintainers::GitHubID;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_cached_across_runs() {
+        let mut rt = Runtime::new().unwrap();
+        let logger = crate::test_logger();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("commits.json");
+        let author = User {
+            login: GitHubName::new("alice"),
+            id: GitHubID::new(1),
+        };
+
+        let github = FakeGitHub::new(FakeState {
+            commits: vec![(
+                "5bd136acd4c683b30470b5dfbb6f0b15dcea42a5".to_string(),
+                Commit {
+                    author: author.clone(),
+                },
+            )]
+            .into_iter()
+            .collect(),
+            ..FakeState::default()
+        });
+        let cache = CommitCache::load(logger.clone(), github, Some(&path)).unwrap();
+        rt.block_on(cache.get_commit(
+            "NixOS",
+            "nixpkgs",
+            "5bd136acd4c683b30470b5dfbb6f0b15dcea42a5",
+        ))
+        .unwrap();
+        cache.save().unwrap();
+
+        // GitHub no longer knows the commit, so it must come from disk
+        let cache =
+            CommitCache::load(logger, FakeGitHub::new(FakeState::default()), Some(&path)).unwrap();
+        assert_eq!(
+            rt.block_on(cache.get_commit(
+                "NixOS",
+                "nixpkgs",
+                "5bd136acd4c683b30470b5dfbb6f0b15dcea42a5",
+            ))
+            .unwrap(),
+            Commit { author }
+        );
+    }
+}
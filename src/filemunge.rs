@@ -11,7 +11,8 @@
 //! Note, regex capture the leading whitespace from the `github =` line
 //! to match indentation, no matter how janky it is.
 //!
-//! Then delete the ID from the hashmap.
+//! Then delete the ID from the hashmap. If the record already has a
+//! githubId further down, leave it be.
 //!
 //! This might work:
 //!
@@ -21,12 +22,112 @@
 use crate::maintainers::{GitHubID, GitHubName};
 use regex::Regex;
 use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
 
-pub fn backfill_file(mut ids: HashMap<GitHubName, GitHubID>, file: String) -> String {
-    lazy_static! {
-        static ref RE: Regex =
-            Regex::new(r#"^(?P<leading_space>\s+)github = "(?P<name>[^"]*)";$"#).unwrap();
+lazy_static! {
+    static ref RE: Regex =
+        Regex::new(r#"^(?P<leading_space>\s+)github = "(?P<name>[^"]*)";$"#).unwrap();
+    static ref ID_RE: Regex = Regex::new(r#"^\s*githubId\s*="#).unwrap();
+}
+
+/// The result of `backfill_file`.
+pub struct Backfilled {
+    pub file: String,
+    /// How many githubId lines were added.
+    pub inserted: usize,
+    /// Names with no `github = "...";` line to put the ID after.
+    pub unplaced: Vec<GitHubName>,
+}
+
+pub fn backfill_file(mut ids: HashMap<GitHubName, GitHubID>, file: String) -> Backfilled {
+    let newline = line_ending(&file);
+    let lines: Vec<&str> = file.lines().collect();
+    let mut inserted = 0;
+
+    let file = lines
+        .iter()
+        .enumerate()
+        .map(|(n, line)| {
+            if let Some(matches) = RE.captures(line) {
+                let username = matches
+                    .name("name")
+                    .expect("name should be in regex")
+                    .as_str();
+
+                if let Some(id) = ids.remove(&GitHubName::new(username.to_string())) {
+                    if has_github_id(&lines[n + 1..]) {
+                        return format!("{}{}", line, newline);
+                    }
+
+                    let leading_space = matches
+                        .name("leading_space")
+                        .expect("leading_space should be in regex")
+                        .as_str();
+
+                    inserted += 1;
+                    return format!(
+                        "{}{}{}githubId = {};{}",
+                        line, newline, leading_space, id, newline
+                    );
+                }
+            }
+
+            return format!("{}{}", line, newline);
+        })
+        .collect();
+
+    let mut unplaced: Vec<GitHubName> = ids.into_iter().map(|(name, _id)| name).collect();
+    unplaced.sort_by_key(|name| name.to_string());
+
+    Backfilled {
+        file,
+        inserted,
+        unplaced,
     }
+}
+
+/// Replace the file's contents, keeping its permissions, so that
+/// it's never left half written.
+pub fn write_in_place(path: &Path, contents: &str) -> Result<(), std::io::Error> {
+    // Replace what a symlink points to, not the symlink.
+    let path = path.canonicalize()?;
+    let dir = path
+        .parent()
+        .expect("a canonical file path always has a parent");
+
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(contents.as_bytes())?;
+    fs::set_permissions(file.path(), fs::metadata(&path)?.permissions())?;
+    file.persist(&path)?;
+
+    Ok(())
+}
+
+/// The line ending most of the file uses. `str::lines` strips either
+/// kind, so lines are written back out with this.
+fn line_ending(file: &str) -> &'static str {
+    let crlf = file.matches("\r\n").count();
+    if crlf * 2 > file.matches('\n').count() {
+        "\r\n"
+    } else {
+        "\n"
+    }
+}
+
+/// Whether there's a githubId before the end of the record `rest`
+/// starts in.
+fn has_github_id(rest: &[&str]) -> bool {
+    rest.iter()
+        .take_while(|line| !line.trim_start().starts_with("};"))
+        .any(|line| ID_RE.is_match(line))
+}
+
+/// Replace the name in each `github = "...";` line which `renames` has
+/// a new name for.
+pub fn rename_handles(renames: &HashMap<GitHubName, GitHubName>, file: String) -> String {
+    let newline = line_ending(&file);
 
     file.lines()
         .map(|line| {
@@ -36,24 +137,24 @@ pub fn backfill_file(mut ids: HashMap<GitHubName, GitHubID>, file: String) -> St
                     .expect("name should be in regex")
                     .as_str();
 
-                if let Some(id) = ids.remove(&GitHubName::new(username.to_string())) {
+                if let Some(new_name) = renames.get(&GitHubName::new(username.to_string())) {
                     let leading_space = matches
                         .name("leading_space")
                         .expect("leading_space should be in regex")
                         .as_str();
 
-                    return format!("{}\n{}githubId = {};\n", line, leading_space, id);
+                    return format!("{}github = \"{}\";{}", leading_space, new_name, newline);
                 }
             }
 
-            return format!("{}\n", line);
+            format!("{}{}", line, newline)
         })
         .collect()
 }
 
 #[cfg(test)]
 mod tests {
-    use super::backfill_file;
+    use super::{backfill_file, line_ending, rename_handles, write_in_place};
     use crate::maintainers::{GitHubID, GitHubName};
     use std::fs::read_to_string;
 
@@ -77,6 +178,155 @@ mod tests {
             input,
         );
 
-        assert_eq!(expect, output);
+        assert_eq!(expect, output.file);
+        assert_eq!(output.inserted, 2);
+        assert!(output.unplaced.is_empty());
+    }
+
+    #[test]
+    fn test_rename_handles() {
+        let input = r#"  alice = {
+    email = "alice@example.com";
+    github = "alice";
+    githubId = 1;
+  };
+  bob = {
+    github = "bob";
+    githubId = 2;
+  };
+"#;
+
+        let output = rename_handles(
+            &vec![(GitHubName::new("Alice"), GitHubName::new("alice2"))]
+                .into_iter()
+                .collect(),
+            input.to_string(),
+        );
+
+        assert_eq!(
+            output,
+            input.replace(r#"github = "alice";"#, r#"github = "alice2";"#)
+        );
+    }
+
+    #[test]
+    fn test_backfill_half_backfilled() {
+        let input = r#"  alice = {
+    github = "alice";
+    name = "Alice";
+    githubId = 1;
+  };
+  bob = {
+    github = "bob";
+    name = "Bob";
+  };
+"#;
+
+        let output = backfill_file(
+            vec![
+                (GitHubName::new("alice"), GitHubID::new(1)),
+                (GitHubName::new("bob"), GitHubID::new(2)),
+            ]
+            .into_iter()
+            .collect(),
+            input.to_string(),
+        )
+        .file;
+
+        assert_eq!(
+            output,
+            input.replace(
+                "    github = \"bob\";\n",
+                "    github = \"bob\";\n    githubId = 2;\n"
+            )
+        );
+
+        // Running it again changes nothing
+        assert_eq!(
+            backfill_file(
+                vec![(GitHubName::new("bob"), GitHubID::new(2))]
+                    .into_iter()
+                    .collect(),
+                output.clone(),
+            )
+            .file,
+            output
+        );
+    }
+
+    #[test]
+    fn test_backfill_crlf() {
+        let input = "  alice = {\r\n    github = \"alice\";\r\n  };\r\n";
+
+        assert_eq!(
+            backfill_file(
+                vec![(GitHubName::new("alice"), GitHubID::new(1))]
+                    .into_iter()
+                    .collect(),
+                input.to_string(),
+            )
+            .file,
+            "  alice = {\r\n    github = \"alice\";\r\n    githubId = 1;\r\n  };\r\n"
+        );
+    }
+
+    #[test]
+    fn test_backfill_tabs() {
+        let input = "\talice = {\n\t\tgithub = \"alice\";\n\t};\n";
+
+        assert_eq!(
+            backfill_file(
+                vec![(GitHubName::new("alice"), GitHubID::new(1))]
+                    .into_iter()
+                    .collect(),
+                input.to_string(),
+            )
+            .file,
+            "\talice = {\n\t\tgithub = \"alice\";\n\t\tgithubId = 1;\n\t};\n"
+        );
+    }
+
+    #[test]
+    fn test_line_ending() {
+        assert_eq!(line_ending("a\nb\n"), "\n");
+        assert_eq!(line_ending("a\r\nb\r\n"), "\r\n");
+        assert_eq!(line_ending("a\r\nb\r\nc\n"), "\r\n");
+        assert_eq!(line_ending("a\r\nb\nc\n"), "\n");
+        assert_eq!(line_ending(""), "\n");
+    }
+
+    #[test]
+    fn test_backfill_unplaced() {
+        let output = backfill_file(
+            vec![
+                (GitHubName::new("alice"), GitHubID::new(1)),
+                (GitHubName::new("bob"), GitHubID::new(2)),
+            ]
+            .into_iter()
+            .collect(),
+            "  alice = {\n    github = \"alice\";\n  };\n  bob = {\n    github  = \"bob\";\n  };\n"
+                .to_string(),
+        );
+
+        assert_eq!(output.inserted, 1);
+        assert_eq!(output.unplaced, vec![GitHubName::new("bob")]);
+    }
+
+    #[test]
+    fn test_write_in_place() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("maintainer-list.nix");
+        std::fs::write(&path, "old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        write_in_place(&path, "new").unwrap();
+
+        assert_eq!(read_to_string(&path).unwrap(), "new");
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
     }
 }
@@ -0,0 +1,1269 @@
+//! The GitHub API calls rfc39 makes, behind a trait so the ops can be
+//! tested against a fake instead of GitHub itself.
+
+use crate::cli::ExitError;
+use crate::maintainers::{GitHubID, GitHubName};
+use crate::metrics::RetryMetrics;
+use crate::proxy;
+use futures::future::{self, Loop};
+use futures::{Future, Stream};
+use hubcaps::issues::IssueOptions;
+use hubcaps::teams::{TeamMemberOptions, TeamMemberRole};
+use hubcaps::{Credentials, Github};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::timer::{Delay, Timeout};
+
+pub type GitHubFuture<T> = Box<dyn Future<Item = T, Error = GitHubError> + Send>;
+pub type GitHubStream<T> = Box<dyn Stream<Item = T, Error = GitHubError> + Send>;
+
+#[derive(Debug)]
+pub enum GitHubError {
+    Hubcaps(hubcaps::Error),
+    /// GitHub didn't answer within the timeout.
+    Timeout(Duration),
+    #[cfg(test)]
+    Fake {
+        message: String,
+        transient: bool,
+    },
+    #[cfg(test)]
+    FakeRateLimit(Duration),
+    #[cfg(test)]
+    FakeNotFound(String),
+    #[cfg(test)]
+    FakeSecondaryRateLimit,
+}
+
+impl GitHubError {
+    #[cfg(test)]
+    pub fn fake<T: Into<String>>(message: T) -> GitHubError {
+        GitHubError::Fake {
+            message: message.into(),
+            transient: false,
+        }
+    }
+
+    /// Whether trying again might succeed: network errors and server
+    /// errors are, client errors are not.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            GitHubError::Hubcaps(e) => match e.kind() {
+                hubcaps::ErrorKind::Fault { code, .. } => code.is_server_error(),
+                hubcaps::ErrorKind::Reqwest(_) | hubcaps::ErrorKind::IO(_) => true,
+                _ => false,
+            },
+            GitHubError::Timeout(_) => true,
+            #[cfg(test)]
+            GitHubError::Fake { transient, .. } => *transient,
+            #[cfg(test)]
+            GitHubError::FakeRateLimit(_) => false,
+            #[cfg(test)]
+            GitHubError::FakeNotFound(_) => false,
+            #[cfg(test)]
+            GitHubError::FakeSecondaryRateLimit => false,
+        }
+    }
+
+    /// Whether GitHub answered that there's no such thing, like a
+    /// deleted account.
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            GitHubError::Hubcaps(e) => match e.kind() {
+                hubcaps::ErrorKind::Fault { code, .. } => code.as_u16() == 404,
+                _ => false,
+            },
+            GitHubError::Timeout(_) => false,
+            #[cfg(test)]
+            GitHubError::Fake { .. }
+            | GitHubError::FakeRateLimit(_)
+            | GitHubError::FakeSecondaryRateLimit => false,
+            #[cfg(test)]
+            GitHubError::FakeNotFound(_) => true,
+        }
+    }
+
+    /// How long until the rate limit resets, if this call failed
+    /// because it was used up.
+    pub fn rate_limit_reset(&self) -> Option<Duration> {
+        match self {
+            GitHubError::Hubcaps(e) => match e.kind() {
+                hubcaps::ErrorKind::RateLimit { reset } => Some(*reset),
+                _ => None,
+            },
+            GitHubError::Timeout(_) => None,
+            #[cfg(test)]
+            GitHubError::Fake { .. } => None,
+            #[cfg(test)]
+            GitHubError::FakeRateLimit(reset) => Some(*reset),
+            #[cfg(test)]
+            GitHubError::FakeNotFound(_) | GitHubError::FakeSecondaryRateLimit => None,
+        }
+    }
+
+    /// Whether GitHub refused the call for coming too quickly after
+    /// others, its secondary rate limit. It answers 403 with a message
+    /// saying so.
+    pub fn is_secondary_rate_limit(&self) -> bool {
+        match self {
+            GitHubError::Hubcaps(e) => match e.kind() {
+                hubcaps::ErrorKind::Fault { code, error } => {
+                    let message = error.message.to_lowercase();
+                    code.as_u16() == 403
+                        && (message.contains("secondary rate limit") || message.contains("abuse"))
+                }
+                _ => false,
+            },
+            GitHubError::Timeout(_) => false,
+            #[cfg(test)]
+            GitHubError::Fake { .. }
+            | GitHubError::FakeRateLimit(_)
+            | GitHubError::FakeNotFound(_) => false,
+            #[cfg(test)]
+            GitHubError::FakeSecondaryRateLimit => true,
+        }
+    }
+}
+
+impl std::fmt::Display for GitHubError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GitHubError::Hubcaps(e) => write!(f, "{}", e),
+            GitHubError::Timeout(timeout) => write!(f, "timed out after {}s", timeout.as_secs()),
+            #[cfg(test)]
+            GitHubError::Fake { message, .. } => write!(f, "{}", message),
+            #[cfg(test)]
+            GitHubError::FakeRateLimit(reset) => {
+                write!(f, "rate limited for {}s", reset.as_secs())
+            }
+            #[cfg(test)]
+            GitHubError::FakeNotFound(message) => write!(f, "{}", message),
+            #[cfg(test)]
+            GitHubError::FakeSecondaryRateLimit => write!(f, "secondary rate limit"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub login: GitHubName,
+    pub id: GitHubID,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Team {
+    pub id: u64,
+    pub name: String,
+    pub slug: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    pub author: User,
+}
+
+/// A user's role in an organization.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OrgRole {
+    Member,
+    Owner,
+}
+
+/// The role a user is given when added to a team.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TeamRole {
+    Member,
+    Maintainer,
+}
+
+impl std::str::FromStr for TeamRole {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "member" => Ok(TeamRole::Member),
+            "maintainer" => Ok(TeamRole::Maintainer),
+            _ => Err(format!(
+                "unsupported role {:?}, expected member or maintainer",
+                role
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for TeamRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TeamRole::Member => write!(f, "member"),
+            TeamRole::Maintainer => write!(f, "maintainer"),
+        }
+    }
+}
+
+/// A repository, written as owner/name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repo {
+    pub owner: String,
+    pub name: String,
+}
+
+impl std::str::FromStr for Repo {
+    type Err = String;
+
+    fn from_str(repo: &str) -> Result<Self, Self::Err> {
+        let mut parts = repo.splitn(2, '/');
+        match (parts.next(), parts.next()) {
+            (Some(owner), Some(name))
+                if !owner.is_empty() && !name.is_empty() && !name.contains('/') =>
+            {
+                Ok(Repo {
+                    owner: owner.to_string(),
+                    name: name.to_string(),
+                })
+            }
+            _ => Err(format!("expected owner/name, got {:?}", repo)),
+        }
+    }
+}
+
+impl std::fmt::Display for Repo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.owner, self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    pub remaining: u64,
+    /// When the quota is restored, in seconds since the epoch.
+    pub reset: u64,
+}
+
+pub trait GitHubApi: Clone + Send + 'static {
+    fn get_user(&self, login: &GitHubName) -> GitHubFuture<User>;
+
+    fn iter_teams(&self, org: &str) -> GitHubStream<Team>;
+
+    fn get_team(&self, org: &str, team_id: u64) -> GitHubFuture<Team>;
+
+    fn iter_team_members(&self, org: &str, team_id: u64) -> GitHubStream<User>;
+
+    fn add_user(
+        &self,
+        org: &str,
+        team_id: u64,
+        login: &GitHubName,
+        role: TeamRole,
+    ) -> GitHubFuture<()>;
+
+    fn remove_user(&self, org: &str, team_id: u64, login: &GitHubName) -> GitHubFuture<()>;
+
+    fn get_org_role(&self, org: &str, login: &GitHubName) -> GitHubFuture<OrgRole>;
+
+    /// Users with a pending invitation to the organization, from every
+    /// page. Invitations sent to an email address have no user and are
+    /// left out.
+    fn iter_invitations(&self, org: &str) -> GitHubStream<GitHubName>;
+
+    fn get_commit(&self, owner: &str, repo: &str, sha: &str) -> GitHubFuture<Commit>;
+
+    /// The core API quota. Checking it doesn't use it up.
+    fn rate_limit(&self) -> GitHubFuture<RateLimit>;
+
+    /// Open an issue, returning its number.
+    fn create_issue(&self, repo: &Repo, title: &str, body: &str) -> GitHubFuture<u64>;
+}
+
+fn user(login: String, id: u64) -> User {
+    User {
+        login: GitHubName::new(login),
+        id: GitHubID::new(id),
+    }
+}
+
+/// A hubcaps client authenticated with `credentials`, which goes
+/// through the environment's proxy if one is set.
+pub fn github_client(logger: &slog::Logger, credentials: Credentials) -> Result<Github, ExitError> {
+    let http = proxy::client(logger, "api.github.com").map_err(|e| {
+        ExitError::InvalidCredentials(format!("failed to create a GitHub client: {}", e))
+    })?;
+    Ok(Github::custom(
+        "https://api.github.com",
+        "NixOS/rfcs#39 (hubcaps)",
+        credentials,
+        http,
+    ))
+}
+
+impl GitHubApi for Github {
+    fn get_user(&self, login: &GitHubName) -> GitHubFuture<User> {
+        Box::new(
+            self.users()
+                .get(login.to_string())
+                .map(|found| user(found.login, found.id))
+                .map_err(GitHubError::Hubcaps),
+        )
+    }
+
+    fn iter_teams(&self, org: &str) -> GitHubStream<Team> {
+        Box::new(
+            self.org(org)
+                .teams()
+                .iter()
+                .map(|team| Team {
+                    id: team.id,
+                    name: team.name,
+                    slug: team.slug,
+                })
+                .map_err(GitHubError::Hubcaps),
+        )
+    }
+
+    fn get_team(&self, org: &str, team_id: u64) -> GitHubFuture<Team> {
+        Box::new(
+            self.org(org)
+                .teams()
+                .get(team_id)
+                .get()
+                .map(|team| Team {
+                    id: team.id,
+                    name: team.name,
+                    slug: team.slug,
+                })
+                .map_err(GitHubError::Hubcaps),
+        )
+    }
+
+    fn iter_team_members(&self, org: &str, team_id: u64) -> GitHubStream<User> {
+        Box::new(
+            self.org(org)
+                .teams()
+                .get(team_id)
+                .iter_members()
+                .map(|member| user(member.login, member.id))
+                .map_err(GitHubError::Hubcaps),
+        )
+    }
+
+    fn add_user(
+        &self,
+        org: &str,
+        team_id: u64,
+        login: &GitHubName,
+        role: TeamRole,
+    ) -> GitHubFuture<()> {
+        Box::new(
+            self.org(org)
+                .teams()
+                .get(team_id)
+                .add_user(
+                    &login.to_string(),
+                    TeamMemberOptions {
+                        role: match role {
+                            TeamRole::Member => TeamMemberRole::Member,
+                            TeamRole::Maintainer => TeamMemberRole::Maintainer,
+                        },
+                    },
+                )
+                .map(|_| ())
+                .map_err(GitHubError::Hubcaps),
+        )
+    }
+
+    fn remove_user(&self, org: &str, team_id: u64, login: &GitHubName) -> GitHubFuture<()> {
+        Box::new(
+            self.org(org)
+                .teams()
+                .get(team_id)
+                .remove_user(&login.to_string())
+                .map_err(GitHubError::Hubcaps),
+        )
+    }
+
+    fn get_org_role(&self, org: &str, login: &GitHubName) -> GitHubFuture<OrgRole> {
+        Box::new(
+            self.org(org)
+                .membership()
+                .get(&login.to_string())
+                .map(|membership| {
+                    // The API calls owners admins.
+                    if membership.role == "admin" {
+                        OrgRole::Owner
+                    } else {
+                        OrgRole::Member
+                    }
+                })
+                .map_err(GitHubError::Hubcaps),
+        )
+    }
+
+    fn iter_invitations(&self, org: &str) -> GitHubStream<GitHubName> {
+        // hubcaps follows each page's `Link: rel="next"` until there
+        // are none left, so the stream ends only after the last page.
+        Box::new(
+            self.org(org)
+                .membership()
+                .invitations()
+                .filter_map(|invite| Some(GitHubName::new(invite.login?)))
+                .map_err(GitHubError::Hubcaps),
+        )
+    }
+
+    fn get_commit(&self, owner: &str, repo: &str, sha: &str) -> GitHubFuture<Commit> {
+        Box::new(
+            self.repo(owner, repo)
+                .commits()
+                .get(sha)
+                .map(|commit| Commit {
+                    author: user(commit.author.login, commit.author.id),
+                })
+                .map_err(GitHubError::Hubcaps),
+        )
+    }
+
+    fn rate_limit(&self) -> GitHubFuture<RateLimit> {
+        Box::new(
+            self.rate_limit()
+                .get()
+                .map(|status| RateLimit {
+                    remaining: status.rate.remaining.into(),
+                    reset: status.rate.reset.into(),
+                })
+                .map_err(GitHubError::Hubcaps),
+        )
+    }
+
+    fn create_issue(&self, repo: &Repo, title: &str, body: &str) -> GitHubFuture<u64> {
+        Box::new(
+            self.repo(repo.owner.as_str(), repo.name.as_str())
+                .issues()
+                .create(&IssueOptions::new(
+                    title,
+                    Some(body),
+                    None::<String>,
+                    None,
+                    Vec::<String>::new(),
+                ))
+                .map(|issue| issue.number)
+                .map_err(GitHubError::Hubcaps),
+        )
+    }
+}
+
+/// Record the remaining quota, warning when it is running low.
+pub fn record_rate_limit<G: GitHubApi>(
+    logger: &slog::Logger,
+    github: &G,
+    rt: &mut tokio::runtime::Runtime,
+    metrics: &RetryMetrics,
+) {
+    match rt.block_on(github.rate_limit()) {
+        Ok(rate_limit) => {
+            metrics
+                .rate_limit_remaining
+                .set(rate_limit.remaining as i64);
+            info!(logger, "GitHub rate limit";
+                  "remaining" => rate_limit.remaining,
+                  "reset" => rate_limit.reset,
+            );
+        }
+        Err(e) => {
+            warn!(logger, "Failed to fetch the GitHub rate limit";
+                  "error" => %e,
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts after the first one. Zero disables retrying.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each one after.
+    pub base_delay: Duration,
+    /// Up to this much is randomly added to each delay, so concurrent
+    /// calls don't retry in lockstep.
+    pub max_jitter: Duration,
+    /// Give up on an attempt which takes longer than this, which counts
+    /// as a transient failure.
+    pub timeout: Option<Duration>,
+    /// How long to back off after hitting the secondary rate limit.
+    /// GitHub sends a Retry-After header with it, but hubcaps doesn't
+    /// pass it on, so this is the minute GitHub asks for without one.
+    pub secondary_rate_limit_wait: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_secs(1),
+            max_jitter: Duration::from_millis(500),
+            timeout: None,
+            secondary_rate_limit_wait: Duration::from_secs(60),
+        }
+    }
+
+    /// Time out attempts after `timeout`, or never for zero.
+    pub fn with_timeout(self, timeout: Duration) -> RetryPolicy {
+        RetryPolicy {
+            timeout: if timeout == Duration::from_secs(0) {
+                None
+            } else {
+                Some(timeout)
+            },
+            ..self
+        }
+    }
+
+    fn attempt<T>(&self, call: GitHubFuture<T>) -> GitHubFuture<T>
+    where
+        T: Send + 'static,
+    {
+        match self.timeout {
+            Some(timeout) => Box::new(Timeout::new(call, timeout).map_err(move |e| {
+                if e.is_elapsed() {
+                    GitHubError::Timeout(timeout)
+                } else if e.is_inner() {
+                    e.into_inner().expect("checked is_inner")
+                } else {
+                    // The timer itself failed, which is worth retrying too.
+                    GitHubError::Timeout(timeout)
+                }
+            })),
+            None => call,
+        }
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        self.base_delay * 2u32.saturating_pow(retry) + jitter(self.max_jitter)
+    }
+}
+
+/// A random duration below `max`.
+fn jitter(max: Duration) -> Duration {
+    let max_ms = max.as_millis() as u64;
+    if max_ms == 0 {
+        return Duration::from_millis(0);
+    }
+
+    // Good enough randomness for spreading calls out.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.subsec_nanos())
+        .unwrap_or(0);
+    Duration::from_millis(u64::from(nanos) % max_ms)
+}
+
+/// Run `call`, running it again after a growing delay when it fails
+/// with a transient error, or once the rate limit resets if it was used
+/// up. Waiting for the rate limit does not count as a retry.
+pub fn retrying<T, F>(
+    logger: slog::Logger,
+    policy: RetryPolicy,
+    metrics: RetryMetrics,
+    call: F,
+) -> GitHubFuture<T>
+where
+    T: Send + 'static,
+    F: Fn() -> GitHubFuture<T> + Send + 'static,
+{
+    Box::new(future::loop_fn(0, move |retry| {
+        let logger = logger.clone();
+        let metrics = metrics.clone();
+        let attempt = policy.attempt(call());
+        attempt.then(move |result| -> GitHubFuture<Loop<T, u32>> {
+            match result {
+                Ok(value) => Box::new(future::ok(Loop::Break(value))),
+                Err(ref e) if e.rate_limit_reset().is_some() => {
+                    // Give GitHub's clock a second of slack.
+                    let wait = e.rate_limit_reset().unwrap() + Duration::from_secs(1);
+                    metrics.rate_limit_remaining.set(0);
+                    warn!(logger, "GitHub rate limit used up, waiting for it to reset";
+                          "wait_seconds" => wait.as_secs(),
+                    );
+                    Box::new(
+                        Delay::new(Instant::now() + wait)
+                            .then(move |_| Ok::<_, GitHubError>(Loop::Continue(retry))),
+                    )
+                }
+                Err(ref e) if e.is_secondary_rate_limit() && retry < policy.max_retries => {
+                    let wait = policy.secondary_rate_limit_wait;
+                    metrics.retries.inc();
+                    warn!(logger, "Hit GitHub's secondary rate limit, backing off";
+                          "retry" => retry + 1,
+                          "wait_seconds" => wait.as_secs(),
+                    );
+                    Box::new(
+                        Delay::new(Instant::now() + wait)
+                            .then(move |_| Ok::<_, GitHubError>(Loop::Continue(retry + 1))),
+                    )
+                }
+                Err(e) if e.is_transient() && retry < policy.max_retries => {
+                    let delay = policy.delay(retry);
+                    metrics.retries.inc();
+                    warn!(logger, "Transient GitHub failure, retrying";
+                          "error" => %e,
+                          "retry" => retry + 1,
+                          "delay_ms" => delay.as_millis() as u64,
+                    );
+                    Box::new(
+                        Delay::new(Instant::now() + delay)
+                            .then(move |_| Ok::<_, GitHubError>(Loop::Continue(retry + 1))),
+                    )
+                }
+                Err(e) => Box::new(future::err(e)),
+            }
+        })
+    }))
+}
+
+/// Retry every call to the wrapped GitHub according to a policy.
+/// Listings are fetched again from the start.
+#[derive(Clone)]
+pub struct Retrying<G> {
+    github: G,
+    policy: RetryPolicy,
+    metrics: RetryMetrics,
+    logger: slog::Logger,
+}
+
+impl<G: GitHubApi> Retrying<G> {
+    pub fn new(
+        logger: slog::Logger,
+        github: G,
+        policy: RetryPolicy,
+        metrics: RetryMetrics,
+    ) -> Retrying<G> {
+        Retrying {
+            github,
+            policy,
+            metrics,
+            logger,
+        }
+    }
+
+    fn retry<T, F>(&self, call: F) -> GitHubFuture<T>
+    where
+        T: Send + 'static,
+        F: Fn(&G) -> GitHubFuture<T> + Send + 'static,
+    {
+        let github = self.github.clone();
+        retrying(
+            self.logger.clone(),
+            self.policy,
+            self.metrics.clone(),
+            move || call(&github),
+        )
+    }
+
+    fn retry_stream<T, F>(&self, call: F) -> GitHubStream<T>
+    where
+        T: Send + 'static,
+        F: Fn(&G) -> GitHubStream<T> + Send + 'static,
+    {
+        Box::new(
+            self.retry(move |github: &G| -> GitHubFuture<Vec<T>> {
+                Box::new(call(github).collect())
+            })
+            .map(futures::stream::iter_ok)
+            .flatten_stream(),
+        )
+    }
+}
+
+impl<G: GitHubApi> GitHubApi for Retrying<G> {
+    fn get_user(&self, login: &GitHubName) -> GitHubFuture<User> {
+        let login = login.clone();
+        self.retry(move |github| github.get_user(&login))
+    }
+
+    fn iter_teams(&self, org: &str) -> GitHubStream<Team> {
+        let org = org.to_string();
+        self.retry_stream(move |github| github.iter_teams(&org))
+    }
+
+    fn get_team(&self, org: &str, team_id: u64) -> GitHubFuture<Team> {
+        let org = org.to_string();
+        self.retry(move |github| github.get_team(&org, team_id))
+    }
+
+    fn iter_team_members(&self, org: &str, team_id: u64) -> GitHubStream<User> {
+        let org = org.to_string();
+        self.retry_stream(move |github| github.iter_team_members(&org, team_id))
+    }
+
+    fn add_user(
+        &self,
+        org: &str,
+        team_id: u64,
+        login: &GitHubName,
+        role: TeamRole,
+    ) -> GitHubFuture<()> {
+        let org = org.to_string();
+        let login = login.clone();
+        self.retry(move |github| github.add_user(&org, team_id, &login, role))
+    }
+
+    fn remove_user(&self, org: &str, team_id: u64, login: &GitHubName) -> GitHubFuture<()> {
+        let org = org.to_string();
+        let login = login.clone();
+        self.retry(move |github| github.remove_user(&org, team_id, &login))
+    }
+
+    fn get_org_role(&self, org: &str, login: &GitHubName) -> GitHubFuture<OrgRole> {
+        let org = org.to_string();
+        let login = login.clone();
+        self.retry(move |github| github.get_org_role(&org, &login))
+    }
+
+    fn iter_invitations(&self, org: &str) -> GitHubStream<GitHubName> {
+        let org = org.to_string();
+        self.retry_stream(move |github| github.iter_invitations(&org))
+    }
+
+    fn get_commit(&self, owner: &str, repo: &str, sha: &str) -> GitHubFuture<Commit> {
+        let owner = owner.to_string();
+        let repo = repo.to_string();
+        let sha = sha.to_string();
+        self.retry(move |github| github.get_commit(&owner, &repo, &sha))
+    }
+
+    fn rate_limit(&self) -> GitHubFuture<RateLimit> {
+        self.retry(move |github| github.rate_limit())
+    }
+
+    /// Not retried: if GitHub opened the issue but the response was
+    /// lost, trying again would open a second one.
+    fn create_issue(&self, repo: &Repo, title: &str, body: &str) -> GitHubFuture<u64> {
+        self.github.create_issue(repo, title, body)
+    }
+}
+
+/// Space out the wrapped GitHub's writes, since GitHub's secondary
+/// rate limit refuses writes which come too quickly one after another.
+/// Reads go straight through.
+#[derive(Clone)]
+pub struct Throttled<G> {
+    github: G,
+    delay: Duration,
+    max_jitter: Duration,
+    next_write: Arc<Mutex<Instant>>,
+}
+
+impl<G: GitHubApi> Throttled<G> {
+    /// Writes start at least `delay`, plus up to `max_jitter`, apart.
+    pub fn new(github: G, delay: Duration, max_jitter: Duration) -> Throttled<G> {
+        Throttled {
+            github,
+            delay,
+            max_jitter,
+            next_write: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Make the write once its turn comes.
+    fn throttle<T, F>(&self, write: F) -> GitHubFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&G) -> GitHubFuture<T> + Send + 'static,
+    {
+        let now = Instant::now();
+        let start = {
+            let mut next_write = self.next_write.lock().unwrap();
+            let start = std::cmp::max(*next_write, now);
+            *next_write = start + self.delay + jitter(self.max_jitter);
+            start
+        };
+
+        if start <= now {
+            write(&self.github)
+        } else {
+            let github = self.github.clone();
+            Box::new(Delay::new(start).then(move |_| write(&github)))
+        }
+    }
+}
+
+impl<G: GitHubApi> GitHubApi for Throttled<G> {
+    fn get_user(&self, login: &GitHubName) -> GitHubFuture<User> {
+        self.github.get_user(login)
+    }
+
+    fn iter_teams(&self, org: &str) -> GitHubStream<Team> {
+        self.github.iter_teams(org)
+    }
+
+    fn get_team(&self, org: &str, team_id: u64) -> GitHubFuture<Team> {
+        self.github.get_team(org, team_id)
+    }
+
+    fn iter_team_members(&self, org: &str, team_id: u64) -> GitHubStream<User> {
+        self.github.iter_team_members(org, team_id)
+    }
+
+    fn add_user(
+        &self,
+        org: &str,
+        team_id: u64,
+        login: &GitHubName,
+        role: TeamRole,
+    ) -> GitHubFuture<()> {
+        let org = org.to_string();
+        let login = login.clone();
+        self.throttle(move |github| github.add_user(&org, team_id, &login, role))
+    }
+
+    fn remove_user(&self, org: &str, team_id: u64, login: &GitHubName) -> GitHubFuture<()> {
+        let org = org.to_string();
+        let login = login.clone();
+        self.throttle(move |github| github.remove_user(&org, team_id, &login))
+    }
+
+    fn get_org_role(&self, org: &str, login: &GitHubName) -> GitHubFuture<OrgRole> {
+        self.github.get_org_role(org, login)
+    }
+
+    fn iter_invitations(&self, org: &str) -> GitHubStream<GitHubName> {
+        self.github.iter_invitations(org)
+    }
+
+    fn get_commit(&self, owner: &str, repo: &str, sha: &str) -> GitHubFuture<Commit> {
+        self.github.get_commit(owner, repo, sha)
+    }
+
+    fn rate_limit(&self) -> GitHubFuture<RateLimit> {
+        self.github.rate_limit()
+    }
+
+    fn create_issue(&self, repo: &Repo, title: &str, body: &str) -> GitHubFuture<u64> {
+        let repo = repo.clone();
+        let title = title.to_string();
+        let body = body.to_string();
+        self.throttle(move |github| github.create_issue(&repo, &title, &body))
+    }
+}
+
+/// An in-memory GitHub with a single organization, for tests.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct FakeGitHub {
+    state: std::sync::Arc<std::sync::Mutex<FakeState>>,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeState {
+    pub users: Vec<User>,
+    pub teams: Vec<Team>,
+    pub members: std::collections::HashMap<u64, Vec<User>>,
+    /// Served `FAKE_PAGE_SIZE` at a time, like GitHub's paginated
+    /// listings.
+    pub invitations: Vec<GitHubName>,
+    pub invitation_pages_fetched: usize,
+    pub owners: Vec<GitHubName>,
+    pub commits: std::collections::HashMap<String, Commit>,
+    /// Opened issues' repository, title and body, numbered from 1.
+    pub issues: Vec<(Repo, String, String)>,
+}
+
+#[cfg(test)]
+const FAKE_PAGE_SIZE: usize = 2;
+
+#[cfg(test)]
+impl FakeGitHub {
+    pub fn new(state: FakeState) -> FakeGitHub {
+        FakeGitHub {
+            state: std::sync::Arc::new(std::sync::Mutex::new(state)),
+        }
+    }
+
+    /// The team's members, sorted by ID.
+    pub fn members(&self, team_id: u64) -> Vec<User> {
+        let mut members = self
+            .state
+            .lock()
+            .unwrap()
+            .members
+            .get(&team_id)
+            .cloned()
+            .unwrap_or_default();
+        members.sort_by_key(|member| member.id);
+        members
+    }
+
+    /// Replace who GitHub knows about.
+    pub fn set_users(&self, users: Vec<User>) {
+        self.state.lock().unwrap().users = users;
+    }
+
+    fn find_user(&self, login: &GitHubName) -> Result<User, GitHubError> {
+        self.state
+            .lock()
+            .unwrap()
+            .users
+            .iter()
+            .find(|user| user.login == *login)
+            .cloned()
+            .ok_or_else(|| GitHubError::FakeNotFound(format!("no user named {}", login)))
+    }
+}
+
+#[cfg(test)]
+impl GitHubApi for FakeGitHub {
+    fn get_user(&self, login: &GitHubName) -> GitHubFuture<User> {
+        Box::new(futures::future::result(self.find_user(login)))
+    }
+
+    fn iter_teams(&self, _org: &str) -> GitHubStream<Team> {
+        Box::new(futures::stream::iter_ok(
+            self.state.lock().unwrap().teams.clone(),
+        ))
+    }
+
+    fn get_team(&self, _org: &str, team_id: u64) -> GitHubFuture<Team> {
+        Box::new(futures::future::result(
+            self.state
+                .lock()
+                .unwrap()
+                .teams
+                .iter()
+                .find(|team| team.id == team_id)
+                .cloned()
+                .ok_or_else(|| GitHubError::fake(format!("no team {}", team_id))),
+        ))
+    }
+
+    fn iter_team_members(&self, _org: &str, team_id: u64) -> GitHubStream<User> {
+        Box::new(futures::stream::iter_ok(self.members(team_id)))
+    }
+
+    fn add_user(
+        &self,
+        _org: &str,
+        team_id: u64,
+        login: &GitHubName,
+        _role: TeamRole,
+    ) -> GitHubFuture<()> {
+        Box::new(futures::future::result(self.find_user(login).map(|user| {
+            self.state
+                .lock()
+                .unwrap()
+                .members
+                .entry(team_id)
+                .or_default()
+                .push(user);
+        })))
+    }
+
+    fn remove_user(&self, _org: &str, team_id: u64, login: &GitHubName) -> GitHubFuture<()> {
+        let mut state = self.state.lock().unwrap();
+        let members = state.members.entry(team_id).or_default();
+        let before = members.len();
+        members.retain(|member| member.login != *login);
+
+        Box::new(futures::future::result(if members.len() < before {
+            Ok(())
+        } else {
+            Err(GitHubError::fake(format!("{} is not a member", login)))
+        }))
+    }
+
+    fn get_org_role(&self, _org: &str, login: &GitHubName) -> GitHubFuture<OrgRole> {
+        Box::new(futures::future::ok(
+            if self.state.lock().unwrap().owners.contains(login) {
+                OrgRole::Owner
+            } else {
+                OrgRole::Member
+            },
+        ))
+    }
+
+    fn iter_invitations(&self, _org: &str) -> GitHubStream<GitHubName> {
+        let state = self.state.clone();
+        Box::new(
+            futures::stream::unfold(Some(0), move |page: Option<usize>| {
+                let page = page?;
+                let mut state = state.lock().unwrap();
+                state.invitation_pages_fetched += 1;
+
+                let start = page * FAKE_PAGE_SIZE;
+                let invitations: Vec<GitHubName> = state
+                    .invitations
+                    .iter()
+                    .skip(start)
+                    .take(FAKE_PAGE_SIZE)
+                    .cloned()
+                    .collect();
+                let next = if start + FAKE_PAGE_SIZE < state.invitations.len() {
+                    Some(page + 1)
+                } else {
+                    None
+                };
+
+                Some(future::ok::<_, GitHubError>((invitations, next)))
+            })
+            .map(futures::stream::iter_ok::<_, GitHubError>)
+            .flatten(),
+        )
+    }
+
+    fn rate_limit(&self) -> GitHubFuture<RateLimit> {
+        Box::new(futures::future::ok(RateLimit {
+            remaining: 5000,
+            reset: 0,
+        }))
+    }
+
+    fn get_commit(&self, _owner: &str, _repo: &str, sha: &str) -> GitHubFuture<Commit> {
+        Box::new(futures::future::result(
+            self.state
+                .lock()
+                .unwrap()
+                .commits
+                .get(sha)
+                .cloned()
+                .ok_or_else(|| GitHubError::fake(format!("no commit {}", sha))),
+        ))
+    }
+
+    fn create_issue(&self, repo: &Repo, title: &str, body: &str) -> GitHubFuture<u64> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .issues
+            .push((repo.clone(), title.to_string(), body.to_string()));
+        Box::new(futures::future::ok(state.issues.len() as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::runtime::Runtime;
+
+    fn policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_millis(1),
+            max_jitter: Duration::from_millis(0),
+            timeout: None,
+            secondary_rate_limit_wait: Duration::from_millis(1),
+        }
+    }
+
+    fn metrics() -> RetryMetrics {
+        Metrics::unregistered().github.retry
+    }
+
+    /// A call which fails with the given errors before succeeding,
+    /// counting how often it was made.
+    fn flaky(transient: Vec<bool>) -> (Arc<AtomicUsize>, impl Fn() -> GitHubFuture<u32>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let call = move || -> GitHubFuture<u32> {
+            let call = counter.fetch_add(1, Ordering::SeqCst);
+            match transient.get(call) {
+                Some(transient) => Box::new(future::err(GitHubError::Fake {
+                    message: "flaky".into(),
+                    transient: *transient,
+                })),
+                None => Box::new(future::ok(call as u32)),
+            }
+        };
+
+        (calls, call)
+    }
+
+    /// Only exercises the stream plumbing against the fake's paging;
+    /// whether hubcaps follows GitHub's Link headers isn't tested here.
+    #[test]
+    fn test_invitations_every_page() {
+        let mut rt = Runtime::new().unwrap();
+        let invitations: Vec<GitHubName> = (0..5)
+            .map(|n| GitHubName::new(format!("user{}", n)))
+            .collect();
+        let fake = FakeGitHub::new(FakeState {
+            invitations: invitations.clone(),
+            ..FakeState::default()
+        });
+        let github = Retrying::new(crate::test_logger(), fake.clone(), policy(0), metrics());
+
+        assert_eq!(
+            rt.block_on(github.iter_invitations("NixOS").collect())
+                .unwrap(),
+            invitations
+        );
+        assert_eq!(fake.state.lock().unwrap().invitation_pages_fetched, 3);
+    }
+
+    #[test]
+    fn test_retry_transient_failures() {
+        let mut rt = Runtime::new().unwrap();
+        let logger = crate::test_logger();
+
+        let retry_metrics = metrics();
+        let (calls, call) = flaky(vec![true, true]);
+        assert_eq!(
+            rt.block_on(retrying(
+                logger.clone(),
+                policy(3),
+                retry_metrics.clone(),
+                call
+            ))
+            .unwrap(),
+            2
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(retry_metrics.retries.get(), 2);
+
+        let (calls, call) = flaky(vec![true, true]);
+        assert!(rt
+            .block_on(retrying(logger, policy(1), metrics(), call))
+            .is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_hung_calls_time_out() {
+        let mut rt = Runtime::new().unwrap();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let call = move || -> GitHubFuture<()> {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Box::new(future::empty())
+        };
+
+        let policy = policy(1).with_timeout(Duration::from_millis(10));
+        match rt.block_on(retrying(crate::test_logger(), policy, metrics(), call)) {
+            Err(GitHubError::Timeout(timeout)) => {
+                assert_eq!(timeout, Duration::from_millis(10))
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        // a timeout is retried like any transient failure
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_no_retry_for_client_errors() {
+        let mut rt = Runtime::new().unwrap();
+
+        let (calls, call) = flaky(vec![false]);
+        assert!(rt
+            .block_on(retrying(crate::test_logger(), policy(3), metrics(), call))
+            .is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_wait_out_rate_limit() {
+        let mut rt = Runtime::new().unwrap();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let call = move || -> GitHubFuture<()> {
+            if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                Box::new(future::err(GitHubError::FakeRateLimit(
+                    Duration::from_secs(0),
+                )))
+            } else {
+                Box::new(future::ok(()))
+            }
+        };
+
+        // waiting for the rate limit is not a retry
+        let retry_metrics = metrics();
+        assert!(rt
+            .block_on(retrying(
+                crate::test_logger(),
+                policy(0),
+                retry_metrics.clone(),
+                call
+            ))
+            .is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(retry_metrics.retries.get(), 0);
+        assert_eq!(retry_metrics.rate_limit_remaining.get(), 0);
+    }
+
+    #[test]
+    fn test_back_off_secondary_rate_limit() {
+        let mut rt = Runtime::new().unwrap();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let call = move || -> GitHubFuture<()> {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Box::new(future::err(GitHubError::FakeSecondaryRateLimit))
+        };
+
+        // unlike the primary rate limit, backing off is a retry, so a
+        // limit that never lifts is eventually given up on
+        assert!(rt
+            .block_on(retrying(crate::test_logger(), policy(2), metrics(), call))
+            .is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_throttle_writes() {
+        let mut rt = Runtime::new().unwrap();
+        let fake = FakeGitHub::new(FakeState {
+            users: vec![User {
+                login: GitHubName::new("alice"),
+                id: GitHubID::new(1),
+            }],
+            ..FakeState::default()
+        });
+        let github = Throttled::new(
+            fake.clone(),
+            Duration::from_millis(50),
+            Duration::from_millis(0),
+        );
+        let alice = GitHubName::new("alice");
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            rt.block_on(github.add_user("NixOS", 10, &alice, TeamRole::Member))
+                .unwrap();
+        }
+        // the first write goes straight out
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(fake.members(10).len(), 3);
+    }
+
+    #[test]
+    fn test_parse_team_role() {
+        assert_eq!("member".parse(), Ok(TeamRole::Member));
+        assert_eq!("maintainer".parse(), Ok(TeamRole::Maintainer));
+        assert!("owner".parse::<TeamRole>().is_err());
+    }
+
+    #[test]
+    fn test_parse_repo() {
+        assert_eq!(
+            "NixOS/nixpkgs".parse(),
+            Ok(Repo {
+                owner: "NixOS".into(),
+                name: "nixpkgs".into(),
+            })
+        );
+        assert!("nixpkgs".parse::<Repo>().is_err());
+        assert!("/nixpkgs".parse::<Repo>().is_err());
+        assert!("NixOS/".parse::<Repo>().is_err());
+        assert!("NixOS/nixpkgs/master".parse::<Repo>().is_err());
+    }
+
+    #[test]
+    fn test_retry_delay_grows() {
+        let policy = policy(3);
+        assert_eq!(policy.delay(0), Duration::from_millis(1));
+        assert_eq!(policy.delay(1), Duration::from_millis(2));
+        assert_eq!(policy.delay(2), Duration::from_millis(4));
+    }
+}
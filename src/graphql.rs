@@ -0,0 +1,319 @@
+//! Just enough of GitHub's GraphQL API for what the REST API can't do.
+
+use crate::cli::ExitError;
+use crate::credentials::Secret;
+use crate::github::Repo;
+use crate::maintainers::{GitHubID, GitHubName};
+use crate::proxy;
+use futures::future::Future;
+use futures::stream::Stream;
+use reqwest::header::{AUTHORIZATION, USER_AGENT};
+use reqwest::StatusCode;
+use serde_json::json;
+use std::collections::HashMap;
+use tokio::runtime::Runtime;
+
+const HOST: &str = "api.github.com";
+const ENDPOINT: &str = "https://api.github.com/graphql";
+
+/// How many nodes to ask for in one query.
+const BATCH_SIZE: usize = 100;
+
+const BLAME_QUERY: &str = r#"
+query($owner: String!, $name: String!, $rev: String!, $path: String!) {
+  repository(owner: $owner, name: $name) {
+    object(expression: $rev) {
+      ... on Commit {
+        blame(path: $path) {
+          ranges {
+            startingLine
+            endingLine
+            commit { oid }
+          }
+        }
+      }
+    }
+  }
+}
+"#;
+
+/// Run a query and return its `data`.
+pub fn query(
+    logger: &slog::Logger,
+    rt: &mut Runtime,
+    token: &Secret<String>,
+    query: &str,
+    variables: serde_json::Value,
+) -> Result<serde_json::Value, ExitError> {
+    let request = proxy::client(logger, HOST)
+        .map_err(ExitError::GraphQL)?
+        .post(ENDPOINT)
+        .header(AUTHORIZATION, format!("bearer {}", token.expose()))
+        .header(USER_AGENT, "NixOS/rfcs#39")
+        .json(&json!({
+            "query": query,
+            "variables": variables,
+        }));
+
+    let (status, body) = rt
+        .block_on(request.send().and_then(|response| {
+            let status = response.status();
+            response
+                .into_body()
+                .fold(vec![], |mut body, chunk| {
+                    body.extend_from_slice(chunk.as_ref());
+                    Ok::<_, reqwest::Error>(body)
+                })
+                .map(move |body| (status, body))
+        }))
+        .map_err(|e| ExitError::GraphQL(e.to_string()))?;
+
+    if status != StatusCode::OK {
+        error!(logger, "GraphQL query failed";
+               "status" => %status,
+        );
+        return Err(ExitError::GraphQL(format!("GitHub responded {}", status)));
+    }
+
+    let mut response: serde_json::Value = serde_json::from_slice(&body)?;
+    if let Some(errors) = response.get("errors").and_then(|errors| errors.as_array()) {
+        // Nodes which don't exist come back as null alongside an error,
+        // which callers can see for themselves.
+        let (missing, failed): (Vec<_>, Vec<_>) = errors
+            .iter()
+            .partition(|error| error["type"] == "NOT_FOUND");
+        for error in missing {
+            debug!(logger, "GraphQL node not found";
+                   "message" => %error["message"],
+            );
+        }
+        if !failed.is_empty() {
+            let failed = serde_json::Value::from(failed.into_iter().cloned().collect::<Vec<_>>());
+            error!(logger, "GraphQL query returned errors";
+                   "errors" => %failed,
+            );
+            return Err(ExitError::GraphQL(failed.to_string()));
+        }
+    }
+
+    Ok(response["data"].take())
+}
+
+/// The commit which last changed each line of `path` at `rev`, as
+/// `git blame -l` would report it.
+pub fn blame(
+    logger: &slog::Logger,
+    rt: &mut Runtime,
+    token: &Secret<String>,
+    repo: &Repo,
+    rev: &str,
+    path: &str,
+) -> Result<Vec<String>, ExitError> {
+    debug!(logger, "Blaming through the GitHub API";
+           "repo" => %repo,
+           "rev" => rev,
+           "path" => path,
+    );
+
+    let data = query(
+        logger,
+        rt,
+        token,
+        BLAME_QUERY,
+        json!({
+            "owner": repo.owner,
+            "name": repo.name,
+            "rev": rev,
+            "path": path,
+        }),
+    )?;
+
+    let ranges = &data["repository"]["object"]["blame"]["ranges"];
+    if ranges.is_null() {
+        return Err(ExitError::GraphQL(format!(
+            "{} has no {} at {}",
+            repo, path, rev
+        )));
+    }
+
+    blame_lines(ranges.clone())
+}
+
+/// The current login of each account. Accounts GitHub doesn't know,
+/// for example because they were deleted, are left out.
+pub fn logins_by_id(
+    logger: &slog::Logger,
+    rt: &mut Runtime,
+    token: &Secret<String>,
+    ids: &[GitHubID],
+) -> Result<HashMap<GitHubID, GitHubName>, ExitError> {
+    let mut logins = HashMap::new();
+    for batch in ids.chunks(BATCH_SIZE) {
+        debug!(logger, "Looking up logins by ID";
+               "count" => batch.len(),
+        );
+
+        let data = query(logger, rt, token, &logins_query(batch), json!({}))?;
+        for (n, id) in batch.iter().enumerate() {
+            if let Some(login) = data[format!("u{}", n)]["login"].as_str() {
+                logins.insert(*id, GitHubName::new(login));
+            }
+        }
+    }
+
+    Ok(logins)
+}
+
+/// The account ID behind each login. Logins GitHub doesn't know are
+/// left out.
+pub fn ids_by_login(
+    logger: &slog::Logger,
+    rt: &mut Runtime,
+    token: &Secret<String>,
+    names: &[GitHubName],
+) -> Result<HashMap<GitHubName, GitHubID>, ExitError> {
+    let mut ids = HashMap::new();
+    for batch in names.chunks(BATCH_SIZE) {
+        debug!(logger, "Looking up IDs by login";
+               "count" => batch.len(),
+        );
+
+        let data = query(logger, rt, token, &ids_query(batch), json!({}))?;
+        for (n, name) in batch.iter().enumerate() {
+            if let Some(id) = data[format!("u{}", n)]["databaseId"].as_u64() {
+                ids.insert(name.clone(), GitHubID::new(id));
+            }
+        }
+    }
+
+    Ok(ids)
+}
+
+fn ids_query(names: &[GitHubName]) -> String {
+    let fields: Vec<String> = names
+        .iter()
+        .enumerate()
+        .map(|(n, name)| {
+            format!(
+                "u{}: user(login: {}) {{ databaseId }}",
+                n,
+                serde_json::Value::from(name.to_string())
+            )
+        })
+        .collect();
+
+    format!("query {{ {} }}", fields.join(" "))
+}
+
+/// Look up users by their node ID, which GitHub derives from the
+/// account ID, with one aliased field per user.
+fn logins_query(ids: &[GitHubID]) -> String {
+    let fields: Vec<String> = ids
+        .iter()
+        .enumerate()
+        .map(|(n, id)| {
+            format!(
+                "u{}: node(id: \"{}\") {{ ... on User {{ login }} }}",
+                n,
+                base64(format!("04:User{}", id).as_bytes())
+            )
+        })
+        .collect();
+
+    format!("query {{ {} }}", fields.join(" "))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | u32::from(*byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BlameRange {
+    starting_line: usize,
+    ending_line: usize,
+    commit: BlameCommit,
+}
+
+#[derive(Deserialize)]
+struct BlameCommit {
+    oid: String,
+}
+
+fn blame_lines(ranges: serde_json::Value) -> Result<Vec<String>, ExitError> {
+    let ranges: Vec<BlameRange> = serde_json::from_value(ranges)?;
+
+    let mut lines = vec![];
+    for range in ranges {
+        if lines.len() < range.ending_line {
+            lines.resize(range.ending_line, String::new());
+        }
+        // GitHub's lines start at 1
+        for line in &mut lines[range.starting_line.saturating_sub(1)..range.ending_line] {
+            *line = range.commit.oid.clone();
+        }
+    }
+
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_node_id() {
+        assert_eq!(base64(b"04:User1"), "MDQ6VXNlcjE=");
+        assert_eq!(base64(b"04:User20536"), "MDQ6VXNlcjIwNTM2");
+        assert_eq!(base64(b"04:User123"), "MDQ6VXNlcjEyMw==");
+    }
+
+    #[test]
+    fn test_logins_query() {
+        assert_eq!(
+            logins_query(&[GitHubID::new(1), GitHubID::new(123)]),
+            "query { u0: node(id: \"MDQ6VXNlcjE=\") { ... on User { login } } \
+             u1: node(id: \"MDQ6VXNlcjEyMw==\") { ... on User { login } } }"
+        );
+    }
+
+    #[test]
+    fn test_ids_query() {
+        assert_eq!(
+            ids_query(&[GitHubName::new("alice"), GitHubName::new("b\"ob")]),
+            "query { u0: user(login: \"alice\") { databaseId } \
+             u1: user(login: \"b\\\"ob\") { databaseId } }"
+        );
+    }
+
+    #[test]
+    fn test_blame_lines() {
+        let ranges = json!([
+            {"startingLine": 1, "endingLine": 2, "commit": {"oid": "aaaa"}},
+            {"startingLine": 3, "endingLine": 3, "commit": {"oid": "bbbb"}},
+            {"startingLine": 4, "endingLine": 5, "commit": {"oid": "aaaa"}},
+        ]);
+
+        assert_eq!(
+            blame_lines(ranges).unwrap(),
+            vec!["aaaa", "aaaa", "bbbb", "aaaa", "aaaa"]
+        );
+    }
+}
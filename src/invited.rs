@@ -1,13 +1,24 @@
+//! Remember who a sync has invited, and when, so people who turned an
+//! invitation down aren't asked again straight away.
+//!
+//! One `github_id,invited_at` line per user, with `invited_at` in
+//! seconds since the epoch. Lines with only an ID come from before
+//! timestamps were kept, and count as invited at 0.
+//!
+//! The list can also be a directory, holding one such file per team.
+
 use crate::cli::ExitError;
 use crate::maintainers::GitHubID;
-use std::collections::HashSet;
-use std::fs::{File, OpenOptions};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
 use std::io::{BufRead, BufReader, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::NamedTempFile;
 
 #[cfg_attr(test, derive(Debug))]
 pub struct Invited {
-    invited: HashSet<GitHubID>,
+    invited: HashMap<GitHubID, u64>,
     logger: slog::Logger,
 }
 
@@ -22,7 +33,7 @@ impl Invited {
     #[cfg(test)]
     pub fn new(logger: slog::Logger) -> Invited {
         Invited {
-            invited: HashSet::new(),
+            invited: HashMap::new(),
             logger,
         }
     }
@@ -45,7 +56,7 @@ impl Invited {
 
         let lines = BufReader::new(file).lines();
 
-        let mut invited = HashSet::new();
+        let mut invited = HashMap::new();
         for line in lines {
             let line = line.map_err(|err| {
                 error!(
@@ -55,25 +66,43 @@ impl Invited {
                 err
             })?;
 
-            let id = line.parse().map_err(|err| {
+            let (id, invited_at) = parse_line(&line).map_err(|err| {
                 error!(
                     logger,
-                    "Failed to parse invited maintainer github id: {:?}", err
+                    "Failed to parse invited maintainer line {:?}: {:?}", line, err
                 );
                 err
             })?;
 
-            invited.insert(GitHubID::new(id));
+            invited.insert(id, invited_at);
         }
 
         Ok(Invited { invited, logger })
     }
 
+    /// Replace the file at `path` by renaming a complete copy over it,
+    /// so a crash mid-save leaves the old list in place.
     pub fn save(&self, path: &Path) -> Result<(), ExitError> {
-        let mut file = File::create(path).map_err(|err| {
+        self.write_temp(path)?.persist(path).map_err(|err| {
             error!(
                 self.logger,
-                "Failed to create invited list file {:?}: {:?}", path, err,
+                "Failed to replace invited list file {:?}: {:?}", path, err.error
+            );
+            err.error
+        })?;
+
+        Ok(())
+    }
+
+    fn write_temp(&self, path: &Path) -> Result<NamedTempFile, ExitError> {
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let mut file = NamedTempFile::new_in(dir).map_err(|err| {
+            error!(
+                self.logger,
+                "Failed to create invited list file next to {:?}: {:?}", path, err,
             );
             err
         })?;
@@ -83,7 +112,7 @@ impl Invited {
 
         let string = values
             .into_iter()
-            .map(|id| id.to_string())
+            .map(|(id, invited_at)| format!("{},{}", id, invited_at))
             .collect::<Vec<_>>()
             .join("\n");
 
@@ -95,7 +124,7 @@ impl Invited {
             err
         })?;
 
-        Ok(())
+        Ok(file)
     }
 
     pub fn len(&self) -> usize {
@@ -103,25 +132,90 @@ impl Invited {
     }
 
     pub fn contains(&self, id: &GitHubID) -> bool {
-        self.invited.contains(id)
+        self.invited.contains_key(id)
+    }
+
+    /// Whether the user was invited less than `window` ago, or at all
+    /// if there's no window.
+    pub fn invited_within(&self, id: &GitHubID, window: Option<Duration>) -> bool {
+        self.invited_within_at(id, window, now())
+    }
+
+    fn invited_within_at(&self, id: &GitHubID, window: Option<Duration>, now: u64) -> bool {
+        match (self.invited.get(id), window) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(invited_at), Some(window)) => invited_at + window.as_secs() > now,
+        }
     }
 
+    /// Record that the user was invited just now.
     pub fn add(&mut self, id: GitHubID) {
-        self.invited.insert(id);
+        self.invited.insert(id, now());
     }
 
     pub fn remove(&mut self, id: &GitHubID) {
         self.invited.remove(id);
     }
+
+    /// Forget every user `keep` says no.
+    pub fn retain<F: FnMut(&GitHubID) -> bool>(&mut self, mut keep: F) {
+        self.invited.retain(|id, _| keep(id));
+    }
+}
+
+/// The file to keep a team's invitations in: `path` itself, or
+/// `<org>-<team_id>.txt` inside it if it's a directory, so declining
+/// one team's invitation doesn't count for another.
+pub fn team_path(path: &Path, org: &str, team_id: u64) -> PathBuf {
+    if path.is_dir() {
+        path.join(format!("{}-{}.txt", org, team_id))
+    } else {
+        path.to_path_buf()
+    }
+}
+
+fn parse_line(line: &str) -> Result<(GitHubID, u64), std::num::ParseIntError> {
+    let mut fields = line.splitn(2, ',');
+    let id = fields.next().unwrap_or("").trim().parse()?;
+    let invited_at = match fields.next() {
+        Some(invited_at) => invited_at.trim().parse()?,
+        None => 0,
+    };
+
+    Ok((GitHubID::new(id), invited_at))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("the clock is before the epoch")
+        .as_secs()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_team_path() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let file = tmpdir.path().join("invited.txt");
+
+        assert_eq!(team_path(&file, "NixOS", 1), file);
+        assert_eq!(
+            team_path(tmpdir.path(), "NixOS", 1),
+            tmpdir.path().join("NixOS-1.txt")
+        );
+        assert_ne!(
+            team_path(tmpdir.path(), "NixOS", 1),
+            team_path(tmpdir.path(), "nix-community", 1)
+        );
+    }
+
     #[test]
     fn test_load_save() {
-        let mut invited = Invited::new(rfc39::test_logger());
+        let mut invited = Invited::new(crate::test_logger());
         let tmpdir = tempfile::tempdir().unwrap();
         let tmpfile = tmpdir.path().join("invited.txt");
 
@@ -131,26 +225,86 @@ mod tests {
 
         invited.save(&tmpfile).unwrap();
 
-        let loaded_invited = Invited::load(rfc39::test_logger(), &tmpfile).unwrap();
+        let loaded_invited = Invited::load(crate::test_logger(), &tmpfile).unwrap();
 
         assert_eq!(invited.len(), loaded_invited.len());
 
         assert_eq!(invited, loaded_invited);
     }
 
+    #[test]
+    fn test_interrupted_save_keeps_old_list() {
+        let mut invited = Invited::new(crate::test_logger());
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpfile = tmpdir.path().join("invited.txt");
+
+        invited.add(GitHubID::new(1));
+        invited.save(&tmpfile).unwrap();
+
+        // Stop after writing the new list but before renaming it
+        invited.add(GitHubID::new(2));
+        drop(invited.write_temp(&tmpfile).unwrap());
+
+        let loaded_invited = Invited::load(crate::test_logger(), &tmpfile).unwrap();
+        assert_eq!(loaded_invited.len(), 1);
+        assert!(loaded_invited.contains(&GitHubID::new(1)));
+        assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 1);
+    }
+
     #[test]
     fn test_load_creates_file_if_doesnt_exist() {
         let tmpdir = tempfile::tempdir().unwrap();
         let tmpfile = tmpdir.path().join("invited.txt");
 
-        let invited = Invited::load(rfc39::test_logger(), &tmpfile).unwrap();
+        let invited = Invited::load(crate::test_logger(), &tmpfile).unwrap();
+
+        assert_eq!(invited, Invited::new(crate::test_logger()));
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line("123"), Ok((GitHubID::new(123), 0)));
+        assert_eq!(
+            parse_line("123,1565000000"),
+            Ok((GitHubID::new(123), 1565000000))
+        );
+        assert!(parse_line("").is_err());
+        assert!(parse_line("123,").is_err());
+        assert!(parse_line("abc,1565000000").is_err());
+    }
+
+    #[test]
+    fn test_load_old_format() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpfile = tmpdir.path().join("invited.txt");
+        std::fs::write(&tmpfile, "1\n2,1565000000").unwrap();
+
+        let invited = Invited::load(crate::test_logger(), &tmpfile).unwrap();
+        assert_eq!(invited.invited.get(&GitHubID::new(1)), Some(&0));
+        assert_eq!(invited.invited.get(&GitHubID::new(2)), Some(&1565000000));
+
+        invited.save(&tmpfile).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&tmpfile).unwrap(),
+            "1,0\n2,1565000000"
+        );
+    }
 
-        assert_eq!(invited, Invited::new(rfc39::test_logger()));
+    #[test]
+    fn test_invited_within() {
+        let mut invited = Invited::new(crate::test_logger());
+        invited.invited.insert(GitHubID::new(1), 1000);
+        let day = Duration::from_secs(86400);
+
+        assert!(invited.invited_within_at(&GitHubID::new(1), None, 1000 + 86400 * 30));
+        assert!(invited.invited_within_at(&GitHubID::new(1), Some(day), 1000 + 86399));
+        assert!(!invited.invited_within_at(&GitHubID::new(1), Some(day), 1000 + 86400));
+        assert!(!invited.invited_within_at(&GitHubID::new(2), None, 1000));
     }
 
     #[test]
     fn test_add_remove_invited() {
-        let mut invited = Invited::new(rfc39::test_logger());
+        let mut invited = Invited::new(crate::test_logger());
 
         assert!(!invited.contains(&GitHubID::new(0)));
 
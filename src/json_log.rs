@@ -0,0 +1,205 @@
+//! Log records as JSON, one object per line, for log aggregators.
+
+use serde_json::{Map, Value};
+use slog::{Drain, Key, OwnedKVList, Record, KV};
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Writes each record as an object with `ts`, `level` and `msg`, the
+/// keys slog-json uses, and every key-value pair of the record and its
+/// logger. Each line is written out before `log` returns.
+pub struct Json<W: Write> {
+    out: Mutex<W>,
+}
+
+impl<W: Write> Json<W> {
+    pub fn new(out: W) -> Json<W> {
+        Json {
+            out: Mutex::new(out),
+        }
+    }
+}
+
+impl<W: Write> Drain for Json<W> {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> io::Result<()> {
+        let mut fields = Fields(Map::new());
+        fields.insert("ts", timestamp(SystemTime::now()).into());
+        fields.insert("level", record.level().as_short_str().into());
+        fields.insert("msg", record.msg().to_string().into());
+        // The record's own pairs go last, so they win over the
+        // logger's.
+        values
+            .serialize(record, &mut fields)
+            .and_then(|_| record.kv().serialize(record, &mut fields))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+        let mut out = self.out.lock().unwrap();
+        serde_json::to_writer(&mut *out, &Value::Object(fields.0))?;
+        out.write_all(b"\n")?;
+        out.flush()
+    }
+}
+
+struct Fields(Map<String, Value>);
+
+impl Fields {
+    fn insert(&mut self, key: &str, value: Value) {
+        self.0.insert(key.to_string(), value);
+    }
+}
+
+impl slog::Serializer for Fields {
+    fn emit_arguments(&mut self, key: Key, value: &fmt::Arguments) -> slog::Result {
+        self.insert(&key, value.to_string().into());
+        Ok(())
+    }
+
+    fn emit_str(&mut self, key: Key, value: &str) -> slog::Result {
+        self.insert(&key, value.into());
+        Ok(())
+    }
+
+    fn emit_bool(&mut self, key: Key, value: bool) -> slog::Result {
+        self.insert(&key, value.into());
+        Ok(())
+    }
+
+    fn emit_u64(&mut self, key: Key, value: u64) -> slog::Result {
+        self.insert(&key, value.into());
+        Ok(())
+    }
+
+    fn emit_i64(&mut self, key: Key, value: i64) -> slog::Result {
+        self.insert(&key, value.into());
+        Ok(())
+    }
+
+    fn emit_u32(&mut self, key: Key, value: u32) -> slog::Result {
+        self.emit_u64(key, value.into())
+    }
+
+    fn emit_i32(&mut self, key: Key, value: i32) -> slog::Result {
+        self.emit_i64(key, value.into())
+    }
+
+    fn emit_usize(&mut self, key: Key, value: usize) -> slog::Result {
+        self.emit_u64(key, value as u64)
+    }
+
+    fn emit_isize(&mut self, key: Key, value: isize) -> slog::Result {
+        self.emit_i64(key, value as i64)
+    }
+
+    fn emit_f64(&mut self, key: Key, value: f64) -> slog::Result {
+        // NaN and the infinities aren't JSON numbers.
+        self.insert(
+            &key,
+            serde_json::Number::from_f64(value).map_or(Value::Null, Value::Number),
+        );
+        Ok(())
+    }
+
+    fn emit_none(&mut self, key: Key) -> slog::Result {
+        self.insert(&key, Value::Null);
+        Ok(())
+    }
+}
+
+/// RFC 3339 in UTC, to the millisecond.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Howard Hinnant's days_from_civil, run backwards.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_millis(951_825_845_250)),
+            "2000-02-29T12:04:05.250Z"
+        );
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(1_798_761_599)),
+            "2026-12-31T23:59:59.000Z"
+        );
+    }
+
+    /// Hands out what was written, once the logger is done with it.
+    #[derive(Clone)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_values() {
+        let buffer = Buffer(Arc::new(Mutex::new(vec![])));
+        let logger = slog::Logger::root(
+            Json::new(buffer.clone()).fuse(),
+            o!("thread" => "main", "who" => "logger"),
+        );
+        info!(logger, "Synced {}", "team";
+              "who" => "alice",
+              "count" => 3,
+              "dry_run" => true,
+              "team" => None::<&str>,
+        );
+
+        let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(written.trim_end()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["msg"], "Synced team");
+        assert_eq!(line["thread"], "main");
+        assert_eq!(line["who"], "alice");
+        assert_eq!(line["count"], 3);
+        assert_eq!(line["dry_run"], true);
+        assert_eq!(line["team"], Value::Null);
+        assert!(line["ts"].as_str().unwrap().ends_with('Z'));
+    }
+}
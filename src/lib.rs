@@ -1,19 +1,216 @@
-use slog;
-use slog_scope;
-use slog_stdlog;
+//! Compare and sync maintainers from Nixpkgs to maintainers on
+//! GitHub Maintainer team, as described in RFC #39:
+//! https://github.com/NixOS/rfcs/blob/master/rfcs/0039-unprivileged-maintainer-teams.md
+//!
+//! The rfc39 binary is a command line over this crate. To run its
+//! operations in-process instead, start with a `ClientBuilder`.
+
+#[macro_use]
+extern crate slog;
+
+#[macro_use]
+extern crate serde;
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate prometheus;
+
+mod audit;
+mod checkpoint;
+pub mod cli;
+mod client;
+pub mod commit_cache;
+pub mod credentials;
+mod filemunge;
+pub mod github;
+pub mod graphql;
+mod invited;
+mod json_log;
+pub mod maintainerhistory;
+pub mod maintainers;
+pub mod metrics;
+pub mod nix;
+pub mod op_backfill;
+pub mod op_blame_author;
+pub mod op_check_handles;
+pub mod op_diff;
+pub mod op_fix_handles;
+pub mod op_list_maintainers;
+pub mod op_stats;
+pub mod op_sync_team;
+mod plan;
+mod protected;
+pub mod proxy;
+pub mod user_resolver;
+mod webhook;
+
+pub use crate::client::{Client, ClientBuilder};
+pub use crate::maintainers::MaintainerList;
+pub use crate::op_backfill::backfill_ids;
+pub use crate::op_check_handles::check_handles;
+pub use crate::op_sync_team::sync_team;
+
+use crate::cli::{ExitError, InputFormat};
+use slog::Drain;
+use sloggers::file::FileLoggerBuilder;
 use sloggers::terminal::{Destination, TerminalLoggerBuilder};
 use sloggers::types::Severity;
 use sloggers::Build;
-extern crate prometheus;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::path::Path;
+
+/// How log records are written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Human readable, colored when stderr is a terminal.
+    Terminal,
+    /// One JSON object per record, for log aggregators.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "terminal" => Ok(LogFormat::Terminal),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unsupported log format {:?}, expected terminal or json",
+                format
+            )),
+        }
+    }
+}
+
+/// Parse a `--log-level`: error, warn, info, debug or trace.
+pub fn parse_log_level(level: &str) -> Result<Severity, String> {
+    match level {
+        "error" => Ok(Severity::Error),
+        "warn" => Ok(Severity::Warning),
+        "info" => Ok(Severity::Info),
+        "debug" => Ok(Severity::Debug),
+        "trace" => Ok(Severity::Trace),
+        _ => Err(format!(
+            "unsupported log level {:?}, expected error, warn, info, debug or trace",
+            level
+        )),
+    }
+}
+
+/// Log to stderr and, with a `log_file`, append to it too. `quiet`
+/// leaves out stderr when there is a `log_file`.
+pub fn default_logger(
+    format: LogFormat,
+    level: Severity,
+    log_file: Option<&Path>,
+    quiet: bool,
+) -> Result<(slog::Logger, slog_scope::GlobalLoggerGuard), String> {
+    let stderr = match format {
+        LogFormat::Terminal => {
+            let mut builder = TerminalLoggerBuilder::new();
+            builder.level(level);
+            builder.destination(Destination::Stderr);
+            builder.build().unwrap()
+        }
+        LogFormat::Json => json_logger(std::io::stderr(), level),
+    };
+
+    let drain = match log_file {
+        Some(path) => {
+            let file = file_logger(format, level, path)
+                .map_err(|e| format!("cannot open log file {}: {}", path.display(), e))?;
+            if quiet {
+                file
+            } else {
+                slog::Logger::root(slog::Duplicate::new(stderr, file).fuse(), slog::o!())
+            }
+        }
+        None => stderr,
+    };
 
-pub fn default_logger() -> (slog::Logger, slog_scope::GlobalLoggerGuard) {
-    let mut builder = TerminalLoggerBuilder::new();
-    builder.level(Severity::Info); // !!! Pass from the CLI
-    builder.destination(Destination::Stderr);
-    let drain = builder.build().unwrap();
     let scope_guard = slog_scope::set_global_logger(drain.clone());
     slog_stdlog::init().unwrap();
-    (drain, scope_guard)
+    Ok((drain, scope_guard))
+}
+
+fn file_logger(format: LogFormat, level: Severity, path: &Path) -> Result<slog::Logger, String> {
+    match format {
+        LogFormat::Terminal => {
+            let mut builder = FileLoggerBuilder::new(path);
+            builder.level(level);
+            builder.build().map_err(|e| e.to_string())
+        }
+        LogFormat::Json => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| e.to_string())?;
+            Ok(json_logger(file, level))
+        }
+    }
+}
+
+fn json_logger<W: std::io::Write + Send + 'static>(out: W, level: Severity) -> slog::Logger {
+    // Synchronous, so nothing is lost when main calls process::exit.
+    let drain = json_log::Json::new(out).fuse();
+    let drain = slog::LevelFilter::new(drain, level.as_level()).fuse();
+    slog::Logger::root(drain, slog::o!())
+}
+
+/// Load the maintainer list from a Nix or JSON file, or from stdin
+/// for `-`. With `attr`, the list is that attribute of the file.
+pub fn load_maintainer_file(
+    logger: slog::Logger,
+    nix_invocation: &nix::NixInvocation,
+    src: &Path,
+    format: InputFormat,
+    attr: Option<&str>,
+) -> Result<MaintainerList, ExitError> {
+    let format = format.resolve(src);
+
+    if src == Path::new("-") {
+        // nix-instantiate needs a file, and it has to outlive the load.
+        let tmpdir = tempfile::tempdir()?;
+        let stdin_file = tmpdir.path().join("maintainers.nix");
+        let mut expression = String::new();
+        std::io::stdin().read_to_string(&mut expression)?;
+        std::fs::write(&stdin_file, expression)?;
+
+        info!(logger, "Loading maintainer information from stdin");
+        return load_maintainer_file(logger, nix_invocation, &stdin_file, format, attr);
+    }
+
+    let maintainers_file = src.canonicalize()?;
+
+    info!(logger, "Loading maintainer information";
+          "from" => src.display(),
+          "absolute" => maintainers_file.display(),
+          "attr" => attr,
+    );
+
+    if format == InputFormat::Json {
+        return MaintainerList::load_json(&maintainers_file);
+    }
+
+    if let Some(attr) = attr {
+        MaintainerList::load_attr(logger.clone(), nix_invocation, &maintainers_file, attr)
+    } else {
+        MaintainerList::load(logger.clone(), nix_invocation, &maintainers_file)
+    }
+    .map_err(|e| {
+        if let nix::NixError::EvalFailed { ref stderr, .. } = e {
+            error!(logger, "Failed to evaluate the maintainer file";
+                   "file" => maintainers_file.display(),
+                   "stderr" => stderr,
+            );
+        }
+        e.into()
+    })
 }
 
 pub fn test_logger() -> slog::Logger {
@@ -22,3 +219,48 @@ pub fn test_logger() -> slog::Logger {
     builder.destination(Destination::Stderr);
     builder.build().unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!("terminal".parse(), Ok(LogFormat::Terminal));
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert!("JSON".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_parse_log_level() {
+        assert_eq!(
+            parse_log_level("warn").map(|level| level.as_level()),
+            Ok(slog::Level::Warning)
+        );
+        assert_eq!(
+            parse_log_level("trace").map(|level| level.as_level()),
+            Ok(slog::Level::Trace)
+        );
+        assert!(parse_log_level("warning").is_err());
+    }
+
+    #[test]
+    fn test_json_file_logger() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("rfc39.log");
+
+        let logger = file_logger(LogFormat::Json, Severity::Info, &path).unwrap();
+        slog::info!(logger, "kept"; "who" => "alice");
+        slog::debug!(logger, "filtered");
+        drop(logger);
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["msg"], "kept");
+        assert_eq!(lines[0]["who"], "alice");
+    }
+}
@@ -1,6 +1,4 @@
-//! Compare and sync maintainers from Nixpkgs to maintainers on
-//! GitHub Maintainer team, as described in RFC #39:
-//! https://github.com/NixOS/rfcs/blob/master/rfcs/0039-unprivileged-maintainer-teams.md
+//! The rfc39 command line. The work itself is in the library crate.
 
 #![warn(missing_docs)]
 
@@ -10,34 +8,25 @@ extern crate slog;
 #[macro_use]
 extern crate serde;
 
-#[macro_use]
-extern crate lazy_static;
-
-#[macro_use]
-extern crate prometheus;
-
+use hubcaps::{Credentials, Github};
+use rfc39::cli::{self, ExecMode, ExitError, Options};
+use rfc39::credentials::{self, app_credentials};
+use rfc39::github::{self, github_client};
+use rfc39::maintainers::MaintainerList;
+use rfc39::metrics::Metrics;
+use rfc39::user_resolver::UserResolver;
+use rfc39::{
+    graphql, load_maintainer_file, maintainerhistory, metrics, nix, op_backfill, op_blame_author,
+    op_check_handles, op_diff, op_fix_handles, op_list_maintainers, op_stats, op_sync_team,
+    ClientBuilder,
+};
 use std::convert::TryInto;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use structopt::StructOpt;
-mod cli;
-use cli::{ExecMode, ExitError, Options};
-mod invited;
-mod maintainers;
-use maintainers::MaintainerList;
-mod filemunge;
-mod maintainerhistory;
-mod metrics;
-mod nix;
-mod op_backfill;
-mod op_blame_author;
-mod op_check_handles;
-mod op_sync_team;
-use hubcaps::{Credentials, Github, InstallationTokenGenerator, JWTCredentials};
-use prometheus::Encoder;
 use std::thread;
 use std::time;
+use structopt::StructOpt;
 
 /// Github Authentication information for the GitHub app.
 /// When creating the application, the only permission it needs
@@ -49,8 +38,8 @@ pub struct GitHubAppAuth {
     /// Overall GitHub Application ID, same for all users
     pub app_id: u64,
 
-    /// DER RSA key. Generate with
-    /// `openssl rsa -in private_rsa_key.pem -outform DER -out private_rsa_key.der`
+    /// RSA private key, either the PEM file GitHub provides or DER
+    /// from `openssl rsa -in private_rsa_key.pem -outform DER -out private_rsa_key.der`
     pub private_key_file: PathBuf,
 
     /// the ID of the installation of this app in to the repo or
@@ -64,209 +53,618 @@ pub struct GitHubAppAuth {
 #[derive(Deserialize)]
 pub struct GitHubTokenAuth {
     /// Personal Access Token
-    pub access_token: String,
+    pub access_token: credentials::Secret<String>,
 }
 
-fn load_maintainer_file(logger: slog::Logger, src: &Path) -> Result<MaintainerList, ExitError> {
-    let maintainers_file = src.canonicalize()?;
+/// The access token from the environment or the credential file, for
+/// the calls which only work with token auth.
+fn access_token(
+    logger: &slog::Logger,
+    nix_invocation: &nix::NixInvocation,
+    credential_file: Option<&Path>,
+) -> Result<credentials::Secret<String>, ExitError> {
+    if let Ok(token) = std::env::var(credentials::TOKEN_VAR) {
+        debug!(logger, "Environment is providing Token Auth.");
+        return Ok(credentials::Secret::new(token));
+    }
+
+    let credential_file = credential_file.ok_or_else(|| {
+        error!(logger, "This needs an access token";
+               "env" => credentials::TOKEN_VAR,
+        );
+        ExitError::MissingCredentials(format!(
+            "set {} or pass --credentials",
+            credentials::TOKEN_VAR
+        ))
+    })?;
+    let token_auth = nix::nix_instantiate_file_to_struct::<GitHubTokenAuth>(
+        logger.new(o!()),
+        nix_invocation,
+        credential_file,
+    )
+    .map_err(|e| {
+        error!(logger, "This needs a credential file with an access_token";
+               "e" => ?e,
+        );
+        e
+    })?;
+
+    Ok(token_auth.access_token)
+}
 
-    info!(logger, "Loading maintainer information";
-          "from" => src.display(),
-          "absolute" => maintainers_file.display()
-    );
+fn history_source(
+    logger: &slog::Logger,
+    nix_invocation: &nix::NixInvocation,
+    credential_file: Option<&Path>,
+    dir: Option<PathBuf>,
+    params: cli::HistoryParams,
+) -> Result<maintainerhistory::HistorySource, ExitError> {
+    let blame = match params.blame_rev {
+        None => maintainerhistory::Blame::Git,
+        Some(rev) => maintainerhistory::Blame::GitHub {
+            token: access_token(logger, nix_invocation, credential_file)?,
+            rev,
+            path: params.blame_path,
+        },
+    };
 
-    Ok(MaintainerList::load(logger.clone(), &maintainers_file)?)
+    Ok(maintainerhistory::HistorySource {
+        repo: params.blame_repo,
+        dir,
+        blame,
+    })
 }
 
-fn gh_client_from_args(logger: slog::Logger, credential_file: &Path) -> Github {
+fn gh_client_from_args(
+    logger: slog::Logger,
+    nix_invocation: &nix::NixInvocation,
+    credential_file: Option<&Path>,
+) -> Result<Github, ExitError> {
+    match credentials::from_env(|name| std::env::var(name).ok()) {
+        Ok(Some(credentials::EnvCredentials::Token(token))) => {
+            info!(
+                logger,
+                "Environment is providing Token Auth, which cannot sync teams."
+            );
+            return github_client(&logger, Credentials::Token(token.into_inner()));
+        }
+        Ok(Some(credentials::EnvCredentials::App {
+            app_id,
+            installation_id,
+            private_key,
+        })) => {
+            debug!(logger, "Environment is providing App Auth.");
+            return github_client(
+                &logger,
+                app_credentials(app_id, installation_id, private_key.into_inner())?,
+            );
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!(logger, "Environment has invalid GitHub credentials";
+                   "error" => &e,
+            );
+            return Err(ExitError::InvalidCredentials(e));
+        }
+    }
+
+    let credential_file = credential_file.ok_or_else(|| {
+        error!(
+            logger,
+            "No GitHub credentials, pass --credentials or set them in the environment"
+        );
+        ExitError::MissingCredentials(
+            "pass --credentials or set them in the environment".to_string(),
+        )
+    })?;
+
     info!(
         logger,
         "Loading GitHub authentication information from {:?}", &credential_file
     );
 
-    let app_auth_load_err: serde_json::error::Error;
-    match nix::nix_instantiate_file_to_struct::<GitHubAppAuth>(logger.new(o!()), credential_file) {
+    let app_auth_load_err = match nix::nix_instantiate_file_to_struct::<GitHubAppAuth>(
+        logger.new(o!()),
+        nix_invocation,
+        credential_file,
+    ) {
         Ok(app_auth) => {
             debug!(logger, "Credential file is providing App Auth.");
             let mut private_key = Vec::new();
-            File::open(&app_auth.private_key_file)
-                .expect("Opening the private key file")
-                .read_to_end(&mut private_key)
-                .expect("Reading the private key");
-
-            return Github::new(
-                String::from("NixOS/rfcs#39 (hubcaps)"),
-                Credentials::InstallationToken(InstallationTokenGenerator::new(
-                    app_auth.installation_id,
-                    JWTCredentials::new(app_auth.app_id, private_key).unwrap(),
-                )),
-            )
-            .expect("Failed to create a GitHub client from the app auth");
-        }
-        Err(e) => {
-            app_auth_load_err = e;
+            File::open(&app_auth.private_key_file)?.read_to_end(&mut private_key)?;
+            let private_key = credentials::private_key_der(private_key).map_err(|e| {
+                error!(logger, "Failed to decode the private key";
+                       "file" => app_auth.private_key_file.display(),
+                       "error" => &e,
+                );
+                ExitError::InvalidCredentials(e)
+            })?;
+
+            return github_client(
+                &logger,
+                app_credentials(app_auth.app_id, app_auth.installation_id, private_key)?,
+            );
         }
-    }
+        Err(e) => e,
+    };
 
-    let token_auth_load_err: serde_json::error::Error;
-    match nix::nix_instantiate_file_to_struct::<GitHubTokenAuth>(logger.new(o!()), credential_file)
-    {
+    let token_auth_load_err = match nix::nix_instantiate_file_to_struct::<GitHubTokenAuth>(
+        logger.new(o!()),
+        nix_invocation,
+        credential_file,
+    ) {
         Ok(token_auth) => {
             info!(
                 logger,
                 "Credential file is providing Token Auth, which cannot sync teams."
             );
-
-            return Github::new(
-                String::from("NixOS/rfcs#39 (hubcaps)"),
-                Credentials::Token(token_auth.access_token),
-            )
-            .expect("Failed to create a GitHub client from the token auth");
-        }
-        Err(e) => {
-            token_auth_load_err = e;
+            return github_client(
+                &logger,
+                Credentials::Token(token_auth.access_token.into_inner()),
+            );
         }
-    }
+        Err(e) => e,
+    };
 
     error!(logger, "Credential file is not a valid App or Token auth method";
            "app_load" => ?app_auth_load_err,
            "token_load" => ?token_auth_load_err,
     );
-    panic!("Credential file is not valid App or Token Auth");
+    Err(ExitError::InvalidCredentialFile {
+        app_auth: app_auth_load_err,
+        token_auth: token_auth_load_err,
+    })
 }
 
-fn execute_ops(logger: slog::Logger, inputs: Options) -> Result<(), ExitError> {
-    // Note: I wanted these in a lazy_static!, but that meant metrics
-    // which would report a 0 would never get reported at all, since
-    // they aren't accessed.... and lazy_static! is lazy.
-    let maintainer_nix_load_failure_counter = register_int_counter!(
-        "rfc39_maintainer_nix_load_failure",
-        "Failures to load maintainers.nix"
-    )
-    .unwrap();
+fn check_duplicate_github_ids(
+    logger: &slog::Logger,
+    metrics: &Metrics,
+    maintainers: &MaintainerList,
+    strict: bool,
+) -> Result<(), ExitError> {
+    let duplicates = maintainers.duplicate_github_ids();
+    for (github_id, handles) in &duplicates {
+        metrics.maintainers.duplicate_github_id.inc();
+        error!(logger, "GitHub ID is used by more than one maintainer, only one will be synced";
+               "github_id" => %github_id,
+               "handles" => handles.iter().map(|handle| handle.to_string()).collect::<Vec<_>>().join(", "),
+        );
+    }
 
-    let maintainers = load_maintainer_file(logger.new(o!()), &inputs.maintainers)
-        .map_err(|d| {
-            maintainer_nix_load_failure_counter.inc();
-            d
-        })
-        .unwrap();
+    if strict && !duplicates.is_empty() {
+        Err(ExitError::DuplicateGitHubIDs(
+            duplicates
+                .into_iter()
+                .map(|(github_id, _)| github_id)
+                .collect(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn execute_ops(logger: slog::Logger, metrics: &Metrics, inputs: Options) -> Result<(), ExitError> {
+    let nix_invocation = inputs.nix_invocation();
 
-    let github = gh_client_from_args(logger.new(o!()), &inputs.credential_file);
+    if inputs.needs_nix() {
+        nix::probe(&nix_invocation.nix_instantiate).map_err(|e| {
+            error!(logger, "nix-instantiate is not available. Install Nix from https://nixos.org/download.html and make sure nix-instantiate is on the PATH";
+                   "error" => %e,
+            );
+            ExitError::NixUnavailable(e)
+        })?;
+    }
 
+    if let ExecMode::Diff { old, new } = &inputs.mode {
+        let load = |path: &Path| {
+            load_maintainer_file(
+                logger.new(o!()),
+                &nix_invocation,
+                path,
+                inputs.maintainers_format,
+                None,
+            )
+            .map_err(|d| {
+                metrics.maintainers.nix_load_failure.inc();
+                d
+            })
+        };
+
+        return op_diff::diff(
+            logger.new(o!("exec-mode" => "Diff")),
+            metrics,
+            load(old)?,
+            load(new)?,
+        );
+    }
+
+    let maintainers = load_maintainer_file(
+        logger.new(o!()),
+        &nix_invocation,
+        &inputs.maintainers,
+        inputs.maintainers_format,
+        inputs.attr.as_deref(),
+    )
+    .map_err(|d| {
+        metrics.maintainers.nix_load_failure.inc();
+        d
+    })?;
+
+    check_duplicate_github_ids(&logger, metrics, &maintainers, inputs.strict)?;
+
+    // Every mode which doesn't need GitHub is done before connecting,
+    // so it needs no credentials.
     match inputs.mode {
-        ExecMode::CheckHandles => op_check_handles::check_handles(
-            logger.new(o!("exec-mode" => "CheckHandles")),
-            maintainers,
-        ),
-        ExecMode::BackfillIDs => op_backfill::backfill_ids(
-            logger.new(o!("exec-mode" => "BackfillIDs")),
-            github,
+        ExecMode::Stats => return op_stats::print_stats(metrics, maintainers),
+        ExecMode::ListMaintainers(list_info) => {
+            return op_list_maintainers::list_maintainers(maintainers, list_info.format)
+        }
+        _ => debug_assert!(inputs.mode.needs_github()),
+    }
+
+    let github = ClientBuilder::new(logger.new(o!()))
+        .max_retries(inputs.max_retries)
+        .github_timeout(time::Duration::from_secs(inputs.github_timeout))
+        .write_delay(
+            time::Duration::from_millis(inputs.write_delay_ms),
+            time::Duration::from_millis(inputs.write_jitter_ms),
+        )
+        .metrics(metrics.clone())
+        .connect(
+            gh_client_from_args(
+                logger.new(o!()),
+                &nix_invocation,
+                inputs.credential_file.as_deref(),
+            )?,
+            inputs.commit_cache.as_deref(),
+        )?;
+    let mut users = UserResolver::new(
+        logger.new(o!()),
+        github.clone(),
+        metrics.github.user_cache_hits.clone(),
+    );
+
+    // One runtime drives every GitHub call this run makes.
+    let mut rt = tokio::runtime::Runtime::new()?;
+
+    github::record_rate_limit(&logger, &github, &mut rt, &metrics.github.retry);
+
+    let result = match inputs.mode {
+        ExecMode::CheckHandles(check_info) => {
+            let token = access_token(&logger, &nix_invocation, inputs.credential_file.as_deref())?;
+            let logger = logger.new(o!("exec-mode" => "CheckHandles"));
+            // Both lookups need the runtime and the resolver, one after
+            // the other.
+            let rt = std::cell::RefCell::new(&mut rt);
+            let users = std::cell::RefCell::new(&mut users);
+            let concurrency = inputs.concurrency;
+            op_check_handles::check_handles(
+                logger.clone(),
+                metrics,
+                maintainers,
+                |ids| {
+                    users.borrow_mut().logins_by_id(ids, |ids| {
+                        graphql::logins_by_id(&logger, &mut rt.borrow_mut(), &token, ids)
+                    })
+                },
+                |names| {
+                    Ok(users
+                        .borrow_mut()
+                        .ids_by_login(&mut rt.borrow_mut(), names, concurrency))
+                },
+                check_info.require_id_coverage,
+                check_info.check_matrix,
+                cli::resolve_artifact_path(
+                    inputs.output_dir.as_deref(),
+                    check_info.report.as_deref(),
+                    "check-handles.json",
+                )
+                .as_deref(),
+                match check_info.format {
+                    cli::CheckFormat::GitHubActions => Some(inputs.maintainers.as_path()),
+                    cli::CheckFormat::Text => None,
+                },
+            )
+        }
+        ExecMode::BackfillIDs(backfill_info) => {
+            let graphql_token = if backfill_info.graphql {
+                Some(access_token(
+                    &logger,
+                    &nix_invocation,
+                    inputs.credential_file.as_deref(),
+                )?)
+            } else {
+                None
+            };
+
+            op_backfill::backfill_ids(
+                logger.new(o!("exec-mode" => "BackfillIDs")),
+                &github,
+                &mut rt,
+                metrics,
+                &mut users,
+                &nix_invocation,
+                &inputs.maintainers,
+                maintainers,
+                history_source(
+                    &logger,
+                    &nix_invocation,
+                    inputs.credential_file.as_deref(),
+                    inputs.history_dir,
+                    backfill_info.history,
+                )?,
+                backfill_info.in_place,
+                backfill_info.format,
+                inputs.concurrency,
+                graphql_token.as_ref(),
+            )
+        }
+        ExecMode::BlameAuthor(blame_info) => op_blame_author::report(
+            logger.new(o!("exec-mode" => "BlameAuthor")),
+            &github,
+            &mut rt,
+            &nix_invocation,
             &inputs.maintainers,
             maintainers,
+            cli::resolve_artifact_path(
+                inputs.output_dir.as_deref(),
+                blame_info.csv_file.as_deref(),
+                "attribution.csv",
+            )
+            .as_deref(),
+            history_source(
+                &logger,
+                &nix_invocation,
+                inputs.credential_file.as_deref(),
+                inputs.history_dir,
+                blame_info.history,
+            )?,
+            inputs.concurrency,
         ),
-        ExecMode::BlameAuthor => op_blame_author::report(
-            logger.new(o!("exec-mode" => "BlameAuthor")),
-            github,
+        ExecMode::FixHandles(fix_info) => op_fix_handles::fix_handles(
+            logger.new(o!("exec-mode" => "FixHandles")),
+            &github,
+            &mut rt,
+            &nix_invocation,
             &inputs.maintainers,
             maintainers,
+            history_source(
+                &logger,
+                &nix_invocation,
+                inputs.credential_file.as_deref(),
+                inputs.history_dir,
+                fix_info.history,
+            )?,
+            fix_info.dry_run,
+            inputs.concurrency,
         ),
-        ExecMode::SyncTeam(team_info) => op_sync_team::sync_team(
-            logger.new(o!("exec-mode" => "SyncTeam")),
-            github,
+        ExecMode::SyncTeam(mut team_info) => {
+            team_info.resolve_artifact_paths(inputs.output_dir.as_deref());
+            op_sync_team::sync_team(
+                logger.new(o!("exec-mode" => "SyncTeam")),
+                &github,
+                &mut rt,
+                metrics,
+                maintainers,
+                team_info,
+            )
+        }
+        ExecMode::PlanSync(plan_info) => op_sync_team::plan_sync(
+            logger.new(o!("exec-mode" => "PlanSync")),
+            &github,
+            &mut rt,
+            metrics,
             maintainers,
-            team_info.invited_list,
-            &team_info.organization,
-            team_info.team_id,
-            team_info.dry_run,
-            team_info.limit,
+            plan_info,
         ),
-        ExecMode::ListTeams(team_info) => op_sync_team::list_teams(github, &team_info.organization),
-    }
-}
+        ExecMode::Diff { .. } => unreachable!("diff is handled before loading --maintainers"),
+        ExecMode::ListMaintainers(_) | ExecMode::Stats => {
+            unreachable!("modes without GitHub are handled before connecting")
+        }
+        ExecMode::ListTeams(team_info) => op_sync_team::list_teams(
+            logger.new(o!("exec-mode" => "ListTeams")),
+            &github,
+            &mut rt,
+            team_info,
+        ),
+    };
 
-fn main() {
-    let begin_counter =
-        register_int_gauge!("rfc39_begin_seconds", "Execution started time").unwrap();
-    begin_counter.set(
-        time::SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            .try_into()
-            .unwrap(),
-    );
+    github.save()?;
+    result
+}
 
-    let op_success_counter = register_int_counter!(
-        "rfc39_op_suceess_counter",
-        "Execution completed without fault."
-    )
-    .unwrap();
-    let op_failed_counter =
-        register_int_counter!("rfc39_op_failure_counter", "Execution failed").unwrap();
-    let op_panic_counter = register_int_counter!(
-        "rfc39_op_panic_counter",
-        "Execution of the operation panicked"
-    )
-    .unwrap();
+fn unix_seconds(time: time::SystemTime) -> i64 {
+    time.duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .try_into()
+        .unwrap()
+}
 
-    let (logger, _scopes) = rfc39::default_logger();
+fn main() {
+    let begin = time::SystemTime::now();
+
+    let args: Vec<String> = std::env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    match cli::completions_shell(&args) {
+        Some(Ok(shell)) => {
+            Options::clap().gen_completions_to("rfc39", shell, &mut std::io::stdout());
+            std::process::exit(0);
+        }
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        None => {}
+    }
 
     let mut inputs = Options::from_args();
 
-    let dump_metrics = inputs.dump_metrics;
-    let metrics_delay = inputs.metrics_delay;
-    let metrics_handle = inputs.metrics_bind.take().map(|bind| {
-        let bind = bind.parse().unwrap();
-        let logger = logger.new(o!("thread" => "metrics"));
-        thread::spawn(move || {
-            info!(logger, "Listening on {:?}", bind);
-
-            metrics::serve(&bind)
-        })
+    let (logger, _scopes) = rfc39::default_logger(
+        inputs.log_format,
+        inputs.log_level,
+        inputs.log_file.as_deref(),
+        inputs.quiet,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(
+            ExitError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)).exit_code(),
+        )
     });
 
+    match inputs.validate() {
+        Ok(warnings) => {
+            for warning in warnings {
+                warn!(logger, "Ineffective option"; "warning" => warning);
+            }
+        }
+        Err(e) => {
+            error!(logger, "Invalid options"; "error" => ?e);
+            std::process::exit(e.exit_code());
+        }
+    }
+
+    let dump_metrics = inputs.dump_metrics || inputs.dump_metrics_file.is_some();
+    let dump_metrics_file =
+        inputs.artifact_path(inputs.dump_metrics_file.as_deref(), "metrics.txt");
+    let metrics_delay = inputs.metrics_delay.unwrap_or(240);
+    let metrics = Metrics::register(
+        prometheus::default_registry(),
+        &inputs.histogram_buckets.take().unwrap_or_default(),
+    )
+    .expect("metrics are only registered once");
+    metrics.begin_seconds.set(unix_seconds(begin));
+    metrics
+        .build_info
+        .with_label_values(&[env!("CARGO_PKG_VERSION"), env!("RFC39_GIT_SHA")])
+        .set(1);
+    let metrics_textfile = inputs.artifact_path(inputs.metrics_textfile.as_deref(), "metrics.prom");
+    let pushgateway = inputs.pushgateway.take();
+    if pushgateway.is_some() && inputs.metrics_bind.is_some() {
+        warn!(
+            logger,
+            "Not serving metrics, they will be pushed to the Pushgateway instead"
+        );
+    }
+    let metrics_handle = inputs
+        .metrics_bind
+        .take()
+        .filter(|_| pushgateway.is_none())
+        .map(|bind| {
+            let logger = logger.new(o!("thread" => "metrics"));
+            let (shutdown, shutdown_signal) = futures::sync::oneshot::channel();
+            let final_scrape = if inputs.metrics_until_scraped {
+                Some(metrics::FinalScrape::new())
+            } else {
+                None
+            };
+            let serve_scrape = final_scrape.as_ref().map(|(scrape, _)| scrape.clone());
+            let handle = thread::spawn(move || {
+                info!(logger, "Listening on {}", bind);
+
+                metrics::serve(&bind, shutdown_signal, serve_scrape);
+                info!(logger, "Metrics server stopped");
+            });
+            (handle, shutdown, final_scrape)
+        });
+
     let op_handle = {
         let logger = logger.new(o!());
+        let metrics = metrics.clone();
         thread::spawn(move || {
-            execute_ops(logger, inputs).map(|ok| {
-                op_success_counter.inc();
-                ok
-            })
+            execute_ops(logger.clone(), &metrics, inputs)
+                .map(|ok| {
+                    metrics.op_success.inc();
+                    metrics
+                        .last_success_seconds
+                        .set(unix_seconds(time::SystemTime::now()));
+                    ok
+                })
+                .map_err(|e| {
+                    error!(logger, "Operation failed"; "error" => ?e);
+                    metrics.op_failure.inc();
+                    e
+                })
         })
     };
 
     let thread_result: Result<Result<(), ExitError>, _> = op_handle.join().map_err(|thread_err| {
         warn!(logger, "Op-handling child panicked: {:#?}", thread_err);
-        op_failed_counter.inc();
-        op_panic_counter.inc();
+        metrics.op_failure.inc();
+        metrics.op_panic.inc();
         thread_err
     });
 
-    let exit_counter = register_int_gauge!("rfc39_stop_seconds", "Execution stopped time").unwrap();
-    exit_counter.set(
-        time::SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            .try_into()
-            .unwrap(),
-    );
+    metrics
+        .stop_seconds
+        .set(unix_seconds(time::SystemTime::now()));
+
+    if let Some((metrics_handle, shutdown, final_scrape)) = metrics_handle {
+        // Give Prometheus a chance to scrape the final values.
+        let delay = time::Duration::from_secs(metrics_delay);
+        if let Some((final_scrape, scraped)) = final_scrape {
+            final_scrape.ready();
+            if scraped.recv_timeout(delay).is_err() {
+                info!(logger, "Final metrics weren't scraped in time";
+                      "metrics_delay" => metrics_delay,
+                );
+            }
+        } else {
+            thread::sleep(delay);
+        }
+        // The server is only gone if it failed, then there's nothing
+        // to stop.
+        let _ = shutdown.send(());
+        if metrics_handle.join().is_err() {
+            warn!(logger, "Metrics server panicked");
+        }
+    }
 
-    if let Some(_metrics_handle) = metrics_handle {
-        thread::sleep(time::Duration::from_millis(1000 * metrics_delay));
-        // we just let the metrics thread die at the end.
-        // Never joined.
+    if let Some(pushgateway) = pushgateway {
+        info!(logger, "Pushing metrics"; "pushgateway" => &pushgateway);
+        if let Err(e) = metrics::push(&pushgateway) {
+            error!(logger, "Failed to push metrics";
+                   "pushgateway" => &pushgateway,
+                   "error" => ?e,
+            );
+        }
     }
 
+    let textfile_result = match metrics_textfile {
+        Some(metrics_textfile) => metrics::write_textfile(&metrics_textfile).map_err(|e| {
+            error!(logger, "Failed to write the metrics textfile";
+                   "file" => metrics_textfile.display(),
+                   "error" => %e,
+            );
+            ExitError::Io(e)
+        }),
+        None => Ok(()),
+    };
+
+    let mut dump_result = Ok(());
     if dump_metrics {
-        let mut buffer = Vec::<u8>::new();
-        prometheus::default_registry();
-        prometheus::TextEncoder::new()
-            .encode(&prometheus::default_registry().gather(), &mut buffer)
-            .unwrap();
-        println!("metrics:\n {}", String::from_utf8(buffer).unwrap());
+        let buffer = metrics::encode();
+        if let Some(dump_metrics_file) = dump_metrics_file {
+            dump_result = File::create(&dump_metrics_file)
+                .and_then(|mut file| file.write_all(&buffer))
+                .map_err(|e| {
+                    error!(logger, "Failed to write the metrics file";
+                           "file" => dump_metrics_file.display(),
+                           "error" => %e,
+                    );
+                    ExitError::Io(e)
+                });
+        } else {
+            println!("metrics:\n {}", String::from_utf8(buffer).unwrap());
+        }
     }
 
-    thread_result.unwrap().unwrap();
+    // The op's own failure matters more than the metrics files'.
+    std::process::exit(match (thread_result, textfile_result.and(dump_result)) {
+        (Ok(Ok(())), Ok(())) => 0,
+        (Ok(Ok(())), Err(e)) | (Ok(Err(e)), _) => e.exit_code(),
+        (Err(_), _) => cli::PANIC_EXIT_CODE,
+    });
 }
@@ -1,137 +1,114 @@
+use crate::cli::ExitError;
+use crate::credentials::Secret;
+use crate::github::{Commit, GitHubApi, GitHubError, Repo};
+use crate::graphql;
 use crate::maintainers::{GitHubID, GitHubName, Handle};
-use crate::nix;
-use hubcaps::Github;
+use crate::nix::{self, NixInvocation};
+use futures::future::{self, Future};
 use std::collections::HashMap;
-use std::fs::File;
+use std::ffi::OsStr;
+use std::fs::{read_dir, read_to_string, File};
 use std::io::BufRead;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
 use tokio::runtime::Runtime;
 
+pub type ConfidenceFuture = Box<dyn Future<Item = Option<Confidence>, Error = ()> + Send>;
+
+/// Where to find the maintainer list's history.
+pub struct HistorySource {
+    /// Where the maintainer file's commits are looked up.
+    pub repo: Repo,
+    /// Read barriers, snapshots and overrides from here instead of
+    /// using the ones built in.
+    pub dir: Option<PathBuf>,
+    /// How to blame the current maintainer file.
+    pub blame: Blame,
+}
+
+// NOTE: DO NOT MAKE "Debug"! This will leak secrets
+pub enum Blame {
+    /// Run `git blame` in the maintainer file's checkout.
+    Git,
+    /// Ask GitHub to blame `path` as of `rev` in the history's repo.
+    GitHub {
+        token: Secret<String>,
+        rev: String,
+        path: String,
+    },
+}
+
 pub struct MaintainerHistory {
     logger: slog::Logger,
+    /// Where the maintainer file's commits are looked up.
+    repo: Repo,
     barriers: Vec<String>,
+    overrides: Arc<Vec<AttributionOverride>>,
     sources: Vec<(Vec<String>, HashMap<Handle, usize>)>,
 }
 
 impl MaintainerHistory {
-    pub fn load(logger: slog::Logger, maintainer_file: &Path) -> MaintainerHistory {
-        MaintainerHistory {
-            logger: logger.clone(),
-            barriers: vec![
-                // sort and format
-                "220459858b342ec880d484160eb63319b7b83af8".into(),
-                // Convert maintainer file entries to attributes
-                "f7da7fa0c3ab40b79a2358861831b925d2cb5a6b".into(),
-                // alphabetize
-                "dea3279593753f0dee2966cd3f0f1f84be5bfbe2".into(),
-                // sort
-                "a3a40b70892774792924824a9b8858a2ffd3489d".into(),
-                // alphabetize
-                "b4f60add6a227bfeb106497c270b8126dad8f8d3".into(),
-                // insert-sort
-                "a58a44e0c2106a87d258706f13cacc320adc8d32".into(),
-                // alphabetize
-                "ac1c3c95e18f6e9839f2ca151c761d1b283831f1".into(),
-            ],
-            sources: vec![
-                // Record a list of breaks in the history of the maintainer
-                // list. Capture the `.blame` file with `git blame -lb`
-                // and capture the .nix file by just copying it out.
-                //
-                // Make sure to keep the list sorted by time.
-                (
-                    // current version from Git
-                    git_blame_list(logger.clone(), maintainer_file).unwrap(),
-                    maintainer_pos(logger.clone(), maintainer_file).unwrap(),
-                ),
-                load_old_data(
-                    logger.clone(),
-                    include_str!(
-                        "../data/maintainer-list-05d273a45ed741d61ac6918361658c0c57b0ba41.blame"
-                    ),
-                    include_str!(
-                        "../data/maintainer-list-05d273a45ed741d61ac6918361658c0c57b0ba41.nix"
-                    ),
-                ),
-                // f7da7fa0c3ab40b79a2358861831b925d2cb5a6b...aa47bac04f06aeea993dc2e2cc6649fde4f31ed7
-                // are all reverts around the maintainer list, so skipping those.
-                // the next commit in the history is cf1b51aba2780fda582a18b1f97b1919339ddcd9,
-                // so I checked that commit out and copied out the maintainer list &
-                // `git blame -lb`'d the maintainer list.
-                // Commit from: Sun Mar 4 00:46:25 2018 +0000
-                load_old_data(
-                    logger.clone(),
-                    include_str!(
-                        "../data/maintainer-list-cf1b51aba2780fda582a18b1f97b1919339ddcd9.blame"
-                    ),
-                    include_str!(
-                        "../data/maintainer-list-cf1b51aba2780fda582a18b1f97b1919339ddcd9.nix"
-                    ),
-                ),
-                // right after dea3279593753f0dee2966cd3f0f1f84be5bfbe2
-                load_old_data(
-                    logger.clone(),
-                    include_str!(
-                        "../data/maintainer-list-26b59efa8a747e82077e8430aa671db365d49b97.blame"
-                    ),
-                    include_str!(
-                        "../data/maintainer-list-26b59efa8a747e82077e8430aa671db365d49b97.nix"
-                    ),
-                ),
-                // right after a3a40b70892774792924824a9b8858a2ffd3489d
-                load_old_data(
-                    logger.clone(),
-                    include_str!(
-                        "../data/maintainer-list-822f480922fe2a0a38bc9de429cb2457b2eda96f.blame"
-                    ),
-                    include_str!(
-                        "../data/maintainer-list-822f480922fe2a0a38bc9de429cb2457b2eda96f.nix"
-                    ),
-                ),
-                // right after b4f60add6a227bfeb106497c270b8126dad8f8d3
-                load_old_data(
-                    logger.clone(),
-                    include_str!(
-                        "../data/maintainer-list-8e462995ba6deaeec9fd6dc6d3b9a110c08e5955.blame"
-                    ),
-                    include_str!(
-                        "../data/maintainer-list-8e462995ba6deaeec9fd6dc6d3b9a110c08e5955.nix"
-                    ),
-                ),
-                // right after a58a44e0c2106a87d258706f13cacc320adc8d32
-                load_old_data(
-                    logger.clone(),
-                    include_str!(
-                        "../data/maintainer-list-15c4a36012e6de9b335eb5576697279ad1cbbd48.blame"
-                    ),
-                    include_str!(
-                        "../data/maintainer-list-15c4a36012e6de9b335eb5576697279ad1cbbd48.nix"
-                    ),
-                ),
-                // right after ac1c3c95e18f6e9839f2ca151c761d1b283831f1
-                load_old_data(
-                    logger.clone(),
-                    include_str!(
-                        "../data/maintainer-list-9ce5fb002a7cf2369cddec8c25519ff73e0cf394.blame"
-                    ),
-                    include_str!(
-                        "../data/maintainer-list-9ce5fb002a7cf2369cddec8c25519ff73e0cf394.nix"
-                    ),
-                ),
-                /*
-                load_old_data(
-                    // Sort maintainer list
-                    logger.clone(),
-                    include_str!("../data/maintainer-list-d706fc953d0afe6bd060459f23f5e41a83c63a59.blame"),
-                    include_str!("../data/maintainer-list-d706fc953d0afe6bd060459f23f5e41a83c63a59.nix"),
-                    // Mon Sep 25 14:50:31 2017 +0100
-                    "d706fc953d0afe6bd060459f23f5e41a83c63a59",
-                ),
-                */
-            ],
-        }
+    /// Load the maintainer list's history. With `source.dir` the
+    /// barriers and old snapshots are read from it, see
+    /// `load_history_dir`, instead of using the ones built in. The
+    /// attribution overrides are read from its `overrides.json`, if
+    /// there is one.
+    pub fn load(
+        logger: slog::Logger,
+        rt: &mut Runtime,
+        nix_invocation: &NixInvocation,
+        maintainer_file: &Path,
+        source: HistorySource,
+    ) -> Result<MaintainerHistory, ExitError> {
+        let current_blame = match source.blame {
+            Blame::Git => git_blame_list(logger.clone(), maintainer_file).unwrap(),
+            Blame::GitHub { token, rev, path } => {
+                graphql::blame(&logger, rt, &token, &source.repo, &rev, &path)?
+            }
+        };
+
+        // Record a list of breaks in the history of the maintainer
+        // list. Capture the `.blame` file with `git blame -lb`
+        // and capture the .nix file by just copying it out.
+        //
+        // Make sure to keep the list sorted by time.
+        let mut sources = vec![(
+            // current version from Git
+            current_blame,
+            maintainer_pos(logger.clone(), nix_invocation, maintainer_file)?,
+        )];
+
+        let history_dir = source.dir.as_deref();
+        let barriers = if let Some(history_dir) = history_dir {
+            let (barriers, snapshots) = load_history_dir(&logger, history_dir)?;
+            for (blame, nix) in snapshots {
+                sources.push(load_old_data(logger.clone(), nix_invocation, &blame, &nix)?);
+            }
+            barriers
+        } else {
+            sources.extend(embedded_sources(&logger, nix_invocation)?);
+            parse_barriers(include_str!("../data/barriers.txt"))
+        };
+
+        let overrides = match history_dir.map(|dir| dir.join("overrides.json")) {
+            Some(ref path) if path.exists() => {
+                debug!(logger, "Loading attribution overrides";
+                       "path" => %path.display(),
+                );
+                parse_overrides(&read_to_string(path)?)?
+            }
+            _ => parse_overrides(include_str!("../data/attribution-overrides.json"))?,
+        };
+
+        Ok(MaintainerHistory {
+            logger,
+            repo: source.repo,
+            barriers,
+            overrides: Arc::new(overrides),
+            sources,
+        })
     }
 
     pub fn commit_for_user(&self, user: &Handle) -> Option<&str> {
@@ -162,24 +139,86 @@ impl MaintainerHistory {
         None
     }
 
+    /// Check the user against the author of the commit which added
+    /// them, without blocking, so many users can be checked at once.
     pub fn confidence_for_user(
         &self,
-        github: &Github,
+        github: &impl GitHubApi,
         user: &Handle,
         github_name: &GitHubName,
         github_id: GitHubID,
-    ) -> Option<Confidence> {
+    ) -> ConfidenceFuture {
         if let Some(hash) = self.commit_for_user(&user) {
-            check_user_hash(&self.logger, &github, &user, &github_name, github_id, hash)
+            check_user_hash(
+                &self.logger,
+                github,
+                &self.repo,
+                self.overrides.clone(),
+                &user,
+                &github_name,
+                github_id,
+                hash,
+            )
         } else {
             warn!(self.logger, "Did not find a suitable commit hash for user";
                   "user" => %user,
             );
-            None
+            Box::new(future::ok(None))
         }
     }
 }
 
+/// A commit known to have added `github_name` on their behalf, so its
+/// author is `author` rather than them.
+#[derive(Debug, Deserialize)]
+pub struct AttributionOverride {
+    commit: String,
+    github_name: GitHubName,
+    author: GitHubName,
+}
+
+impl AttributionOverride {
+    fn matches(&self, commit_hash: &str, github_name: &GitHubName, author: &GitHubName) -> bool {
+        self.commit == commit_hash && self.github_name == *github_name && self.author == *author
+    }
+
+    /// The login the user's GitHub ID has now, if the commit which
+    /// added them shows their handle has changed since.
+    pub fn renamed_handle(
+        &self,
+        github: &impl GitHubApi,
+        user: &Handle,
+        github_name: &GitHubName,
+        github_id: GitHubID,
+    ) -> Box<dyn Future<Item = Option<GitHubName>, Error = ()> + Send> {
+        let commit_hash = match self.commit_for_user(user) {
+            Some(hash) => hash.to_string(),
+            None => return Box::new(future::ok(None)),
+        };
+
+        let github = github.clone();
+        let repo = self.repo.clone();
+        Box::new(
+            self.confidence_for_user(&github, user, github_name, github_id)
+                .and_then(
+                    move |confidence| -> Box<dyn Future<Item = Option<GitHubName>, Error = ()> + Send> {
+                        match confidence {
+                            // The commit was just fetched, so this is
+                            // answered from the commit cache.
+                            Some(Confidence::ChangedHandle) => Box::new(
+                                github
+                                    .get_commit(&repo.owner, &repo.name, &commit_hash)
+                                    .map(|commit| Some(commit.author.login))
+                                    .or_else(|_| Ok(None)),
+                            ),
+                            _ => Box::new(future::ok(None)),
+                        }
+                    },
+                ),
+        )
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Confidence {
     Total,
@@ -187,31 +226,80 @@ pub enum Confidence {
     ChangedHandle,
     MismatchedNameAndID,
     CommitMissing,
+    /// GitHub has no account by the recorded name.
+    AccountDeleted,
 }
 
 fn check_user_hash(
     logger: &slog::Logger,
-    github: &Github,
+    github: &impl GitHubApi,
+    repo: &Repo,
+    overrides: Arc<Vec<AttributionOverride>>,
     user: &Handle,
     github_name: &GitHubName,
     github_id: GitHubID,
     commit_hash: &str,
-) -> Option<Confidence> {
-    let mut rt = Runtime::new().unwrap();
+) -> ConfidenceFuture {
     trace!(logger, "Looking up commit";
            "commit" => %commit_hash,
     );
 
-    let commit = rt.block_on(github.repo("NixOS", "nixpkgs").commits().get(commit_hash));
+    let logger = logger.clone();
+    let github = github.clone();
+    let user = user.clone();
+    let github_name = github_name.clone();
+    let commit_hash = commit_hash.to_string();
+    Box::new(
+        github
+            .get_commit(&repo.owner, &repo.name, &commit_hash)
+            .then(move |commit| -> ConfidenceFuture {
+                let confidence = commit_confidence(
+                    &logger,
+                    &overrides,
+                    &user,
+                    &github_name,
+                    github_id,
+                    &commit_hash,
+                    commit,
+                );
+                if confidence != Some(Confidence::CommitMissing) {
+                    return Box::new(future::ok(confidence));
+                }
+
+                // Tell a missing commit apart from a missing account.
+                Box::new(
+                    github
+                        .get_user(&github_name)
+                        .then(move |found| match found {
+                            Err(ref e) if e.is_not_found() => {
+                                warn!(logger, "GitHub account no longer exists";
+                                      "github_name" => %github_name,
+                                      "handle" => %user,
+                                );
+                                Ok(Some(Confidence::AccountDeleted))
+                            }
+                            _ => Ok(confidence),
+                        }),
+                )
+            }),
+    )
+}
+
+fn commit_confidence(
+    logger: &slog::Logger,
+    overrides: &[AttributionOverride],
+    user: &Handle,
+    github_name: &GitHubName,
+    github_id: GitHubID,
+    commit_hash: &str,
+    commit: Result<Commit, GitHubError>,
+) -> Option<Confidence> {
     match commit {
         Ok(commit) => match (
-            (GitHubName::new(commit.author.login.clone()) == *github_name),
-            (GitHubID::new(commit.author.id) == github_id),
-            format!("{}", github_name).as_str(),
-            commit.author.login.as_str(),
-            commit_hash,
+            (commit.author.login == *github_name),
+            (commit.author.id == github_id),
         ) {
-            (true, true, _, _, _) => {
+            (true, true) => {
                 debug!(logger, "Commit Details Match 100%";
                        "user" => %commit.author.login,
                        "commit" => %commit_hash,
@@ -220,22 +308,21 @@ fn check_user_hash(
                 Some(Confidence::Total)
             }
 
-            // mismatch, mismatch, added user, added by, in commit
-            (
-                false,
-                false,
-                "rlupton20",
-                "offlinehacker",
-                "5bd136acd4c683b30470b5dfbb6f0b15dcea42a5",
-            ) => Some(Confidence::Total),
-            (false, false, "zx2c4", "Mic92", "6b1087d9b135c94b929fec3d4cf3724b9539c6b5") => {
-                Some(Confidence::Total)
-            }
-            (false, false, "the-kenny", "bjornfor", "6b1087d9b135c94b929fec3d4cf3724b9539c6b5") => {
+            (_, false)
+                if overrides
+                    .iter()
+                    .any(|o| o.matches(commit_hash, github_name, &commit.author.login)) =>
+            {
+                debug!(logger, "Commit author differs, but is overridden";
+                       "recorded_github_name" => %github_name,
+                       "actual_github_name" => %commit.author.login,
+                       "commit" => %commit_hash,
+                );
+
                 Some(Confidence::Total)
             }
 
-            (true, false, _, _, _) => {
+            (true, false) => {
                 error!(logger, "Bug or recorded GitHub ID is wrong, as the ID does not match who authored the maintainer addition PR!";
                        "recorded_github_name" => %github_name,
                        "recorded_user_id" => %github_id,
@@ -246,7 +333,7 @@ fn check_user_hash(
                 Some(Confidence::BadAttribution)
             }
 
-            (false, true, _, _, _) => {
+            (false, true) => {
                 warn!(logger, "Our user named {} changed their GitHub handle", user;
                       "recorded_github_name" => %github_name,
                       "actual_github_name" => %commit.author.login,
@@ -256,7 +343,7 @@ fn check_user_hash(
                 Some(Confidence::ChangedHandle)
             }
 
-            (false, false, _, _, _) => {
+            (false, false) => {
                 warn!(logger, "Bug or recorded GitHub ID and GitHub Name is wrong, as neither the ID or the GitHub name match the author of the maintainer addition PR!";
                       "recorded_github_name" => %github_name,
                       "recorded_user_id" => %github_id,
@@ -278,6 +365,145 @@ fn check_user_hash(
     }
 }
 
+/// The snapshots built into rfc39, newest first.
+fn embedded_sources(
+    logger: &slog::Logger,
+    nix_invocation: &NixInvocation,
+) -> Result<Vec<(Vec<String>, HashMap<Handle, usize>)>, ExitError> {
+    Ok(vec![
+        load_old_data(
+            logger.clone(),
+            nix_invocation,
+            include_str!("../data/maintainer-list-05d273a45ed741d61ac6918361658c0c57b0ba41.blame"),
+            include_str!("../data/maintainer-list-05d273a45ed741d61ac6918361658c0c57b0ba41.nix"),
+        )?,
+        // f7da7fa0c3ab40b79a2358861831b925d2cb5a6b...aa47bac04f06aeea993dc2e2cc6649fde4f31ed7
+        // are all reverts around the maintainer list, so skipping those.
+        // the next commit in the history is cf1b51aba2780fda582a18b1f97b1919339ddcd9,
+        // so I checked that commit out and copied out the maintainer list &
+        // `git blame -lb`'d the maintainer list.
+        // Commit from: Sun Mar 4 00:46:25 2018 +0000
+        load_old_data(
+            logger.clone(),
+            nix_invocation,
+            include_str!("../data/maintainer-list-cf1b51aba2780fda582a18b1f97b1919339ddcd9.blame"),
+            include_str!("../data/maintainer-list-cf1b51aba2780fda582a18b1f97b1919339ddcd9.nix"),
+        )?,
+        // right after dea3279593753f0dee2966cd3f0f1f84be5bfbe2
+        load_old_data(
+            logger.clone(),
+            nix_invocation,
+            include_str!("../data/maintainer-list-26b59efa8a747e82077e8430aa671db365d49b97.blame"),
+            include_str!("../data/maintainer-list-26b59efa8a747e82077e8430aa671db365d49b97.nix"),
+        )?,
+        // right after a3a40b70892774792924824a9b8858a2ffd3489d
+        load_old_data(
+            logger.clone(),
+            nix_invocation,
+            include_str!("../data/maintainer-list-822f480922fe2a0a38bc9de429cb2457b2eda96f.blame"),
+            include_str!("../data/maintainer-list-822f480922fe2a0a38bc9de429cb2457b2eda96f.nix"),
+        )?,
+        // right after b4f60add6a227bfeb106497c270b8126dad8f8d3
+        load_old_data(
+            logger.clone(),
+            nix_invocation,
+            include_str!("../data/maintainer-list-8e462995ba6deaeec9fd6dc6d3b9a110c08e5955.blame"),
+            include_str!("../data/maintainer-list-8e462995ba6deaeec9fd6dc6d3b9a110c08e5955.nix"),
+        )?,
+        // right after a58a44e0c2106a87d258706f13cacc320adc8d32
+        load_old_data(
+            logger.clone(),
+            nix_invocation,
+            include_str!("../data/maintainer-list-15c4a36012e6de9b335eb5576697279ad1cbbd48.blame"),
+            include_str!("../data/maintainer-list-15c4a36012e6de9b335eb5576697279ad1cbbd48.nix"),
+        )?,
+        // right after ac1c3c95e18f6e9839f2ca151c761d1b283831f1
+        load_old_data(
+            logger.clone(),
+            nix_invocation,
+            include_str!("../data/maintainer-list-9ce5fb002a7cf2369cddec8c25519ff73e0cf394.blame"),
+            include_str!("../data/maintainer-list-9ce5fb002a7cf2369cddec8c25519ff73e0cf394.nix"),
+        )?,
+        /*
+        load_old_data(
+            // Sort maintainer list
+            logger.clone(),
+            include_str!("../data/maintainer-list-d706fc953d0afe6bd060459f23f5e41a83c63a59.blame"),
+            include_str!("../data/maintainer-list-d706fc953d0afe6bd060459f23f5e41a83c63a59.nix"),
+            // Mon Sep 25 14:50:31 2017 +0100
+            "d706fc953d0afe6bd060459f23f5e41a83c63a59",
+        ),
+        */
+    ])
+}
+
+/// Read barriers from `barriers.txt` in `dir`, and every
+/// `<name>.blame`/`<name>.nix` snapshot pair. Snapshots are tried in
+/// reverse file name order, so name them to sort oldest first, for
+/// example by prefixing the commit date.
+fn load_history_dir(
+    logger: &slog::Logger,
+    dir: &Path,
+) -> Result<(Vec<String>, Vec<(String, String)>), ExitError> {
+    let barriers = parse_barriers(&read_to_string(dir.join("barriers.txt")).map_err(|err| {
+        error!(logger, "Failed to read barriers.txt";
+               "history_dir" => %dir.display(),
+               "e" => %err,
+        );
+        err
+    })?);
+
+    let mut snapshots = vec![];
+    for (blame, nix) in snapshot_pairs(dir)? {
+        debug!(logger, "Loading maintainer list snapshot";
+               "blame" => %blame.display(),
+               "nix" => %nix.display(),
+        );
+        snapshots.push((read_to_string(&blame)?, read_to_string(&nix)?));
+    }
+
+    info!(logger, "Loaded maintainer list history";
+          "history_dir" => %dir.display(),
+          "barriers" => barriers.len(),
+          "snapshots" => snapshots.len(),
+    );
+
+    Ok((barriers, snapshots))
+}
+
+/// Find the `.blame` files in `dir` which have a matching `.nix` file,
+/// newest first.
+fn snapshot_pairs(dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>, std::io::Error> {
+    let mut pairs = vec![];
+    for entry in read_dir(dir)? {
+        let blame = entry?.path();
+        if blame.extension() == Some(OsStr::new("blame")) {
+            let nix = blame.with_extension("nix");
+            if nix.is_file() {
+                pairs.push((blame, nix));
+            }
+        }
+    }
+
+    pairs.sort();
+    pairs.reverse();
+    Ok(pairs)
+}
+
+fn parse_overrides(overrides: &str) -> Result<Vec<AttributionOverride>, ExitError> {
+    Ok(serde_json::from_str(overrides)?)
+}
+
+/// One commit hash per line, ignoring blank lines and `#` comments.
+fn parse_barriers(barriers: &str) -> Vec<String> {
+    barriers
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
 fn git_blame_list(logger: slog::Logger, file: &Path) -> Result<Vec<String>, ()> {
     let output = Command::new("git")
         .args(&[
@@ -313,9 +539,10 @@ fn git_blame_list(logger: slog::Logger, file: &Path) -> Result<Vec<String>, ()>
 
 fn load_old_data(
     logger: slog::Logger,
+    nix_invocation: &NixInvocation,
     blame: &str,
     nix: &str,
-) -> (Vec<String>, HashMap<Handle, usize>) {
+) -> Result<(Vec<String>, HashMap<Handle, usize>), ExitError> {
     let hash_list: Vec<String> = blame
         .lines()
         .map(|line| {
@@ -327,28 +554,30 @@ fn load_old_data(
         .collect();
 
     let positions = {
-        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir = tempfile::tempdir()?;
         let file_path = tmpdir.path().join("old-maintainers.nix");
-        let mut file = File::create(&file_path).unwrap();
-        file.write_all(nix.as_bytes()).unwrap();
-        file.sync_all().unwrap();
+        let mut file = File::create(&file_path)?;
+        file.write_all(nix.as_bytes())?;
+        file.sync_all()?;
         drop(file);
 
-        let ret = maintainer_pos(logger.clone(), &file_path).unwrap();
+        let ret = maintainer_pos(logger.clone(), nix_invocation, &file_path)?;
         drop(tmpdir);
         ret
     };
 
-    (hash_list, positions)
+    Ok((hash_list, positions))
 }
 
 fn maintainer_pos(
     logger: slog::Logger,
+    nix_invocation: &NixInvocation,
     maintainer_file: &Path,
-) -> Result<HashMap<Handle, usize>, serde_json::error::Error> {
+) -> Result<HashMap<Handle, usize>, nix::NixError> {
     Ok(
         nix::nix_instantiate_expr_args_to_struct::<HashMap<Handle, usize>>(
             logger,
+            nix_invocation,
             r#"
 { maintainerFile }:
 let
@@ -369,3 +598,126 @@ in builtins.listToAttrs
         .collect(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedded_barriers() {
+        let barriers = parse_barriers(include_str!("../data/barriers.txt"));
+        assert_eq!(barriers.len(), 7);
+        assert_eq!(barriers[0], "220459858b342ec880d484160eb63319b7b83af8");
+    }
+
+    #[test]
+    fn test_attribution_override() {
+        let logger = crate::test_logger();
+        let overrides =
+            parse_overrides(&read_to_string("./samples/attribution-overrides.json").unwrap())
+                .unwrap();
+        let commit = || {
+            Ok(Commit {
+                author: crate::github::User {
+                    login: GitHubName::new("bob"),
+                    id: GitHubID::new(2),
+                },
+            })
+        };
+        let check = |overrides: &[AttributionOverride], commit_hash: &str| {
+            commit_confidence(
+                &logger,
+                overrides,
+                &Handle::new("alice"),
+                &GitHubName::new("alice"),
+                GitHubID::new(1),
+                commit_hash,
+                commit(),
+            )
+        };
+
+        assert_eq!(
+            check(&[], "0123456789abcdef0123456789abcdef01234567"),
+            Some(Confidence::MismatchedNameAndID)
+        );
+        assert_eq!(
+            check(&overrides, "0123456789abcdef0123456789abcdef01234567"),
+            Some(Confidence::Total)
+        );
+        assert_eq!(
+            check(&overrides, "5bd136acd4c683b30470b5dfbb6f0b15dcea42a5"),
+            Some(Confidence::MismatchedNameAndID)
+        );
+    }
+
+    #[test]
+    fn test_account_deleted() {
+        use crate::github::{FakeGitHub, FakeState};
+
+        let logger = crate::test_logger();
+        let github = FakeGitHub::new(FakeState {
+            users: vec![crate::github::User {
+                login: GitHubName::new("alice"),
+                id: GitHubID::new(1),
+            }],
+            ..Default::default()
+        });
+        let repo: Repo = "NixOS/nixpkgs".parse().unwrap();
+        let check = |name: &str, id: u64| {
+            check_user_hash(
+                &logger,
+                &github,
+                &repo,
+                Arc::new(vec![]),
+                &Handle::new(name),
+                &GitHubName::new(name),
+                GitHubID::new(id),
+                "0123456789abcdef0123456789abcdef01234567",
+            )
+            .wait()
+            .unwrap()
+        };
+
+        assert_eq!(check("alice", 1), Some(Confidence::CommitMissing));
+        assert_eq!(check("gone", 2), Some(Confidence::AccountDeleted));
+    }
+
+    #[test]
+    fn test_embedded_overrides() {
+        assert_eq!(
+            parse_overrides(include_str!("../data/attribution-overrides.json"))
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_snapshot_pairs() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        for name in &[
+            "2018-03-04.blame",
+            "2018-03-04.nix",
+            "2019-01-01.blame",
+            "2019-01-01.nix",
+            "unpaired.blame",
+            "barriers.txt",
+        ] {
+            File::create(tmpdir.path().join(name)).unwrap();
+        }
+
+        assert_eq!(
+            snapshot_pairs(tmpdir.path()).unwrap(),
+            vec![
+                (
+                    tmpdir.path().join("2019-01-01.blame"),
+                    tmpdir.path().join("2019-01-01.nix")
+                ),
+                (
+                    tmpdir.path().join("2018-03-04.blame"),
+                    tmpdir.path().join("2018-03-04.nix")
+                ),
+            ]
+        );
+    }
+}
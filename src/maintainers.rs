@@ -1,17 +1,20 @@
 //! Given a checkout of nixpkgs, extract a dataset of GitHub account
 //! information from the maintainer list.
 
-use crate::nix;
-use serde::Deserialize;
-use std::collections::HashMap;
+use crate::nix::{self, NixInvocation};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
 use std::path::Path;
 
-#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct MaintainerList {
     maintainers: HashMap<Handle, Information>,
+    by_github_id: HashMap<GitHubID, Handle>,
+    by_github_name: HashMap<GitHubName, Handle>,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Deserialize)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Serialize, Deserialize)]
 pub struct Handle(String);
 impl std::fmt::Display for Handle {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -27,7 +30,7 @@ impl Handle {
         Handle(name.into())
     }
 }
-#[derive(Debug, Eq, Clone, Deserialize)]
+#[derive(Debug, Eq, Clone, Serialize, Deserialize)]
 pub struct GitHubName(String);
 impl std::fmt::Display for GitHubName {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -51,9 +54,23 @@ impl GitHubName {
     {
         GitHubName(name.into())
     }
+
+    /// Whether GitHub would accept this as a username: up to 39 ASCII
+    /// letters, digits and single hyphens, not starting or ending
+    /// with a hyphen. Catches typos like a leading `@` or stray
+    /// whitespace.
+    pub fn is_valid(&self) -> bool {
+        let name = &self.0;
+        !name.is_empty()
+            && name.len() <= 39
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !name.starts_with('-')
+            && !name.ends_with('-')
+            && !name.contains("--")
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone, Deserialize)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone, Serialize, Deserialize)]
 pub struct GitHubID(u64);
 impl std::fmt::Display for GitHubID {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -66,28 +83,138 @@ impl GitHubID {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Information {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub github: Option<GitHubName>,
-    #[serde(rename = "githubId")]
+    #[serde(rename = "githubId", skip_serializing_if = "Option::is_none")]
     pub github_id: Option<GitHubID>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keys: Option<Vec<MaintainerKey>>,
+}
+
+/// A PGP key listed for a maintainer.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintainerKey {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longkeyid: Option<String>,
+    pub fingerprint: String,
 }
 
 impl MaintainerList {
-    #[cfg(test)]
     pub fn new(maintainers: HashMap<Handle, Information>) -> MaintainerList {
-        MaintainerList { maintainers }
+        let mut by_github_id = HashMap::new();
+        let mut by_github_name = HashMap::new();
+        for (handle, info) in &maintainers {
+            if let Some(github_id) = info.github_id {
+                by_github_id.insert(github_id, handle.clone());
+            }
+            if let Some(ref github_name) = info.github {
+                by_github_name.insert(github_name.clone(), handle.clone());
+            }
+        }
+
+        MaintainerList {
+            maintainers,
+            by_github_id,
+            by_github_name,
+        }
     }
 
     pub fn load(
         logger: slog::Logger,
+        nix_invocation: &NixInvocation,
         path: &Path,
-    ) -> Result<MaintainerList, serde_json::error::Error> {
-        Ok(MaintainerList {
-            maintainers: nix::nix_instantiate_file_to_struct(logger, path)?,
-        })
+    ) -> Result<MaintainerList, nix::NixError> {
+        Ok(MaintainerList::new(nix::nix_instantiate_file_to_struct(
+            logger,
+            nix_invocation,
+            path,
+        )?))
+    }
+
+    /// Load a maintainer list already rendered to JSON, as by
+    /// `nix-instantiate --eval --strict --json`, without Nix.
+    pub fn load_json(path: &Path) -> Result<MaintainerList, crate::cli::ExitError> {
+        Ok(MaintainerList::new(serde_json::from_reader(
+            std::io::BufReader::new(File::open(path)?),
+        )?))
+    }
+
+    /// Load the maintainers from an attribute of a Nixpkgs-like
+    /// checkout, like `lib.maintainers`, instead of a standalone file.
+    pub fn load_attr(
+        logger: slog::Logger,
+        nix_invocation: &NixInvocation,
+        nixpkgs_root: &Path,
+        attr: &str,
+    ) -> Result<MaintainerList, nix::NixError> {
+        let expr = format!(
+            "{{ nixpkgs }}: (import nixpkgs {{}}).{}",
+            nix::attr_path(attr)
+        );
+
+        Ok(MaintainerList::new(
+            nix::nix_instantiate_expr_args_to_struct(
+                logger,
+                nix_invocation,
+                &expr,
+                vec![("nixpkgs", nixpkgs_root.as_os_str())],
+            )?,
+        ))
+    }
+
+    /// Find the maintainer with this GitHub ID. If several claim it,
+    /// see `duplicate_github_ids`, one of them is returned.
+    pub fn by_github_id(&self, id: &GitHubID) -> Option<(&Handle, &Information)> {
+        let handle = self.by_github_id.get(id)?;
+        self.maintainers.get_key_value(handle)
+    }
+
+    /// Find the maintainer with this GitHub name, ignoring case.
+    pub fn by_github_name(&self, name: &GitHubName) -> Option<(&Handle, &Information)> {
+        let handle = self.by_github_name.get(name)?;
+        self.maintainers.get_key_value(handle)
+    }
+
+    /// GitHub IDs claimed by more than one handle, with the handles
+    /// claiming them, sorted.
+    pub fn duplicate_github_ids(&self) -> Vec<(GitHubID, Vec<&Handle>)> {
+        let mut by_id: HashMap<GitHubID, Vec<&Handle>> = HashMap::new();
+        for (handle, info) in &self.maintainers {
+            if let Some(github_id) = info.github_id {
+                by_id.entry(github_id).or_default().push(handle);
+            }
+        }
+
+        let mut duplicates: Vec<(GitHubID, Vec<&Handle>)> = by_id
+            .into_iter()
+            .filter(|(_, handles)| handles.len() > 1)
+            .map(|(github_id, mut handles)| {
+                handles.sort();
+                (github_id, handles)
+            })
+            .collect();
+        duplicates.sort();
+
+        duplicates
+    }
+
+    /// The maintainers as a JSON object keyed by handle, in the same
+    /// shape as the Nix maintainer list.
+    pub fn to_json(&self) -> Result<String, serde_json::error::Error> {
+        let sorted: BTreeMap<&Handle, &Information> = self.maintainers.iter().collect();
+        serde_json::to_string_pretty(&sorted)
+    }
+
+    pub fn iter(&self) -> std::collections::hash_map::Iter<Handle, Information> {
+        self.maintainers.iter()
     }
 }
 
@@ -102,16 +229,48 @@ impl IntoIterator for MaintainerList {
 
 #[cfg(test)]
 mod tests {
-    use super::{GitHubID, GitHubName, Handle, Information, MaintainerList};
+    use super::{GitHubID, GitHubName, Handle, Information, MaintainerKey, MaintainerList};
+    use crate::nix::NixInvocation;
     use std::path::Path;
 
+    #[test]
+    pub fn test_github_name_is_valid() {
+        for valid in &[
+            "0x4A6F",
+            "1000101",
+            "a",
+            "jan-hrnko",
+            "a".repeat(39).as_str(),
+        ] {
+            assert!(GitHubName::new(*valid).is_valid(), "{} is valid", valid);
+        }
+        for invalid in &[
+            "",
+            "@alice",
+            " alice",
+            "alice ",
+            "-alice",
+            "alice-",
+            "al--ice",
+            "al_ice",
+            "ålice",
+            "a".repeat(40).as_str(),
+        ] {
+            assert!(
+                !GitHubName::new(*invalid).is_valid(),
+                "{} is invalid",
+                invalid
+            );
+        }
+    }
+
     #[test]
     pub fn test_load_9175a201bbb28e679d72e9f7d28c84ab7d1f742_reduced() {
-        let logger = rfc39::test_logger();
+        let logger = crate::test_logger();
 
         let sample = Path::new("./samples/9175a201bbb28e679d72e9f7d28c84ab7d1f742b.reduced.nix");
-        let expect = MaintainerList {
-            maintainers: vec![
+        let expect = MaintainerList::new(
+            vec![
                 (
                     Handle("0x4A6F".into()),
                     Information {
@@ -119,6 +278,8 @@ mod tests {
                         name: Some("Joachim Ernst".into()),
                         github: Some(GitHubName("0x4A6F".into())),
                         github_id: None,
+                        matrix: None,
+                        keys: None,
                     },
                 ),
                 (
@@ -128,6 +289,8 @@ mod tests {
                         name: Some("Jan Hrnko".into()),
                         github: Some(GitHubName("1000101".into())),
                         github_id: None,
+                        matrix: None,
+                        keys: None,
                     },
                 ),
                 (
@@ -137,22 +300,27 @@ mod tests {
                         name: Some("Adam Russell".into()),
                         github: None,
                         github_id: None,
+                        matrix: None,
+                        keys: None,
                     },
                 ),
             ]
             .into_iter()
             .collect(),
-        };
-        assert_eq!(expect, MaintainerList::load(logger, &sample).unwrap(),);
+        );
+        assert_eq!(
+            expect,
+            MaintainerList::load(logger, &NixInvocation::default(), &sample).unwrap(),
+        );
     }
 
     #[test]
     pub fn test_load_9175a201bbb28e679d72e9f7d28c84ab7d1f742_proposed() {
-        let logger = rfc39::test_logger();
+        let logger = crate::test_logger();
 
         let sample = Path::new("./samples/9175a201bbb28e679d72e9f7d28c84ab7d1f742b.proposed.nix");
-        let expect = MaintainerList {
-            maintainers: vec![
+        let expect = MaintainerList::new(
+            vec![
                 (
                     Handle("0x4A6F".into()),
                     Information {
@@ -160,6 +328,8 @@ mod tests {
                         name: Some("Joachim Ernst".into()),
                         github: Some(GitHubName("0x4A6F".into())),
                         github_id: None,
+                        matrix: None,
+                        keys: None,
                     },
                 ),
                 (
@@ -169,6 +339,8 @@ mod tests {
                         name: Some("Jan Hrnko".into()),
                         github: Some(GitHubName("1000101".into())),
                         github_id: Some(GitHubID(791309)),
+                        matrix: None,
+                        keys: None,
                     },
                 ),
                 (
@@ -178,28 +350,216 @@ mod tests {
                         name: Some("Adam Russell".into()),
                         github: None,
                         github_id: Some(GitHubID(241628)),
+                        matrix: None,
+                        keys: None,
                     },
                 ),
             ]
             .into_iter()
             .collect(),
-        };
-        assert_eq!(expect, MaintainerList::load(logger, &sample).unwrap(),);
+        );
+        assert_eq!(
+            expect,
+            MaintainerList::load(logger, &NixInvocation::default(), &sample).unwrap(),
+        );
     }
 
     #[test]
     pub fn test_load_9175a201bbb28e679d72e9f7d28c84ab7d1f742() {
-        let logger = rfc39::test_logger();
+        let logger = crate::test_logger();
 
         let sample = Path::new("./samples/9175a201bbb28e679d72e9f7d28c84ab7d1f742b.nix");
-        MaintainerList::load(logger, sample).unwrap();
+        MaintainerList::load(logger, &NixInvocation::default(), sample).unwrap();
+    }
+
+    #[test]
+    pub fn test_load_no_email() {
+        let logger = crate::test_logger();
+
+        let sample = Path::new("./samples/no-email.nix");
+        let expect = MaintainerList::new(
+            vec![(
+                Handle("0x4A6F".into()),
+                Information {
+                    email: None,
+                    name: Some("Joachim Ernst".into()),
+                    github: Some(GitHubName("0x4A6F".into())),
+                    github_id: Some(GitHubID(9675338)),
+                    matrix: None,
+                    keys: None,
+                },
+            )]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(
+            expect,
+            MaintainerList::load(logger, &NixInvocation::default(), &sample).unwrap(),
+        );
+    }
+
+    #[test]
+    pub fn test_load_matrix_and_keys() {
+        let logger = crate::test_logger();
+
+        let sample = Path::new("./samples/matrix-keys.nix");
+        let expect = MaintainerList::new(
+            vec![(
+                Handle("0x4A6F".into()),
+                Information {
+                    email: Some("0x4A6F@shackspace.de".into()),
+                    name: Some("Joachim Ernst".into()),
+                    github: Some(GitHubName("0x4A6F".into())),
+                    github_id: Some(GitHubID(9675338)),
+                    matrix: Some("@0x4a6f:matrix.org".into()),
+                    keys: Some(vec![MaintainerKey {
+                        longkeyid: Some("rsa4096/0x1E7F6A7D6C9A1B2C".into()),
+                        fingerprint: "F466 A548 AD3F C1F1 8C88  4576 8702 7528 B006 D66D".into(),
+                    }]),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(
+            expect,
+            MaintainerList::load(logger, &NixInvocation::default(), &sample).unwrap(),
+        );
+    }
+
+    #[test]
+    pub fn test_load_json() {
+        let logger = crate::test_logger();
+
+        assert_eq!(
+            MaintainerList::load(
+                logger,
+                &NixInvocation::default(),
+                Path::new("./samples/matrix-keys.nix")
+            )
+            .unwrap(),
+            MaintainerList::load_json(Path::new("./samples/matrix-keys.json")).unwrap(),
+        );
+    }
+
+    #[test]
+    pub fn test_load_attr() {
+        let logger = crate::test_logger();
+
+        let sample = Path::new("./samples/9175a201bbb28e679d72e9f7d28c84ab7d1f742b.reduced.nix")
+            .canonicalize()
+            .unwrap();
+        let nixpkgs = tempfile::tempdir().unwrap();
+        std::fs::write(
+            nixpkgs.path().join("default.nix"),
+            format!(
+                "{{ }}: {{ lib.maintainers = import {}; }}",
+                sample.display()
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(
+            MaintainerList::load(logger.clone(), &NixInvocation::default(), &sample).unwrap(),
+            MaintainerList::load_attr(
+                logger,
+                &NixInvocation::default(),
+                nixpkgs.path(),
+                "lib.maintainers"
+            )
+            .unwrap(),
+        );
+    }
+
+    #[test]
+    pub fn test_duplicate_github_ids() {
+        let information = |github: &str, github_id: Option<u64>| Information {
+            email: None,
+            name: None,
+            github: Some(GitHubName(github.into())),
+            github_id: github_id.map(GitHubID),
+            matrix: None,
+            keys: None,
+        };
+
+        let maintainers = MaintainerList::new(
+            vec![
+                (Handle("alice".into()), information("alice", Some(1))),
+                (Handle("alice2".into()), information("alice", Some(1))),
+                (Handle("bob".into()), information("bob", Some(2))),
+                (Handle("charlie".into()), information("charlie", None)),
+                (Handle("dave".into()), information("dave", None)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        assert_eq!(
+            maintainers.duplicate_github_ids(),
+            vec![(
+                GitHubID(1),
+                vec![&Handle("alice".into()), &Handle("alice2".into())]
+            )]
+        );
+    }
+
+    #[test]
+    pub fn test_reverse_lookups() {
+        let maintainers = MaintainerList::new(
+            vec![(
+                Handle("alice".into()),
+                Information {
+                    email: None,
+                    name: None,
+                    github: Some(GitHubName("Alice".into())),
+                    github_id: Some(GitHubID(1)),
+                    matrix: None,
+                    keys: None,
+                },
+            )]
+            .into_iter()
+            .collect(),
+        );
+
+        assert_eq!(
+            maintainers
+                .by_github_id(&GitHubID(1))
+                .map(|(handle, _)| handle),
+            Some(&Handle("alice".into()))
+        );
+        assert_eq!(
+            maintainers
+                .by_github_name(&GitHubName("alice".into()))
+                .map(|(handle, _)| handle),
+            Some(&Handle("alice".into()))
+        );
+        assert!(maintainers.by_github_id(&GitHubID(2)).is_none());
+        assert!(maintainers
+            .by_github_name(&GitHubName("bob".into()))
+            .is_none());
+    }
+
+    #[test]
+    pub fn test_json_round_trip() {
+        let logger = crate::test_logger();
+
+        let sample = Path::new("./samples/matrix-keys.nix");
+        let maintainers =
+            MaintainerList::load(logger.clone(), &NixInvocation::default(), &sample).unwrap();
+        let json = maintainers.to_json().unwrap();
+
+        assert!(json.contains(r#""githubId": 9675338"#), "{}", json);
+        assert_eq!(
+            maintainers,
+            MaintainerList::new(serde_json::from_str(&json).unwrap())
+        );
     }
 
     #[test]
     pub fn test_load_stderr() {
-        let logger = rfc39::test_logger();
+        let logger = crate::test_logger();
 
         let sample = Path::new("./samples/stderr.nix");
-        MaintainerList::load(logger, sample).unwrap();
+        MaintainerList::load(logger, &NixInvocation::default(), sample).unwrap();
     }
 }
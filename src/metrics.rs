@@ -1,22 +1,741 @@
 use futures::future::Future;
+use futures::sync::oneshot;
+use hyper::header::CONTENT_TYPE;
+use hyper::http::uri::InvalidUri;
 use hyper::service::service_fn_ok;
-use hyper::{Body, Request, Response, Server};
-use prometheus::Encoder;
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode, Uri};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, IntGaugeVec, Opts, Registry,
+};
+use std::io::Write;
 use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 
-pub fn serve(bind: &SocketAddr) {
-    let server = Server::bind(bind)
-        .serve(|| {
-            let registry = prometheus::default_registry();
-            let encoder = prometheus::TextEncoder::new();
+/// Upper bounds, in seconds, of the buckets GitHub call latencies are
+/// counted into.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Buckets(Vec<f64>);
 
-            service_fn_ok(move |_: Request<_>| {
-                let mut buffer = Vec::<u8>::new();
-                encoder.encode(&registry.gather(), &mut buffer).unwrap();
+impl Default for Buckets {
+    /// GitHub calls take from tens of milliseconds to several seconds.
+    fn default() -> Buckets {
+        Buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0])
+    }
+}
+
+impl std::str::FromStr for Buckets {
+    type Err = String;
 
-                Response::new(Body::from(buffer))
+    /// Comma separated seconds, in increasing order.
+    fn from_str(buckets: &str) -> Result<Self, Self::Err> {
+        let bounds = buckets
+            .split(',')
+            .map(|bound| {
+                bound
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|bound| bound.is_finite() && *bound > 0.0)
+                    .ok_or_else(|| format!("expected a number of seconds, got {:?}", bound))
             })
+            .collect::<Result<Vec<f64>, String>>()?;
+
+        if bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(format!("bucket bounds must increase, got {:?}", buckets));
+        }
+
+        Ok(Buckets(bounds))
+    }
+}
+
+/// Every metric rfc39 reports. They're all registered up front, so
+/// the ones which stay at zero are still reported.
+///
+/// Counters add up across every operation given the same `Metrics`,
+/// so give each sync its own when its change limits should start
+/// from zero.
+#[derive(Clone)]
+pub struct Metrics {
+    pub begin_seconds: IntGauge,
+    pub stop_seconds: IntGauge,
+    pub build_info: IntGaugeVec,
+    pub op_success: IntCounter,
+    /// Stays 0 when the run fails, so an alert on its age fires.
+    pub last_success_seconds: IntGauge,
+    pub op_failure: IntCounter,
+    pub op_panic: IntCounter,
+    pub maintainers: MaintainerMetrics,
+    pub github: GitHubMetrics,
+    pub sync: SyncMetrics,
+    pub backfill: BackfillMetrics,
+    pub diff: DiffMetrics,
+    pub check_handles: CheckHandlesMetrics,
+}
+
+/// What's wrong with the maintainer list itself.
+#[derive(Clone)]
+pub struct MaintainerMetrics {
+    pub nix_load_failure: IntCounter,
+    pub duplicate_github_id: IntGauge,
+    pub missing_github: IntGauge,
+    pub missing_github_id: IntGauge,
+    pub id_without_github: IntGauge,
+}
+
+/// The GitHub calls rfc39 makes.
+#[derive(Clone)]
+pub struct GitHubMetrics {
+    pub list_teams: GitHubCall,
+    pub get_team: GitHubCall,
+    pub get_team_members: GitHubCall,
+    pub get_invitations: GitHubCall,
+    pub get_user: GitHubCall,
+    pub add_user: GitHubCall,
+    pub remove_user: GitHubCall,
+    pub get_org_role: GitHubCall,
+    pub create_issue: GitHubCall,
+    /// User lookups answered without calling GitHub.
+    pub user_cache_hits: IntCounter,
+    pub retry: RetryMetrics,
+}
+
+/// What retrying GitHub calls reports.
+#[derive(Clone)]
+pub struct RetryMetrics {
+    pub retries: IntCounter,
+    pub rate_limit_remaining: IntGauge,
+}
+
+/// How long one kind of GitHub call takes, and how often it fails.
+#[derive(Clone)]
+pub struct GitHubCall {
+    pub duration: Histogram,
+    pub failures: IntCounter,
+    /// Shared by every kind of call.
+    pub all_calls: IntCounter,
+}
+
+#[derive(Clone)]
+pub struct SyncMetrics {
+    pub team_member_count: IntGauge,
+    pub invitation_count: IntGauge,
+    pub add_blocked_id_mismatch: IntGauge,
+    pub remove_blocked_id_mismatch: IntGauge,
+    pub invited_list_loaded: IntGauge,
+    pub invited_list_saved: IntGauge,
+    pub change_limit: IntGauge,
+    pub add_limit: IntGauge,
+    pub remove_limit: IntGauge,
+    pub noops: IntCounter,
+    pub additions: IntCounter,
+    pub removals: IntCounter,
+    pub pending_invitations: IntCounter,
+    pub previously_invited: IntCounter,
+    pub skipped: IntCounter,
+    pub protected: IntCounter,
+    pub owners_kept: IntCounter,
+    pub awaiting_grace: IntCounter,
+    pub errors: IntCounter,
+}
+
+#[derive(Clone)]
+pub struct BackfillMetrics {
+    pub inserted: IntGauge,
+    pub unplaced: IntGauge,
+    pub account_deleted: IntGauge,
+}
+
+#[derive(Clone)]
+pub struct DiffMetrics {
+    pub added: IntGauge,
+    pub removed: IntGauge,
+    pub changed: IntGauge,
+}
+
+#[derive(Clone)]
+pub struct CheckHandlesMetrics {
+    pub up_to_date: IntCounter,
+    pub changed_handle: IntCounter,
+    pub invalid_github: IntCounter,
+    pub id_mismatch: IntCounter,
+    pub lookup_failed: IntCounter,
+    pub account_deleted: IntCounter,
+}
+
+impl Metrics {
+    /// Register every metric in `registry`. Fails if any of them is
+    /// already registered there.
+    pub fn register(registry: &Registry, buckets: &Buckets) -> prometheus::Result<Metrics> {
+        let counter = |name: &str, help: &str| -> prometheus::Result<IntCounter> {
+            let counter = IntCounter::new(name, help)?;
+            registry.register(Box::new(counter.clone()))?;
+            Ok(counter)
+        };
+        let gauge = |name: &str, help: &str| -> prometheus::Result<IntGauge> {
+            let gauge = IntGauge::new(name, help)?;
+            registry.register(Box::new(gauge.clone()))?;
+            Ok(gauge)
+        };
+        let all_calls = counter(
+            "rfc39_github_call_count",
+            "Code-level calls to GitHub API methods (not a count of actual calls made to GitHub.)",
+        )?;
+        let call =
+            |name: &str, help: &str, failures_help: &str| -> prometheus::Result<GitHubCall> {
+                let duration = Histogram::with_opts(
+                    HistogramOpts::new(name, help).buckets(buckets.0.clone()),
+                )?;
+                registry.register(Box::new(duration.clone()))?;
+                Ok(GitHubCall {
+                    duration,
+                    failures: counter(&format!("{}_failures", name), failures_help)?,
+                    all_calls: all_calls.clone(),
+                })
+            };
+
+        let build_info = IntGaugeVec::new(
+            Opts::new(
+                "rfc39_build_info",
+                "The version and commit rfc39 was built from, always 1",
+            ),
+            &["version", "git_sha"],
+        )?;
+        registry.register(Box::new(build_info.clone()))?;
+
+        Ok(Metrics {
+            begin_seconds: gauge("rfc39_begin_seconds", "Execution started time")?,
+            stop_seconds: gauge("rfc39_stop_seconds", "Execution stopped time")?,
+            build_info,
+            op_success: counter(
+                "rfc39_op_suceess_counter",
+                "Execution completed without fault.",
+            )?,
+            last_success_seconds: gauge(
+                "rfc39_last_success_seconds",
+                "Time the last successful execution completed",
+            )?,
+            op_failure: counter("rfc39_op_failure_counter", "Execution failed")?,
+            op_panic: counter(
+                "rfc39_op_panic_counter",
+                "Execution of the operation panicked",
+            )?,
+            maintainers: MaintainerMetrics {
+                nix_load_failure: counter(
+                    "rfc39_maintainer_nix_load_failure",
+                    "Failures to load maintainers.nix",
+                )?,
+                duplicate_github_id: gauge(
+                    "rfc39_maintainer_duplicate_github_id",
+                    "GitHub IDs used by more than one maintainer handle",
+                )?,
+                missing_github: gauge(
+                    "rfc39_maintainer_missing_key_github",
+                    "Maintainers missing a github handle.",
+                )?,
+                missing_github_id: gauge(
+                    "rfc39_maintainer_missing_key_github_id",
+                    "Maintainers missing a github_id.",
+                )?,
+                id_without_github: gauge(
+                    "rfc39_maintainer_id_without_github",
+                    "Maintainers with a github_id but no github handle, who can't be added to the team.",
+                )?,
+            },
+            github: GitHubMetrics {
+                list_teams: call(
+                    "rfc39_github_list_teams",
+                    "Time to list the organization's teams",
+                    "Number of failed attempts to list the organization's teams",
+                )?,
+                get_team: call(
+                    "rfc39_github_get_team",
+                    "Time to fetch a team",
+                    "Number of failed attempts to get a team",
+                )?,
+                get_team_members: call(
+                    "rfc39_github_get_team_members",
+                    "Time to fetch team members",
+                    "Number of failed attempts to get a team's members",
+                )?,
+                get_invitations: call(
+                    "rfc39_github_get_invitations",
+                    "Time to fetch invitations",
+                    "Number of failed attempts to get a team's pending invitations",
+                )?,
+                get_user: call(
+                    "rfc39_github_get_user",
+                    "Time to fetch a GitHub user",
+                    "Number of failed attempts to get a user",
+                )?,
+                add_user: call(
+                    "rfc39_github_add_user",
+                    "Time to add a GitHub user to a team",
+                    "Number of failed attempts to add a user",
+                )?,
+                remove_user: call(
+                    "rfc39_github_remove_user",
+                    "Time to remove a GitHub user from a team",
+                    "Number of failed attempts to remove a user",
+                )?,
+                get_org_role: call(
+                    "rfc39_github_get_org_role",
+                    "Time to fetch a user's role in the organization",
+                    "Number of failed attempts to get a user's organization role",
+                )?,
+                create_issue: call(
+                    "rfc39_github_create_issue",
+                    "Time to open an issue",
+                    "Number of failed attempts to open an issue",
+                )?,
+                user_cache_hits: counter(
+                    "rfc39_github_user_cache_hits",
+                    "User lookups answered without calling GitHub.",
+                )?,
+                retry: RetryMetrics {
+                    retries: counter(
+                        "rfc39_github_retries",
+                        "GitHub calls retried after a transient failure",
+                    )?,
+                    rate_limit_remaining: gauge(
+                        "rfc39_github_rate_limit_remaining",
+                        "GitHub API calls left before the rate limit resets",
+                    )?,
+                },
+            },
+            sync: SyncMetrics {
+                team_member_count: gauge("rfc39_github_team_member_count", "Fetched team members")?,
+                invitation_count: gauge("rfc39_github_invitation_count", "Currently invited users")?,
+                add_blocked_id_mismatch: gauge(
+                    "rfc39_add_blocked_id_mismatch",
+                    "Number of maintainers not added because of out of date usernames, due to a mismatched ID",
+                )?,
+                remove_blocked_id_mismatch: gauge(
+                    "rfc39_remove_blocked_id_mismatch",
+                    "Number of team members not removed because of out of date usernames, due to a mismatched ID",
+                )?,
+                invited_list_loaded: gauge(
+                    "rfc39_invited_list_loaded",
+                    "Number of github ids loaded from the previously invited list",
+                )?,
+                invited_list_saved: gauge(
+                    "rfc39_invited_list_saved",
+                    "Number of github ids saved to the previously invited list",
+                )?,
+                change_limit: gauge(
+                    "rfc39_team_sync_change_limit",
+                    "Total number of additions and changed allowed in a single run",
+                )?,
+                add_limit: gauge(
+                    "rfc39_team_sync_add_limit",
+                    "Total number of additions allowed in a single run",
+                )?,
+                remove_limit: gauge(
+                    "rfc39_team_sync_remove_limit",
+                    "Total number of removals allowed in a single run",
+                )?,
+                noops: counter(
+                    "rfc39_team_sync_noops",
+                    "Total count of noop team sync actions",
+                )?,
+                additions: counter("rfc39_team_sync_additions", "Total team additions")?,
+                removals: counter("rfc39_team_sync_removals", "Total team removals")?,
+                pending_invitations: counter(
+                    "rfc39_team_sync_invite_pending",
+                    "Total pending team invitations",
+                )?,
+                previously_invited: counter(
+                    "rfc39_team_sync_previously_invited",
+                    "Total users not invited because we know we invited them already",
+                )?,
+                skipped: counter(
+                    "rfc39_team_sync_skipped",
+                    "Total team sync actions skipped by --no-add or --no-remove",
+                )?,
+                protected: counter(
+                    "rfc39_team_sync_protected",
+                    "Total team members not removed because they are protected",
+                )?,
+                owners_kept: counter(
+                    "rfc39_team_sync_owners_kept",
+                    "Total organization owners not removed without --remove-owners",
+                )?,
+                awaiting_grace: counter(
+                    "rfc39_team_sync_awaiting_grace",
+                    "Total team members not removed yet because they were notified recently",
+                )?,
+                errors: counter("rfc39_team_sync_errors", "Total team errors")?,
+            },
+            backfill: BackfillMetrics {
+                inserted: gauge(
+                    "rfc39_backfill_inserted",
+                    "GitHub IDs added to the maintainer file",
+                )?,
+                unplaced: gauge(
+                    "rfc39_backfill_unplaced",
+                    "GitHub IDs found but with no github line to add them after",
+                )?,
+                account_deleted: gauge(
+                    "rfc39_backfill_account_deleted",
+                    "Maintainers whose GitHub account no longer exists",
+                )?,
+            },
+            diff: DiffMetrics {
+                added: gauge("rfc39_diff_added", "Maintainer handles only in the new list")?,
+                removed: gauge("rfc39_diff_removed", "Maintainer handles only in the old list")?,
+                changed: gauge(
+                    "rfc39_diff_changed",
+                    "Maintainer handles in both lists with different information",
+                )?,
+            },
+            check_handles: CheckHandlesMetrics {
+                up_to_date: counter(
+                    "rfc39_check_handles_up_to_date",
+                    "Maintainers whose GitHub ID still belongs to their GitHub handle.",
+                )?,
+                changed_handle: counter(
+                    "rfc39_check_handles_changed_handle",
+                    "Maintainers whose GitHub ID now has a different login.",
+                )?,
+                invalid_github: counter(
+                    "rfc39_check_handles_invalid_github",
+                    "Maintainers whose GitHub handle isn't a valid GitHub username.",
+                )?,
+                id_mismatch: counter(
+                    "rfc39_check_handles_id_mismatch",
+                    "Maintainers whose GitHub handle and GitHub ID belong to different accounts.",
+                )?,
+                lookup_failed: counter(
+                    "rfc39_check_handles_lookup_failed",
+                    "Maintainers whose GitHub ID could not be found.",
+                )?,
+                account_deleted: counter(
+                    "rfc39_check_handles_account_deleted",
+                    "Maintainers whose GitHub ID and GitHub handle both no longer exist.",
+                )?,
+            },
+        })
+    }
+
+    /// Metrics in a registry of their own, which nothing reports.
+    pub fn unregistered() -> Metrics {
+        Metrics::register(&Registry::new(), &Buckets::default())
+            .expect("a new registry has no metrics to clash with")
+    }
+}
+
+/// Where the metrics server listens.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bind {
+    Tcp(SocketAddr),
+    /// A Unix domain socket, for deployments which may not open ports.
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for Bind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Bind::Tcp(addr) => write!(f, "{}", addr),
+            Bind::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Tells the metrics server the final values are in, and hears back
+/// once it has served them.
+#[derive(Clone)]
+pub struct FinalScrape {
+    ready: Arc<AtomicBool>,
+    scraped: Arc<Mutex<mpsc::Sender<()>>>,
+}
+
+impl FinalScrape {
+    pub fn new() -> (FinalScrape, mpsc::Receiver<()>) {
+        let (scraped, receiver) = mpsc::channel();
+        (
+            FinalScrape {
+                ready: Arc::new(AtomicBool::new(false)),
+                scraped: Arc::new(Mutex::new(scraped)),
+            },
+            receiver,
+        )
+    }
+
+    /// Scrapes from now on see the final values.
+    pub fn ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Serve the metrics until `shutdown` fires or its sender is dropped.
+/// With `final_scrape`, each scrape of the final values is reported.
+pub fn serve(bind: &Bind, shutdown: oneshot::Receiver<()>, final_scrape: Option<FinalScrape>) {
+    let new_service = move || {
+        let final_scrape = final_scrape.clone();
+        service_fn_ok(move |request: Request<Body>| {
+            respond(request.uri().path(), final_scrape.as_ref())
         })
-        .map_err(|e| eprintln!("Server error: {}", e));
-    hyper::rt::run(server);
+    };
+    let shutdown = shutdown.then(|_| Ok::<(), ()>(()));
+
+    match bind {
+        Bind::Tcp(addr) => {
+            let server = Server::bind(addr)
+                .serve(new_service)
+                .with_graceful_shutdown(shutdown)
+                .map_err(|e| eprintln!("Server error: {}", e));
+            hyper::rt::run(server);
+        }
+        Bind::Unix(path) => {
+            // A socket left behind by an earlier run would make the
+            // bind fail.
+            if std::fs::symlink_metadata(path)
+                .map(|metadata| metadata.file_type().is_socket())
+                .unwrap_or(false)
+            {
+                let _ = std::fs::remove_file(path);
+            }
+            let listener = match tokio::net::UnixListener::bind(path) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Server error: {}", e);
+                    return;
+                }
+            };
+            let server = Server::builder(listener.incoming())
+                .serve(new_service)
+                .with_graceful_shutdown(shutdown)
+                .map_err(|e| eprintln!("Server error: {}", e));
+            hyper::rt::run(server);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// `/metrics` for Prometheus, and a cheap `/healthz` for liveness
+/// probes. Metrics are always in the Prometheus text format, whatever
+/// the `Accept` header: the prometheus crate can't encode OpenMetrics,
+/// and scrapers which ask for it, like Prometheus itself, fall back to
+/// the text format.
+fn respond(path: &str, final_scrape: Option<&FinalScrape>) -> Response<Body> {
+    let (status, content_type, body) = match path {
+        "/metrics" => {
+            // Checked before encoding, so a scrape which started early
+            // doesn't count.
+            let ready = final_scrape.filter(|scrape| scrape.ready.load(Ordering::SeqCst));
+            let body = encode();
+            if let Some(scrape) = ready {
+                // Nobody is waiting any more once the sender is gone.
+                let _ = scrape.scraped.lock().unwrap().send(());
+            }
+            (
+                StatusCode::OK,
+                prometheus::TextEncoder::new().format_type().to_string(),
+                Body::from(body),
+            )
+        }
+        "/healthz" => (StatusCode::OK, "text/plain".to_string(), Body::from("ok\n")),
+        _ => (
+            StatusCode::NOT_FOUND,
+            "text/plain".to_string(),
+            Body::from("not found\n"),
+        ),
+    };
+
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(body)
+        .unwrap()
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    InvalidUrl(InvalidUri),
+    Http(hyper::Error),
+    Status(StatusCode),
+    Runtime(std::io::Error),
+}
+
+/// Replace the rfc39 job's metrics on a Prometheus Pushgateway with
+/// the current contents of the default registry.
+pub fn push(pushgateway: &str) -> Result<(), PushError> {
+    let uri = push_url(pushgateway).map_err(PushError::InvalidUrl)?;
+
+    let request = Request::builder()
+        .method(Method::PUT)
+        .uri(uri)
+        .header(CONTENT_TYPE, prometheus::TextEncoder::new().format_type())
+        .body(Body::from(encode()))
+        .unwrap();
+
+    let mut rt = tokio::runtime::Runtime::new().map_err(PushError::Runtime)?;
+    let response = rt
+        .block_on(Client::new().request(request))
+        .map_err(PushError::Http)?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(PushError::Status(response.status()))
+    }
+}
+
+/// Write the metrics for node_exporter's textfile collector. The file
+/// is replaced atomically, so the collector never reads a partial
+/// file.
+pub fn write_textfile(path: &Path) -> Result<(), std::io::Error> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(&encode())?;
+    file.persist(path)?;
+
+    Ok(())
+}
+
+/// The default registry's metrics in the Prometheus text format.
+pub fn encode() -> Vec<u8> {
+    let mut buffer = Vec::<u8>::new();
+    prometheus::TextEncoder::new()
+        .encode(&prometheus::default_registry().gather(), &mut buffer)
+        .unwrap();
+    buffer
+}
+
+fn push_url(pushgateway: &str) -> Result<Uri, InvalidUri> {
+    format!("{}/metrics/job/rfc39", pushgateway.trim_end_matches('/')).parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::Stream;
+
+    #[test]
+    fn test_push_url() {
+        assert_eq!(
+            push_url("http://localhost:9091/").unwrap(),
+            "http://localhost:9091/metrics/job/rfc39"
+        );
+        assert_eq!(
+            push_url("http://localhost:9091").unwrap(),
+            "http://localhost:9091/metrics/job/rfc39"
+        );
+        assert!(push_url("not a url").is_err());
+    }
+
+    #[test]
+    fn test_respond() {
+        let body = |response: Response<Body>| {
+            String::from_utf8(response.into_body().concat2().wait().unwrap().to_vec()).unwrap()
+        };
+
+        let healthz = respond("/healthz", None);
+        assert_eq!(healthz.status(), StatusCode::OK);
+        assert_eq!(body(healthz), "ok\n");
+
+        let metrics = respond("/metrics", None);
+        assert_eq!(metrics.status(), StatusCode::OK);
+        assert_eq!(
+            metrics.headers()[CONTENT_TYPE].to_str().unwrap(),
+            "text/plain; version=0.0.4"
+        );
+
+        assert_eq!(respond("/", None).status(), StatusCode::NOT_FOUND);
+        assert_eq!(respond("/metrics/x", None).status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_bind_display() {
+        assert_eq!(
+            Bind::Tcp("127.0.0.1:9000".parse().unwrap()).to_string(),
+            "127.0.0.1:9000"
+        );
+        assert_eq!(
+            Bind::Unix(PathBuf::from("/run/rfc39/metrics.sock")).to_string(),
+            "unix:/run/rfc39/metrics.sock"
+        );
+    }
+
+    #[test]
+    fn test_final_scrape() {
+        let (final_scrape, scraped) = FinalScrape::new();
+
+        respond("/metrics", Some(&final_scrape));
+        respond("/healthz", Some(&final_scrape));
+        assert!(scraped.try_recv().is_err());
+
+        final_scrape.ready();
+        respond("/healthz", Some(&final_scrape));
+        assert!(scraped.try_recv().is_err());
+        respond("/metrics", Some(&final_scrape));
+        assert!(scraped.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_parse_buckets() {
+        assert_eq!(
+            "0.1, 1,10".parse::<Buckets>(),
+            Ok(Buckets(vec![0.1, 1.0, 10.0]))
+        );
+        assert!("".parse::<Buckets>().is_err());
+        assert!("1,0.5".parse::<Buckets>().is_err());
+        assert!("1,1".parse::<Buckets>().is_err());
+        assert!("-1".parse::<Buckets>().is_err());
+        assert!("inf".parse::<Buckets>().is_err());
+    }
+
+    #[test]
+    fn test_histogram_buckets() {
+        let registry = Registry::new();
+        let metrics = Metrics::register(&registry, &"0.25,0.5".parse().unwrap()).unwrap();
+        metrics.github.get_user.duration.observe(0.3);
+
+        let mut buffer = vec![];
+        prometheus::TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+        let written = String::from_utf8(buffer).unwrap();
+        assert!(
+            written.contains(r#"rfc39_github_get_user_bucket{le="0.25"} 0"#),
+            "{}",
+            written
+        );
+        assert!(
+            written.contains(r#"rfc39_github_get_user_bucket{le="0.5"} 1"#),
+            "{}",
+            written
+        );
+    }
+
+    #[test]
+    fn test_register_once_per_registry() {
+        let registry = Registry::new();
+        assert!(Metrics::register(&registry, &Buckets::default()).is_ok());
+        assert!(Metrics::register(&registry, &Buckets::default()).is_err());
+        Metrics::unregistered();
+        Metrics::unregistered();
+    }
+
+    #[test]
+    fn test_write_textfile() {
+        let counter =
+            register_int_counter!("rfc39_test_textfile", "Written to the textfile").unwrap();
+        counter.inc();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rfc39.prom");
+        write_textfile(&path).unwrap();
+        write_textfile(&path).unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.contains("rfc39_test_textfile 1"), "{}", written);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
 }
@@ -1,46 +1,142 @@
 use std::ffi::OsStr;
-use std::path::Path;
-use std::process::Command;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
 
-pub fn nix_instantiate_file_to_struct<T>(
-    logger: slog::Logger,
-    file: &Path,
-) -> Result<T, serde_json::error::Error>
+/// Check that `nix-instantiate` can be started at all, so ops which
+/// need Nix fail up front instead of partway through.
+pub fn probe<S>(nix_instantiate: S) -> Result<(), io::Error>
 where
-    T: serde::de::DeserializeOwned,
+    S: AsRef<OsStr>,
 {
-    let output = Command::new("nix-instantiate")
-        .args(&["--eval", "--strict", "--json"])
-        .arg(file)
-        .output()
-        .expect("Failed to start nix-instantiate!");
+    let output = Command::new(nix_instantiate).arg("--version").output()?;
 
-    if !output.stderr.is_empty() {
-        warn!(logger, "Stderr from nix-instantiate";
-              "stderr" => String::from_utf8_lossy(&output.stderr).to_string()
-        );
-        // "stderr" => stderr);
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "nix-instantiate --version failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ))
     }
+}
 
-    if !output.status.success() {
-        panic!(
-            "Nix failed! {:#?}",
-            String::from_utf8_lossy(&output.stderr).to_string()
-        );
+/// Turn a dotted attribute path like `lib.maintainers` into Nix
+/// source, quoting each part so it can't be anything but an attribute.
+pub fn attr_path(attr: &str) -> String {
+    attr.split('.')
+        .map(|part| {
+            format!(
+                "\"{}\"",
+                part.replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace("${", "\\${")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[derive(Debug)]
+pub enum NixError {
+    /// nix-instantiate could not be started at all.
+    SpawnFailed(io::Error),
+    /// nix-instantiate ran, but evaluation of `source` (a file path or
+    /// an expression) failed. `stderr` is Nix's full error output.
+    EvalFailed {
+        source: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// Evaluation succeeded, but produced JSON of the wrong shape.
+    Deserialize(serde_json::error::Error),
+}
+
+impl std::fmt::Display for NixError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NixError::SpawnFailed(e) => write!(f, "failed to start nix-instantiate: {}", e),
+            NixError::EvalFailed {
+                source,
+                status,
+                stderr,
+            } => write!(
+                f,
+                "nix-instantiate failed to evaluate {} ({}): {}",
+                source, status, stderr
+            ),
+            NixError::Deserialize(e) => write!(f, "unexpected nix-instantiate output: {}", e),
+        }
     }
+}
 
-    serde_json::from_slice(&output.stdout)
+/// How to run nix-instantiate: which binary, with what NIX_PATH, and
+/// with which extra `--option`s.
+#[derive(Debug, Clone)]
+pub struct NixInvocation {
+    pub nix_instantiate: PathBuf,
+    /// Set NIX_PATH to this instead of inheriting it.
+    pub nix_path: Option<String>,
+    /// Passed as `--option key value`.
+    pub options: Vec<(String, String)>,
+}
+
+impl Default for NixInvocation {
+    fn default() -> NixInvocation {
+        NixInvocation {
+            nix_instantiate: PathBuf::from("nix-instantiate"),
+            nix_path: None,
+            options: vec![],
+        }
+    }
+}
+
+impl NixInvocation {
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.nix_instantiate);
+
+        if let Some(ref nix_path) = self.nix_path {
+            cmd.env("NIX_PATH", nix_path);
+        }
+
+        for (key, value) in &self.options {
+            cmd.arg("--option");
+            cmd.arg(key);
+            cmd.arg(value);
+        }
+
+        cmd
+    }
+}
+
+pub fn nix_instantiate_file_to_struct<T>(
+    logger: slog::Logger,
+    nix_invocation: &NixInvocation,
+    file: &Path,
+) -> Result<T, NixError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let mut cmd = nix_invocation.command();
+    cmd.args(&["--eval", "--strict", "--json"]);
+    cmd.arg(file);
+
+    run_to_struct(logger, cmd, file.display().to_string())
 }
 
 pub fn nix_instantiate_expr_args_to_struct<T>(
     logger: slog::Logger,
+    nix_invocation: &NixInvocation,
     expr: &str,
     args: Vec<(&str, &OsStr)>,
-) -> Result<T, serde_json::error::Error>
+) -> Result<T, NixError>
 where
     T: serde::de::DeserializeOwned,
 {
-    let mut cmd = Command::new("nix-instantiate");
+    let mut cmd = nix_invocation.command();
     cmd.args(&["--eval", "--strict", "--json", "--expr"]);
     cmd.arg(expr);
 
@@ -50,21 +146,89 @@ where
         cmd.arg(val);
     }
 
-    let output = cmd.output().expect("Failed to start nix-instantiate!");
+    run_to_struct(logger, cmd, expr.to_string())
+}
+
+fn run_to_struct<T>(logger: slog::Logger, mut cmd: Command, source: String) -> Result<T, NixError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let output = cmd.output().map_err(NixError::SpawnFailed)?;
 
     if !output.stderr.is_empty() {
         warn!(logger, "Stderr from nix-instantiate";
               "stderr" => String::from_utf8_lossy(&output.stderr).to_string()
         );
-        // "stderr" => stderr);
     }
 
     if !output.status.success() {
-        panic!(
-            "Nix failed! {:#?}",
-            String::from_utf8_lossy(&output.stderr).to_string()
-        );
+        return Err(NixError::EvalFailed {
+            source,
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        });
     }
 
-    serde_json::from_slice(&output.stdout)
+    serde_json::from_slice(&output.stdout).map_err(NixError::Deserialize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_missing_binary() {
+        assert!(probe("/nonexistent/bin/nix-instantiate").is_err());
+    }
+
+    #[test]
+    fn test_attr_path() {
+        assert_eq!(attr_path("lib.maintainers"), r#""lib"."maintainers""#);
+        assert_eq!(attr_path(r#"a"b.${c}"#), r#""a\"b"."\${c}""#);
+    }
+
+    #[test]
+    fn test_invocation_options_and_nix_path() {
+        let nix_invocation = NixInvocation {
+            nix_path: Some("nixpkgs=/nonexistent/nixpkgs".into()),
+            options: vec![("show-trace".into(), "true".into())],
+            ..NixInvocation::default()
+        };
+
+        let found: String = nix_instantiate_expr_args_to_struct(
+            crate::test_logger(),
+            &nix_invocation,
+            "builtins.getEnv \"NIX_PATH\"",
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(found, "nixpkgs=/nonexistent/nixpkgs");
+
+        let cmd = format!("{:?}", nix_invocation.command());
+        assert!(cmd.contains(r#""--option" "show-trace" "true""#), "{}", cmd);
+    }
+
+    #[test]
+    fn test_eval_failure_is_an_error() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let file = tmpdir.path().join("broken.nix");
+        std::fs::write(&file, "{ this is not nix").unwrap();
+
+        match nix_instantiate_file_to_struct::<serde_json::Value>(
+            crate::test_logger(),
+            &NixInvocation::default(),
+            &file,
+        ) {
+            Err(NixError::EvalFailed {
+                source,
+                status,
+                stderr,
+            }) => {
+                assert_eq!(source, file.display().to_string());
+                assert!(!status.success());
+                assert!(stderr.contains("error:"), "stderr: {}", stderr);
+            }
+            other => panic!("expected an evaluation failure, got {:?}", other),
+        }
+    }
 }
@@ -3,11 +3,18 @@
 
 #![warn(missing_docs)]
 
-use crate::cli::ExitError;
+use crate::cli::{BackfillFormat, ExitError};
+use crate::credentials::Secret;
 use crate::filemunge;
-use crate::maintainerhistory::{Confidence, MaintainerHistory};
-use crate::maintainers::{GitHubID, GitHubName, MaintainerList};
-use hubcaps::Github;
+use crate::github::GitHubApi;
+use crate::graphql;
+use crate::maintainerhistory::{Confidence, HistorySource, MaintainerHistory};
+use crate::maintainers::{GitHubID, GitHubName, Handle, Information, MaintainerList};
+use crate::metrics::Metrics;
+use crate::nix::{self, NixInvocation};
+use crate::user_resolver::UserResolver;
+use futures::future::Future;
+use futures::stream::{self, Stream};
 use std::collections::HashMap;
 use std::fs::read_to_string;
 use std::path::Path;
@@ -15,13 +22,20 @@ use tokio::runtime::Runtime;
 
 pub fn backfill_ids(
     logger: slog::Logger,
-    github: Github,
+    github: &impl GitHubApi,
+    rt: &mut Runtime,
+    metrics: &Metrics,
+    users: &mut UserResolver,
+    nix_invocation: &NixInvocation,
     file: &Path,
     maintainers: MaintainerList,
+    history_source: HistorySource,
+    in_place: bool,
+    format: BackfillFormat,
+    concurrency: usize,
+    graphql_token: Option<&Secret<String>>,
 ) -> Result<(), ExitError> {
-    let mut rt = Runtime::new().unwrap();
-
-    let missing_ids = maintainers
+    let missing_ids: Vec<_> = maintainers
         .into_iter()
         .filter(|(_handle, maintainer)| {
             maintainer.github.is_some() && maintainer.github_id.is_none()
@@ -35,58 +49,512 @@ pub fn backfill_ids(
                 maintainer,
                 handle,
             )
-        });
+        })
+        .collect();
 
     info!(logger, "Loading the maintainer list's GitHub accounts and blame history";
           "commit" => "");
 
-    let history = MaintainerHistory::load(logger.clone(), file);
+    let history =
+        MaintainerHistory::load(logger.clone(), rt, nix_invocation, file, history_source)?;
 
     info!(logger, "Loaded the maintainer list's GitHub accounts and blame history";
           "commit" => "");
 
-    let found_ids: HashMap<GitHubName, GitHubID> = missing_ids
-        .filter_map(|(github_name, maintainer, handle)| {
-            debug!(logger, "Getting ID for user";
+    let names: Vec<GitHubName> = missing_ids
+        .iter()
+        .map(|(github_name, _maintainer, _handle)| github_name.clone())
+        .collect();
+    let graphql_ids = graphql_token.and_then(|token| {
+        graphql::ids_by_login(&logger, rt, token, &names)
+            .map_err(|e| {
+                warn!(logger, "Looking up IDs with GraphQL failed, falling back to REST";
+                      "error" => ?e,
+                );
+            })
+            .ok()
+    });
+    if graphql_ids.is_none() {
+        users.prefetch(rt, names, concurrency);
+    }
+
+    let mut deleted: i64 = 0;
+    let confidence_checks: Vec<_> = missing_ids
+        .into_iter()
+        .filter_map(|(github_name, _maintainer, handle)| {
+            let github_id = match graphql_ids {
+                Some(ref ids) => ids.get(&github_name).cloned(),
+                None => users.get(rt, &github_name).map(|user| user.id),
+            };
+            let github_id = match github_id {
+                Some(github_id) => github_id,
+                None => {
+                    // GraphQL leaves out exactly the logins GitHub
+                    // doesn't know.
+                    if graphql_ids.is_some() || users.is_missing(&github_name) {
+                        warn!(logger, "GitHub account no longer exists";
+                              "github_account" => %github_name,
+                              "user" => %handle);
+                        deleted += 1;
+                    }
+                    return None;
+                }
+            };
+            debug!(logger, "Found ID for user";
                   "github_account" => %github_name,
-            );
-
-            match rt.block_on(github.users().get(github_name.to_string())) {
-                Ok(user) => {
-                    debug!(logger, "Found ID for user";
-                          "github_account" => %github_name,
-                          "id" => %user.id);
-                    Some((github_name, maintainer, GitHubID::new(user.id), handle))
+                  "id" => %github_id);
+
+            let logger = logger.clone();
+            Some(
+                history
+                    .confidence_for_user(github, &handle, &github_name, github_id)
+                    .map(move |confidence| match confidence {
+                        Some(Confidence::Total) => Some((github_name, github_id)),
+                        Some(confidence) => {
+                            info!(logger,
+                                  "Non-total confidence for user";
+                                  "confidence" => %format!("{:#?}", confidence),
+                                  "user" => %handle,
+                            );
+                            None
+                        }
+                        None => None,
+                    }),
+            )
+        })
+        .collect();
+
+    // Results are keyed by name, so the order they finish in doesn't
+    // matter.
+    let found_ids: HashMap<GitHubName, GitHubID> = rt
+        .block_on(
+            stream::iter_ok(confidence_checks)
+                .buffer_unordered(concurrency.max(1))
+                .filter_map(|found| found)
+                .collect(),
+        )
+        .expect("confidence checks report their own failures")
+        .into_iter()
+        .collect();
+
+    metrics.backfill.account_deleted.set(deleted);
+
+    let requested = found_ids.len();
+    let original = read_to_string(file)?;
+    let backfilled = filemunge::backfill_file(found_ids.clone(), original.clone());
+    metrics.backfill.inserted.set(backfilled.inserted as i64);
+    metrics
+        .backfill
+        .unplaced
+        .set(backfilled.unplaced.len() as i64);
+
+    info!(
+        logger,
+        "Filled {} of {} GitHub IDs", backfilled.inserted, requested
+    );
+    for name in &backfilled.unplaced {
+        warn!(logger, "Could not find the github line to add the ID after";
+              "github_account" => %name,
+        );
+    }
+
+    if backfilled.file != original {
+        verify_backfill(&logger, nix_invocation, file, &backfilled.file, &found_ids)?;
+    }
+
+    if in_place {
+        info!(logger, "Writing the backfilled maintainer file";
+              "file" => %file.display(),
+        );
+        filemunge::write_in_place(file, &backfilled.file)?;
+    }
+
+    match format {
+        BackfillFormat::File if !in_place => println!("{}", backfilled.file),
+        BackfillFormat::File => {}
+        BackfillFormat::Diff => print!("{}", unified_diff(file, &original, &backfilled.file)),
+    }
+
+    Ok(())
+}
+
+/// Evaluate the backfilled file and make sure the only change from
+/// the original is the IDs that were meant to be added.
+fn verify_backfill(
+    logger: &slog::Logger,
+    nix_invocation: &NixInvocation,
+    file: &Path,
+    backfilled: &str,
+    ids: &HashMap<GitHubName, GitHubID>,
+) -> Result<(), ExitError> {
+    let tmpdir = tempfile::tempdir()?;
+    let backfilled_file = tmpdir.path().join("maintainers.nix");
+    std::fs::write(&backfilled_file, backfilled)?;
+
+    let evaluate = |path: &Path| -> Result<HashMap<Handle, Information>, nix::NixError> {
+        nix::nix_instantiate_expr_args_to_struct(
+            logger.clone(),
+            nix_invocation,
+            "{ file }: import file",
+            vec![("file", path.as_os_str())],
+        )
+    };
+    let before = evaluate(file)?;
+    let after = evaluate(&backfilled_file).map_err(|e| {
+        error!(logger, "The backfilled maintainer file doesn't evaluate";
+               "error" => ?e,
+        );
+        e
+    })?;
+
+    let changed = unexpected_changes(&before, &after, ids);
+    if changed.is_empty() {
+        debug!(logger, "The backfilled maintainer file only gained IDs");
+        return Ok(());
+    }
+
+    for handle in &changed {
+        error!(logger, "Backfilling changed more than the GitHub ID";
+               "user" => %handle,
+        );
+    }
+    Err(ExitError::BackfillChanged(changed))
+}
+
+/// The maintainers which aren't the same after backfilling, other
+/// than having gained the ID in `ids` for their GitHub name.
+fn unexpected_changes(
+    before: &HashMap<Handle, Information>,
+    after: &HashMap<Handle, Information>,
+    ids: &HashMap<GitHubName, GitHubID>,
+) -> Vec<Handle> {
+    let mut changed: Vec<Handle> = before
+        .keys()
+        .chain(after.keys().filter(|handle| !before.contains_key(handle)))
+        .filter(|handle| match (before.get(handle), after.get(handle)) {
+            (Some(before), Some(after)) => {
+                let id_added = before.github_id.is_none()
+                    && after.github_id.is_some()
+                    && before.github.as_ref().and_then(|name| ids.get(name))
+                        == after.github_id.as_ref();
+                let id_kept = before.github_id == after.github_id;
+
+                !(id_added || id_kept)
+                    || before.email != after.email
+                    || before.name != after.name
+                    || before.github != after.github
+                    || before.matrix != after.matrix
+                    || before.keys != after.keys
+            }
+            _ => true,
+        })
+        .cloned()
+        .collect();
+    changed.sort();
+    changed
+}
+
+/// Lines of context around each change.
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LineOp {
+    Equal,
+    Delete,
+    Insert,
+}
+
+fn unified_diff(file: &Path, old: &str, new: &str) -> String {
+    let name = file.display();
+    let old = lines_with_endings(old);
+    let new = lines_with_endings(new);
+    let ops = diff_lines(&old, &new);
+
+    let changes: Vec<usize> = (0..ops.len())
+        .filter(|&i| ops[i] != LineOp::Equal)
+        .collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+
+    // Changes closer together than twice the context share a hunk.
+    let mut hunks = vec![];
+    let mut first = changes[0];
+    let mut last = changes[0];
+    for &change in &changes[1..] {
+        if change - last - 1 > 2 * CONTEXT {
+            hunks.push((first, last));
+            first = change;
+        }
+        last = change;
+    }
+    hunks.push((first, last));
+
+    let mut out = format!("--- a/{}\n+++ b/{}\n", name, name);
+    for (first, last) in hunks {
+        let start = first.saturating_sub(CONTEXT);
+        let end = (last + CONTEXT + 1).min(ops.len());
+        let old_before = ops[..start]
+            .iter()
+            .filter(|&&op| op != LineOp::Insert)
+            .count();
+        let new_before = ops[..start]
+            .iter()
+            .filter(|&&op| op != LineOp::Delete)
+            .count();
+        let old_len = ops[start..end]
+            .iter()
+            .filter(|&&op| op != LineOp::Insert)
+            .count();
+        let new_len = ops[start..end]
+            .iter()
+            .filter(|&&op| op != LineOp::Delete)
+            .count();
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(old_before, old_len),
+            hunk_range(new_before, new_len)
+        ));
+
+        let (mut o, mut n) = (old_before, new_before);
+        for &op in &ops[start..end] {
+            let (prefix, line) = match op {
+                LineOp::Equal => {
+                    o += 1;
+                    n += 1;
+                    (' ', old[o - 1])
                 }
-                Err(e) => {
-                    warn!(logger, "Error fetching ID for user";
-                          "github_account" => %github_name,
-                          "e" => %e);
-                    None
+                LineOp::Delete => {
+                    o += 1;
+                    ('-', old[o - 1])
                 }
+                LineOp::Insert => {
+                    n += 1;
+                    ('+', new[n - 1])
+                }
+            };
+            out.push(prefix);
+            out.push_str(line);
+            if !line.ends_with('\n') {
+                out.push_str("\n\\ No newline at end of file\n");
             }
-        })
-        .filter_map(|(github_name, _maintainer, github_id, handle)| {
-            let confidence =
-                history.confidence_for_user(&github, &handle, &github_name, github_id)?;
+        }
+    }
+    out
+}
+
+/// The range a hunk covers, as `diff -u` writes it.
+fn hunk_range(before: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", before),
+        1 => format!("{}", before + 1),
+        _ => format!("{},{}", before + 1, len),
+    }
+}
 
-            if confidence == Confidence::Total {
-                Some((github_name, github_id))
+/// Each line, with its line ending, so a changed ending shows up as a
+/// changed line.
+fn lines_with_endings(text: &str) -> Vec<&str> {
+    let mut lines = vec![];
+    let mut start = 0;
+    for (end, _) in text.match_indices('\n') {
+        lines.push(&text[start..=end]);
+        start = end + 1;
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+    lines
+}
+
+/// The shortest edit turning `old` into `new`, with Myers' algorithm.
+/// Backfilling only adds a few lines to a long file, so few edits are
+/// ever needed.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<LineOp> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let offset = n + m + 1;
+    let mut v = vec![0isize; 2 * offset as usize + 1];
+    // The furthest x reached on each diagonal -d..=d after each step d.
+    let mut trace: Vec<Vec<isize>> = vec![];
+
+    'search: for d in 0..=(n + m) {
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d
+                || (k != d && v[(offset + k - 1) as usize] < v[(offset + k + 1) as usize])
+            {
+                v[(offset + k + 1) as usize]
             } else {
-                info!(logger,
-                      "Non-total confidence for user";
-                      "confidence" => %format!("{:#?}", confidence),
-                      "user" => %handle,
-                );
-                None
+                v[(offset + k - 1) as usize] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
             }
-        })
+            v[(offset + k) as usize] = x;
+
+            if x >= n && y >= m {
+                trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+                break 'search;
+            }
+        }
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+    }
+
+    let mut ops = vec![];
+    let (mut x, mut y) = (n, m);
+    for d in (1..trace.len() as isize).rev() {
+        let prev = &trace[d as usize - 1];
+        let furthest = |k: isize| prev[(k + d - 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && furthest(k - 1) < furthest(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = furthest(prev_k);
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            ops.push(LineOp::Equal);
+            x -= 1;
+            y -= 1;
+        }
+        ops.push(if x == prev_x {
+            LineOp::Insert
+        } else {
+            LineOp::Delete
+        });
+        x = prev_x;
+        y = prev_y;
+    }
+    for _ in 0..x {
+        ops.push(LineOp::Equal);
+    }
+    ops.reverse();
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn maintainer(github: &str, github_id: Option<u64>) -> Information {
+        Information {
+            email: None,
+            name: None,
+            github: Some(GitHubName::new(github)),
+            github_id: github_id.map(GitHubID::new),
+            matrix: None,
+            keys: None,
+        }
+    }
+
+    #[test]
+    fn test_unexpected_changes() {
+        let ids: HashMap<GitHubName, GitHubID> = vec![
+            (GitHubName::new("alice"), GitHubID::new(1)),
+            (GitHubName::new("bob"), GitHubID::new(2)),
+        ]
+        .into_iter()
+        .collect();
+        let before: HashMap<Handle, Information> = vec![
+            (Handle::new("alice"), maintainer("alice", None)),
+            (Handle::new("bob"), maintainer("bob", None)),
+            (Handle::new("carol"), maintainer("carol", Some(3))),
+            (Handle::new("dave"), maintainer("dave", None)),
+        ]
+        .into_iter()
         .collect();
 
-    println!(
-        "{}",
-        filemunge::backfill_file(found_ids, read_to_string(file)?,)
-    );
+        let backfilled = || -> HashMap<Handle, Information> {
+            vec![
+                (Handle::new("alice"), maintainer("alice", Some(1))),
+                (Handle::new("bob"), maintainer("bob", None)),
+                (Handle::new("carol"), maintainer("carol", Some(3))),
+                (Handle::new("dave"), maintainer("dave", None)),
+            ]
+            .into_iter()
+            .collect()
+        };
+        assert!(unexpected_changes(&before, &backfilled(), &ids).is_empty());
 
-    Ok(())
+        let mut after = backfilled();
+        // bob got someone else's ID, carol lost their entry, dave's name
+        // changed and erin appeared from nowhere
+        after.insert(Handle::new("bob"), maintainer("bob", Some(1)));
+        after.remove(&Handle::new("carol"));
+        after.insert(Handle::new("dave"), maintainer("dave2", None));
+        after.insert(Handle::new("erin"), maintainer("erin", None));
+        assert_eq!(
+            unexpected_changes(&before, &after, &ids),
+            vec![
+                Handle::new("bob"),
+                Handle::new("carol"),
+                Handle::new("dave"),
+                Handle::new("erin"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let old = "  alice = {\n    github = \"alice\";\n  };\n";
+        let new = "  alice = {\n    github = \"alice\";\n    githubId = 1;\n  };\n";
+
+        assert_eq!(
+            unified_diff(Path::new("maintainer-list.nix"), old, new),
+            "--- a/maintainer-list.nix
++++ b/maintainer-list.nix
+@@ -1,3 +1,4 @@
+   alice = {
+     github = \"alice\";
++    githubId = 1;
+   };
+"
+        );
+    }
+
+    #[test]
+    fn test_unified_diff_hunks() {
+        let old: String = (1..=20).map(|n| format!("{}\n", n)).collect();
+        let new = (1..=20)
+            .map(|n| match n {
+                2 => "2\ntwo\n".to_string(),
+                18 => "eighteen\n".to_string(),
+                _ => format!("{}\n", n),
+            })
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        assert_eq!(
+            unified_diff(Path::new("f"), &old, &new),
+            "--- a/f
++++ b/f
+@@ -1,5 +1,6 @@
+ 1
+ 2
++two
+ 3
+ 4
+ 5
+@@ -15,6 +16,6 @@
+ 15
+ 16
+ 17
+-18
++eighteen
+ 19
+-20
++20
+\\ No newline at end of file
+"
+        );
+        assert_eq!(unified_diff(Path::new("f"), &old, &old), "");
+        assert_eq!(
+            unified_diff(Path::new("f"), "", "a\n"),
+            "--- a/f\n+++ b/f\n@@ -0,0 +1 @@\n+a\n"
+        );
+    }
 }
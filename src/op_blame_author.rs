@@ -1,27 +1,148 @@
 use crate::cli::ExitError;
-use crate::maintainerhistory::MaintainerHistory;
-use crate::maintainers::MaintainerList;
-use hubcaps::Github;
+use crate::github::GitHubApi;
+use crate::maintainerhistory::{Confidence, HistorySource, MaintainerHistory};
+use crate::maintainers::{GitHubID, GitHubName, Handle, MaintainerList};
+use crate::nix::NixInvocation;
+use futures::future::Future;
+use futures::stream::{self, Stream};
+use std::fs::File;
+use std::io::Write;
 use std::path::Path;
+use tokio::runtime::Runtime;
 
 pub fn report(
     logger: slog::Logger,
-    github: Github,
+    github: &impl GitHubApi,
+    rt: &mut Runtime,
+    nix_invocation: &NixInvocation,
     maintainer_file: &Path,
     maintainers: MaintainerList,
+    csv_file: Option<&Path>,
+    history_source: HistorySource,
+    concurrency: usize,
 ) -> Result<(), ExitError> {
     info!(logger, "Verifying our maintainer list GitHub accounts match the author of the commit which added the maintainer entry";
           "commit" => "");
 
-    let history = MaintainerHistory::load(logger.clone(), maintainer_file);
+    let history = MaintainerHistory::load(
+        logger.clone(),
+        rt,
+        nix_invocation,
+        maintainer_file,
+        history_source,
+    )?;
 
+    let mut checks = vec![];
     for (user, information) in maintainers {
         if let Some(github_name) = information.github {
             if let Some(github_id) = information.github_id {
-                history.confidence_for_user(&github, &user, &github_name, github_id);
+                let commit = history.commit_for_user(&user).map(String::from);
+                checks.push(
+                    history
+                        .confidence_for_user(github, &user, &github_name, github_id)
+                        .map(move |confidence| AttributionRow {
+                            handle: user,
+                            github_name,
+                            github_id,
+                            commit,
+                            confidence,
+                        }),
+                );
             }
         }
     }
 
+    let mut rows: Vec<AttributionRow> = rt
+        .block_on(
+            stream::iter_ok(checks)
+                .buffer_unordered(concurrency.max(1))
+                .collect(),
+        )
+        .expect("confidence checks report their own failures");
+    // Checks finish in any order.
+    rows.sort_by(|a, b| a.handle.cmp(&b.handle));
+
+    if let Some(csv_file) = csv_file {
+        info!(logger, "Writing the attribution report";
+              "file" => %csv_file.display(),
+        );
+
+        let mut file = File::create(csv_file)?;
+        write_csv(&mut file, &rows)?;
+    }
+
     Ok(())
 }
+
+struct AttributionRow {
+    handle: Handle,
+    github_name: GitHubName,
+    github_id: GitHubID,
+    commit: Option<String>,
+    confidence: Option<Confidence>,
+}
+
+fn write_csv<W: Write>(out: &mut W, rows: &[AttributionRow]) -> Result<(), std::io::Error> {
+    writeln!(out, "handle,github,github_id,commit,confidence")?;
+
+    for row in rows {
+        writeln!(
+            out,
+            "{},{},{},{},{}",
+            csv_field(&row.handle.to_string()),
+            csv_field(&row.github_name.to_string()),
+            row.github_id,
+            csv_field(row.commit.as_deref().unwrap_or("")),
+            row.confidence
+                .as_ref()
+                .map(|confidence| format!("{:?}", confidence))
+                .unwrap_or_default(),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_csv() {
+        let rows = vec![
+            AttributionRow {
+                handle: Handle::new("alice"),
+                github_name: GitHubName::new("alice"),
+                github_id: GitHubID::new(1),
+                commit: Some("5bd136acd4c683b30470b5dfbb6f0b15dcea42a5".into()),
+                confidence: Some(Confidence::Total),
+            },
+            AttributionRow {
+                handle: Handle::new("bob,jr"),
+                github_name: GitHubName::new("bob"),
+                github_id: GitHubID::new(2),
+                commit: None,
+                confidence: None,
+            },
+        ];
+
+        let mut out = Vec::new();
+        write_csv(&mut out, &rows).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "handle,github,github_id,commit,confidence
+alice,alice,1,5bd136acd4c683b30470b5dfbb6f0b15dcea42a5,Total
+\"bob,jr\",bob,2,,
+"
+        );
+    }
+}
@@ -1,34 +1,692 @@
-//! Somewhat half-hearted attempt at checking all the handles and IDs,
-//! but it doesn't really work right now.
+//! Check every maintainer's GitHub handle and ID, and that the two
+//! still belong together.
 
 use crate::cli::ExitError;
-use crate::maintainers::MaintainerList;
+use crate::maintainers::{GitHubID, GitHubName, Handle, MaintainerList};
+use crate::metrics::Metrics;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
 
-pub fn check_handles(logger: slog::Logger, maintainers: MaintainerList) -> Result<(), ExitError> {
+lazy_static! {
+    /// The top level `handle = {` lines of the maintainer list.
+    static ref ENTRY_RE: Regex = Regex::new(r#"^  "?(?P<handle>[^"\s=]+)"? = \{"#).unwrap();
+}
+
+/// One maintainer's entry in the `--report` file.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReportRow {
+    handle: Handle,
+    github: Option<GitHubName>,
+    github_id: Option<GitHubID>,
+    /// The login the GitHub ID has now, if it changed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_github: Option<GitHubName>,
+    /// The ID of the account with the recorded login, if it isn't
+    /// the recorded ID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    github_id_of_github: Option<GitHubID>,
+    status: Status,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Ok,
+    MissingId,
+    MissingGithub,
+    IdWithoutGithub,
+    HandleChanged,
+    /// The handle and the ID are two different, existing accounts.
+    IdMismatch,
+    /// GitHub doesn't know the ID, but the handle is still an
+    /// account, or looking it up failed.
+    LookupFailed,
+    /// Neither the ID nor the handle is an account any more.
+    AccountDeleted,
+}
+
+#[derive(Debug, PartialEq)]
+enum IdStatus {
+    UpToDate,
+    HandleChanged(GitHubName),
+    /// The ID's account has this login now, and the recorded login
+    /// belongs to the account with this other ID.
+    IdMismatch(GitHubName, GitHubID),
+    LookupFailed,
+    AccountDeleted,
+}
+
+/// `current_logins` finds the login each GitHub ID has now, leaving
+/// out the ones it can't find, and `current_ids` the ID each login has
+/// now, `None` for logins with no account, leaving out the ones whose
+/// lookup failed. With `annotate`, every problem is also printed as a
+/// GitHub Actions workflow command pointing into that maintainer file.
+pub fn check_handles<F, G>(
+    logger: slog::Logger,
+    metrics: &Metrics,
+    maintainers: MaintainerList,
+    current_logins: F,
+    current_ids: G,
+    require_id_coverage: Option<f64>,
+    check_matrix: bool,
+    report: Option<&Path>,
+    annotate: Option<&Path>,
+) -> Result<(), ExitError>
+where
+    F: FnOnce(&[GitHubID]) -> Result<HashMap<GitHubID, GitHubName>, ExitError>,
+    G: FnOnce(&[GitHubName]) -> Result<HashMap<GitHubName, Option<GitHubID>>, ExitError>,
+{
+    let coverage = id_coverage(&maintainers);
+    info!(logger, "GitHub ID coverage";
+          "percent" => coverage,
+    );
+
+    let ids: Vec<GitHubID> = maintainers
+        .iter()
+        .filter(|(_, info)| info.github.is_some())
+        .filter_map(|(_, info)| info.github_id)
+        .collect();
+    let logins = current_logins(&ids)?;
+
+    // A login which no longer matches its ID was either renamed, or
+    // was never the ID's account: tell them apart by what the login
+    // itself belongs to. An ID which is gone was deleted along with
+    // its account only if the login is gone too.
+    let mismatched: Vec<GitHubName> = maintainers
+        .iter()
+        .filter_map(|(_, info)| match (&info.github, info.github_id) {
+            (Some(name), Some(id)) if name.is_valid() => match logins.get(&id) {
+                Some(login) if login == name => None,
+                _ => Some(name.clone()),
+            },
+            _ => None,
+        })
+        .collect();
+    let login_ids = if mismatched.is_empty() {
+        HashMap::new()
+    } else {
+        current_ids(&mismatched)?
+    };
+
+    let mut rows = vec![];
     for (handle, info) in maintainers {
-        match (info.github, info.github_id) {
-            (Some(name), Some(id)) => {
-                info!(logger, "todo: check if ID is up to date";
-                      "github_account" => %name,
-                      "github_id" => %id,
+        if check_matrix && info.matrix.is_none() {
+            warn!(logger, "Missing Matrix handle";
+                  "who" => %handle);
+        }
+
+        if let Some(ref name) = info.github {
+            if !name.is_valid() {
+                metrics.check_handles.invalid_github.inc();
+                warn!(logger, "GitHub handle is not a valid GitHub username";
+                      "who" => %handle,
+                      "github_account" => ?name.to_string(),
                 );
             }
+        }
+
+        let mut new_github = None;
+        let mut github_id_of_github = None;
+        let status = match (&info.github, info.github_id) {
+            (Some(name), Some(id)) => match id_status(name, id, &logins, &login_ids) {
+                IdStatus::UpToDate => {
+                    metrics.check_handles.up_to_date.inc();
+                    debug!(logger, "GitHub ID is up to date";
+                           "github_account" => %name,
+                           "github_id" => %id,
+                    );
+                    Status::Ok
+                }
+                IdStatus::HandleChanged(login) => {
+                    metrics.check_handles.changed_handle.inc();
+                    warn!(logger, "GitHub handle changed";
+                          "who" => %handle,
+                          "github_account" => %name,
+                          "github_id" => %id,
+                          "new_github_account" => %login,
+                    );
+                    new_github = Some(login);
+                    Status::HandleChanged
+                }
+                IdStatus::IdMismatch(login, other_id) => {
+                    metrics.check_handles.id_mismatch.inc();
+                    error!(logger, "GitHub handle and GitHub ID are different accounts";
+                           "who" => %handle,
+                           "github_account" => %name,
+                           "github_id" => %id,
+                           "github_id_login" => %login,
+                           "github_account_id" => %other_id,
+                    );
+                    new_github = Some(login);
+                    github_id_of_github = Some(other_id);
+                    Status::IdMismatch
+                }
+                IdStatus::LookupFailed => {
+                    metrics.check_handles.lookup_failed.inc();
+                    error!(logger, "GitHub ID not found";
+                           "who" => %handle,
+                           "github_account" => %name,
+                           "github_id" => %id,
+                    );
+                    Status::LookupFailed
+                }
+                IdStatus::AccountDeleted => {
+                    metrics.check_handles.account_deleted.inc();
+                    error!(logger, "GitHub account no longer exists";
+                           "who" => %handle,
+                           "github_account" => %name,
+                           "github_id" => %id,
+                    );
+                    Status::AccountDeleted
+                }
+            },
             (Some(name), None) => {
                 warn!(logger, "Missing GitHub ID";
                        "github_account" => %name);
+                Status::MissingId
             }
             (None, Some(id)) => {
                 error!(logger, "Missing GitHub Account, but ID present";
                        "who" => %handle,
                        "github_id" => %id,
                 );
+                Status::IdWithoutGithub
             }
             (None, None) => {
                 debug!(logger, "Missing GitHub Account and ID";
                        "who" => %handle);
+                Status::MissingGithub
+            }
+        };
+
+        rows.push(ReportRow {
+            handle,
+            github: info.github,
+            github_id: info.github_id,
+            new_github,
+            github_id_of_github,
+            status,
+        });
+    }
+
+    rows.sort_by(|a, b| a.handle.cmp(&b.handle));
+
+    if let Some(report) = report {
+        info!(logger, "Writing the check report";
+              "file" => %report.display(),
+        );
+        serde_json::to_writer_pretty(File::create(report)?, &rows)?;
+    }
+
+    if let Some(file) = annotate {
+        // A maintainer file from stdin or in JSON has no entries to
+        // point at, so those annotations are for the whole run.
+        let lines = std::fs::read_to_string(file)
+            .map(|contents| entry_lines(&contents))
+            .unwrap_or_default();
+        for row in &rows {
+            if let Some(annotation) = annotation(row, &file.to_string_lossy(), &lines) {
+                println!("{}", annotation);
             }
         }
     }
 
+    if let Some(required) = require_id_coverage {
+        if coverage < required {
+            error!(logger, "GitHub ID coverage is below the required threshold";
+                   "percent" => coverage,
+                   "required" => required,
+            );
+            return Err(ExitError::InsufficientIDCoverage { coverage, required });
+        }
+    }
+
     Ok(())
 }
+
+/// The 1-based line each maintainer's entry starts on.
+fn entry_lines(contents: &str) -> HashMap<String, usize> {
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(|(n, line)| {
+            ENTRY_RE
+                .captures(line)
+                .map(|captures| (captures["handle"].to_string(), n + 1))
+        })
+        .collect()
+}
+
+/// The workflow command for a row's problem, if it has one.
+fn annotation(row: &ReportRow, file: &str, lines: &HashMap<String, usize>) -> Option<String> {
+    let none = || "?".to_string();
+    let github = row
+        .github
+        .as_ref()
+        .map_or_else(none, |name| name.to_string());
+    let github_id = row.github_id.map_or_else(none, |id| id.to_string());
+    let new_github = row
+        .new_github
+        .as_ref()
+        .map_or_else(none, |name| name.to_string());
+
+    let (level, message) = match row.status {
+        Status::Ok => return None,
+        Status::MissingId => (
+            "warning",
+            format!("GitHub handle {} has no githubId", github),
+        ),
+        Status::MissingGithub => ("warning", "no GitHub handle".to_string()),
+        Status::HandleChanged => (
+            "warning",
+            format!(
+                "GitHub ID {} is now {}, not {}",
+                github_id, new_github, github
+            ),
+        ),
+        Status::IdWithoutGithub => (
+            "error",
+            format!("githubId {} has no GitHub handle", github_id),
+        ),
+        Status::IdMismatch => (
+            "error",
+            format!(
+                "{} is GitHub ID {}, but githubId {} is {}",
+                github,
+                row.github_id_of_github
+                    .map_or_else(none, |id| id.to_string()),
+                github_id,
+                new_github
+            ),
+        ),
+        Status::LookupFailed => ("error", format!("GitHub ID {} not found", github_id)),
+        Status::AccountDeleted => (
+            "error",
+            format!("GitHub account {} ({}) no longer exists", github, github_id),
+        ),
+    };
+
+    let handle = row.handle.to_string();
+    let properties = match lines.get(&handle) {
+        Some(line) => format!(
+            " file={},line={},title={}",
+            escape_property(file),
+            line,
+            escape_property(&handle)
+        ),
+        None => format!(" title={}", escape_property(&handle)),
+    };
+    Some(format!(
+        "::{}{}::{}: {}",
+        level,
+        properties,
+        escape_data(&handle),
+        escape_data(&message)
+    ))
+}
+
+/// Workflow command messages can't hold line breaks as they are.
+fn escape_data(data: &str) -> String {
+    data.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Property values can't hold `:` or `,` either.
+fn escape_property(value: &str) -> String {
+    escape_data(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+fn id_status(
+    name: &GitHubName,
+    id: GitHubID,
+    logins: &HashMap<GitHubID, GitHubName>,
+    login_ids: &HashMap<GitHubName, Option<GitHubID>>,
+) -> IdStatus {
+    match logins.get(&id) {
+        Some(login) if login == name => IdStatus::UpToDate,
+        Some(login) => match login_ids.get(name) {
+            Some(Some(other_id)) if *other_id != id => {
+                IdStatus::IdMismatch(login.clone(), *other_id)
+            }
+            _ => IdStatus::HandleChanged(login.clone()),
+        },
+        // Only GitHub saying there's no such account makes it deleted,
+        // not a lookup which failed.
+        None => match login_ids.get(name) {
+            Some(None) => IdStatus::AccountDeleted,
+            Some(Some(_)) | None => IdStatus::LookupFailed,
+        },
+    }
+}
+
+/// Percentage of maintainers with a GitHub account who also have a
+/// GitHub ID. A list with no GitHub accounts is fully covered.
+fn id_coverage(maintainers: &MaintainerList) -> f64 {
+    let (with_github, with_id) = maintainers
+        .iter()
+        .filter(|(_, info)| info.github.is_some())
+        .fold((0, 0), |(with_github, with_id), (_, info)| {
+            if info.github_id.is_some() {
+                (with_github + 1, with_id + 1)
+            } else {
+                (with_github + 1, with_id)
+            }
+        });
+
+    if with_github == 0 {
+        100.0
+    } else {
+        f64::from(with_id) * 100.0 / f64::from(with_github)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maintainers::{Handle, Information};
+
+    /// Every ID still belongs to the handle it was recorded with.
+    fn unchanged(ids: &[GitHubID]) -> Result<HashMap<GitHubID, GitHubName>, ExitError> {
+        Ok(ids
+            .iter()
+            .map(|id| (*id, GitHubName::new(format!("user{}", id))))
+            .collect())
+    }
+
+    /// No login belongs to an account at all.
+    fn no_ids(_: &[GitHubName]) -> Result<HashMap<GitHubName, Option<GitHubID>>, ExitError> {
+        Ok(HashMap::new())
+    }
+
+    fn maintainers(github: u32, github_id: u32) -> MaintainerList {
+        MaintainerList::new(
+            (0..github)
+                .map(|n| {
+                    (
+                        Handle::new(format!("user{}", n)),
+                        Information {
+                            email: None,
+                            name: None,
+                            github: Some(GitHubName::new(format!("user{}", n))),
+                            github_id: if n < github_id {
+                                Some(GitHubID::new(n.into()))
+                            } else {
+                                None
+                            },
+                            matrix: None,
+                            keys: None,
+                        },
+                    )
+                })
+                .chain(vec![(
+                    Handle::new("nogithub"),
+                    Information {
+                        email: None,
+                        name: None,
+                        github: None,
+                        github_id: None,
+                        matrix: None,
+                        keys: None,
+                    },
+                )])
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_id_coverage() {
+        assert_eq!(id_coverage(&maintainers(0, 0)), 100.0);
+        assert_eq!(id_coverage(&maintainers(4, 0)), 0.0);
+        assert_eq!(id_coverage(&maintainers(4, 1)), 25.0);
+        assert_eq!(id_coverage(&maintainers(4, 4)), 100.0);
+    }
+
+    #[test]
+    fn test_require_id_coverage() {
+        let logger = crate::test_logger();
+
+        assert!(check_handles(
+            logger.clone(),
+            &Metrics::unregistered(),
+            maintainers(4, 3),
+            unchanged,
+            no_ids,
+            None,
+            false,
+            None,
+            None
+        )
+        .is_ok());
+        assert!(check_handles(
+            logger.clone(),
+            &Metrics::unregistered(),
+            maintainers(4, 3),
+            unchanged,
+            no_ids,
+            Some(75.0),
+            false,
+            None,
+            None
+        )
+        .is_ok());
+        match check_handles(
+            logger,
+            &Metrics::unregistered(),
+            maintainers(4, 3),
+            unchanged,
+            no_ids,
+            Some(80.0),
+            false,
+            None,
+            None,
+        ) {
+            Err(ExitError::InsufficientIDCoverage { coverage, required }) => {
+                assert_eq!(coverage, 75.0);
+                assert_eq!(required, 80.0);
+            }
+            other => panic!("expected insufficient coverage, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_id_status() {
+        let logins: HashMap<GitHubID, GitHubName> = vec![
+            (GitHubID::new(1), GitHubName::new("Alice")),
+            (GitHubID::new(2), GitHubName::new("carol")),
+            (GitHubID::new(4), GitHubName::new("frank")),
+        ]
+        .into_iter()
+        .collect();
+        let login_ids: HashMap<GitHubName, Option<GitHubID>> = vec![
+            (GitHubName::new("dave"), Some(GitHubID::new(6))),
+            (GitHubName::new("erin"), Some(GitHubID::new(5))),
+            (GitHubName::new("gone"), None),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            id_status(
+                &GitHubName::new("alice"),
+                GitHubID::new(1),
+                &logins,
+                &login_ids
+            ),
+            IdStatus::UpToDate
+        );
+        assert_eq!(
+            id_status(
+                &GitHubName::new("bob"),
+                GitHubID::new(2),
+                &logins,
+                &login_ids
+            ),
+            IdStatus::HandleChanged(GitHubName::new("carol"))
+        );
+        assert_eq!(
+            id_status(
+                &GitHubName::new("dave"),
+                GitHubID::new(3),
+                &logins,
+                &login_ids
+            ),
+            IdStatus::LookupFailed
+        );
+        assert_eq!(
+            id_status(
+                &GitHubName::new("gone"),
+                GitHubID::new(7),
+                &logins,
+                &login_ids
+            ),
+            IdStatus::AccountDeleted
+        );
+        // The login's own lookup failed, so it may well still exist.
+        assert_eq!(
+            id_status(
+                &GitHubName::new("flaky"),
+                GitHubID::new(8),
+                &logins,
+                &login_ids
+            ),
+            IdStatus::LookupFailed
+        );
+        assert_eq!(
+            id_status(
+                &GitHubName::new("erin"),
+                GitHubID::new(4),
+                &logins,
+                &login_ids
+            ),
+            IdStatus::IdMismatch(GitHubName::new("frank"), GitHubID::new(5))
+        );
+    }
+
+    #[test]
+    fn test_lookup_errors_propagate() {
+        match check_handles(
+            crate::test_logger(),
+            &Metrics::unregistered(),
+            maintainers(4, 3),
+            |_| Err(ExitError::GraphQL("test".into())),
+            no_ids,
+            None,
+            false,
+            None,
+            None,
+        ) {
+            Err(ExitError::GraphQL(_)) => {}
+            other => panic!("expected the lookup error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let report = tmpdir.path().join("report.json");
+        let renamed = |ids: &[GitHubID]| -> Result<HashMap<GitHubID, GitHubName>, ExitError> {
+            Ok(ids
+                .iter()
+                .map(|id| (*id, GitHubName::new(format!("renamed{}", id))))
+                .collect())
+        };
+
+        let not_found =
+            |names: &[GitHubName]| -> Result<HashMap<GitHubName, Option<GitHubID>>, ExitError> {
+                assert_eq!(names, &[GitHubName::new("user0")]);
+                Ok(names.iter().map(|name| (name.clone(), None)).collect())
+            };
+
+        check_handles(
+            crate::test_logger(),
+            &Metrics::unregistered(),
+            maintainers(2, 1),
+            renamed,
+            not_found,
+            None,
+            false,
+            Some(&report),
+            None,
+        )
+        .unwrap();
+
+        let written: serde_json::Value =
+            serde_json::from_reader(File::open(&report).unwrap()).unwrap();
+        assert_eq!(
+            written,
+            serde_json::json!([
+                {
+                    "handle": "nogithub",
+                    "github": null,
+                    "githubId": null,
+                    "status": "missing_github",
+                },
+                {
+                    "handle": "user0",
+                    "github": "user0",
+                    "githubId": 0,
+                    "newGithub": "renamed0",
+                    "status": "handle_changed",
+                },
+                {
+                    "handle": "user1",
+                    "github": "user1",
+                    "githubId": null,
+                    "status": "missing_id",
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_entry_lines() {
+        let lines = entry_lines(
+            r#"{
+  /* Example:
+    handle = {
+  */
+  "0x4A6F" = {
+    github = "0x4A6F";
+  };
+  alice = {
+    github = "alice";
+  };
+}"#,
+        );
+
+        assert_eq!(
+            lines,
+            vec![("0x4A6F".to_string(), 5), ("alice".to_string(), 8)]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn test_annotation() {
+        let lines: HashMap<String, usize> = vec![("alice".to_string(), 8)].into_iter().collect();
+        let row = |handle: &str, status| ReportRow {
+            handle: Handle::new(handle),
+            github: Some(GitHubName::new(handle)),
+            github_id: Some(GitHubID::new(1)),
+            new_github: Some(GitHubName::new("renamed")),
+            github_id_of_github: None,
+            status,
+        };
+
+        assert_eq!(annotation(&row("alice", Status::Ok), "m.nix", &lines), None);
+        assert_eq!(
+            annotation(&row("alice", Status::HandleChanged), "m.nix", &lines).unwrap(),
+            "::warning file=m.nix,line=8,title=alice::alice: GitHub ID 1 is now renamed, not alice"
+        );
+        assert_eq!(
+            annotation(&row("bob", Status::LookupFailed), "m.nix", &lines).unwrap(),
+            "::error title=bob::bob: GitHub ID 1 not found"
+        );
+        assert_eq!(escape_property("a:b,c%\n"), "a%3Ab%2Cc%25%0A");
+    }
+}
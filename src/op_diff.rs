@@ -0,0 +1,223 @@
+//! Compare two maintainer lists, to review changes to the maintainer
+//! file before they land.
+
+use crate::cli::ExitError;
+use crate::maintainers::{Handle, Information, MaintainerList};
+use crate::metrics::Metrics;
+use std::collections::BTreeMap;
+
+pub fn diff(
+    logger: slog::Logger,
+    metrics: &Metrics,
+    old: MaintainerList,
+    new: MaintainerList,
+) -> Result<(), ExitError> {
+    let diff = MaintainerDiff::new(&old, &new)?;
+    metrics.diff.added.set(diff.added.len() as i64);
+    metrics.diff.removed.set(diff.removed.len() as i64);
+    metrics.diff.changed.set(diff.changed.len() as i64);
+
+    print!("{}", diff);
+
+    info!(logger, "Compared maintainer lists";
+          "added" => diff.added.len(),
+          "removed" => diff.removed.len(),
+          "changed" => diff.changed.len(),
+    );
+
+    if diff.is_empty() {
+        Ok(())
+    } else {
+        Err(ExitError::MaintainersDiffer {
+            added: diff.added.len(),
+            removed: diff.removed.len(),
+            changed: diff.changed.len(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct MaintainerDiff {
+    added: Vec<Handle>,
+    removed: Vec<Handle>,
+    changed: Vec<(Handle, Vec<FieldChange>)>,
+}
+
+/// A field as it is named in the maintainer file, with its old and
+/// new values. None means the field is not set.
+#[derive(Debug, PartialEq)]
+struct FieldChange {
+    field: String,
+    old: Option<serde_json::Value>,
+    new: Option<serde_json::Value>,
+}
+
+impl MaintainerDiff {
+    fn new(old: &MaintainerList, new: &MaintainerList) -> Result<MaintainerDiff, ExitError> {
+        let old: BTreeMap<&Handle, &Information> = old.iter().collect();
+        let new: BTreeMap<&Handle, &Information> = new.iter().collect();
+
+        let mut diff = MaintainerDiff {
+            added: new
+                .keys()
+                .filter(|handle| !old.contains_key(*handle))
+                .map(|handle| (*handle).clone())
+                .collect(),
+            removed: old
+                .keys()
+                .filter(|handle| !new.contains_key(*handle))
+                .map(|handle| (*handle).clone())
+                .collect(),
+            changed: vec![],
+        };
+
+        for (handle, old_info) in &old {
+            if let Some(new_info) = new.get(handle) {
+                let changes = field_changes(old_info, new_info)?;
+                if !changes.is_empty() {
+                    diff.changed.push(((*handle).clone(), changes));
+                }
+            }
+        }
+
+        Ok(diff)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compare the serialized fields, so the names match the maintainer
+/// file and new fields are covered without changes here.
+fn field_changes(old: &Information, new: &Information) -> Result<Vec<FieldChange>, ExitError> {
+    let old = fields(old)?;
+    let mut new = fields(new)?;
+
+    let mut changes = vec![];
+    for (field, old_value) in old {
+        let new_value = new.remove(&field);
+        if Some(&old_value) != new_value.as_ref() {
+            changes.push(FieldChange {
+                field,
+                old: Some(old_value),
+                new: new_value,
+            });
+        }
+    }
+    for (field, new_value) in new {
+        changes.push(FieldChange {
+            field,
+            old: None,
+            new: Some(new_value),
+        });
+    }
+    changes.sort_by(|a, b| a.field.cmp(&b.field));
+
+    Ok(changes)
+}
+
+fn fields(info: &Information) -> Result<BTreeMap<String, serde_json::Value>, ExitError> {
+    match serde_json::to_value(info)? {
+        serde_json::Value::Object(fields) => Ok(fields.into_iter().collect()),
+        _ => Ok(BTreeMap::new()),
+    }
+}
+
+impl std::fmt::Display for MaintainerDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for handle in &self.added {
+            writeln!(f, "+ {}", handle)?;
+        }
+        for handle in &self.removed {
+            writeln!(f, "- {}", handle)?;
+        }
+        for (handle, changes) in &self.changed {
+            writeln!(f, "~ {}", handle)?;
+            for change in changes {
+                writeln!(
+                    f,
+                    "    {}: {} -> {}",
+                    change.field,
+                    display_value(&change.old),
+                    display_value(&change.new)
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn display_value(value: &Option<serde_json::Value>) -> String {
+    value
+        .as_ref()
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| String::from("(unset)"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maintainers::{GitHubID, GitHubName};
+
+    fn info(github: &str, github_id: u64) -> Information {
+        Information {
+            email: None,
+            name: None,
+            github: Some(GitHubName::new(github)),
+            github_id: Some(GitHubID::new(github_id)),
+            matrix: None,
+            keys: None,
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = MaintainerList::new(
+            vec![
+                (Handle::new("alice"), info("alice", 1)),
+                (Handle::new("bob"), info("bob", 2)),
+                (Handle::new("carol"), info("carol", 3)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let new = MaintainerList::new(
+            vec![
+                (Handle::new("alice"), info("alice", 1)),
+                (Handle::new("carol"), info("carol-renamed", 3)),
+                (Handle::new("dave"), info("dave", 4)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        let diff = MaintainerDiff::new(&old, &new).unwrap();
+        assert_eq!(
+            diff,
+            MaintainerDiff {
+                added: vec![Handle::new("dave")],
+                removed: vec![Handle::new("bob")],
+                changed: vec![(
+                    Handle::new("carol"),
+                    vec![FieldChange {
+                        field: "github".into(),
+                        old: Some("carol".into()),
+                        new: Some("carol-renamed".into()),
+                    }]
+                )],
+            }
+        );
+        assert_eq!(
+            diff.to_string(),
+            "+ dave
+- bob
+~ carol
+    github: \"carol\" -> \"carol-renamed\"
+"
+        );
+
+        assert!(MaintainerDiff::new(&old, &old).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,73 @@
+//! Print the maintainer list with every GitHub handle which blame
+//! shows has changed replaced by the current one.
+
+use crate::cli::ExitError;
+use crate::filemunge;
+use crate::github::GitHubApi;
+use crate::maintainerhistory::{HistorySource, MaintainerHistory};
+use crate::maintainers::{GitHubName, MaintainerList};
+use crate::nix::NixInvocation;
+use futures::future::Future;
+use futures::stream::{self, Stream};
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::Path;
+use tokio::runtime::Runtime;
+
+pub fn fix_handles(
+    logger: slog::Logger,
+    github: &impl GitHubApi,
+    rt: &mut Runtime,
+    nix_invocation: &NixInvocation,
+    file: &Path,
+    maintainers: MaintainerList,
+    history_source: HistorySource,
+    dry_run: bool,
+    concurrency: usize,
+) -> Result<(), ExitError> {
+    let history =
+        MaintainerHistory::load(logger.clone(), rt, nix_invocation, file, history_source)?;
+
+    let mut checks = vec![];
+    for (handle, information) in maintainers {
+        if let (Some(github_name), Some(github_id)) = (information.github, information.github_id) {
+            checks.push(
+                history
+                    .renamed_handle(github, &handle, &github_name, github_id)
+                    .map(move |renamed| renamed.map(|new_name| (handle, github_name, new_name))),
+            );
+        }
+    }
+
+    let mut renames: Vec<_> = rt
+        .block_on(
+            stream::iter_ok(checks)
+                .buffer_unordered(concurrency.max(1))
+                .filter_map(|renamed| renamed)
+                .collect(),
+        )
+        .expect("confidence checks report their own failures");
+    renames.sort_by(|a, b| a.0.cmp(&b.0));
+
+    info!(logger, "Found changed GitHub handles";
+          "count" => renames.len(),
+    );
+
+    if dry_run {
+        for (handle, old_name, new_name) in renames {
+            println!("{}: {} -> {}", handle, old_name, new_name);
+        }
+        return Ok(());
+    }
+
+    let renames: HashMap<GitHubName, GitHubName> = renames
+        .into_iter()
+        .map(|(_handle, old_name, new_name)| (old_name, new_name))
+        .collect();
+    print!(
+        "{}",
+        filemunge::rename_handles(&renames, read_to_string(file)?)
+    );
+
+    Ok(())
+}
@@ -0,0 +1,16 @@
+//! Print the parsed maintainer list, for auditing or for feeding
+//! other tools.
+
+use crate::cli::{ExitError, MaintainerFormat};
+use crate::maintainers::MaintainerList;
+
+pub fn list_maintainers(
+    maintainers: MaintainerList,
+    format: MaintainerFormat,
+) -> Result<(), ExitError> {
+    match format {
+        MaintainerFormat::Json => println!("{}", maintainers.to_json()?),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+//! Count how complete the maintainer list's entries are, without
+//! asking GitHub anything.
+
+use crate::cli::ExitError;
+use crate::maintainers::MaintainerList;
+use crate::metrics::Metrics;
+use std::convert::TryInto;
+
+#[derive(Debug, Default, PartialEq)]
+struct Stats {
+    maintainers: usize,
+    github: usize,
+    github_id: usize,
+    both: usize,
+    neither: usize,
+    email: usize,
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "maintainers:            {}", self.maintainers)?;
+        writeln!(f, "with github:            {}", self.github)?;
+        writeln!(f, "with githubId:          {}", self.github_id)?;
+        writeln!(f, "with github & githubId: {}", self.both)?;
+        writeln!(f, "with neither:           {}", self.neither)?;
+        writeln!(f, "with email:             {}", self.email)
+    }
+}
+
+fn stats(maintainers: &MaintainerList) -> Stats {
+    let mut stats = Stats::default();
+    for (_, info) in maintainers.iter() {
+        stats.maintainers += 1;
+        match (info.github.is_some(), info.github_id.is_some()) {
+            (true, true) => stats.both += 1,
+            (false, false) => stats.neither += 1,
+            _ => {}
+        }
+        if info.github.is_some() {
+            stats.github += 1;
+        }
+        if info.github_id.is_some() {
+            stats.github_id += 1;
+        }
+        if info.email.is_some() {
+            stats.email += 1;
+        }
+    }
+    stats
+}
+
+pub fn print_stats(metrics: &Metrics, maintainers: MaintainerList) -> Result<(), ExitError> {
+    let stats = stats(&maintainers);
+
+    metrics
+        .maintainers
+        .missing_github
+        .set((stats.maintainers - stats.github).try_into().unwrap());
+    metrics
+        .maintainers
+        .missing_github_id
+        .set((stats.maintainers - stats.github_id).try_into().unwrap());
+
+    print!("{}", stats);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maintainers::{GitHubID, GitHubName, Handle, Information};
+
+    #[test]
+    fn test_stats() {
+        let information = |github: Option<&str>, github_id: Option<u64>, email: bool| Information {
+            email: if email {
+                Some("someone@example.com".into())
+            } else {
+                None
+            },
+            name: None,
+            github: github.map(GitHubName::new),
+            github_id: github_id.map(GitHubID::new),
+            matrix: None,
+            keys: None,
+        };
+
+        let maintainers = MaintainerList::new(
+            vec![
+                (
+                    Handle::new("alice"),
+                    information(Some("alice"), Some(1), true),
+                ),
+                (Handle::new("bob"), information(Some("bob"), None, true)),
+                (Handle::new("carol"), information(None, Some(3), false)),
+                (Handle::new("dave"), information(None, None, false)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        assert_eq!(
+            stats(&maintainers),
+            Stats {
+                maintainers: 4,
+                github: 2,
+                github_id: 2,
+                both: 1,
+                neither: 1,
+                email: 2,
+            }
+        );
+    }
+}
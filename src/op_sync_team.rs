@@ -1,141 +1,269 @@
-use crate::cli::ExitError;
-use crate::invited::Invited;
+use crate::audit::AuditLog;
+use crate::checkpoint::Checkpoint;
+use crate::cli::{ExitError, ListTeamParams, PlanSyncParams, SyncTeamParams, TeamFormat};
+use crate::github::{GitHubApi, GitHubError, OrgRole, Team, TeamRole, User};
+use crate::invited::{self, Invited};
 use crate::maintainers::{GitHubID, GitHubName, Handle, MaintainerList};
+use crate::metrics::{GitHubCall, MaintainerMetrics, Metrics, SyncMetrics};
+use crate::plan::{Plan, PlannedAction};
+use crate::protected::Protected;
+use crate::webhook;
+use futures::future::{self, Future};
 use futures::stream::Stream;
-use hubcaps::teams::{TeamMemberOptions, TeamMemberRole};
-use hubcaps::Github;
-use prometheus::{Histogram, IntCounter, IntGauge};
-use std::collections::HashMap;
+use prometheus::{IntCounter, IntGauge};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::path::PathBuf;
+use std::fs::File;
+use std::time::Duration;
 use tokio::runtime::Runtime;
 
-lazy_static! {
-    static ref GITHUB_CALLS: IntCounter = register_int_counter!(
-        "rfc39_github_call_count",
-        "Code-level calls to GitHub API methods (not a count of actual calls made to GitHub.)"
-    )
-    .unwrap();
+pub fn list_teams(
+    logger: slog::Logger,
+    github: &impl GitHubApi,
+    rt: &mut Runtime,
+    params: ListTeamParams,
+) -> Result<(), ExitError> {
+    let org: &str = &params.organization;
+    let teams: Vec<Team> = rt
+        .block_on(github.iter_teams(org).collect())
+        .map_err(|e| github_failed(&logger, org, "Failed to list teams", e))?;
+
+    let mut rows = vec![];
+    for team in teams {
+        let members = if params.with_counts {
+            let members: Vec<User> = rt
+                .block_on(github.iter_team_members(org, team.id).collect())
+                .map_err(|e| github_failed(&logger, org, "Failed to fetch team members", e))?;
+            Some(members.len())
+        } else {
+            None
+        };
+
+        rows.push(TeamRow {
+            id: team.id,
+            slug: team.slug,
+            name: team.name,
+            members,
+        });
+    }
+
+    print!("{}", format_teams(&rows, &params.format)?);
+
+    Ok(())
 }
 
-pub fn list_teams(github: Github, org: &str) -> Result<(), ExitError> {
-    let mut rt = Runtime::new().unwrap();
+#[derive(Debug, Serialize)]
+struct TeamRow {
+    id: u64,
+    slug: String,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    members: Option<usize>,
+}
 
-    rt.block_on(github.org(org).teams().iter().for_each(|team| {
-        println!("{:10} {}", team.id, team.name);
-        Ok(())
-    }))
-    .expect("Failed to list teams");
+fn format_teams(rows: &[TeamRow], format: &TeamFormat) -> Result<String, ExitError> {
+    Ok(match format {
+        TeamFormat::Json => format!("{}\n", serde_json::to_string_pretty(rows)?),
+        TeamFormat::Text => rows
+            .iter()
+            .map(|row| match row.members {
+                Some(members) => {
+                    format!("{:10} {:30} {:6} {}\n", row.id, row.slug, members, row.name)
+                }
+                None => format!("{:10} {:30} {}\n", row.id, row.slug, row.name),
+            })
+            .collect(),
+    })
+}
+
+/// The changes sync-team would make, before --protect, owners, limits
+/// and the invited list are taken into account. Only reads from
+/// GitHub.
+pub fn plan_sync(
+    logger: slog::Logger,
+    github: &impl GitHubApi,
+    rt: &mut Runtime,
+    metrics: &Metrics,
+    maintainers: MaintainerList,
+    params: PlanSyncParams,
+) -> Result<(), ExitError> {
+    let org: &str = &params.organization;
+
+    let org_teams: Vec<Team> = rt
+        .block_on(github.iter_teams(org).collect())
+        .map_err(|e| github_failed(&logger, org, "Failed to list the organization's teams", e))?;
+    let team_id = resolve_team_id(&logger, org, params.team_id, params.team_slug, &org_teams)?;
+
+    let team = rt
+        .block_on(github.get_team(org, team_id))
+        .map_err(|e| team_not_found(&logger, org, team_id, "Failed to fetch team", e))?;
+    info!(logger, "Planning the team sync";
+          "team_name" => %team.name,
+          "team_id" => %team.id,
+    );
+
+    let current_members: HashMap<GitHubID, GitHubName> = rt
+        .block_on(
+            github
+                .iter_team_members(org, team_id)
+                .map(|user| (user.id, user.login))
+                .collect(),
+        )
+        .map_err(|e| team_not_found(&logger, org, team_id, "Failed to fetch team members", e))?
+        .into_iter()
+        .collect();
+    let pending_invites: Vec<GitHubName> = rt
+        .block_on(github.iter_invitations(org).collect())
+        .map_err(|e| github_failed(&logger, org, "Failed to list existing invitations", e))?;
+
+    let members = team_membership(current_members, &pending_invites, &maintainers);
+    let actions = partition_actions(maintainer_team_diff(
+        maintainers,
+        &members,
+        &metrics.maintainers,
+    ));
+
+    print!("{}", format_plan(&actions));
 
     Ok(())
 }
 
+/// The issue title and body telling `members` they are about to be
+/// removed from the team.
+fn removal_notice(
+    org: &str,
+    team_name: &str,
+    grace_days: u64,
+    members: &[(GitHubName, GitHubID, Option<Handle>)],
+) -> (String, String) {
+    let title = format!("Removing {} from {}/{}", members.len(), org, team_name);
+    let mut body = format!(
+        "These accounts will be removed from the {}/{} team in {} days:\n\n",
+        org, team_name, grace_days
+    );
+    for (github_name, _, handle) in members {
+        match handle {
+            Some(handle) => body.push_str(&format!(
+                "- @{}: listed by `{}` without this GitHub ID\n",
+                github_name, handle
+            )),
+            None => body.push_str(&format!("- @{}: not in the maintainer list\n", github_name)),
+        }
+    }
+    body.push_str("\nTo stay on the team, add or fix your entry in maintainers/maintainer-list.nix before then.\n");
+    (title, body)
+}
+
+fn format_plan(actions: &PartitionedActions) -> String {
+    let mut plan = String::new();
+    for (github_name, github_id, handle) in &actions.additions {
+        plan.push_str(&format!(
+            "add {} ({}) for {}\n",
+            github_name, github_id, handle
+        ));
+    }
+    for (github_name, github_id, handle) in &actions.removals {
+        match handle {
+            Some(handle) => plan.push_str(&format!(
+                "remove {} ({}), still listed by {}\n",
+                github_name, github_id, handle
+            )),
+            None => plan.push_str(&format!("remove {} ({})\n", github_name, github_id)),
+        }
+    }
+    for (github_name, github_id, handle) in &actions.pending {
+        plan.push_str(&format!(
+            "pending {} ({}) for {}\n",
+            github_name, github_id, handle
+        ));
+    }
+    for (github_id, handle) in &actions.unnamed {
+        plan.push_str(&format!(
+            "skip {} ({}), no GitHub handle\n",
+            handle, github_id
+        ));
+    }
+    plan.push_str(&format!("keep {} members\n", actions.keeps.len()));
+    plan
+}
+
 pub fn sync_team(
     logger: slog::Logger,
-    github: Github,
+    github: &impl GitHubApi,
+    rt: &mut Runtime,
+    metrics: &Metrics,
     maintainers: MaintainerList,
-    invited_list: PathBuf,
-    org: &str,
-    team_id: u64,
-    dry_run: bool,
-    limit: Option<u64>,
+    params: SyncTeamParams,
 ) -> Result<(), ExitError> {
-    // initialize the counters :(
-    GITHUB_CALLS.get();
-
-    let get_team_histogram: Histogram =
-        register_histogram!("rfc39_github_get_team", "Time to fetch a team").unwrap();
-    let get_team_failures: IntCounter = register_int_counter!(
-        "rfc39_github_get_team_failures",
-        "Number of failed attempts to get a team"
-    )
-    .unwrap();
-
-    let get_team_members_histogram: Histogram = register_histogram!(
-        "rfc39_github_get_team_members",
-        "Time to fetch team members"
-    )
-    .unwrap();
-    let get_team_members_failures: IntCounter = register_int_counter!(
-        "rfc39_github_get_team_members_failures",
-        "Number of failed attempts to get a team's members"
-    )
-    .unwrap();
-    let current_team_member_gauge: IntGauge =
-        register_int_gauge!("rfc39_github_team_member_count", "Fetched team members").unwrap();
-
-    let get_invitations_histogram: Histogram =
-        register_histogram!("rfc39_github_get_invitations", "Time to fetch invitations").unwrap();
-    let get_invitations_failures: IntCounter = register_int_counter!(
-        "rfc39_github_get_team_invitation_failures",
-        "Number of failed attempts to get a team's pending invitations"
-    )
-    .unwrap();
-    let current_invitations_gauge: IntGauge =
-        register_int_gauge!("rfc39_github_invitation_count", "Currently invited users").unwrap();
-
-    let github_get_user_histogram: Histogram =
-        register_histogram!("rfc39_github_get_user", "Time to fetch a GitHub user").unwrap();
-    let github_get_user_failures: IntCounter = register_int_counter!(
-        "rfc39_github_get_user_failures",
-        "Number of failed attempts to get a user"
-    )
-    .unwrap();
-
-    let github_add_user_histogram: Histogram = register_histogram!(
-        "rfc39_github_add_user",
-        "Time to add a GitHub user to a team"
-    )
-    .unwrap();
-    let github_add_user_failures: IntCounter = register_int_counter!(
-        "rfc39_github_add_user_failures",
-        "Number of failed attempts to add a user"
-    )
-    .unwrap();
-
-    let github_remove_user_histogram: Histogram = register_histogram!(
-        "rfc39_github_remove_user",
-        "Time to remove a GitHub user from a team"
-    )
-    .unwrap();
-    let github_remove_user_failures: IntCounter = register_int_counter!(
-        "rfc39_github_remove_user_failures",
-        "Number of failed attempts to remove a user"
-    )
-    .unwrap();
-
-    let github_user_unchanged_username_id_mismatch: IntGauge = register_int_gauge!(
-        "rfc39_github_username_id_mismatch",
-        "Number of maintainers not added because of out of date usernames, due to a mismatched ID"
-    )
-    .unwrap();
-
-    let invited_list_loaded_gauge: IntGauge = register_int_gauge!(
-        "rfc39_invited_list_loaded",
-        "Number of github ids loaded from the previously invited list"
-    )
-    .unwrap();
-
-    let invited_list_saved_gauge: IntGauge = register_int_gauge!(
-        "rfc39_invited_list_saved",
-        "Number of github ids saved to the previously invited list"
-    )
-    .unwrap();
-
-    let mut rt = TrackedReactor {
-        rt: Runtime::new().unwrap(),
-    };
+    let SyncTeamParams {
+        organization: org,
+        team_id,
+        team_slug,
+        dry_run,
+        role,
+        no_add,
+        no_remove,
+        remove_owners,
+        limit,
+        add_limit,
+        remove_limit,
+        invited_list,
+        reinvite_after,
+        protect,
+        audit_log,
+        summary,
+        checkpoint_file,
+        notify_issue,
+        notified_list,
+        removal_grace_days,
+        webhook,
+        plan_out,
+        plan_in,
+    } = params;
+    let reinvite_after = reinvite_after.map(|days| Duration::from_secs(days * 86400));
+    let removal_grace = Duration::from_secs(removal_grace_days * 86400);
+    let org: &str = &org;
+
+    let calls = &metrics.github;
+    let SyncMetrics {
+        team_member_count,
+        invitation_count,
+        add_blocked_id_mismatch,
+        remove_blocked_id_mismatch,
+        invited_list_loaded,
+        invited_list_saved,
+        change_limit,
+        add_limit: add_limit_metric,
+        remove_limit: remove_limit_metric,
+        noops,
+        additions,
+        removals,
+        pending_invitations,
+        previously_invited,
+        skipped,
+        protected: protected_members,
+        owners_kept,
+        awaiting_grace,
+        errors,
+    } = &metrics.sync;
+
+    let mut rt = TrackedReactor { rt };
 
     let do_it_live = !dry_run;
 
-    let team_actions = github.org(org).teams().get(team_id);
+    let org_teams: Vec<Team> = rt
+        .block_on(github.iter_teams(org).collect(), &calls.list_teams)
+        .map_err(|e| github_failed(&logger, org, "Failed to list the organization's teams", e))?;
+    let team_id = resolve_team_id(&logger, org, team_id, team_slug, &org_teams)?;
+
     let team = rt
-        .block_on(team_actions.get(), &get_team_histogram, &get_team_failures)
-        .expect("Failed to fetch team");
+        .block_on(github.get_team(org, team_id), &calls.get_team)
+        .map_err(|e| team_not_found(&logger, org, team_id, "Failed to fetch team", e))?;
 
     info!(logger, "Syncing team";
           "team_name" => %team.name,
           "team_id" => %team.id,
+          "role" => %role,
     );
 
     info!(logger, "Fetching current team members";
@@ -145,76 +273,84 @@ pub fn sync_team(
 
     let current_members: HashMap<GitHubID, GitHubName> = rt
         .block_on(
-            team_actions
-                .iter_members()
-                .map(|user| (GitHubID::new(user.id), GitHubName::new(user.login)))
+            github
+                .iter_team_members(org, team_id)
+                .map(|user| (user.id, user.login))
                 .collect(),
-            &get_team_members_histogram,
-            &get_team_members_failures,
+            &calls.get_team_members,
         )
-        .expect("Failed to fetch team members")
+        .map_err(|e| team_not_found(&logger, org, team_id, "Failed to fetch team members", e))?
         .into_iter()
         .collect();
 
-    current_team_member_gauge.set(current_members.len().try_into().unwrap());
+    team_member_count.set(current_members.len().try_into().unwrap());
+
+    let protected = match protect {
+        Some(ref path) => Protected::load(&logger, path)?,
+        None => Protected::default(),
+    };
 
+    let invited_list = invited::team_path(&invited_list, org, team_id);
     let mut invited = Invited::load(logger.clone(), &invited_list)?;
-    invited_list_loaded_gauge.set(invited.len().try_into().unwrap());
+    invited_list_loaded.set(invited.len().try_into().unwrap());
+
+    // Only kept when removals wait for a notice.
+    let notified_list = match (&notify_issue, notified_list) {
+        (Some(_), Some(path)) => Some(invited::team_path(&path, org, team_id)),
+        _ => None,
+    };
+    let mut notified = match notified_list {
+        Some(ref path) => Some(Invited::load(logger.clone(), path)?),
+        None => None,
+    };
 
     debug!(logger, "Fetching existing invitations");
     let pending_invites: Vec<GitHubName> = rt
         .block_on(
-            github
-                .org(org)
-                .membership()
-                .invitations()
-                .filter_map(|invite| Some(GitHubName::new(invite.login?)))
-                .collect(),
-            &get_invitations_histogram,
-            &get_invitations_failures,
+            github.iter_invitations(org).collect(),
+            &calls.get_invitations,
         )
-        .expect("failed to list existing invitations");
-    current_invitations_gauge.set(pending_invites.len().try_into().unwrap());
+        .map_err(|e| github_failed(&logger, org, "Failed to list existing invitations", e))?;
+    invitation_count.set(pending_invites.len().try_into().unwrap());
 
     debug!(logger, "Fetched invitations.";
            "pending_invitations" => pending_invites.len()
     );
 
-    let diff = maintainer_team_diff(maintainers, &current_members);
+    let members = team_membership(current_members, &pending_invites, &maintainers);
+    // Whoever is still due for removal, even if a plan or checkpoint
+    // leaves them out of this run.
+    let unwanted = unwanted_members(&maintainers, &members);
+    let actions = match plan_in {
+        Some(ref path) => {
+            let plan = Plan::load(&logger, path)?;
+            check_plan_team(&logger, org, team_id, &plan)?;
+            check_plan(&logger, github, &mut rt, &calls.get_user, &members, &plan)?;
+            plan_actions(plan)
+        }
+        None => partition_actions(maintainer_team_diff(
+            maintainers,
+            &members,
+            &metrics.maintainers,
+        )),
+    };
 
-    let limit_metric = register_int_gauge!(
-        "rfc39_team_sync_change_limit",
-        "Total number of additions and changed allowed in a single run"
-    )
-    .unwrap();
     if let Some(limit) = limit {
-        limit_metric.set(limit.try_into().unwrap());
+        change_limit.set(limit.try_into().unwrap());
+    }
+    if let Some(limit) = add_limit {
+        add_limit_metric.set(limit.try_into().unwrap());
+    }
+    if let Some(limit) = remove_limit {
+        remove_limit_metric.set(limit.try_into().unwrap());
     }
 
     let limit: Option<i64> = limit.map(|lim| lim.try_into().unwrap());
+    let add_limit: Option<i64> = add_limit.map(|lim| lim.try_into().unwrap());
+    let remove_limit: Option<i64> = remove_limit.map(|lim| lim.try_into().unwrap());
 
-    let noops = register_int_counter!(
-        "rfc39_team_sync_noops",
-        "Total count of noop team sync actions"
-    )
-    .unwrap();
-    let additions =
-        register_int_counter!("rfc39_team_sync_additions", "Total team additions").unwrap();
-    let removals =
-        register_int_counter!("rfc39_team_sync_removals", "Total team removals").unwrap();
-    let pending_invitations = register_int_counter!(
-        "rfc39_team_sync_invite_pending",
-        "Total pending team invitations"
-    )
-    .unwrap();
-    let previously_invited = register_int_counter!(
-        "rfc39_team_sync_previously_invited",
-        "Total users not invited because we know we invited them already"
-    )
-    .unwrap();
-    let errors = register_int_counter!("rfc39_team_sync_errors", "Total team errors").unwrap();
-    for (github_id, action) in diff {
-        let logger = logger.new(o!(
+    let action_logger = |github_id: GitHubID| {
+        logger.new(o!(
             "dry-run" => dry_run,
             "github-id" => format!("{}", github_id),
             "changed" => additions.get() + removals.get(),
@@ -224,246 +360,1340 @@ pub fn sync_team(
             "previously-invited" => previously_invited.get(),
             "noops" => noops.get(),
             "errors" => errors.get(),
-        ));
+        ))
+    };
+    let limit_reached = || {
         if let Some(limit) = limit {
-            if (additions.get() + removals.get()) >= limit {
-                info!(logger, "Hit maximum change limit");
-                break;
+            (additions.get() + removals.get()) >= limit
+        } else {
+            false
+        }
+    };
+    let add_limit_reached =
+        || limit_reached() || add_limit.map_or(false, |limit| additions.get() >= limit);
+    let remove_limit_reached =
+        || limit_reached() || remove_limit.map_or(false, |limit| removals.get() >= limit);
+
+    // Only a live sync makes progress worth resuming from.
+    let mut checkpoint = match checkpoint_file {
+        Some(ref path) if do_it_live => Some(Checkpoint::load(logger.clone(), path)?),
+        _ => None,
+    };
+
+    let actions = skip_completed(actions, checkpoint.as_ref());
+
+    let unmatched = unmatched_members(&actions.removals);
+    for member in &unmatched {
+        info!(logger, "Team member has no matching maintainer";
+              "github-name" => %member.github_name,
+              "github-id" => %member.github_id,
+              "listed-by" => member.listed_by.as_ref().map(|handle| format!("{}", handle)),
+        );
+    }
+
+    let mut audit = AuditLog::open(&logger, audit_log.as_deref(), dry_run)?;
+    let mut limit_hit = false;
+    let mut planned: Vec<PlannedAction> = vec![];
+
+    let keeps = actions.keeps.len();
+    for handle in actions.keeps {
+        let logger = logger.new(o!(
+            "nixpkgs-handle" => format!("{}", handle),
+        ));
+
+        noops.inc();
+        trace!(logger, "Keeping user on the team");
+        audit.record("keep", None, None, Some(&handle), "noop")?;
+    }
+
+    for (github_name, github_id, handle) in actions.pending {
+        let logger = action_logger(github_id).new(o!(
+            "nixpkgs-handle" => format!("{}", handle),
+            "github-name" => format!("{}", github_name),
+        ));
+
+        noops.inc();
+        pending_invitations.inc();
+        debug!(logger, "User already has a pending invitation");
+        audit.record(
+            "add",
+            Some(github_id),
+            Some(&github_name),
+            Some(&handle),
+            "pending_invitation",
+        )?;
+    }
+
+    for (github_id, handle) in actions.unnamed {
+        let logger = action_logger(github_id).new(o!(
+            "nixpkgs-handle" => format!("{}", handle),
+        ));
+
+        noops.inc();
+        warn!(
+            logger,
+            "Maintainer has a GitHub ID but no GitHub handle, not adding to the team"
+        );
+        audit.record(
+            "add",
+            Some(github_id),
+            None,
+            Some(&handle),
+            "missing_github",
+        )?;
+    }
+
+    // Additions are independent of each other and low-risk, so they are
+    // decided here and then verified and performed concurrently below.
+    let mut planned_additions: Vec<(slog::Logger, GitHubName, GitHubID)> = vec![];
+    let mut planned_handles: HashMap<GitHubID, (GitHubName, Handle)> = HashMap::new();
+    for (github_name, github_id, handle) in actions.additions {
+        let mut audit_add = |result: &str| {
+            audit.record(
+                "add",
+                Some(github_id),
+                Some(&github_name),
+                Some(&handle),
+                result,
+            )
+        };
+
+        let logger = action_logger(github_id).new(o!(
+            "nixpkgs-handle" => format!("{}", handle),
+            "github-name" => format!("{}", github_name),
+        ));
+
+        if no_add {
+            skipped.inc();
+            debug!(logger, "Not adding user, because of --no-add");
+            audit_add("skipped")?;
+        } else if invited.invited_within(&github_id, reinvite_after) {
+            noops.inc();
+            previously_invited.inc();
+            debug!(logger, "User was already invited previously (since there's no pending invitation we can assume the user rejected the invite)");
+            audit_add("previously_invited")?;
+        } else if add_limit_reached() {
+            info!(logger, "Hit maximum change limit");
+            audit_add("limit_reached")?;
+            limit_hit = true;
+            break;
+        } else {
+            additions.inc();
+            info!(logger, "Adding user to the team");
+
+            if do_it_live {
+                planned_additions.push((logger, github_name.clone(), github_id));
+                planned_handles.insert(github_id, (github_name, handle));
+            } else {
+                audit_add("planned")?;
+                planned.push(PlannedAction::Add {
+                    github_name: github_name.clone(),
+                    github_id,
+                    handle: handle.clone(),
+                });
             }
         }
-        match action {
-            TeamAction::Add(github_name, github_id, handle) => {
-                let logger = logger.new(o!(
-                    "nixpkgs-handle" => format!("{}", handle),
-                    "github-name" => format!("{}", github_name),
-                ));
+    }
 
-                if pending_invites.contains(&github_name) {
-                    noops.inc();
-                    pending_invitations.inc();
-                    debug!(logger, "User already has a pending invitation");
-                } else if invited.contains(&github_id) {
+    let addition_metrics = AdditionMetrics {
+        get_user: calls.get_user.clone(),
+        add_user: calls.add_user.clone(),
+        id_mismatch: add_blocked_id_mismatch.clone(),
+        errors: errors.clone(),
+    };
+    // Each outcome is recorded as soon as its addition finishes, so a
+    // sync killed halfway through doesn't redo the ones already done.
+    let (sender, added) = std::sync::mpsc::channel();
+    {
+        let github = github.clone();
+        let org = org.to_string();
+        rt.rt.spawn(
+            futures::stream::iter_ok(planned_additions)
+                .map(move |(logger, github_name, github_id)| {
+                    add_member(
+                        &github,
+                        &org,
+                        team_id,
+                        logger,
+                        github_name,
+                        github_id,
+                        role,
+                        &addition_metrics,
+                    )
+                })
+                .buffer_unordered(ADDITION_CONCURRENCY)
+                // Only fails once recording has, and stopped listening.
+                .for_each(move |outcome| sender.send(outcome).map_err(|_| ())),
+        );
+    }
+    for (github_id, added) in added {
+        let (github_name, handle) = &planned_handles[&github_id];
+        let result = if added { "added" } else { "failed" };
+        audit.record(
+            "add",
+            Some(github_id),
+            Some(github_name),
+            Some(handle),
+            result,
+        )?;
+        if added {
+            // keep track of the invitation locally so that we don't
+            // spam users that have already been invited and rejected
+            // the invitation
+            invited.add(github_id);
+        }
+        if let Some(ref mut checkpoint) = checkpoint {
+            checkpoint.complete(github_id, result)?;
+        }
+    }
+
+    let mut to_notify: Vec<(GitHubName, GitHubID, Option<Handle>)> = vec![];
+
+    // Removals are high-risk, so they stay strictly one at a time.
+    for (github_name, github_id, handle) in actions.removals {
+        let logger = action_logger(github_id).new(o!(
+            "github-name" => format!("{}", github_name),
+            "nixpkgs-handle" => handle.as_ref().map(|handle| format!("{}", handle)),
+        ));
+
+        let mut audit_remove = |result: &str| {
+            audit.record(
+                "remove",
+                Some(github_id),
+                Some(&github_name),
+                handle.as_ref(),
+                result,
+            )
+        };
+
+        if no_remove {
+            skipped.inc();
+            debug!(logger, "Not removing user, because of --no-remove");
+            audit_remove("skipped")?;
+            continue;
+        }
+
+        if protected.contains(&github_name, &github_id) {
+            noops.inc();
+            protected_members.inc();
+            info!(logger, "User is protected, not removing from the team");
+            audit_remove("protected")?;
+            continue;
+        }
+
+        if !remove_owners {
+            let role = rt.block_on(github.get_org_role(org, &github_name), &calls.get_org_role);
+            match role {
+                Ok(OrgRole::Member) => {}
+                Ok(OrgRole::Owner) => {
                     noops.inc();
-                    previously_invited.inc();
-                    debug!(logger, "User was already invited previously (since there's no pending invitation we can assume the user rejected the invite)");
-                } else {
-                    additions.inc();
-                    info!(logger, "Adding user to the team");
-
-                    if do_it_live {
-                        // verify the ID and name still match
-                        let get_user = rt.block_on(
-                            github.users().get(&format!("{}", github_name)),
-                            &github_get_user_histogram,
-                            &github_get_user_failures,
-                        )
-                            .map_err(|e| {
-                                errors.inc();
-                                warn!(logger, "Failed to fetch user by name, incrementing noops. error: {:#?}", e);
-                                e
-                            })
-                            .map(|user| {
-                                if GitHubID::new(user.id) != github_id {
-                                    github_user_unchanged_username_id_mismatch.inc();
-                                    warn!(logger, "Recorded username mismatch, not adding");
-                                    None
-                                } else {
-                                    Some(user)
-                                }
-                            });
-
-                        if let Ok(Some(_user)) = get_user {
-                            let add_attempt = rt.block_on(
-                                team_actions.add_user(
-                                    &format!("{}", github_name),
-                                    TeamMemberOptions {
-                                        role: TeamMemberRole::Member,
-                                    },
-                                ),
-                                &github_add_user_histogram,
-                                &github_add_user_failures,
-                            );
-
-                            match add_attempt {
-                                Ok(_) => {
-                                    // keep track of the invitation locally so that we don't
-                                    // spam users that have already been invited and rejected
-                                    // the invitation
-                                    invited.add(github_id.clone());
-                                }
-                                Err(e) => {
-                                    errors.inc();
-                                    warn!(logger, "Failed to add a user to the team, not decrementing additions as it may have succeeded: {:#?}", e);
-                                }
-                            }
-                        }
+                    owners_kept.inc();
+                    warn!(logger, "User is an organization owner, not removing from the team without --remove-owners");
+                    audit_remove("owner")?;
+                    // Saves looking up their role again on resume.
+                    if let Some(ref mut checkpoint) = checkpoint {
+                        checkpoint.complete(github_id, "owner")?;
                     }
+                    continue;
+                }
+                Err(e) => {
+                    errors.inc();
+                    warn!(logger, "Failed to fetch the user's organization role, not removing from the team. error: {:#?}", e);
+                    audit_remove("lookup_failed")?;
+                    continue;
                 }
             }
-            TeamAction::Keep(handle) => {
-                let logger = logger.new(o!(
-                    "nixpkgs-handle" => format!("{}", handle),
-                ));
+        }
 
+        if let Some(ref notified) = notified {
+            if !notified.contains(&github_id) {
                 noops.inc();
-                trace!(logger, "Keeping user on the team");
+                info!(logger, "Notifying user before removing them from the team");
+                audit_remove("notify")?;
+                to_notify.push((github_name, github_id, handle));
+                continue;
             }
-            TeamAction::Remove(github_name, github_id) => {
-                let logger = logger.new(o!(
-                    "github-name" => format!("{}", github_name),                ));
-
-                removals.inc();
-                info!(logger, "Removing user from the team");
-                if do_it_live {
-                    // verify the ID and name still match
-                    let get_user = rt
-                        .block_on(
-                            github.users().get(&format!("{}", github_name)),
-                            &github_get_user_histogram,
-                            &github_get_user_failures,
-                        )
-                        .map_err(|e| {
-                            errors.inc();
-                            warn!(
-                                logger,
-                                "Failed to fetch user by name, incrementing noops. error: {:#?}", e
-                            );
-                            e
-                        })
-                        .map(|user| {
-                            if GitHubID::new(user.id) != github_id {
-                                github_user_unchanged_username_id_mismatch.inc();
-                                warn!(logger, "Recorded username mismatch, not adding");
-                                None
-                            } else {
-                                Some(user)
-                            }
-                        });
+            if notified.invited_within(&github_id, Some(removal_grace)) {
+                noops.inc();
+                awaiting_grace.inc();
+                debug!(
+                    logger,
+                    "User was notified recently, not removing from the team yet"
+                );
+                audit_remove("grace_period")?;
+                continue;
+            }
+        }
 
-                    if let Ok(Some(_)) = get_user {
-                        let remove_attempt = rt.block_on(
-                            team_actions.remove_user(&format!("{}", github_name)),
-                            &github_remove_user_histogram,
-                            &github_remove_user_failures,
-                        );
+        if remove_limit_reached() {
+            info!(logger, "Hit maximum change limit");
+            audit_remove("limit_reached")?;
+            limit_hit = true;
+            break;
+        }
+
+        removals.inc();
+        info!(logger, "Removing user from the team");
+        if !do_it_live {
+            audit_remove("planned")?;
+            planned.push(PlannedAction::Remove {
+                github_name: github_name.clone(),
+                github_id,
+                handle: handle.clone(),
+            });
+        } else {
+            // verify the ID and name still match
+            let get_user = rt
+                .block_on(github.get_user(&github_name), &calls.get_user)
+                .map_err(|e| {
+                    errors.inc();
+                    warn!(
+                        logger,
+                        "Failed to fetch user by name, incrementing noops. error: {:#?}", e
+                    );
+                    e
+                })
+                .map(|user| {
+                    if id_still_matches(
+                        &logger,
+                        github_id,
+                        user.id,
+                        remove_blocked_id_mismatch,
+                        "removing",
+                    ) {
+                        Some(user)
+                    } else {
+                        None
+                    }
+                });
+
+            let result = match get_user {
+                Ok(Some(_)) => {
+                    let remove_attempt = rt.block_on(
+                        github.remove_user(org, team_id, &github_name),
+                        &calls.remove_user,
+                    );
 
-                        match remove_attempt {
-                            Ok(_) => invited.remove(&github_id),
-                            Err(e) => {
-                                errors.inc();
-                                warn!(logger, "Failed to remove a user from the team: {:#?}", e);
+                    match remove_attempt {
+                        Ok(_) => {
+                            invited.remove(&github_id);
+                            if let Some(ref mut notified) = notified {
+                                notified.remove(&github_id);
                             }
+                            "removed"
+                        }
+                        Err(e) => {
+                            errors.inc();
+                            warn!(logger, "Failed to remove a user from the team: {:#?}", e);
+                            "failed"
                         }
                     }
                 }
+                Ok(None) => "id_mismatch",
+                Err(_) => "lookup_failed",
+            };
+            audit_remove(result)?;
+
+            if let Some(ref mut checkpoint) = checkpoint {
+                checkpoint.complete(github_id, result)?;
             }
         }
     }
 
+    if let Some(ref path) = plan_out {
+        let plan = Plan {
+            organization: org.to_string(),
+            team_id,
+            actions: planned,
+        };
+        plan.save(&logger, path)?;
+    }
+
     invited.save(&invited_list)?;
-    invited_list_saved_gauge.set(invited.len().try_into().unwrap());
+    invited_list_saved.set(invited.len().try_into().unwrap());
+
+    if let (Some(repo), Some(mut notified)) = (notify_issue, notified) {
+        // Anyone who was relisted since their notice starts over.
+        notified.retain(|id| unwanted.contains(id));
+
+        if !to_notify.is_empty() {
+            let (title, body) = removal_notice(org, &team.name, removal_grace_days, &to_notify);
+            if do_it_live {
+                let created = rt.block_on(
+                    github.create_issue(&repo, &title, &body),
+                    &calls.create_issue,
+                );
+                match created {
+                    Ok(number) => {
+                        info!(logger, "Notified team members of their removal";
+                              "repo" => %repo,
+                              "issue" => number,
+                              "users" => to_notify.len(),
+                        );
+                        for (_, github_id, _) in &to_notify {
+                            notified.add(*github_id);
+                        }
+                    }
+                    Err(e) => {
+                        errors.inc();
+                        warn!(logger, "Failed to open the removal notice, they'll be notified next run: {:#?}", e;
+                              "repo" => %repo,
+                        );
+                    }
+                }
+            } else {
+                info!(logger, "Would open a removal notice";
+                      "repo" => %repo,
+                      "title" => &title,
+                      "users" => to_notify.len(),
+                );
+            }
+        }
+
+        if do_it_live {
+            if let Some(ref path) = notified_list {
+                notified.save(path)?;
+            }
+        }
+    }
+
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.clear()?;
+    }
+
+    let run_summary = Summary {
+        dry_run,
+        additions: additions.get(),
+        removals: removals.get(),
+        keeps,
+        noops: noops.get(),
+        errors: errors.get(),
+        id_mismatches: add_blocked_id_mismatch.get() + remove_blocked_id_mismatch.get(),
+        limit_hit,
+        unmatched,
+    };
+    print!("{}", run_summary);
+    if let Some(ref path) = summary {
+        serde_json::to_writer_pretty(File::create(path)?, &run_summary)?;
+    }
+    if let Some(ref url) = webhook {
+        let payload = webhook_payload(org, &team.name, &run_summary);
+        if let Err(e) = webhook::post(&logger, rt.rt, url, &payload) {
+            warn!(logger, "Failed to post the summary to the webhook: {}", e);
+        }
+    }
 
     Ok(())
 }
 
-struct TrackedReactor {
-    rt: Runtime,
+/// What a sync run did, all in one place.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Summary {
+    dry_run: bool,
+    additions: i64,
+    removals: i64,
+    keeps: usize,
+    noops: i64,
+    errors: i64,
+    /// Users left alone because their GitHub name now belongs to a
+    /// different ID.
+    id_mismatches: i64,
+    limit_hit: bool,
+    /// Every team member up for removal, whether or not this run
+    /// removed them.
+    unmatched: Vec<UnmatchedMember>,
+}
+
+/// The summary as Slack (`text`) and Discord (`content`) incoming
+/// webhooks both accept it, with the counts alongside for anything
+/// else.
+fn webhook_payload(org: &str, team_name: &str, summary: &Summary) -> serde_json::Value {
+    let message = format!(
+        "rfc39 synced {}/{}{}: {} added, {} removed, {} noops, {} errors",
+        org,
+        team_name,
+        if summary.dry_run { " (dry run)" } else { "" },
+        summary.additions,
+        summary.removals,
+        summary.noops,
+        summary.errors,
+    );
+    serde_json::json!({
+        "text": message,
+        "content": message,
+        "dryRun": summary.dry_run,
+        "additions": summary.additions,
+        "removals": summary.removals,
+        "noops": summary.noops,
+        "errors": summary.errors,
+    })
+}
+
+/// A team member no maintainer lists with their GitHub ID.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UnmatchedMember {
+    github_name: GitHubName,
+    github_id: GitHubID,
+    /// The maintainer whose entry has this GitHub name, but a missing
+    /// or different ID. None means the maintainer file doesn't
+    /// mention them at all: they left the list, or were added to the
+    /// team by hand.
+    listed_by: Option<Handle>,
+}
+
+fn unmatched_members(removals: &[(GitHubName, GitHubID, Option<Handle>)]) -> Vec<UnmatchedMember> {
+    removals
+        .iter()
+        .map(|(github_name, github_id, handle)| UnmatchedMember {
+            github_name: github_name.clone(),
+            github_id: *github_id,
+            listed_by: handle.clone(),
+        })
+        .collect()
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.dry_run {
+            writeln!(f, "Sync summary (dry run):")?;
+        } else {
+            writeln!(f, "Sync summary:")?;
+        }
+        writeln!(f, "  additions:     {}", self.additions)?;
+        writeln!(f, "  removals:      {}", self.removals)?;
+        writeln!(f, "  keeps:         {}", self.keeps)?;
+        writeln!(f, "  noops:         {}", self.noops)?;
+        writeln!(f, "  errors:        {}", self.errors)?;
+        writeln!(f, "  id mismatches: {}", self.id_mismatches)?;
+        if self.limit_hit {
+            writeln!(f, "  stopped early: change limit reached")?;
+        }
+        for member in &self.unmatched {
+            match &member.listed_by {
+                Some(handle) => writeln!(
+                    f,
+                    "  unmatched: {} ({}), listed by {} without this ID",
+                    member.github_name, member.github_id, handle
+                )?,
+                None => writeln!(
+                    f,
+                    "  unmatched: {} ({}), not in the maintainer file",
+                    member.github_name, member.github_id
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct TrackedReactor<'a> {
+    rt: &'a mut Runtime,
 }
 
-impl TrackedReactor {
-    fn block_on<F, E, I>(
-        &mut self,
-        what: F,
-        histogram: &Histogram,
-        fails: &IntCounter,
-    ) -> Result<I, E>
+impl<'a> TrackedReactor<'a> {
+    fn block_on<F, E, I>(&mut self, what: F, call: &GitHubCall) -> Result<I, E>
     where
-        F: Send + 'static + futures::future::Future<Item = I, Error = E>,
+        F: Send + 'static + Future<Item = I, Error = E>,
         E: Send + 'static,
         I: Send + 'static,
     {
-        GITHUB_CALLS.inc();
-        let _timer = histogram.start_timer();
-        self.rt.block_on(what).map_err(|e| {
+        self.rt.block_on(tracked(what, call))
+    }
+}
+
+/// Wrap a GitHub call so it is counted, timed, and its failures
+/// recorded, without blocking on it.
+fn tracked<F, E, I>(what: F, call: &GitHubCall) -> impl Future<Item = I, Error = E> + Send + 'static
+where
+    F: Send + 'static + Future<Item = I, Error = E>,
+    E: Send + 'static,
+    I: Send + 'static,
+{
+    call.all_calls.inc();
+    let timer = call.duration.start_timer();
+    let fails = call.failures.clone();
+    what.then(move |result| {
+        timer.observe_duration();
+        result.map_err(|e| {
             fails.inc();
             e
         })
+    })
+}
+
+/// Check the user currently holding the recorded name is still the
+/// recorded user. If not, count it against `blocked` so each kind of
+/// action can be told apart.
+fn id_still_matches(
+    logger: &slog::Logger,
+    recorded: GitHubID,
+    actual: GitHubID,
+    blocked: &IntGauge,
+    action: &str,
+) -> bool {
+    if recorded == actual {
+        true
+    } else {
+        blocked.inc();
+        warn!(logger, "Recorded username mismatch, not {}", action;
+              "actual-github-id" => %actual,
+        );
+        false
     }
 }
 
+/// How many additions are verified and performed at the same time.
+const ADDITION_CONCURRENCY: usize = 4;
+
+#[derive(Clone)]
+struct AdditionMetrics {
+    get_user: GitHubCall,
+    add_user: GitHubCall,
+    id_mismatch: IntGauge,
+    errors: IntCounter,
+}
+
+/// Verify the recorded name still belongs to the recorded ID, then add
+/// the user to the team. Resolves to the ID and whether the user was
+/// added.
+fn add_member(
+    github: &impl GitHubApi,
+    org: &str,
+    team_id: u64,
+    logger: slog::Logger,
+    github_name: GitHubName,
+    github_id: GitHubID,
+    role: TeamRole,
+    metrics: &AdditionMetrics,
+) -> Box<dyn Future<Item = (GitHubID, bool), Error = ()> + Send> {
+    let github = github.clone();
+    let org = org.to_string();
+    let metrics = metrics.clone();
+
+    // verify the ID and name still match
+    let get_user = tracked(github.get_user(&github_name), &metrics.get_user);
+
+    Box::new(get_user.then(
+        move |get_user| -> Box<dyn Future<Item = (GitHubID, bool), Error = ()> + Send> {
+            match get_user {
+                Err(e) => {
+                    metrics.errors.inc();
+                    warn!(
+                        logger,
+                        "Failed to fetch user by name, incrementing noops. error: {:#?}", e
+                    );
+                    Box::new(future::ok((github_id, false)))
+                }
+                Ok(ref user)
+                    if !id_still_matches(
+                        &logger,
+                        github_id,
+                        user.id,
+                        &metrics.id_mismatch,
+                        "adding",
+                    ) =>
+                {
+                    Box::new(future::ok((github_id, false)))
+                }
+                Ok(user) => {
+                    // The maintainer file's casing may not be GitHub's,
+                    // which is the one to use from here on.
+                    if user.login.to_string() != github_name.to_string() {
+                        debug!(logger, "Using the GitHub name's casing from GitHub";
+                               "github-login" => %user.login,
+                        );
+                    }
+                    let errors = metrics.errors.clone();
+                    let add_attempt = tracked(
+                        github.add_user(&org, team_id, &user.login, role),
+                        &metrics.add_user,
+                    );
+
+                    Box::new(add_attempt.then(move |add_attempt| match add_attempt {
+                        Ok(_) => Ok((github_id, true)),
+                        Err(e) => {
+                            errors.inc();
+                            warn!(logger, "Failed to add a user to the team, not decrementing additions as it may have succeeded: {:#?}", e);
+                            Ok((github_id, false))
+                        }
+                    }))
+                }
+            }
+        },
+    ))
+}
+
 #[derive(Debug, PartialEq)]
 enum TeamAction {
     Add(GitHubName, GitHubID, Handle),
-    Remove(GitHubName, GitHubID),
+    /// The handle is the maintainer who still lists this GitHub name,
+    /// if any, but under a different or missing GitHub ID.
+    Remove(GitHubName, GitHubID, Option<Handle>),
     Keep(Handle),
+    /// Invited to the organization but hasn't accepted yet, so neither
+    /// on the team nor worth inviting again.
+    Pending(GitHubName, GitHubID, Handle),
+    /// A maintainer with a GitHub ID but no GitHub name, who can't be
+    /// added since team memberships are by name.
+    Unnamed(GitHubID, Handle),
 }
 
-fn maintainer_team_diff(
-    maintainers: MaintainerList,
-    teammembers: &HashMap<GitHubID, GitHubName>,
-) -> HashMap<GitHubID, TeamAction> {
-    let missing_github_handle = register_int_gauge!(
-        "rfc39_maintainer_missing_key_github",
-        "Maintainers missing a github handle."
-    )
-    .unwrap();
-    let missing_github_id = register_int_gauge!(
-        "rfc39_maintainer_missing_key_github_id",
-        "Maintainers missing a github_id."
-    )
-    .unwrap();
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MemberState {
+    Active,
+    PendingInvite,
+}
 
-    let mut diff: HashMap<GitHubID, TeamAction> = maintainers
+#[derive(Debug, Clone, PartialEq)]
+struct Member {
+    name: GitHubName,
+    state: MemberState,
+}
+
+/// Combine the team's members with the maintainers who have a pending
+/// invitation. Invitations only name the user, so the ID comes from
+/// the maintainer list.
+fn team_membership(
+    active: HashMap<GitHubID, GitHubName>,
+    pending_invites: &[GitHubName],
+    maintainers: &MaintainerList,
+) -> HashMap<GitHubID, Member> {
+    let mut members: HashMap<GitHubID, Member> = active
         .into_iter()
-        .inspect(|(_, maintainer)| {
-            if maintainer.github.is_none() {
-                missing_github_handle.inc();
-            }
-            if maintainer.github_id.is_none() {
-                missing_github_id.inc();
-            }
-        })
-        .filter_map(|(handle, m)| {
-            if teammembers.contains_key(&m.github_id?) {
-                Some((m.github_id?, TeamAction::Keep(handle)))
-            } else {
-                Some((
-                    m.github_id?,
-                    TeamAction::Add(m.github?, m.github_id?, handle),
-                ))
-            }
+        .map(|(github_id, name)| {
+            (
+                github_id,
+                Member {
+                    name,
+                    state: MemberState::Active,
+                },
+            )
         })
         .collect();
 
-    for (github_id, github_name) in teammembers {
-        // the diff list already has an entry for who should be in it
-        // now create removals for who should no longer be present
-        if !diff.contains_key(github_id) {
-            diff.insert(
-                *github_id,
-                TeamAction::Remove(github_name.clone(), *github_id),
-            );
+    for name in pending_invites {
+        let github_id = maintainers
+            .by_github_name(name)
+            .and_then(|(_, info)| info.github_id);
+        if let Some(github_id) = github_id {
+            members.entry(github_id).or_insert_with(|| Member {
+                name: name.clone(),
+                state: MemberState::PendingInvite,
+            });
         }
     }
 
-    diff
+    members
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::maintainers::Information;
+/// The team ID from --team-id or --team-slug.
+fn resolve_team_id(
+    logger: &slog::Logger,
+    org: &str,
+    team_id: Option<u64>,
+    team_slug: Option<String>,
+    org_teams: &[Team],
+) -> Result<u64, ExitError> {
+    match (team_id, team_slug) {
+        (Some(team_id), _) => {
+            let org_team_ids: Vec<u64> = org_teams.iter().map(|team| team.id).collect();
+            check_team_in_org(logger, org, team_id, &org_team_ids)?;
+            Ok(team_id)
+        }
+        (None, Some(slug)) => {
+            let team_id = team_id_for_slug(logger, org, &slug, org_teams)?;
+            info!(logger, "Resolved the team slug";
+                  "team_slug" => %slug,
+                  "team_id" => team_id,
+            );
+            Ok(team_id)
+        }
+        (None, None) => Err(ExitError::InvalidArguments(
+            "one of --team-id or --team-slug is required".to_string(),
+        )),
+    }
+}
 
-    #[test]
-    fn test_add_remove_members() {
-        let on_github: HashMap<GitHubID, GitHubName> = vec![
-            (GitHubID::new(1), GitHubName::new("alice")),
-            (GitHubID::new(2), GitHubName::new("bob")),
-        ]
-        .into_iter()
-        .collect();
+/// Make sure the team ID is one of the organization's teams, which
+/// catches a team ID copied from a different organization.
+fn check_team_in_org(
+    logger: &slog::Logger,
+    org: &str,
+    team_id: u64,
+    org_team_ids: &[u64],
+) -> Result<(), ExitError> {
+    if org_team_ids.contains(&team_id) {
+        Ok(())
+    } else {
+        error!(logger, "Team does not belong to the organization, use list-teams to find the right ID";
+               "organization" => org,
+               "team_id" => team_id,
+        );
+        Err(ExitError::TeamOrgMismatch {
+            organization: org.to_string(),
+            team_id,
+        })
+    }
+}
 
-        let wanted = MaintainerList::new(
+fn team_id_for_slug(
+    logger: &slog::Logger,
+    org: &str,
+    slug: &str,
+    org_teams: &[Team],
+) -> Result<u64, ExitError> {
+    match org_teams.iter().find(|team| team.slug == slug) {
+        Some(team) => Ok(team.id),
+        None => {
+            error!(logger, "No team with this slug in the organization, use list-teams to find it";
+                   "organization" => org,
+                   "team_slug" => slug,
+            );
+            Err(ExitError::UnknownTeamSlug {
+                organization: org.to_string(),
+                slug: slug.to_string(),
+            })
+        }
+    }
+}
+
+fn team_not_found(
+    logger: &slog::Logger,
+    org: &str,
+    team_id: u64,
+    what: &str,
+    error: GitHubError,
+) -> ExitError {
+    error!(logger, "{}, use list-teams to find the right ID", what;
+           "organization" => org,
+           "team_id" => team_id,
+           "error" => ?error,
+    );
+    ExitError::TeamNotFound {
+        organization: org.to_string(),
+        team_id,
+    }
+}
+
+fn github_failed(logger: &slog::Logger, org: &str, what: &str, error: GitHubError) -> ExitError {
+    error!(logger, "{}", what;
+           "organization" => org,
+           "error" => ?error,
+    );
+    ExitError::GitHub(what.to_string())
+}
+
+#[derive(Debug, PartialEq)]
+struct PartitionedActions {
+    additions: Vec<(GitHubName, GitHubID, Handle)>,
+    removals: Vec<(GitHubName, GitHubID, Option<Handle>)>,
+    keeps: Vec<Handle>,
+    pending: Vec<(GitHubName, GitHubID, Handle)>,
+    unnamed: Vec<(GitHubID, Handle)>,
+}
+
+/// Split a diff by kind so additions and removals can be processed
+/// differently. Each list is sorted by GitHub ID to keep runs
+/// predictable.
+fn partition_actions(diff: HashMap<GitHubID, TeamAction>) -> PartitionedActions {
+    let mut actions: Vec<(GitHubID, TeamAction)> = diff.into_iter().collect();
+    actions.sort_by_key(|(github_id, _)| *github_id);
+
+    let mut partitioned = PartitionedActions {
+        additions: vec![],
+        removals: vec![],
+        keeps: vec![],
+        pending: vec![],
+        unnamed: vec![],
+    };
+    for (_, action) in actions {
+        match action {
+            TeamAction::Add(github_name, github_id, handle) => {
+                partitioned.additions.push((github_name, github_id, handle))
+            }
+            TeamAction::Remove(github_name, github_id, handle) => {
+                partitioned.removals.push((github_name, github_id, handle))
+            }
+            TeamAction::Keep(handle) => partitioned.keeps.push(handle),
+            TeamAction::Pending(github_name, github_id, handle) => {
+                partitioned.pending.push((github_name, github_id, handle))
+            }
+            TeamAction::Unnamed(github_id, handle) => partitioned.unnamed.push((github_id, handle)),
+        }
+    }
+
+    partitioned
+}
+
+/// The plan's actions, in its order.
+fn plan_actions(plan: Plan) -> PartitionedActions {
+    let mut partitioned = PartitionedActions {
+        additions: vec![],
+        removals: vec![],
+        keeps: vec![],
+        pending: vec![],
+        unnamed: vec![],
+    };
+    for action in plan.actions {
+        match action {
+            PlannedAction::Add {
+                github_name,
+                github_id,
+                handle,
+            } => partitioned.additions.push((github_name, github_id, handle)),
+            PlannedAction::Remove {
+                github_name,
+                github_id,
+                handle,
+            } => partitioned.removals.push((github_name, github_id, handle)),
+        }
+    }
+
+    partitioned
+}
+
+fn check_plan_team(
+    logger: &slog::Logger,
+    org: &str,
+    team_id: u64,
+    plan: &Plan,
+) -> Result<(), ExitError> {
+    if plan.organization == org && plan.team_id == team_id {
+        Ok(())
+    } else {
+        error!(logger, "The plan is for a different team";
+               "plan_organization" => &plan.organization,
+               "plan_team_id" => plan.team_id,
+        );
+        Err(ExitError::InvalidArguments(format!(
+            "the plan is for team {} of {}, not team {} of {}",
+            plan.team_id, plan.organization, team_id, org
+        )))
+    }
+}
+
+/// Make sure the team is still as the plan found it: nobody it adds
+/// has joined or been invited since, everyone it removes is still on
+/// the team, and every name still belongs to the same GitHub ID.
+/// Every mismatch is logged before failing.
+fn check_plan(
+    logger: &slog::Logger,
+    github: &impl GitHubApi,
+    rt: &mut TrackedReactor,
+    get_user: &GitHubCall,
+    members: &HashMap<GitHubID, Member>,
+    plan: &Plan,
+) -> Result<(), ExitError> {
+    let mut drifted = 0;
+    for action in &plan.actions {
+        let logger = logger.new(o!(
+            "github-name" => format!("{}", action.github_name()),
+            "github-id" => format!("{}", action.github_id()),
+        ));
+
+        let team_matches = match (action, members.get(&action.github_id())) {
+            (PlannedAction::Add { .. }, None) => true,
+            (PlannedAction::Remove { github_name, .. }, Some(member)) => {
+                member.state == MemberState::Active && member.name == *github_name
+            }
+            _ => false,
+        };
+        if !team_matches {
+            warn!(logger, "Team membership changed since the plan was made");
+            drifted += 1;
+            continue;
+        }
+
+        match rt.block_on(github.get_user(action.github_name()), get_user) {
+            Ok(ref user) if user.id == action.github_id() => {}
+            Ok(user) => {
+                warn!(logger, "GitHub name belongs to a different user since the plan was made";
+                      "actual-github-id" => %user.id,
+                );
+                drifted += 1;
+            }
+            Err(e) => {
+                warn!(
+                    logger,
+                    "Failed to fetch user by name, can't check the plan. error: {:#?}", e
+                );
+                drifted += 1;
+            }
+        }
+    }
+
+    if drifted > 0 {
+        error!(logger, "The team changed since the plan was made, not carrying it out";
+               "drifted" => drifted,
+        );
+        return Err(ExitError::PlanDrifted(drifted));
+    }
+    Ok(())
+}
+
+/// Drop the actions a previous, interrupted sync already handled.
+fn skip_completed(
+    actions: PartitionedActions,
+    checkpoint: Option<&Checkpoint>,
+) -> PartitionedActions {
+    let checkpoint = match checkpoint {
+        Some(checkpoint) => checkpoint,
+        None => return actions,
+    };
+
+    PartitionedActions {
+        additions: actions
+            .additions
+            .into_iter()
+            .filter(|(_, github_id, _)| !checkpoint.done(github_id))
+            .collect(),
+        removals: actions
+            .removals
+            .into_iter()
+            .filter(|(_, github_id, _)| !checkpoint.done(github_id))
+            .collect(),
+        keeps: actions.keeps,
+        pending: actions.pending,
+        unnamed: actions.unnamed,
+    }
+}
+
+/// The active team members no maintainer has the ID of, who are the
+/// removals a full diff would come up with.
+fn unwanted_members(
+    maintainers: &MaintainerList,
+    teammembers: &HashMap<GitHubID, Member>,
+) -> HashSet<GitHubID> {
+    teammembers
+        .iter()
+        .filter(|(github_id, member)| {
+            member.state == MemberState::Active && maintainers.by_github_id(github_id).is_none()
+        })
+        .map(|(github_id, _)| *github_id)
+        .collect()
+}
+
+fn maintainer_team_diff(
+    maintainers: MaintainerList,
+    teammembers: &HashMap<GitHubID, Member>,
+    metrics: &MaintainerMetrics,
+) -> HashMap<GitHubID, TeamAction> {
+    // Name the maintainer behind a removal where we can, which is
+    // usually someone whose githubId is missing or out of date.
+    let removal_handles: HashMap<GitHubID, Handle> = teammembers
+        .iter()
+        .filter(|(github_id, _)| maintainers.by_github_id(github_id).is_none())
+        .filter_map(|(github_id, member)| {
+            let (handle, _) = maintainers.by_github_name(&member.name)?;
+            Some((*github_id, handle.clone()))
+        })
+        .collect();
+
+    let mut diff: HashMap<GitHubID, TeamAction> = maintainers
+        .into_iter()
+        .inspect(|(_, maintainer)| {
+            if maintainer.github.is_none() {
+                metrics.missing_github.inc();
+            }
+            if maintainer.github_id.is_none() {
+                metrics.missing_github_id.inc();
+            }
+        })
+        .filter_map(|(handle, m)| {
+            let github_id = m.github_id?;
+            match teammembers.get(&github_id) {
+                Some(member) if member.state == MemberState::Active => {
+                    Some((github_id, TeamAction::Keep(handle)))
+                }
+                // GitHub's casing, which the maintainer file may not
+                // share, since names match case-insensitively.
+                Some(member) => Some((
+                    github_id,
+                    TeamAction::Pending(member.name.clone(), github_id, handle),
+                )),
+                None => match m.github {
+                    Some(github_name) => {
+                        Some((github_id, TeamAction::Add(github_name, github_id, handle)))
+                    }
+                    None => {
+                        metrics.id_without_github.inc();
+                        Some((github_id, TeamAction::Unnamed(github_id, handle)))
+                    }
+                },
+            }
+        })
+        .collect();
+
+    for (github_id, member) in teammembers {
+        // the diff list already has an entry for who should be in it
+        // now create removals for who should no longer be present
+        if !diff.contains_key(github_id) && member.state == MemberState::Active {
+            diff.insert(
+                *github_id,
+                TeamAction::Remove(
+                    member.name.clone(),
+                    *github_id,
+                    removal_handles.get(github_id).cloned(),
+                ),
+            );
+        }
+    }
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::{FakeGitHub, FakeState};
+    use crate::maintainers::Information;
+
+    fn maintainer(github: &str, github_id: u64) -> Information {
+        Information {
+            email: None,
+            name: None,
+            github: Some(GitHubName::new(github)),
+            github_id: Some(GitHubID::new(github_id)),
+            matrix: None,
+            keys: None,
+        }
+    }
+
+    fn user(login: &str, id: u64) -> User {
+        User {
+            login: GitHubName::new(login),
+            id: GitHubID::new(id),
+        }
+    }
+
+    #[test]
+    fn test_sync_team() {
+        let github = FakeGitHub::new(FakeState {
+            users: vec![
+                user("alice", 1),
+                user("bob", 2),
+                user("carol", 3),
+                user("dave", 4),
+                user("erin", 50),
+            ],
+            teams: vec![Team {
+                id: 10,
+                name: "maintainers".into(),
+                slug: "maintainers".into(),
+            }],
+            members: vec![(10, vec![user("bob", 2), user("carol", 3), user("oscar", 6)])]
+                .into_iter()
+                .collect(),
+            // dave's invitation is on the second page
+            invitations: vec![
+                GitHubName::new("frank"),
+                GitHubName::new("grace"),
+                GitHubName::new("dave"),
+            ],
+            owners: vec![GitHubName::new("oscar")],
+            ..FakeState::default()
+        });
+
+        let maintainers = MaintainerList::new(
+            vec![
+                // added
+                (Handle::new("alice"), maintainer("alice", 1)),
+                // kept
+                (Handle::new("bob"), maintainer("bob", 2)),
+                // already invited
+                (Handle::new("dave"), maintainer("dave", 4)),
+                // the name now belongs to someone else
+                (Handle::new("erin"), maintainer("erin", 5)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let invited_list = tmpdir.path().join("invited.txt");
+        let metrics = Metrics::unregistered();
+
+        sync_team(
+            crate::test_logger(),
+            &github,
+            &mut Runtime::new().unwrap(),
+            &metrics,
+            maintainers,
+            SyncTeamParams {
+                organization: "NixOS".into(),
+                team_id: None,
+                team_slug: Some("maintainers".into()),
+                dry_run: false,
+                role: TeamRole::Member,
+                no_add: false,
+                no_remove: false,
+                remove_owners: false,
+                limit: None,
+                add_limit: None,
+                remove_limit: None,
+                invited_list: invited_list.clone(),
+                reinvite_after: None,
+                protect: None,
+                audit_log: None,
+                summary: None,
+                checkpoint_file: None,
+                notify_issue: None,
+                notified_list: None,
+                removal_grace_days: 7,
+                webhook: None,
+                plan_out: None,
+                plan_in: None,
+            },
+        )
+        .unwrap();
+
+        // carol is not a maintainer, dave wasn't invited again, and
+        // oscar owns the organization
+        assert_eq!(
+            github.members(10),
+            vec![user("alice", 1), user("bob", 2), user("oscar", 6)]
+        );
+        assert!(std::fs::read_to_string(&invited_list)
+            .unwrap()
+            .starts_with("1,"));
+        assert_eq!(metrics.sync.additions.get(), 2);
+        assert_eq!(metrics.sync.removals.get(), 1);
+        assert_eq!(metrics.sync.owners_kept.get(), 1);
+        assert_eq!(metrics.sync.add_blocked_id_mismatch.get(), 1);
+    }
+
+    fn plan_github() -> FakeGitHub {
+        FakeGitHub::new(FakeState {
+            users: vec![user("alice", 1), user("bob", 2), user("carol", 3)],
+            teams: vec![Team {
+                id: 10,
+                name: "maintainers".into(),
+                slug: "maintainers".into(),
+            }],
+            members: vec![(10, vec![user("bob", 2), user("carol", 3)])]
+                .into_iter()
+                .collect(),
+            ..FakeState::default()
+        })
+    }
+
+    fn plan_params(tmpdir: &std::path::Path) -> SyncTeamParams {
+        SyncTeamParams {
+            organization: "NixOS".into(),
+            team_id: Some(10),
+            team_slug: None,
+            dry_run: false,
+            role: TeamRole::Member,
+            no_add: false,
+            no_remove: false,
+            remove_owners: false,
+            limit: None,
+            add_limit: None,
+            remove_limit: None,
+            invited_list: tmpdir.join("invited.txt"),
+            reinvite_after: None,
+            protect: None,
+            audit_log: None,
+            summary: None,
+            checkpoint_file: None,
+            notify_issue: None,
+            notified_list: None,
+            removal_grace_days: 7,
+            webhook: None,
+            plan_out: None,
+            plan_in: None,
+        }
+    }
+
+    fn make_plan(github: &FakeGitHub, tmpdir: &std::path::Path) -> std::path::PathBuf {
+        let plan = tmpdir.join("plan.json");
+        let maintainers = MaintainerList::new(
+            vec![
+                (Handle::new("alice"), maintainer("alice", 1)),
+                (Handle::new("bob"), maintainer("bob", 2)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        sync_team(
+            crate::test_logger(),
+            github,
+            &mut Runtime::new().unwrap(),
+            &Metrics::unregistered(),
+            maintainers,
+            SyncTeamParams {
+                dry_run: true,
+                plan_out: Some(plan.clone()),
+                ..plan_params(tmpdir)
+            },
+        )
+        .unwrap();
+        plan
+    }
+
+    #[test]
+    fn test_plan_round_trip() {
+        let github = plan_github();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let plan = make_plan(&github, tmpdir.path());
+        assert_eq!(github.members(10), vec![user("bob", 2), user("carol", 3)]);
+
+        // The maintainer list plays no part once there is a plan.
+        sync_team(
+            crate::test_logger(),
+            &github,
+            &mut Runtime::new().unwrap(),
+            &Metrics::unregistered(),
+            MaintainerList::new(HashMap::new()),
+            SyncTeamParams {
+                plan_in: Some(plan),
+                ..plan_params(tmpdir.path())
+            },
+        )
+        .unwrap();
+        assert_eq!(github.members(10), vec![user("alice", 1), user("bob", 2)]);
+    }
+
+    #[test]
+    fn test_plan_drifted() {
+        let github = plan_github();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let plan = make_plan(&github, tmpdir.path());
+
+        // alice renamed their account and someone else took the name.
+        github.set_users(vec![user("alice", 7), user("bob", 2), user("carol", 3)]);
+        let result = sync_team(
+            crate::test_logger(),
+            &github,
+            &mut Runtime::new().unwrap(),
+            &Metrics::unregistered(),
+            MaintainerList::new(HashMap::new()),
+            SyncTeamParams {
+                plan_in: Some(plan),
+                ..plan_params(tmpdir.path())
+            },
+        );
+        match result {
+            Err(ExitError::PlanDrifted(1)) => {}
+            other => panic!("expected one drifted action, got {:?}", other),
+        }
+        // Not even carol's removal, which still matched, went ahead.
+        assert_eq!(github.members(10), vec![user("bob", 2), user("carol", 3)]);
+    }
+
+    #[test]
+    fn test_add_remove_members() {
+        let on_github: HashMap<GitHubID, GitHubName> = vec![
+            (GitHubID::new(1), GitHubName::new("alice")),
+            (GitHubID::new(2), GitHubName::new("bob")),
+            (GitHubID::new(4), GitHubName::new("dave")),
+        ]
+        .into_iter()
+        .collect();
+
+        let wanted = MaintainerList::new(
             vec![
                 (
                     Handle::new("bob"),
@@ -472,6 +1702,8 @@ mod tests {
                         name: Some("Bob".into()),
                         github: Some(GitHubName::new("bob")),
                         github_id: Some(GitHubID::new(2)),
+                        matrix: None,
+                        keys: None,
                     },
                 ),
                 (
@@ -481,6 +1713,19 @@ mod tests {
                         name: Some("Charlie".into()),
                         github: Some(GitHubName::new("charlie")),
                         github_id: Some(GitHubID::new(3)),
+                        matrix: None,
+                        keys: None,
+                    },
+                ),
+                (
+                    Handle::new("dave"),
+                    Information {
+                        email: Some("dave@example.com".into()),
+                        name: Some("Dave".into()),
+                        github: Some(GitHubName::new("Dave")),
+                        github_id: Some(GitHubID::new(40)),
+                        matrix: None,
+                        keys: None,
                     },
                 ),
             ]
@@ -492,7 +1737,7 @@ mod tests {
             vec![
                 (
                     GitHubID::new(1),
-                    TeamAction::Remove(GitHubName::new("alice"), GitHubID::new(1))
+                    TeamAction::Remove(GitHubName::new("alice"), GitHubID::new(1), None)
                 ),
                 (GitHubID::new(2), TeamAction::Keep(Handle::new("bob"))),
                 (
@@ -503,10 +1748,627 @@ mod tests {
                         Handle::new("charlie")
                     )
                 ),
+                (
+                    GitHubID::new(4),
+                    TeamAction::Remove(
+                        GitHubName::new("dave"),
+                        GitHubID::new(4),
+                        Some(Handle::new("dave"))
+                    )
+                ),
+                (
+                    GitHubID::new(40),
+                    TeamAction::Add(
+                        GitHubName::new("Dave"),
+                        GitHubID::new(40),
+                        Handle::new("dave")
+                    )
+                ),
             ]
             .into_iter()
             .collect::<HashMap<GitHubID, TeamAction>>(),
-            maintainer_team_diff(wanted, &on_github)
+            maintainer_team_diff(
+                wanted,
+                &team_membership(on_github, &[], &MaintainerList::new(HashMap::new())),
+                &Metrics::unregistered().maintainers
+            )
+        );
+    }
+
+    #[test]
+    fn test_pending_invite_member() {
+        let maintainers = || {
+            MaintainerList::new(
+                vec![
+                    (Handle::new("alice"), maintainer("alice", 1)),
+                    (Handle::new("bob"), maintainer("bob", 2)),
+                ]
+                .into_iter()
+                .collect(),
+            )
+        };
+        let active: HashMap<GitHubID, GitHubName> =
+            vec![(GitHubID::new(1), GitHubName::new("alice"))]
+                .into_iter()
+                .collect();
+        // carol isn't a maintainer, so there's no ID for her invite
+        let pending_invites = vec![
+            GitHubName::new("alice"),
+            GitHubName::new("bob"),
+            GitHubName::new("carol"),
+        ];
+
+        let members = team_membership(active, &pending_invites, &maintainers());
+        assert_eq!(
+            members,
+            vec![
+                (
+                    GitHubID::new(1),
+                    Member {
+                        name: GitHubName::new("alice"),
+                        state: MemberState::Active,
+                    }
+                ),
+                (
+                    GitHubID::new(2),
+                    Member {
+                        name: GitHubName::new("bob"),
+                        state: MemberState::PendingInvite,
+                    }
+                ),
+            ]
+            .into_iter()
+            .collect()
+        );
+
+        assert_eq!(
+            maintainer_team_diff(
+                maintainers(),
+                &members,
+                &Metrics::unregistered().maintainers
+            ),
+            vec![
+                (GitHubID::new(1), TeamAction::Keep(Handle::new("alice"))),
+                (
+                    GitHubID::new(2),
+                    TeamAction::Pending(
+                        GitHubName::new("bob"),
+                        GitHubID::new(2),
+                        Handle::new("bob")
+                    )
+                ),
+            ]
+            .into_iter()
+            .collect()
+        );
+
+        // Someone who was invited but is no longer a maintainer isn't
+        // removed, since they aren't on the team.
+        let pending_only: HashMap<GitHubID, Member> = vec![(
+            GitHubID::new(2),
+            Member {
+                name: GitHubName::new("bob"),
+                state: MemberState::PendingInvite,
+            },
+        )]
+        .into_iter()
+        .collect();
+        assert!(maintainer_team_diff(
+            MaintainerList::new(HashMap::new()),
+            &pending_only,
+            &Metrics::unregistered().maintainers
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_diff_mixed_case() {
+        let maintainers = MaintainerList::new(
+            vec![
+                (Handle::new("alice"), maintainer("Alice", 1)),
+                (Handle::new("bob"), maintainer("bob", 2)),
+                (Handle::new("carol"), maintainer("carol", 30)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let members = team_membership(
+            vec![
+                (GitHubID::new(2), GitHubName::new("BOB")),
+                (GitHubID::new(3), GitHubName::new("Carol")),
+            ]
+            .into_iter()
+            .collect(),
+            &[GitHubName::new("alice")],
+            &maintainers,
+        );
+
+        let actions = partition_actions(maintainer_team_diff(
+            maintainers,
+            &members,
+            &Metrics::unregistered().maintainers,
+        ));
+        assert_eq!(actions.keeps, vec![Handle::new("bob")]);
+        // Names match case-insensitively, so compare the strings to
+        // see they come out as GitHub has them, not as the file does.
+        assert_eq!(actions.pending.len(), 1);
+        assert_eq!(actions.pending[0].0.to_string(), "alice");
+        assert_eq!(actions.removals.len(), 1);
+        assert_eq!(actions.removals[0].0.to_string(), "Carol");
+        assert_eq!(actions.removals[0].2, Some(Handle::new("carol")));
+    }
+
+    #[test]
+    fn test_unwanted_members() {
+        let maintainers = MaintainerList::new(
+            vec![
+                (Handle::new("alice"), maintainer("alice", 1)),
+                (Handle::new("bob"), maintainer("bob", 2)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let members = team_membership(
+            vec![
+                (GitHubID::new(1), GitHubName::new("alice")),
+                (GitHubID::new(3), GitHubName::new("carol")),
+            ]
+            .into_iter()
+            .collect(),
+            &[GitHubName::new("bob")],
+            &maintainers,
+        );
+
+        let unwanted = unwanted_members(&maintainers, &members);
+        let removals: HashSet<GitHubID> = partition_actions(maintainer_team_diff(
+            maintainers,
+            &members,
+            &Metrics::unregistered().maintainers,
+        ))
+        .removals
+        .into_iter()
+        .map(|(_, github_id, _)| github_id)
+        .collect();
+        assert_eq!(unwanted, vec![GitHubID::new(3)].into_iter().collect());
+        assert_eq!(unwanted, removals);
+    }
+
+    #[test]
+    fn test_diff_id_without_github() {
+        let maintainers = MaintainerList::new(
+            vec![(
+                Handle::new("frank"),
+                Information {
+                    github: None,
+                    ..maintainer("frank", 6)
+                },
+            )]
+            .into_iter()
+            .collect(),
+        );
+
+        // Not silently dropped, but not addable either.
+        assert_eq!(
+            maintainer_team_diff(
+                maintainers,
+                &HashMap::new(),
+                &Metrics::unregistered().maintainers
+            ),
+            vec![(
+                GitHubID::new(6),
+                TeamAction::Unnamed(GitHubID::new(6), Handle::new("frank"))
+            )]
+            .into_iter()
+            .collect()
+        );
+    }
+
+    #[test]
+    fn test_partition_actions() {
+        let diff: HashMap<GitHubID, TeamAction> = vec![
+            (
+                GitHubID::new(4),
+                TeamAction::Remove(GitHubName::new("dave"), GitHubID::new(4), None),
+            ),
+            (GitHubID::new(2), TeamAction::Keep(Handle::new("bob"))),
+            (
+                GitHubID::new(3),
+                TeamAction::Add(
+                    GitHubName::new("charlie"),
+                    GitHubID::new(3),
+                    Handle::new("charlie"),
+                ),
+            ),
+            (
+                GitHubID::new(5),
+                TeamAction::Pending(
+                    GitHubName::new("erin"),
+                    GitHubID::new(5),
+                    Handle::new("erin"),
+                ),
+            ),
+            (
+                GitHubID::new(1),
+                TeamAction::Remove(GitHubName::new("alice"), GitHubID::new(1), None),
+            ),
+            (
+                GitHubID::new(6),
+                TeamAction::Unnamed(GitHubID::new(6), Handle::new("frank")),
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            PartitionedActions {
+                additions: vec![(
+                    GitHubName::new("charlie"),
+                    GitHubID::new(3),
+                    Handle::new("charlie")
+                )],
+                removals: vec![
+                    (GitHubName::new("alice"), GitHubID::new(1), None),
+                    (GitHubName::new("dave"), GitHubID::new(4), None),
+                ],
+                keeps: vec![Handle::new("bob")],
+                pending: vec![(
+                    GitHubName::new("erin"),
+                    GitHubID::new(5),
+                    Handle::new("erin")
+                )],
+                unnamed: vec![(GitHubID::new(6), Handle::new("frank"))],
+            },
+            partition_actions(diff)
+        );
+    }
+
+    #[test]
+    fn test_format_plan() {
+        let actions = PartitionedActions {
+            additions: vec![(
+                GitHubName::new("charlie"),
+                GitHubID::new(3),
+                Handle::new("charlie"),
+            )],
+            removals: vec![
+                (
+                    GitHubName::new("alice"),
+                    GitHubID::new(1),
+                    Some(Handle::new("alice")),
+                ),
+                (GitHubName::new("dave"), GitHubID::new(4), None),
+            ],
+            keeps: vec![Handle::new("bob")],
+            pending: vec![(
+                GitHubName::new("erin"),
+                GitHubID::new(5),
+                Handle::new("erin"),
+            )],
+            unnamed: vec![(GitHubID::new(6), Handle::new("frank"))],
+        };
+
+        assert_eq!(
+            format_plan(&actions),
+            "add charlie (3) for charlie
+remove alice (1), still listed by alice
+remove dave (4)
+pending erin (5) for erin
+skip frank (6), no GitHub handle
+keep 1 members
+"
+        );
+    }
+
+    #[test]
+    fn test_removal_notice() {
+        let (title, body) = removal_notice(
+            "NixOS",
+            "maintainers",
+            7,
+            &[
+                (
+                    GitHubName::new("alice"),
+                    GitHubID::new(1),
+                    Some(Handle::new("alice")),
+                ),
+                (GitHubName::new("dave"), GitHubID::new(4), None),
+            ],
+        );
+
+        assert_eq!(title, "Removing 2 from NixOS/maintainers");
+        assert_eq!(
+            body,
+            "These accounts will be removed from the NixOS/maintainers team in 7 days:
+
+- @alice: listed by `alice` without this GitHub ID
+- @dave: not in the maintainer list
+
+To stay on the team, add or fix your entry in maintainers/maintainer-list.nix before then.
+"
+        );
+    }
+
+    #[test]
+    fn test_skip_completed() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpfile = tmpdir.path().join("checkpoint.txt");
+        let mut checkpoint = Checkpoint::load(crate::test_logger(), &tmpfile).unwrap();
+        checkpoint.complete(GitHubID::new(1), "added").unwrap();
+        checkpoint.complete(GitHubID::new(2), "failed").unwrap();
+        checkpoint.complete(GitHubID::new(3), "removed").unwrap();
+
+        let actions = || PartitionedActions {
+            additions: vec![
+                (
+                    GitHubName::new("alice"),
+                    GitHubID::new(1),
+                    Handle::new("alice"),
+                ),
+                (GitHubName::new("bob"), GitHubID::new(2), Handle::new("bob")),
+            ],
+            removals: vec![
+                (GitHubName::new("charlie"), GitHubID::new(3), None),
+                (GitHubName::new("dave"), GitHubID::new(4), None),
+            ],
+            keeps: vec![Handle::new("eve")],
+            pending: vec![],
+            unnamed: vec![],
+        };
+
+        assert_eq!(skip_completed(actions(), None), actions());
+        assert_eq!(
+            skip_completed(actions(), Some(&checkpoint)),
+            PartitionedActions {
+                additions: vec![(GitHubName::new("bob"), GitHubID::new(2), Handle::new("bob"))],
+                removals: vec![(GitHubName::new("dave"), GitHubID::new(4), None)],
+                keeps: vec![Handle::new("eve")],
+                pending: vec![],
+                unnamed: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_check_team_in_org() {
+        let logger = crate::test_logger();
+
+        assert!(check_team_in_org(&logger, "NixOS", 2, &[1, 2, 3]).is_ok());
+        match check_team_in_org(&logger, "NixOS", 4, &[1, 2, 3]) {
+            Err(ExitError::TeamOrgMismatch {
+                organization,
+                team_id,
+            }) => {
+                assert_eq!(organization, "NixOS");
+                assert_eq!(team_id, 4);
+            }
+            other => panic!("expected a team/org mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let summary = Summary {
+            dry_run: true,
+            additions: 2,
+            removals: 1,
+            keeps: 10,
+            noops: 13,
+            errors: 0,
+            id_mismatches: 1,
+            limit_hit: true,
+            unmatched: unmatched_members(&[
+                (
+                    GitHubName::new("alice"),
+                    GitHubID::new(1),
+                    Some(Handle::new("alice")),
+                ),
+                (GitHubName::new("dave"), GitHubID::new(4), None),
+            ]),
+        };
+
+        assert_eq!(
+            summary.to_string(),
+            "Sync summary (dry run):
+  additions:     2
+  removals:      1
+  keeps:         10
+  noops:         13
+  errors:        0
+  id mismatches: 1
+  stopped early: change limit reached
+  unmatched: alice (1), listed by alice without this ID
+  unmatched: dave (4), not in the maintainer file
+"
+        );
+        assert_eq!(
+            serde_json::to_value(&summary).unwrap(),
+            serde_json::json!({
+                "dryRun": true,
+                "additions": 2,
+                "removals": 1,
+                "keeps": 10,
+                "noops": 13,
+                "errors": 0,
+                "idMismatches": 1,
+                "limitHit": true,
+                "unmatched": [
+                    {"githubName": "alice", "githubId": 1, "listedBy": "alice"},
+                    {"githubName": "dave", "githubId": 4, "listedBy": null},
+                ],
+            })
         );
     }
+
+    #[test]
+    fn test_webhook_payload() {
+        let summary = Summary {
+            dry_run: false,
+            additions: 2,
+            removals: 1,
+            keeps: 10,
+            noops: 13,
+            errors: 1,
+            id_mismatches: 0,
+            limit_hit: false,
+            unmatched: vec![],
+        };
+
+        let message = "rfc39 synced NixOS/maintainers: 2 added, 1 removed, 13 noops, 1 errors";
+        assert_eq!(
+            webhook_payload("NixOS", "maintainers", &summary),
+            serde_json::json!({
+                "text": message,
+                "content": message,
+                "dryRun": false,
+                "additions": 2,
+                "removals": 1,
+                "noops": 13,
+                "errors": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_format_teams() {
+        let rows = vec![TeamRow {
+            id: 10,
+            slug: "nixpkgs-maintainers".into(),
+            name: "Nixpkgs Maintainers".into(),
+            members: None,
+        }];
+
+        assert_eq!(
+            format_teams(&rows, &TeamFormat::Text).unwrap(),
+            format!(
+                "{:10} {:30} {}\n",
+                10, "nixpkgs-maintainers", "Nixpkgs Maintainers"
+            )
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(
+                &format_teams(&rows, &TeamFormat::Json).unwrap()
+            )
+            .unwrap(),
+            serde_json::json!([{
+                "id": 10,
+                "slug": "nixpkgs-maintainers",
+                "name": "Nixpkgs Maintainers",
+            }])
+        );
+
+        let counted = vec![TeamRow {
+            members: Some(3),
+            ..rows.into_iter().next().unwrap()
+        }];
+        assert!(format_teams(&counted, &TeamFormat::Text)
+            .unwrap()
+            .contains("      3 Nixpkgs Maintainers"));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(
+                &format_teams(&counted, &TeamFormat::Json).unwrap()
+            )
+            .unwrap()[0]["members"],
+            3
+        );
+    }
+
+    #[test]
+    fn test_team_id_for_slug() {
+        let logger = crate::test_logger();
+        let teams = vec![Team {
+            id: 10,
+            name: "Nixpkgs Maintainers".into(),
+            slug: "nixpkgs-maintainers".into(),
+        }];
+
+        assert_eq!(
+            team_id_for_slug(&logger, "NixOS", "nixpkgs-maintainers", &teams).unwrap(),
+            10
+        );
+        match team_id_for_slug(&logger, "NixOS", "Nixpkgs Maintainers", &teams) {
+            Err(ExitError::UnknownTeamSlug { organization, slug }) => {
+                assert_eq!(organization, "NixOS");
+                assert_eq!(slug, "Nixpkgs Maintainers");
+            }
+            other => panic!("expected an unknown slug, got {:?}", other),
+        }
+        match resolve_team_id(&logger, "NixOS", None, None, &teams) {
+            Err(ExitError::InvalidArguments(_)) => {}
+            other => panic!("expected invalid arguments, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_github_failed() {
+        match github_failed(
+            &crate::test_logger(),
+            "NixOS",
+            "Failed to list the organization's teams",
+            GitHubError::fake("server error"),
+        ) {
+            ExitError::GitHub(what) => {
+                assert_eq!(what, "Failed to list the organization's teams")
+            }
+            other => panic!("expected a GitHub failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_team_not_found() {
+        match team_not_found(
+            &crate::test_logger(),
+            "NixOS",
+            4,
+            "Failed to fetch team",
+            GitHubError::fake("no team 4"),
+        ) {
+            ExitError::TeamNotFound {
+                organization,
+                team_id,
+            } => {
+                assert_eq!(organization, "NixOS");
+                assert_eq!(team_id, 4);
+            }
+            other => panic!("expected team not found, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_id_mismatch_blocks_are_tracked_separately() {
+        let logger = crate::test_logger();
+        let add_blocked = IntGauge::new("add_blocked", "add blocked").unwrap();
+        let remove_blocked = IntGauge::new("remove_blocked", "remove blocked").unwrap();
+
+        assert!(id_still_matches(
+            &logger,
+            GitHubID::new(1),
+            GitHubID::new(1),
+            &add_blocked,
+            "adding"
+        ));
+        assert!(!id_still_matches(
+            &logger,
+            GitHubID::new(1),
+            GitHubID::new(2),
+            &add_blocked,
+            "adding"
+        ));
+        assert!(!id_still_matches(
+            &logger,
+            GitHubID::new(3),
+            GitHubID::new(4),
+            &remove_blocked,
+            "removing"
+        ));
+        assert!(!id_still_matches(
+            &logger,
+            GitHubID::new(5),
+            GitHubID::new(6),
+            &remove_blocked,
+            "removing"
+        ));
+
+        assert_eq!(add_blocked.get(), 1);
+        assert_eq!(remove_blocked.get(), 2);
+    }
 }
@@ -0,0 +1,122 @@
+//! The additions and removals a sync-team dry run decided on, written
+//! out for review so a later run can carry out exactly those.
+
+use crate::cli::ExitError;
+use crate::maintainers::{GitHubID, GitHubName, Handle};
+use std::fs::File;
+use std::path::Path;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Plan {
+    pub organization: String,
+    pub team_id: u64,
+    /// In the order the dry run would have made them.
+    pub actions: Vec<PlannedAction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "camelCase")]
+pub enum PlannedAction {
+    #[serde(rename_all = "camelCase")]
+    Add {
+        github_name: GitHubName,
+        github_id: GitHubID,
+        handle: Handle,
+    },
+    #[serde(rename_all = "camelCase")]
+    Remove {
+        github_name: GitHubName,
+        github_id: GitHubID,
+        handle: Option<Handle>,
+    },
+}
+
+impl PlannedAction {
+    pub fn github_name(&self) -> &GitHubName {
+        match self {
+            PlannedAction::Add { github_name, .. } | PlannedAction::Remove { github_name, .. } => {
+                github_name
+            }
+        }
+    }
+
+    pub fn github_id(&self) -> GitHubID {
+        match self {
+            PlannedAction::Add { github_id, .. } | PlannedAction::Remove { github_id, .. } => {
+                *github_id
+            }
+        }
+    }
+}
+
+impl Plan {
+    pub fn load(logger: &slog::Logger, path: &Path) -> Result<Plan, ExitError> {
+        let file = File::open(path).map_err(|err| {
+            error!(logger, "Failed to open plan file {:?}: {:?}", path, err);
+            err
+        })?;
+        let plan: Plan = serde_json::from_reader(file).map_err(|err| {
+            error!(logger, "Failed to parse plan file {:?}: {:?}", path, err);
+            err
+        })?;
+
+        info!(logger, "Loaded the sync plan";
+              "organization" => &plan.organization,
+              "team_id" => plan.team_id,
+              "actions" => plan.actions.len(),
+        );
+        Ok(plan)
+    }
+
+    pub fn save(&self, logger: &slog::Logger, path: &Path) -> Result<(), ExitError> {
+        let file = File::create(path).map_err(|err| {
+            error!(logger, "Failed to create plan file {:?}: {:?}", path, err);
+            err
+        })?;
+        serde_json::to_writer_pretty(file, self)?;
+
+        info!(logger, "Wrote the sync plan";
+              "path" => path.display(),
+              "actions" => self.actions.len(),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("plan.json");
+        let plan = Plan {
+            organization: "NixOS".into(),
+            team_id: 10,
+            actions: vec![
+                PlannedAction::Add {
+                    github_name: GitHubName::new("alice"),
+                    github_id: GitHubID::new(1),
+                    handle: Handle::new("alice"),
+                },
+                PlannedAction::Remove {
+                    github_name: GitHubName::new("carol"),
+                    github_id: GitHubID::new(3),
+                    handle: None,
+                },
+            ],
+        };
+
+        plan.save(&crate::test_logger(), &path).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["teamId"], 10);
+        assert_eq!(written["actions"][0]["action"], "add");
+        assert_eq!(written["actions"][0]["githubName"], "alice");
+        assert_eq!(written["actions"][1]["action"], "remove");
+
+        assert_eq!(Plan::load(&crate::test_logger(), &path).unwrap(), plan);
+    }
+}
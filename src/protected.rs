@@ -0,0 +1,78 @@
+//! Team members sync-team must never remove, like bots and admins
+//! added to the team by hand.
+//!
+//! One GitHub login or numeric GitHub ID per line. Blank lines and
+//! lines starting with `#` are ignored.
+
+use crate::cli::ExitError;
+use crate::maintainers::{GitHubID, GitHubName};
+use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct Protected {
+    ids: HashSet<GitHubID>,
+    names: HashSet<GitHubName>,
+}
+
+impl Protected {
+    pub fn load(logger: &slog::Logger, path: &Path) -> Result<Protected, ExitError> {
+        let contents = read_to_string(path).map_err(|err| {
+            error!(
+                logger,
+                "Failed to read protected list file {:?}: {:?}", path, err
+            );
+            err
+        })?;
+
+        let protected = Protected::parse(&contents);
+        info!(logger, "Loaded protected team members";
+              "ids" => protected.ids.len(),
+              "names" => protected.names.len(),
+        );
+
+        Ok(protected)
+    }
+
+    fn parse(contents: &str) -> Protected {
+        let mut protected = Protected::default();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.parse() {
+                Ok(id) => protected.ids.insert(GitHubID::new(id)),
+                Err(_) => protected.names.insert(GitHubName::new(line)),
+            };
+        }
+
+        protected
+    }
+
+    pub fn contains(&self, github_name: &GitHubName, github_id: &GitHubID) -> bool {
+        self.ids.contains(github_id) || self.names.contains(github_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let protected = Protected::parse(
+            "# the org's bots
+nixos-bot
+  1234
+
+",
+        );
+
+        assert!(protected.contains(&GitHubName::new("NixOS-Bot"), &GitHubID::new(1)));
+        assert!(protected.contains(&GitHubName::new("alice"), &GitHubID::new(1234)));
+        assert!(!protected.contains(&GitHubName::new("alice"), &GitHubID::new(1)));
+        assert!(!protected.contains(&GitHubName::new("# the org's bots"), &GitHubID::new(1)));
+    }
+}
@@ -0,0 +1,87 @@
+//! The standard HTTPS_PROXY and NO_PROXY environment variables.
+
+use hyper::Uri;
+use reqwest::r#async::Client;
+
+/// An HTTP client for `host`, which goes through the environment's
+/// proxy if one applies to it.
+pub fn client(logger: &slog::Logger, host: &str) -> Result<Client, String> {
+    let mut builder = Client::builder();
+    if let Some(proxy_uri) = for_host(host) {
+        // The proxy's URI may carry credentials.
+        debug!(logger, "Connecting through a proxy";
+               "host" => host,
+               "proxy" => proxy_uri.host().unwrap_or_default(),
+        );
+        let proxy = reqwest::Proxy::https(&proxy_uri.to_string())
+            .map_err(|e| format!("setting up the proxy failed: {}", e))?;
+        builder = builder.proxy(proxy);
+    }
+    builder
+        .build()
+        .map_err(|e| format!("setting up the HTTP client failed: {}", e))
+}
+
+/// The proxy to reach `host` through, if the environment sets one
+/// which applies to it.
+pub fn for_host(host: &str) -> Option<Uri> {
+    let var = |name: &str| {
+        std::env::var(name)
+            .or_else(|_| std::env::var(name.to_lowercase()))
+            .ok()
+    };
+
+    proxy_for(host, var("HTTPS_PROXY"), var("NO_PROXY"))
+}
+
+fn proxy_for(host: &str, https_proxy: Option<String>, no_proxy: Option<String>) -> Option<Uri> {
+    let https_proxy = https_proxy.filter(|proxy| !proxy.is_empty())?;
+
+    let bypassed = no_proxy.map_or(false, |no_proxy| {
+        no_proxy.split(',').map(str::trim).any(|pattern| {
+            let domain = pattern.trim_start_matches('.');
+            pattern == "*"
+                || (!domain.is_empty()
+                    && (host == domain || host.ends_with(&format!(".{}", domain))))
+        })
+    });
+    if bypassed {
+        return None;
+    }
+
+    https_proxy.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proxy_for;
+
+    fn proxy(no_proxy: Option<&str>) -> Option<String> {
+        proxy_for(
+            "api.github.com",
+            Some("http://proxy.example.com:3128".into()),
+            no_proxy.map(String::from),
+        )
+        .map(|uri| uri.to_string())
+    }
+
+    #[test]
+    fn test_proxy_for() {
+        assert_eq!(proxy(None), Some("http://proxy.example.com:3128/".into()));
+        assert_eq!(
+            proxy(Some("localhost, example.com")),
+            Some("http://proxy.example.com:3128/".into())
+        );
+        assert_eq!(proxy(Some("localhost,github.com")), None);
+        assert_eq!(proxy(Some(".github.com")), None);
+        assert_eq!(proxy(Some("api.github.com")), None);
+        assert_eq!(proxy(Some("*")), None);
+        assert_eq!(
+            proxy(Some("hub.com")),
+            Some("http://proxy.example.com:3128/".into())
+        );
+
+        assert_eq!(proxy_for("api.github.com", None, None), None);
+        assert_eq!(proxy_for("api.github.com", Some("".into()), None), None);
+    }
+}
@@ -0,0 +1,353 @@
+//! Look up GitHub users by name, remembering the answer for the rest
+//! of the run so ops which check the same accounts don't ask GitHub
+//! twice.
+
+use crate::cli::ExitError;
+use crate::github::{GitHubApi, User};
+use crate::maintainers::{GitHubID, GitHubName};
+use futures::future::Future;
+use futures::stream::{self, Stream};
+use prometheus::IntCounter;
+use std::collections::{HashMap, HashSet};
+use tokio::runtime::Runtime;
+
+type Lookup = Box<dyn Future<Item = Answer, Error = ()> + Send>;
+
+/// What GitHub said about a name.
+enum Answer {
+    Found(User),
+    /// There's no account by that name, any more.
+    NotFound,
+    Failed,
+}
+
+pub struct UserResolver {
+    lookup: Box<dyn Fn(&GitHubName) -> Lookup>,
+    cache: HashMap<GitHubName, Answer>,
+    /// Every user found so far, by ID.
+    by_id: HashMap<GitHubID, User>,
+    cache_hits: IntCounter,
+}
+
+impl UserResolver {
+    pub fn new(
+        logger: slog::Logger,
+        github: impl GitHubApi,
+        cache_hits: IntCounter,
+    ) -> UserResolver {
+        UserResolver::from_lookup(cache_hits, move |github_name| {
+            debug!(logger, "Getting user";
+                   "github_account" => %github_name,
+            );
+
+            let logger = logger.clone();
+            let github_name = github_name.clone();
+            Box::new(github.get_user(&github_name).then(move |user| match user {
+                Ok(user) => Ok(Answer::Found(user)),
+                Err(ref e) if e.is_not_found() => {
+                    warn!(logger, "GitHub account does not exist";
+                          "github_account" => %github_name);
+                    Ok(Answer::NotFound)
+                }
+                Err(e) => {
+                    warn!(logger, "Error fetching user";
+                          "github_account" => %github_name,
+                          "e" => %e);
+                    Ok(Answer::Failed)
+                }
+            }))
+        })
+    }
+
+    fn from_lookup<F>(cache_hits: IntCounter, lookup: F) -> UserResolver
+    where
+        F: Fn(&GitHubName) -> Lookup + 'static,
+    {
+        UserResolver {
+            lookup: Box::new(lookup),
+            cache: HashMap::new(),
+            by_id: HashMap::new(),
+            cache_hits,
+        }
+    }
+
+    fn insert(&mut self, github_name: GitHubName, answer: Answer) {
+        if let Answer::Found(user) = &answer {
+            self.by_id.insert(user.id, user.clone());
+        }
+        self.cache.insert(github_name, answer);
+    }
+
+    /// Fetch the user, or `None` if GitHub could not provide it. Failed
+    /// lookups are cached too.
+    pub fn get(&mut self, rt: &mut Runtime, github_name: &GitHubName) -> Option<&User> {
+        if self.cache.contains_key(github_name) {
+            self.cache_hits.inc();
+        } else {
+            let answer = rt
+                .block_on((self.lookup)(github_name))
+                .unwrap_or(Answer::Failed);
+            self.insert(github_name.clone(), answer);
+        }
+
+        match &self.cache[github_name] {
+            Answer::Found(user) => Some(user),
+            Answer::NotFound | Answer::Failed => None,
+        }
+    }
+
+    /// A user an earlier lookup found, by ID. Never asks GitHub.
+    pub fn get_by_id(&self, github_id: GitHubID) -> Option<&User> {
+        let user = self.by_id.get(&github_id);
+        if user.is_some() {
+            self.cache_hits.inc();
+        }
+        user
+    }
+
+    /// Remember a user learned about some other way, so later lookups
+    /// by name or ID don't ask GitHub for them.
+    pub fn remember(&mut self, user: User) {
+        self.insert(user.login.clone(), Answer::Found(user));
+    }
+
+    /// Whether an earlier lookup found GitHub has no account by this
+    /// name, as opposed to failing to ask.
+    pub fn is_missing(&self, github_name: &GitHubName) -> bool {
+        match self.cache.get(github_name) {
+            Some(Answer::NotFound) => true,
+            _ => false,
+        }
+    }
+
+    /// Look up every user not already cached, with up to `concurrency`
+    /// lookups in flight at once, so later `get`s are answered from the
+    /// cache.
+    pub fn prefetch<I>(&mut self, rt: &mut Runtime, github_names: I, concurrency: usize)
+    where
+        I: IntoIterator<Item = GitHubName>,
+    {
+        let mut seen = HashSet::new();
+        let cache = &self.cache;
+        let cache_hits = &self.cache_hits;
+        let lookups: Vec<_> = github_names
+            .into_iter()
+            .filter(|github_name| {
+                let cached = cache.contains_key(github_name);
+                if cached {
+                    cache_hits.inc();
+                }
+                !cached
+            })
+            .filter(|github_name| seen.insert(github_name.clone()))
+            .map(|github_name| (self.lookup)(&github_name).map(move |user| (github_name, user)))
+            .collect();
+
+        let found: Vec<(GitHubName, Answer)> = rt
+            .block_on(
+                stream::iter_ok(lookups)
+                    .buffer_unordered(concurrency.max(1))
+                    .collect(),
+            )
+            .unwrap_or_default();
+        for (github_name, answer) in found {
+            self.insert(github_name, answer);
+        }
+    }
+
+    /// The ID each name has now, or `None` if there's no account by
+    /// that name. Names whose lookup failed are left out.
+    pub fn ids_by_login(
+        &mut self,
+        rt: &mut Runtime,
+        github_names: &[GitHubName],
+        concurrency: usize,
+    ) -> HashMap<GitHubName, Option<GitHubID>> {
+        self.prefetch(rt, github_names.iter().cloned(), concurrency);
+        github_names
+            .iter()
+            .filter_map(|github_name| match self.cache.get(github_name) {
+                Some(Answer::Found(user)) => Some((github_name.clone(), Some(user.id))),
+                Some(Answer::NotFound) => Some((github_name.clone(), None)),
+                Some(Answer::Failed) | None => None,
+            })
+            .collect()
+    }
+
+    /// The login each ID has now. IDs an earlier lookup found are
+    /// answered from the cache, and `fetch` is asked about the rest.
+    pub fn logins_by_id<F>(
+        &mut self,
+        github_ids: &[GitHubID],
+        fetch: F,
+    ) -> Result<HashMap<GitHubID, GitHubName>, ExitError>
+    where
+        F: FnOnce(&[GitHubID]) -> Result<HashMap<GitHubID, GitHubName>, ExitError>,
+    {
+        let mut logins = HashMap::new();
+        let mut uncached = vec![];
+        for &github_id in github_ids {
+            match self.get_by_id(github_id) {
+                Some(user) => {
+                    logins.insert(github_id, user.login.clone());
+                }
+                None => uncached.push(github_id),
+            }
+        }
+
+        if !uncached.is_empty() {
+            for (github_id, login) in fetch(&uncached)? {
+                self.remember(User {
+                    login: login.clone(),
+                    id: github_id,
+                });
+                logins.insert(github_id, login);
+            }
+        }
+        Ok(logins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use futures::future;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn counting_resolver() -> (Arc<AtomicUsize>, UserResolver) {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let counter = lookups.clone();
+        let cache_hits = Metrics::unregistered().github.user_cache_hits;
+        let resolver = UserResolver::from_lookup(cache_hits, move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Box::new(future::ok(Answer::Failed))
+        });
+
+        (lookups, resolver)
+    }
+
+    #[test]
+    fn test_repeated_lookup_is_cached() {
+        let mut rt = Runtime::new().unwrap();
+        let (lookups, mut resolver) = counting_resolver();
+
+        assert!(resolver.get(&mut rt, &GitHubName::new("alice")).is_none());
+        assert!(resolver.get(&mut rt, &GitHubName::new("alice")).is_none());
+        assert!(resolver.get(&mut rt, &GitHubName::new("Alice")).is_none());
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        assert!(resolver.get(&mut rt, &GitHubName::new("bob")).is_none());
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_prefetch_fills_the_cache() {
+        let mut rt = Runtime::new().unwrap();
+        let (lookups, mut resolver) = counting_resolver();
+
+        resolver.get(&mut rt, &GitHubName::new("alice"));
+        resolver.prefetch(
+            &mut rt,
+            vec![
+                GitHubName::new("alice"),
+                GitHubName::new("bob"),
+                GitHubName::new("Bob"),
+                GitHubName::new("carol"),
+            ],
+            2,
+        );
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+
+        resolver.get(&mut rt, &GitHubName::new("carol"));
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_missing_is_not_a_failure() {
+        let mut rt = Runtime::new().unwrap();
+        let cache_hits = Metrics::unregistered().github.user_cache_hits;
+        let mut resolver = UserResolver::from_lookup(cache_hits, |name| {
+            if name == &GitHubName::new("gone") {
+                Box::new(future::ok(Answer::NotFound))
+            } else {
+                Box::new(future::ok(Answer::Failed))
+            }
+        });
+
+        assert!(resolver.get(&mut rt, &GitHubName::new("gone")).is_none());
+        assert!(resolver.get(&mut rt, &GitHubName::new("flaky")).is_none());
+        assert!(resolver.is_missing(&GitHubName::new("Gone")));
+        assert!(!resolver.is_missing(&GitHubName::new("flaky")));
+        assert!(!resolver.is_missing(&GitHubName::new("unasked")));
+
+        let ids = resolver.ids_by_login(
+            &mut rt,
+            &[GitHubName::new("gone"), GitHubName::new("flaky")],
+            1,
+        );
+        assert_eq!(ids.get(&GitHubName::new("gone")), Some(&None));
+        assert!(!ids.contains_key(&GitHubName::new("flaky")));
+    }
+
+    #[test]
+    fn test_found_users_are_indexed_by_id() {
+        let mut rt = Runtime::new().unwrap();
+        let cache_hits = Metrics::unregistered().github.user_cache_hits;
+        let mut resolver = UserResolver::from_lookup(cache_hits.clone(), |name| {
+            Box::new(future::ok(Answer::Found(User {
+                login: name.clone(),
+                id: GitHubID::new(1),
+            })))
+        });
+
+        assert!(resolver.get_by_id(GitHubID::new(1)).is_none());
+        resolver.get(&mut rt, &GitHubName::new("alice"));
+        assert_eq!(
+            resolver.get_by_id(GitHubID::new(1)).unwrap().login,
+            GitHubName::new("alice")
+        );
+
+        resolver.remember(User {
+            login: GitHubName::new("bob"),
+            id: GitHubID::new(2),
+        });
+        assert!(resolver.get_by_id(GitHubID::new(2)).is_some());
+        assert!(resolver.get(&mut rt, &GitHubName::new("bob")).is_some());
+        assert_eq!(cache_hits.get(), 3);
+    }
+
+    #[test]
+    fn test_logins_by_id_only_fetches_unknown_ids() {
+        let mut rt = Runtime::new().unwrap();
+        let cache_hits = Metrics::unregistered().github.user_cache_hits;
+        let mut resolver = UserResolver::from_lookup(cache_hits, |name| {
+            Box::new(future::ok(Answer::Found(User {
+                login: name.clone(),
+                id: GitHubID::new(1),
+            })))
+        });
+
+        let ids = resolver.ids_by_login(&mut rt, &[GitHubName::new("alice")], 1);
+        assert_eq!(ids[&GitHubName::new("alice")], Some(GitHubID::new(1)));
+
+        let logins = resolver
+            .logins_by_id(&[GitHubID::new(1), GitHubID::new(2)], |ids| {
+                assert_eq!(ids, &[GitHubID::new(2)]);
+                Ok(vec![(GitHubID::new(2), GitHubName::new("bob"))]
+                    .into_iter()
+                    .collect())
+            })
+            .unwrap();
+        assert_eq!(logins[&GitHubID::new(1)], GitHubName::new("alice"));
+        assert_eq!(logins[&GitHubID::new(2)], GitHubName::new("bob"));
+
+        // Both are known now, so nothing is fetched.
+        resolver
+            .logins_by_id(&[GitHubID::new(1), GitHubID::new(2)], |_| {
+                panic!("should be cached")
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+//! Chat incoming webhooks, as Slack and Discord offer them.
+
+use crate::credentials::Secret;
+use crate::proxy;
+use futures::future::Future;
+use futures::stream::Stream;
+use hyper::Uri;
+use reqwest::header::USER_AGENT;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::timer::Timeout;
+
+/// A chat service that doesn't answer by then won't.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// POST `payload` as JSON. The URL carries the webhook's token, so
+/// only its host is ever logged.
+pub fn post(
+    logger: &slog::Logger,
+    rt: &mut Runtime,
+    url: &Secret<Uri>,
+    payload: &serde_json::Value,
+) -> Result<(), String> {
+    let host = url.expose().host().unwrap_or_default().to_string();
+    let request = proxy::client(logger, &host)?
+        .post(url.expose().to_string().as_str())
+        .header(USER_AGENT, "NixOS/rfcs#39")
+        .json(payload);
+
+    debug!(logger, "Posting to the webhook";
+           "host" => &host,
+    );
+    let response = request.send().and_then(|response| {
+        let status = response.status();
+        // Drain the body so the connection closes cleanly.
+        response
+            .into_body()
+            .for_each(|_| Ok(()))
+            .map(move |_| status)
+    });
+    let status =
+        rt.block_on(Timeout::new(response, TIMEOUT))
+            .map_err(|e| match e.into_inner() {
+                Some(e) => redact(&e, &host),
+                None => format!("timed out after {}s", TIMEOUT.as_secs()),
+            })?;
+
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(format!("{} responded {}", host, status))
+    }
+}
+
+/// reqwest puts the URL, and so the token, in its errors.
+fn redact(error: &reqwest::Error, host: &str) -> String {
+    let message = error.to_string();
+    match error.url() {
+        Some(url) => message.replace(url.as_str(), host),
+        None => message,
+    }
+}
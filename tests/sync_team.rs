@@ -0,0 +1,279 @@
+//! `sync_team` end to end, against a local server standing in for
+//! api.github.com.
+
+use futures::future::Future;
+use hubcaps::{Credentials, Github};
+use hyper::service::service_fn_ok;
+use hyper::{Body, Request, Response, Server, StatusCode};
+use rfc39::cli::SyncTeamParams;
+use rfc39::github::{RetryPolicy, Retrying, TeamRole};
+use rfc39::metrics::Metrics;
+use rfc39::MaintainerList;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+fn user(login: &str, id: u64) -> Value {
+    let url = format!("https://api.github.com/users/{}", login);
+    json!({
+        "login": login,
+        "id": id,
+        "node_id": format!("MDQ6VXNlcj{}", id),
+        "avatar_url": format!("https://avatars.githubusercontent.com/u/{}?v=4", id),
+        "gravatar_id": "",
+        "url": url,
+        "html_url": format!("https://github.com/{}", login),
+        "followers_url": format!("{}/followers", url),
+        "following_url": format!("{}/following{{/other_user}}", url),
+        "gists_url": format!("{}/gists{{/gist_id}}", url),
+        "starred_url": format!("{}/starred{{/owner}}{{/repo}}", url),
+        "subscriptions_url": format!("{}/subscriptions", url),
+        "organizations_url": format!("{}/orgs", url),
+        "repos_url": format!("{}/repos", url),
+        "events_url": format!("{}/events{{/privacy}}", url),
+        "received_events_url": format!("{}/received_events", url),
+        "type": "User",
+        "site_admin": false,
+    })
+}
+
+/// A user as `GET /users/:login` has them, with their profile too.
+fn full_user(login: &str, id: u64) -> Value {
+    let mut user = user(login, id);
+    let profile = json!({
+        "name": null,
+        "company": null,
+        "blog": "",
+        "location": null,
+        "email": null,
+        "hireable": null,
+        "bio": null,
+        "public_repos": 0,
+        "public_gists": 0,
+        "followers": 0,
+        "following": 0,
+        "created_at": "2019-08-08T18:13:15Z",
+        "updated_at": "2019-08-08T18:13:15Z",
+    });
+    for (key, value) in profile.as_object().unwrap() {
+        user[key] = value.clone();
+    }
+    user
+}
+
+fn team() -> Value {
+    json!({
+        "id": 10,
+        "node_id": "MDQ6VGVhbTEw",
+        "url": "https://api.github.com/teams/10",
+        "html_url": "https://github.com/orgs/NixOS/teams/maintainers",
+        "name": "maintainers",
+        "slug": "maintainers",
+        "description": "Nixpkgs maintainers",
+        "privacy": "closed",
+        "permission": "pull",
+        "members_url": "https://api.github.com/teams/10/members{/member}",
+        "repositories_url": "https://api.github.com/teams/10/repos",
+        "parent": null,
+    })
+}
+
+fn invitation(login: &str, id: u64) -> Value {
+    json!({
+        "id": id,
+        "node_id": format!("MDIyOk9yZ2FuaXphdGlvbkludml0YXRpb24{}", id),
+        "login": login,
+        "email": null,
+        "role": "direct_member",
+        "created_at": "2019-08-08T18:13:15Z",
+        "failed_at": null,
+        "failed_reason": null,
+        "inviter": user("rfc39", 100),
+        "team_count": 1,
+        "invitation_team_url": format!("https://api.github.com/organizations/1/invitations/{}/teams", id),
+    })
+}
+
+fn org_membership(login: &str, id: u64) -> Value {
+    json!({
+        "url": format!("https://api.github.com/orgs/NixOS/memberships/{}", login),
+        "state": "active",
+        "role": "member",
+        "organization_url": "https://api.github.com/orgs/NixOS",
+        "organization": {
+            "login": "NixOS",
+            "id": 487568,
+            "node_id": "MDEyOk9yZ2FuaXphdGlvbjQ4NzU2OA==",
+            "url": "https://api.github.com/orgs/NixOS",
+            "repos_url": "https://api.github.com/orgs/NixOS/repos",
+            "events_url": "https://api.github.com/orgs/NixOS/events",
+            "hooks_url": "https://api.github.com/orgs/NixOS/hooks",
+            "issues_url": "https://api.github.com/orgs/NixOS/issues",
+            "members_url": "https://api.github.com/orgs/NixOS/members{/member}",
+            "public_members_url": "https://api.github.com/orgs/NixOS/public_members{/member}",
+            "avatar_url": "https://avatars.githubusercontent.com/u/487568?v=4",
+            "description": "",
+        },
+        "user": user(login, id),
+    })
+}
+
+fn team_membership(login: &str) -> Value {
+    json!({
+        "url": format!("https://api.github.com/teams/10/memberships/{}", login),
+        "role": "member",
+        "state": "pending",
+    })
+}
+
+/// The team has bob and carol, and dave has a pending invitation.
+fn respond(method: &str, path: &str) -> (StatusCode, Option<Value>) {
+    let ids = [
+        ("alice", 1u64),
+        ("bob", 2),
+        ("carol", 3),
+        ("dave", 4),
+        ("erin", 5),
+    ];
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let known = |login: &str| ids.iter().find(|(name, _)| *name == login).cloned();
+
+    match (method, segments.as_slice()) {
+        ("GET", ["orgs", "NixOS", "teams"]) => (StatusCode::OK, Some(json!([team()]))),
+        ("GET", ["teams", "10"]) => (StatusCode::OK, Some(team())),
+        ("GET", ["teams", "10", "members"]) => (
+            StatusCode::OK,
+            Some(json!([user("bob", 2), user("carol", 3)])),
+        ),
+        ("GET", ["orgs", "NixOS", "invitations"]) => {
+            (StatusCode::OK, Some(json!([invitation("dave", 40)])))
+        }
+        ("GET", ["users", login]) => match known(login) {
+            Some((login, id)) => (StatusCode::OK, Some(full_user(login, id))),
+            None => (StatusCode::NOT_FOUND, Some(json!({"message": "Not Found"}))),
+        },
+        ("GET", ["orgs", "NixOS", "memberships", login]) => match known(login) {
+            Some((login, id)) => (StatusCode::OK, Some(org_membership(login, id))),
+            None => (StatusCode::NOT_FOUND, Some(json!({"message": "Not Found"}))),
+        },
+        ("PUT", ["teams", "10", "memberships", login]) => {
+            (StatusCode::OK, Some(team_membership(login)))
+        }
+        ("DELETE", ["teams", "10", "memberships", _]) => (StatusCode::NO_CONTENT, None),
+        _ => (StatusCode::NOT_FOUND, Some(json!({"message": "Not Found"}))),
+    }
+}
+
+/// Serve `respond` on a free local port, recording each request as
+/// "METHOD /path".
+fn mock_github() -> (String, Arc<Mutex<Vec<String>>>) {
+    let requests = Arc::new(Mutex::new(vec![]));
+    let recorded = requests.clone();
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+        let recorded = recorded.clone();
+        service_fn_ok(move |request: Request<Body>| {
+            let path = request.uri().path().to_string();
+            recorded
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", request.method(), path));
+
+            let (status, body) = respond(request.method().as_str(), &path);
+            Response::builder()
+                .status(status)
+                .header("Content-Type", "application/json")
+                .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+                .unwrap()
+        })
+    });
+    let host = format!("http://{}", server.local_addr());
+    std::thread::spawn(move || {
+        hyper::rt::run(server.map_err(|e| eprintln!("Mock GitHub error: {}", e)))
+    });
+    (host, requests)
+}
+
+#[test]
+fn test_sync_team_against_mock_github() {
+    let logger = rfc39::test_logger();
+    let (host, requests) = mock_github();
+    let metrics = Metrics::unregistered();
+    let github = Retrying::new(
+        logger.clone(),
+        Github::host(
+            host,
+            "NixOS/rfcs#39 (tests)",
+            Credentials::Token("token".to_string()),
+        )
+        .unwrap(),
+        RetryPolicy::new(0),
+        metrics.github.retry.clone(),
+    );
+    let maintainers = MaintainerList::load_json(Path::new("samples/sync-team.json")).unwrap();
+    let tmpdir = tempfile::tempdir().unwrap();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+    rfc39::sync_team(
+        logger,
+        &github,
+        &mut rt,
+        &metrics,
+        maintainers,
+        SyncTeamParams {
+            organization: "NixOS".into(),
+            team_id: None,
+            team_slug: Some("maintainers".into()),
+            dry_run: false,
+            role: TeamRole::Member,
+            no_add: false,
+            no_remove: false,
+            remove_owners: false,
+            limit: None,
+            add_limit: None,
+            remove_limit: None,
+            invited_list: tmpdir.path().join("invited.txt"),
+            reinvite_after: None,
+            protect: None,
+            audit_log: None,
+            summary: None,
+            checkpoint_file: None,
+            notify_issue: None,
+            notified_list: None,
+            removal_grace_days: 7,
+            webhook: None,
+            plan_out: None,
+            plan_in: None,
+        },
+    )
+    .unwrap();
+
+    // Additions run concurrently, so only what changed is compared,
+    // not in which order.
+    let mut changes: Vec<String> = requests
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|request| !request.starts_with("GET "))
+        .cloned()
+        .collect();
+    changes.sort();
+    // alice and erin are added, bob stays, dave's invitation is still
+    // pending, and carol isn't a maintainer.
+    assert_eq!(
+        changes,
+        vec![
+            "DELETE /teams/10/memberships/carol",
+            "PUT /teams/10/memberships/alice",
+            "PUT /teams/10/memberships/erin",
+        ]
+    );
+
+    let requests = requests.lock().unwrap();
+    // Everyone is looked up again right before being changed.
+    for login in &["alice", "erin", "carol"] {
+        assert!(requests.contains(&format!("GET /users/{}", login)));
+    }
+    assert!(requests.contains(&"GET /orgs/NixOS/memberships/carol".to_string()));
+    assert!(!requests.iter().any(|request| request.ends_with("/bob")));
+    assert!(!requests.iter().any(|request| request.ends_with("/dave")));
+}